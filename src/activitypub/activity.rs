@@ -0,0 +1,215 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Deserializer};
+use serde_json::Value;
+
+pub const CONTEXT: &'static str = "https://www.w3.org/ns/activitystreams";
+pub const PUBLIC: &'static str = "https://www.w3.org/ns/activitystreams#Public";
+pub const ACTIVITY_JSON: &'static str = "application/activity+json";
+
+/// An ActivityStreams 2.0 activity, such as `Create` or `Follow`
+///
+/// Only the fields this server acts on are modeled, anything else in an incoming document is
+/// ignored.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Activity {
+    #[serde(rename = "@context", default, skip_serializing_if = "Option::is_none")]
+    pub context: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub actor: String,
+    pub object: ObjectRef,
+    #[serde(default, deserialize_with = "one_or_many", skip_serializing_if = "Vec::is_empty")]
+    pub to: Vec<String>,
+    #[serde(default, deserialize_with = "one_or_many", skip_serializing_if = "Vec::is_empty")]
+    pub cc: Vec<String>,
+}
+
+impl Activity {
+    pub fn new(kind: &str, actor: String, object: ObjectRef) -> Self {
+        Activity {
+            context: Some(Value::String(CONTEXT.to_owned())),
+            id: None,
+            kind: kind.to_owned(),
+            actor: actor,
+            object: object,
+            to: Vec::new(),
+            cc: Vec::new(),
+        }
+    }
+
+    /// Strips the `@context`, for activities embedded in other activities
+    pub fn embedded(mut self) -> Self {
+        self.context = None;
+        self
+    }
+}
+
+/// The `object` of an activity, either a bare IRI or an embedded document
+///
+/// Variants are tried in order, so an embedded document with `actor` and `object` fields is
+/// read as an `Activity`, and anything else as an `Object`.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum ObjectRef {
+    Iri(String),
+    Activity(Box<Activity>),
+    Object(Box<Object>),
+}
+
+impl ObjectRef {
+    /// The IRI of the referenced object, if it has one
+    pub fn id(&self) -> Option<&str> {
+        match *self {
+            ObjectRef::Iri(ref iri) => Some(iri.as_str()),
+            ObjectRef::Activity(ref activity) => activity.id.as_ref().map(|id| id.as_str()),
+            ObjectRef::Object(ref object) => Some(object.id.as_str()),
+        }
+    }
+}
+
+/// An `OrderedCollection`, such as an outbox
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Collection {
+    #[serde(rename = "@context", default, skip_serializing_if = "Option::is_none")]
+    pub context: Option<Value>,
+    pub id: String,
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub total_items: usize,
+    pub ordered_items: Vec<String>,
+}
+
+impl Collection {
+    pub fn ordered(id: String, items: Vec<String>) -> Self {
+        Collection {
+            context: Some(Value::String(CONTEXT.to_owned())),
+            id: id,
+            kind: "OrderedCollection".to_owned(),
+            total_items: items.len(),
+            ordered_items: items,
+        }
+    }
+}
+
+/// A non-activity object, such as a `Note`
+///
+/// The `id` is left empty for objects a client posts to its outbox, since the server assigns it.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Object {
+    #[serde(default)]
+    pub id: String,
+    #[serde(rename = "type")]
+    pub kind: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attributed_to: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub in_reply_to: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub summary: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content: Option<String>,
+    /// The content keyed by its language
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub content_map: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<Source>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub published: Option<String>,
+    #[serde(default, deserialize_with = "one_or_many", skip_serializing_if = "Vec::is_empty")]
+    pub to: Vec<String>,
+    #[serde(default, deserialize_with = "one_or_many", skip_serializing_if = "Vec::is_empty")]
+    pub cc: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tag: Vec<Tag>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub attachment: Vec<Document>,
+}
+
+impl Object {
+    pub fn new(kind: &str, id: String) -> Self {
+        Object {
+            id: id,
+            kind: kind.to_owned(),
+            attributed_to: None,
+            in_reply_to: None,
+            summary: None,
+            content: None,
+            content_map: BTreeMap::new(),
+            source: None,
+            published: None,
+            to: Vec::new(),
+            cc: Vec::new(),
+            tag: Vec::new(),
+            attachment: Vec::new(),
+        }
+    }
+}
+
+/// The markup an object's `content` was rendered from
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Source {
+    pub content: String,
+    pub media_type: String,
+}
+
+/// Media attached to an object, as Mastodon sends it
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Document {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub url: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub media_type: Option<String>,
+    /// The alt text
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub blurhash: Option<String>,
+}
+
+/// An entry in an object's `tag` list, such as a `Mention` or `Hashtag`
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Tag {
+    #[serde(rename = "type")]
+    pub kind: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub href: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+}
+
+impl Tag {
+    pub fn mention(href: String) -> Self {
+        Tag {
+            kind: "Mention".to_owned(),
+            href: Some(href),
+            name: None,
+        }
+    }
+}
+
+/// Addressing fields may hold a single IRI or a list of them
+fn one_or_many<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(iri) => Ok(vec![iri]),
+        OneOrMany::Many(iris) => Ok(iris),
+    }
+}
@@ -0,0 +1,75 @@
+use serde_json::Value;
+
+use actors::UserId;
+use actors::user::Profile;
+use super::{IriMap, CONTEXT};
+
+const SECURITY_CONTEXT: &'static str = "https://w3id.org/security/v1";
+
+/// The actor document for a local user
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Person {
+    #[serde(rename = "@context")]
+    pub context: Value,
+    pub id: String,
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub preferred_username: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub summary: Option<String>,
+    pub inbox: String,
+    pub outbox: String,
+    pub followers: String,
+    pub following: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub public_key: Option<PublicKey>,
+}
+
+impl Person {
+    pub fn new(iris: &IriMap, user_id: UserId, profile: Profile, public_key_pem: String) -> Self {
+        let id = iris.user_iri(user_id);
+
+        Person {
+            context: Value::Array(vec![
+                Value::String(CONTEXT.to_owned()),
+                Value::String(SECURITY_CONTEXT.to_owned()),
+            ]),
+            kind: "Person".to_owned(),
+            preferred_username: profile.username,
+            name: profile.display_name,
+            summary: profile.summary,
+            inbox: format!("{}/inbox", id),
+            outbox: format!("{}/outbox", id),
+            followers: format!("{}/followers", id),
+            following: format!("{}/following", id),
+            public_key: Some(PublicKey::new(&id, public_key_pem)),
+            id: id,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PublicKey {
+    pub id: String,
+    pub owner: String,
+    pub public_key_pem: String,
+}
+
+impl PublicKey {
+    pub fn new(owner: &str, public_key_pem: String) -> Self {
+        PublicKey {
+            id: key_id(owner),
+            owner: owner.to_owned(),
+            public_key_pem: public_key_pem,
+        }
+    }
+}
+
+/// The `keyId` an actor's signed requests carry
+pub fn key_id(actor_iri: &str) -> String {
+    format!("{}#main-key", actor_iri)
+}
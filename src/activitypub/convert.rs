@@ -0,0 +1,574 @@
+use std::collections::BTreeSet;
+
+use actors::{PostId, UserId};
+use actors::posts::{Attachment, Content, Visibility};
+use actors::posts::messages::DeletePost;
+use actors::user::messages::{AcceptFollowRequest, BlockUser, Blocked, Boost, Boosted,
+                             DenyFollowRequest, FollowRequest, FollowRequestAccepted,
+                             FollowRequestDenied, FollowerRemoved, Like, Liked, NewPostIn,
+                             NewPostOut, RemoveFollower, RequestFollow, UnblockUser, Unblocked,
+                             Unboost, Unboosted, Unfollow, Unfollowed, Unlike, Unliked};
+use super::{Activity, Document, Error, IriMap, Object, ObjectRef, Source, Tag, PUBLIC};
+
+const PLAIN_TEXT: &'static str = "text/plain";
+
+pub trait ToActivity {
+    /// Builds the activity `source` delivers to `target`'s inbox for this message
+    fn to_activity(&self, iris: &mut IriMap, source: UserId, target: UserId) -> Activity;
+}
+
+/// The inbox messages an incoming activity can be turned into
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Incoming {
+    NewPost(NewPostIn),
+    FollowRequest(FollowRequest),
+    FollowRequestAccepted(FollowRequestAccepted),
+    FollowRequestDenied(FollowRequestDenied),
+    Unfollowed(Unfollowed),
+    FollowerRemoved(FollowerRemoved),
+    Blocked(Blocked),
+    Unblocked(Unblocked),
+    DeletePost(DeletePost),
+    Boosted(Boosted),
+    Unboosted(Unboosted),
+    Liked(Liked),
+    Unliked(Unliked),
+}
+
+impl Incoming {
+    pub fn from_activity(activity: Activity, iris: &mut IriMap) -> Result<Self, Error> {
+        let Activity {
+            kind,
+            actor,
+            object,
+            ..
+        } = activity;
+        let actor = iris.user_id(&actor)?;
+
+        match kind.as_str() {
+            "Create" => new_post(object, actor, iris).map(Incoming::NewPost),
+            "Follow" => Ok(Incoming::FollowRequest(FollowRequest(actor))),
+            "Accept" => {
+                check_follow(&object)?;
+                Ok(Incoming::FollowRequestAccepted(FollowRequestAccepted(actor)))
+            }
+            "Reject" => {
+                check_follow(&object)?;
+                Ok(Incoming::FollowRequestDenied(FollowRequestDenied(actor)))
+            }
+            "Undo" if undoes("Announce", &object) => match reacted(&object, iris)? {
+                (booster, post_id) if booster == actor => {
+                    Ok(Incoming::Unboosted(Unboosted(post_id, actor)))
+                }
+                _ => Err(Error::InvalidObject),
+            },
+            "Undo" if undoes("Like", &object) => match reacted(&object, iris)? {
+                (liker, post_id) if liker == actor => {
+                    Ok(Incoming::Unliked(Unliked(post_id, actor)))
+                }
+                _ => Err(Error::InvalidObject),
+            },
+            "Undo" => match undone(&object, iris)? {
+                ("Follow", follower, _) if follower == actor => {
+                    Ok(Incoming::Unfollowed(Unfollowed(actor)))
+                }
+                ("Follow", _, followed) if followed == actor => {
+                    Ok(Incoming::FollowerRemoved(FollowerRemoved(actor)))
+                }
+                ("Block", blocker, _) if blocker == actor => {
+                    Ok(Incoming::Unblocked(Unblocked(actor)))
+                }
+                _ => Err(Error::InvalidObject),
+            },
+            "Block" => Ok(Incoming::Blocked(Blocked(actor))),
+            // Only the actor that created a remote post can delete it, and never a local one
+            "Delete" => {
+                let iri = object.id().ok_or(Error::InvalidObject)?;
+                Ok(Incoming::DeletePost(DeletePost(iris.authored_post(iri, actor)?)))
+            }
+            "Announce" => {
+                let iri = object.id().ok_or(Error::InvalidObject)?;
+                Ok(Incoming::Boosted(Boosted(iris.post_id(iri)?, actor)))
+            }
+            "Like" => {
+                let iri = object.id().ok_or(Error::InvalidObject)?;
+                Ok(Incoming::Liked(Liked(iris.post_id(iri)?, actor)))
+            }
+            kind => Err(Error::Unsupported(kind.to_owned())),
+        }
+    }
+}
+
+/// The outbox messages an activity posted by a local user can be turned into
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Outgoing {
+    NewPost(NewPostOut),
+    RequestFollow(RequestFollow),
+    AcceptFollowRequest(AcceptFollowRequest),
+    DenyFollowRequest(DenyFollowRequest),
+    Unfollow(Unfollow),
+    RemoveFollower(RemoveFollower),
+    BlockUser(BlockUser),
+    UnblockUser(UnblockUser),
+    DeletePost(DeletePost),
+    Boost(Boost),
+    Unboost(Unboost),
+    Like(Like),
+    Unlike(Unlike),
+}
+
+impl Outgoing {
+    /// Parses an activity `owner` submitted to their outbox
+    pub fn from_activity(
+        activity: Activity,
+        owner: UserId,
+        iris: &mut IriMap,
+    ) -> Result<Self, Error> {
+        let Activity {
+            kind,
+            actor,
+            object,
+            ..
+        } = activity;
+
+        if iris.user_id(&actor)? != owner {
+            return Err(Error::WrongActor(actor));
+        }
+
+        match kind.as_str() {
+            "Create" => {
+                let note = note(object)?;
+                let mentions = mentions(&note, iris)?;
+                let visibility = visibility(&note, &actor);
+                let in_reply_to = in_reply_to(&note, iris)?;
+
+                Ok(Outgoing::NewPost(NewPostOut(
+                    mentions,
+                    content(&note),
+                    visibility,
+                    in_reply_to,
+                )))
+            }
+            "Follow" => Ok(Outgoing::RequestFollow(RequestFollow(object_user(&object, iris)?))),
+            "Accept" => {
+                let follower = follower(&object, iris)?;
+                Ok(Outgoing::AcceptFollowRequest(AcceptFollowRequest(follower)))
+            }
+            "Reject" => {
+                let follower = follower(&object, iris)?;
+                Ok(Outgoing::DenyFollowRequest(DenyFollowRequest(follower)))
+            }
+            "Undo" if undoes("Announce", &object) => match reacted(&object, iris)? {
+                (booster, post_id) if booster == owner => Ok(Outgoing::Unboost(Unboost(post_id))),
+                _ => Err(Error::InvalidObject),
+            },
+            "Undo" if undoes("Like", &object) => match reacted(&object, iris)? {
+                (liker, post_id) if liker == owner => Ok(Outgoing::Unlike(Unlike(post_id))),
+                _ => Err(Error::InvalidObject),
+            },
+            "Undo" => match undone(&object, iris)? {
+                ("Follow", follower, followed) if follower == owner => {
+                    Ok(Outgoing::Unfollow(Unfollow(followed)))
+                }
+                ("Follow", follower, followed) if followed == owner => {
+                    Ok(Outgoing::RemoveFollower(RemoveFollower(follower)))
+                }
+                ("Block", blocker, blocked) if blocker == owner => {
+                    Ok(Outgoing::UnblockUser(UnblockUser(blocked)))
+                }
+                _ => Err(Error::InvalidObject),
+            },
+            "Block" => Ok(Outgoing::BlockUser(BlockUser(object_user(&object, iris)?))),
+            "Delete" => {
+                let iri = object.id().ok_or(Error::InvalidObject)?;
+                Ok(Outgoing::DeletePost(DeletePost(iris.post_id(iri)?)))
+            }
+            "Announce" => {
+                let iri = object.id().ok_or(Error::InvalidObject)?;
+                Ok(Outgoing::Boost(Boost(iris.post_id(iri)?)))
+            }
+            "Like" => {
+                let iri = object.id().ok_or(Error::InvalidObject)?;
+                Ok(Outgoing::Like(Like(iris.post_id(iri)?)))
+            }
+            kind => Err(Error::Unsupported(kind.to_owned())),
+        }
+    }
+}
+
+impl ToActivity for NewPostIn {
+    fn to_activity(&self, iris: &mut IriMap, _: UserId, target: UserId) -> Activity {
+        let NewPostIn(post_id, author, ref mentions, ref content, visibility, in_reply_to) = *self;
+
+        let author = iris.user_iri(author);
+        let mentioned: Vec<String> = mentions.iter().map(|m| iris.user_iri(*m)).collect();
+        let (to, cc) = addressing(visibility, &author, iris.user_iri(target), &mentioned);
+
+        let mut note = Object::new("Note", iris.post_iri(post_id));
+        note.attributed_to = Some(author.clone());
+        note.in_reply_to = in_reply_to.map(|parent| iris.post_iri(parent));
+        note.to = to.clone();
+        note.cc = cc.clone();
+        note.tag = mentioned.into_iter().map(Tag::mention).collect();
+        set_content(&mut note, content);
+
+        let mut activity = Activity::new("Create", author, ObjectRef::Object(Box::new(note)));
+        activity.id = Some(iris.activity_iri());
+        activity.to = to;
+        activity.cc = cc;
+
+        activity
+    }
+}
+
+impl ToActivity for FollowRequest {
+    fn to_activity(&self, iris: &mut IriMap, _: UserId, target: UserId) -> Activity {
+        follow(iris, self.0, target)
+    }
+}
+
+impl ToActivity for FollowRequestAccepted {
+    fn to_activity(&self, iris: &mut IriMap, _: UserId, target: UserId) -> Activity {
+        answer_follow("Accept", iris, self.0, target)
+    }
+}
+
+impl ToActivity for FollowRequestDenied {
+    fn to_activity(&self, iris: &mut IriMap, _: UserId, target: UserId) -> Activity {
+        answer_follow("Reject", iris, self.0, target)
+    }
+}
+
+impl ToActivity for Unfollowed {
+    fn to_activity(&self, iris: &mut IriMap, _: UserId, target: UserId) -> Activity {
+        undo_follow(iris, self.0, self.0, target)
+    }
+}
+
+impl ToActivity for FollowerRemoved {
+    fn to_activity(&self, iris: &mut IriMap, _: UserId, target: UserId) -> Activity {
+        undo_follow(iris, self.0, target, self.0)
+    }
+}
+
+impl ToActivity for Blocked {
+    fn to_activity(&self, iris: &mut IriMap, _: UserId, target: UserId) -> Activity {
+        block(iris, self.0, target)
+    }
+}
+
+impl ToActivity for Unblocked {
+    fn to_activity(&self, iris: &mut IriMap, _: UserId, target: UserId) -> Activity {
+        let block = block(iris, self.0, target).embedded();
+        let object = ObjectRef::Activity(Box::new(block));
+
+        addressed(Activity::new("Undo", iris.user_iri(self.0), object), iris, target)
+    }
+}
+
+impl ToActivity for DeletePost {
+    fn to_activity(&self, iris: &mut IriMap, source: UserId, target: UserId) -> Activity {
+        let object = ObjectRef::Iri(iris.post_iri(self.0));
+
+        addressed(Activity::new("Delete", iris.user_iri(source), object), iris, target)
+    }
+}
+
+impl ToActivity for Boosted {
+    fn to_activity(&self, iris: &mut IriMap, _: UserId, target: UserId) -> Activity {
+        react("Announce", iris, self.0, self.1, target)
+    }
+}
+
+impl ToActivity for Unboosted {
+    fn to_activity(&self, iris: &mut IriMap, _: UserId, target: UserId) -> Activity {
+        undo_reaction("Announce", iris, self.0, self.1, target)
+    }
+}
+
+impl ToActivity for Liked {
+    fn to_activity(&self, iris: &mut IriMap, _: UserId, target: UserId) -> Activity {
+        react("Like", iris, self.0, self.1, target)
+    }
+}
+
+impl ToActivity for Unliked {
+    fn to_activity(&self, iris: &mut IriMap, _: UserId, target: UserId) -> Activity {
+        undo_reaction("Like", iris, self.0, self.1, target)
+    }
+}
+
+fn addressed(mut activity: Activity, iris: &mut IriMap, target: UserId) -> Activity {
+    activity.id = Some(iris.activity_iri());
+    activity.to = vec![iris.user_iri(target)];
+    activity
+}
+
+fn follow(iris: &mut IriMap, follower: UserId, followed: UserId) -> Activity {
+    let object = ObjectRef::Iri(iris.user_iri(followed));
+
+    addressed(Activity::new("Follow", iris.user_iri(follower), object), iris, followed)
+}
+
+fn block(iris: &mut IriMap, blocker: UserId, blocked: UserId) -> Activity {
+    let object = ObjectRef::Iri(iris.user_iri(blocked));
+
+    addressed(Activity::new("Block", iris.user_iri(blocker), object), iris, blocked)
+}
+
+/// Announces and likes, which only reference the post reacted to
+fn react(kind: &str, iris: &mut IriMap, post_id: PostId, user: UserId, target: UserId) -> Activity {
+    let object = ObjectRef::Iri(iris.post_iri(post_id));
+
+    addressed(Activity::new(kind, iris.user_iri(user), object), iris, target)
+}
+
+fn undo_reaction(
+    kind: &str,
+    iris: &mut IriMap,
+    post_id: PostId,
+    user: UserId,
+    target: UserId,
+) -> Activity {
+    let reaction = react(kind, iris, post_id, user, target).embedded();
+    let object = ObjectRef::Activity(Box::new(reaction));
+
+    addressed(Activity::new("Undo", iris.user_iri(user), object), iris, target)
+}
+
+/// Accepts and rejects carry the follow they answer, rebuilt here from the two users involved
+fn answer_follow(kind: &str, iris: &mut IriMap, followed: UserId, follower: UserId) -> Activity {
+    let follow = follow(iris, follower, followed).embedded();
+    let object = ObjectRef::Activity(Box::new(follow));
+
+    addressed(Activity::new(kind, iris.user_iri(followed), object), iris, follower)
+}
+
+/// Either side of a follow can undo it, the follower to unfollow and the followed user to remove
+/// the follower
+fn undo_follow(iris: &mut IriMap, actor: UserId, follower: UserId, followed: UserId) -> Activity {
+    let follow = follow(iris, follower, followed).embedded();
+    let object = ObjectRef::Activity(Box::new(follow));
+    let target = if actor == follower { followed } else { follower };
+
+    addressed(Activity::new("Undo", iris.user_iri(actor), object), iris, target)
+}
+
+fn check_follow(object: &ObjectRef) -> Result<(), Error> {
+    match *object {
+        ObjectRef::Iri(_) => Ok(()),
+        ObjectRef::Activity(ref activity) if activity.kind == "Follow" => Ok(()),
+        _ => Err(Error::InvalidObject),
+    }
+}
+
+fn object_user(object: &ObjectRef, iris: &mut IriMap) -> Result<UserId, Error> {
+    match *object {
+        ObjectRef::Iri(ref iri) => iris.user_id(iri),
+        _ => Err(Error::InvalidObject),
+    }
+}
+
+/// The follower named by an answered follow, which must be embedded rather than referenced
+fn follower(object: &ObjectRef, iris: &mut IriMap) -> Result<UserId, Error> {
+    match *object {
+        ObjectRef::Activity(ref activity) if activity.kind == "Follow" => {
+            iris.user_id(&activity.actor)
+        }
+        _ => Err(Error::InvalidObject),
+    }
+}
+
+/// The kind, actor and object user of an undone follow or block, which must be embedded
+fn undone<'a>(
+    object: &'a ObjectRef,
+    iris: &mut IriMap,
+) -> Result<(&'a str, UserId, UserId), Error> {
+    match *object {
+        ObjectRef::Activity(ref activity)
+            if activity.kind == "Follow" || activity.kind == "Block" =>
+        {
+            let actor = iris.user_id(&activity.actor)?;
+            let user = object_user(&activity.object, iris)?;
+
+            Ok((&activity.kind, actor, user))
+        }
+        _ => Err(Error::Unsupported("Undo".to_owned())),
+    }
+}
+
+fn undoes(kind: &str, object: &ObjectRef) -> bool {
+    match *object {
+        ObjectRef::Activity(ref activity) => activity.kind == kind,
+        _ => false,
+    }
+}
+
+/// The user and post of an undone announce or like, which must be embedded
+fn reacted(object: &ObjectRef, iris: &mut IriMap) -> Result<(UserId, PostId), Error> {
+    match *object {
+        ObjectRef::Activity(ref activity) => {
+            let user = iris.user_id(&activity.actor)?;
+            let iri = activity.object.id().ok_or(Error::InvalidObject)?;
+
+            Ok((user, iris.post_id(iri)?))
+        }
+        _ => Err(Error::InvalidObject),
+    }
+}
+
+fn note(object: ObjectRef) -> Result<Box<Object>, Error> {
+    match object {
+        ObjectRef::Object(note) => Ok(note),
+        _ => Err(Error::InvalidObject),
+    }
+}
+
+fn mentions(note: &Object, iris: &mut IriMap) -> Result<BTreeSet<UserId>, Error> {
+    note.tag
+        .iter()
+        .filter(|tag| tag.kind == "Mention")
+        .filter_map(|tag| tag.href.as_ref())
+        .map(|href| iris.user_id(href))
+        .collect()
+}
+
+fn in_reply_to(note: &Object, iris: &mut IriMap) -> Result<Option<PostId>, Error> {
+    match note.in_reply_to {
+        Some(ref parent) => iris.post_id(parent).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// The `to` and `cc` of a post delivered to `target`, which tell other servers how visible it is
+fn addressing(
+    visibility: Visibility,
+    author: &str,
+    target: String,
+    mentioned: &[String],
+) -> (Vec<String>, Vec<String>) {
+    let mut to = vec![target];
+    let mut cc = mentioned.to_vec();
+
+    match visibility {
+        Visibility::Public => to.push(PUBLIC.to_owned()),
+        Visibility::Unlisted => cc.push(PUBLIC.to_owned()),
+        Visibility::FollowersOnly => cc.push(followers_iri(author)),
+        Visibility::Direct => (),
+    }
+
+    (to, cc)
+}
+
+/// Reads a note's visibility back from its addressing, the way Mastodon does
+fn visibility(note: &Object, author: &str) -> Visibility {
+    let followers = followers_iri(author);
+
+    if note.to.iter().any(|iri| is_public(iri)) {
+        Visibility::Public
+    } else if note.cc.iter().any(|iri| is_public(iri)) {
+        Visibility::Unlisted
+    } else if note.to.iter().chain(&note.cc).any(|iri| *iri == followers) {
+        Visibility::FollowersOnly
+    } else {
+        Visibility::Direct
+    }
+}
+
+/// The public collection may also be named by its compacted forms
+fn is_public(iri: &str) -> bool {
+    iri == PUBLIC || iri == "as:Public" || iri == "Public"
+}
+
+fn followers_iri(user_iri: &str) -> String {
+    format!("{}/followers", user_iri)
+}
+
+/// Reads a post's content from a note, taking its language from a single-entry `contentMap`
+fn content(note: &Object) -> Content {
+    let html = note.content
+        .clone()
+        .or_else(|| note.content_map.values().next().cloned())
+        .unwrap_or_default();
+
+    let text = note.source.as_ref().and_then(|source| {
+        if source.media_type == PLAIN_TEXT {
+            Some(source.content.clone())
+        } else {
+            None
+        }
+    });
+
+    let attachments = note.attachment
+        .iter()
+        .map(|document| Attachment {
+            url: document.url.clone(),
+            media_type: document
+                .media_type
+                .clone()
+                .unwrap_or_else(|| "application/octet-stream".to_owned()),
+            alt: document.name.clone(),
+            blurhash: document.blurhash.clone(),
+        })
+        .collect();
+
+    Content {
+        html: html,
+        text: text,
+        summary: note.summary.clone(),
+        language: note.content_map.keys().next().cloned(),
+        attachments: attachments,
+    }
+}
+
+fn set_content(note: &mut Object, content: &Content) {
+    note.content = Some(content.html.clone());
+    note.summary = content.summary.clone();
+
+    if let Some(ref language) = content.language {
+        note.content_map.insert(language.clone(), content.html.clone());
+    }
+
+    note.source = content.text.as_ref().map(|text| Source {
+        content: text.clone(),
+        media_type: PLAIN_TEXT.to_owned(),
+    });
+
+    note.attachment = content
+        .attachments
+        .iter()
+        .map(|attachment| Document {
+            kind: "Document".to_owned(),
+            url: attachment.url.clone(),
+            media_type: Some(attachment.media_type.clone()),
+            name: attachment.alt.clone(),
+            blurhash: attachment.blurhash.clone(),
+        })
+        .collect();
+}
+
+/// A post created by the activity's actor, who must also be the one it's attributed to
+fn new_post(object: ObjectRef, actor: UserId, iris: &mut IriMap) -> Result<NewPostIn, Error> {
+    let note = note(object)?;
+
+    if let Some(ref author) = note.attributed_to {
+        if iris.user_id(author)? != actor {
+            return Err(Error::NotOwned(note.id.clone()));
+        }
+    }
+
+    let author_iri = iris.user_iri(actor);
+    let post_id = iris.created_post(&note.id, actor)?;
+    let mentions = mentions(&note, iris)?;
+    let visibility = visibility(&note, &author_iri);
+    let in_reply_to = in_reply_to(&note, iris)?;
+
+    Ok(NewPostIn(
+        post_id,
+        actor,
+        mentions,
+        content(&note),
+        visibility,
+        in_reply_to,
+    ))
+}
@@ -0,0 +1,1170 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+use actors::{Clock, Id, PostId, UserId};
+use storage::Storage;
+
+mod activity;
+mod actor;
+mod convert;
+mod signature;
+
+pub use self::activity::{Activity, Collection, Document, Object, ObjectRef, Source, Tag,
+                         ACTIVITY_JSON, CONTEXT, PUBLIC};
+pub use self::actor::{key_id, Person, PublicKey};
+pub use self::convert::{Incoming, Outgoing, ToActivity};
+pub use self::signature::{digest, KeyPair, Signature, SignatureError, SIGNED_HEADERS};
+pub use actors::REMOTE_ID;
+
+/// The counters remote ids and activity IRIs are handed out from
+const REMOTE_USERS: &'static str = "remote_users";
+const REMOTE_POSTS: &'static str = "remote_posts";
+const ACTIVITIES: &'static str = "activities";
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Error {
+    /// The activity type isn't one this server handles
+    Unsupported(String),
+    /// The activity's object isn't of the expected shape
+    InvalidObject,
+    /// The IRI claims to be local but doesn't name a local user
+    InvalidIri(String),
+    /// The IRI claims to be a local post but doesn't name one
+    UnknownPost(String),
+    /// The activity was submitted to an outbox other than its actor's
+    WrongActor(String),
+    /// The object is a local one, or another actor's, which the activity's actor can't act on
+    NotOwned(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Unsupported(ref kind) => write!(f, "Unsupported activity type: {}", kind),
+            Error::InvalidObject => write!(f, "Invalid activity object"),
+            Error::InvalidIri(ref iri) => write!(f, "Invalid local IRI: {}", iri),
+            Error::UnknownPost(ref iri) => write!(f, "Unknown post: {}", iri),
+            Error::WrongActor(ref iri) => write!(f, "Activity is not by this user: {}", iri),
+            Error::NotOwned(ref iri) => write!(f, "Object is not the actor's own: {}", iri),
+        }
+    }
+}
+
+/// Maps between `UserId`s/`PostId`s and the IRIs that name them in ActivityPub documents
+///
+/// Local users are named `{base}/users/{user_id}` and local posts `{base}/posts/{post_id}`, both
+/// rebuilt from the IRI alone. Remote actors and posts are assigned ids under `REMOTE_ID` the first
+/// time they're seen, which are stored so that relations to them still hold after a restart.
+pub struct IriMap {
+    base: String,
+    remote_users: BTreeMap<String, UserId>,
+    remote_user_iris: BTreeMap<UserId, String>,
+    posts: HashMap<String, PostId>,
+    post_iris: HashMap<PostId, String>,
+    /// The actors that created remote posts, the only ones who may delete them
+    post_authors: HashMap<PostId, UserId>,
+    clock: Clock,
+    next_remote_user: u64,
+    next_remote_post: u64,
+    next_activity: u64,
+    storage: Storage,
+}
+
+impl IriMap {
+    pub fn new(base: &str, storage: Storage) -> Self {
+        let mut iris = IriMap {
+            base: base.trim_right_matches('/').to_owned(),
+            remote_users: BTreeMap::new(),
+            remote_user_iris: BTreeMap::new(),
+            posts: HashMap::new(),
+            post_iris: HashMap::new(),
+            post_authors: HashMap::new(),
+            clock: Clock::new(),
+            next_remote_user: storage.counter(REMOTE_USERS),
+            next_remote_post: storage.counter(REMOTE_POSTS),
+            next_activity: storage.counter(ACTIVITIES),
+            storage: storage,
+        };
+
+        for (iri, user_id) in iris.storage.remote_actors() {
+            iris.remote_user_iris.insert(user_id, iri.clone());
+            iris.remote_users.insert(iri, user_id);
+        }
+
+        for (iri, post_id, author) in iris.storage.remote_posts() {
+            iris.post_iris.insert(post_id, iri.clone());
+            iris.posts.insert(iri, post_id);
+
+            if let Some(author) = author {
+                iris.post_authors.insert(post_id, author);
+            }
+        }
+
+        iris
+    }
+
+    pub fn base(&self) -> &str {
+        &self.base
+    }
+
+    /// The host users are addressed at, as in `acct:{username}@{host}`
+    pub fn host(&self) -> &str {
+        self.base
+            .splitn(2, "://")
+            .nth(1)
+            .unwrap_or(&self.base)
+    }
+
+    pub fn is_remote(&self, user_id: UserId) -> bool {
+        self.remote_user_iris.contains_key(&user_id)
+    }
+
+    pub fn user_iri(&self, user_id: UserId) -> String {
+        self.remote_user_iris
+            .get(&user_id)
+            .cloned()
+            .unwrap_or_else(|| format!("{}/users/{}", self.base, user_id))
+    }
+
+    pub fn user_id(&mut self, iri: &str) -> Result<UserId, Error> {
+        if let Some(local) = self.local_path(iri, "/users/") {
+            return local.parse().map_err(|_| Error::InvalidIri(iri.to_owned()));
+        }
+
+        if let Some(user_id) = self.remote_users.get(iri) {
+            return Ok(*user_id);
+        }
+
+        let user_id = UserId(REMOTE_ID, Id::new(self.next_remote_user));
+        self.next_remote_user += 1;
+        self.storage.set_counter(REMOTE_USERS, self.next_remote_user);
+
+        self.remote_users.insert(iri.to_owned(), user_id);
+        self.remote_user_iris.insert(user_id, iri.to_owned());
+        self.storage.save_remote_actor(iri, user_id);
+
+        Ok(user_id)
+    }
+
+    pub fn post_iri(&self, post_id: PostId) -> String {
+        self.post_iris
+            .get(&post_id)
+            .cloned()
+            .unwrap_or_else(|| format!("{}/posts/{}", self.base, post_id))
+    }
+
+    pub fn post_id(&mut self, iri: &str) -> Result<PostId, Error> {
+        if let Some(post_id) = self.posts.get(iri) {
+            return Ok(*post_id);
+        }
+
+        if let Some(local) = self.local_path(iri, "/posts/") {
+            return local.parse().map_err(|_| Error::UnknownPost(iri.to_owned()));
+        }
+
+        let post_id = PostId::new(REMOTE_ID, Id::new(self.next_remote_post), self.clock.now());
+        self.next_remote_post += 1;
+        self.storage.set_counter(REMOTE_POSTS, self.next_remote_post);
+
+        self.posts.insert(iri.to_owned(), post_id);
+        self.post_iris.insert(post_id, iri.to_owned());
+        self.storage.save_remote_post(iri, post_id, None);
+
+        Ok(post_id)
+    }
+
+    /// The remote post created by `author` under `iri`, which can't name a local post or one
+    /// created by another actor
+    pub fn created_post(&mut self, iri: &str, author: UserId) -> Result<PostId, Error> {
+        if self.local_path(iri, "/").is_some() {
+            return Err(Error::NotOwned(iri.to_owned()));
+        }
+
+        let post_id = self.post_id(iri)?;
+
+        match self.post_authors.get(&post_id).cloned() {
+            Some(creator) if creator == author => Ok(post_id),
+            Some(_) => Err(Error::NotOwned(iri.to_owned())),
+            None => {
+                self.post_authors.insert(post_id, author);
+                self.storage.save_remote_post(iri, post_id, Some(author));
+                Ok(post_id)
+            }
+        }
+    }
+
+    /// The remote post `iri` names, as long as `author` created it
+    pub fn authored_post(&self, iri: &str, author: UserId) -> Result<PostId, Error> {
+        match self.posts.get(iri) {
+            Some(post_id) if self.post_authors.get(post_id) == Some(&author) => Ok(*post_id),
+            _ => Err(Error::NotOwned(iri.to_owned())),
+        }
+    }
+
+    /// Generates a fresh IRI for an outgoing activity
+    pub fn activity_iri(&mut self) -> String {
+        let iri = format!("{}/activities/{}", self.base, self.next_activity);
+        self.next_activity += 1;
+        self.storage.set_counter(ACTIVITIES, self.next_activity);
+
+        iri
+    }
+
+    fn local_path<'a>(&self, iri: &'a str, kind: &str) -> Option<&'a str> {
+        if iri.starts_with(&self.base) && iri[self.base.len()..].starts_with(kind) {
+            Some(&iri[self.base.len() + kind.len()..])
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+    use std::time::{Duration, SystemTime};
+
+    use httpdate::fmt_http_date;
+    use serde_json;
+
+    use actors::{Clock, Id, PostId, UserId};
+    use actors::posts::{Attachment, Content, Visibility};
+    use actors::posts::messages::DeletePost;
+    use actors::user::Profile;
+    use actors::user::messages::{AcceptFollowRequest, BlockUser, Blocked, Boost, Boosted,
+                                 FollowRequest, FollowRequestAccepted, FollowRequestDenied,
+                                 FollowerRemoved, Like, Liked, NewPostIn, NewPostOut,
+                                 RemoveFollower, UnblockUser, Unblocked, Unboost, Unboosted,
+                                 Unfollow, Unfollowed, Unlike, Unliked};
+    use storage::Storage;
+    use super::*;
+
+    const BASE: &'static str = "https://example.com";
+    const REMOTE: &'static str = "https://remote.example";
+    const TEST_KEY: &'static str = include_str!("test_key.pem");
+
+    fn local_user(id: u64) -> UserId {
+        UserId(Id::new(0), Id::new(id))
+    }
+
+    fn round_trip<T>(message: T, iris: &mut IriMap, source: UserId, target: UserId) -> Incoming
+    where
+        T: ToActivity,
+    {
+        let activity = message.to_activity(iris, source, target);
+        let json = serde_json::to_string(&activity).unwrap();
+        let parsed: Activity = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(parsed, activity);
+
+        Incoming::from_activity(parsed, iris).unwrap()
+    }
+
+    /// Parses a message the way the server at `REMOTE` receives it from this one
+    fn delivered<T>(
+        message: T,
+        iris: &mut IriMap,
+        remote: &mut IriMap,
+        source: UserId,
+        target: UserId,
+    ) -> Result<Incoming, Error>
+    where
+        T: ToActivity,
+    {
+        let activity = message.to_activity(iris, source, target);
+        let json = serde_json::to_string(&activity).unwrap();
+
+        Incoming::from_activity(serde_json::from_str(&json).unwrap(), remote)
+    }
+
+    #[test]
+    fn new_post_round_trips() {
+        let mut iris = IriMap::new(BASE, Storage::memory());
+        let post_id = PostId::new(Id::new(0), Id::new(3), Clock::new().now());
+        let mut mentions = BTreeSet::new();
+        mentions.insert(local_user(2));
+        let content = Content {
+            html: "<p>hi</p>".to_owned(),
+            text: Some("hi".to_owned()),
+            summary: Some("greetings".to_owned()),
+            language: Some("en".to_owned()),
+            attachments: vec![Attachment {
+                url: "https://example.com/media/1.png".to_owned(),
+                media_type: "image/png".to_owned(),
+                alt: Some("a wave".to_owned()),
+                blurhash: Some("LEHV6nWB2yk8pyo0adR*.7kCMdnj".to_owned()),
+            }],
+        };
+        let parent = PostId::new(Id::new(0), Id::new(2), Clock::new().now());
+        let msg = NewPostIn(
+            post_id,
+            local_user(0),
+            mentions,
+            content.clone(),
+            Visibility::Public,
+            Some(parent),
+        );
+
+        let mut remote = IriMap::new(REMOTE, Storage::memory());
+        let incoming = delivered(msg, &mut iris, &mut remote, local_user(0), local_user(1));
+
+        // The other server names the users and posts by the IRIs they were sent under
+        let author = remote.user_id(&iris.user_iri(local_user(0))).unwrap();
+        let mentioned = remote.user_id(&iris.user_iri(local_user(2))).unwrap();
+        let post_id = remote.post_id(&iris.post_iri(post_id)).unwrap();
+        let parent = remote.post_id(&iris.post_iri(parent)).unwrap();
+
+        assert_eq!(
+            incoming,
+            Ok(Incoming::NewPost(NewPostIn(
+                post_id,
+                author,
+                vec![mentioned].into_iter().collect(),
+                content,
+                Visibility::Public,
+                Some(parent),
+            )))
+        );
+    }
+
+    #[test]
+    fn creates_must_be_the_actors_own() {
+        let mut iris = IriMap::new(BASE, Storage::memory());
+        let mut remote = IriMap::new(REMOTE, Storage::memory());
+        let post_id = PostId::new(Id::new(0), Id::new(3), Clock::new().now());
+        let msg = NewPostIn(
+            post_id,
+            local_user(0),
+            BTreeSet::new(),
+            Content::default(),
+            Visibility::Public,
+            None,
+        );
+
+        // Posts under local IRIs can only be made here
+        let activity = msg.to_activity(&mut iris, local_user(0), local_user(1));
+        assert_eq!(
+            Incoming::from_activity(activity, &mut iris),
+            Err(Error::NotOwned(iris.post_iri(post_id)))
+        );
+
+        // Another actor can't pass a post off as the author's
+        let mut activity = msg.to_activity(&mut iris, local_user(0), local_user(1));
+        activity.actor = iris.user_iri(local_user(1));
+        assert_eq!(
+            Incoming::from_activity(activity, &mut remote),
+            Err(Error::NotOwned(iris.post_iri(post_id)))
+        );
+    }
+
+    #[test]
+    fn visibility_survives_addressing() {
+        let mut iris = IriMap::new(BASE, Storage::memory());
+        let mut remote = IriMap::new(REMOTE, Storage::memory());
+        let mut clock = Clock::new();
+        let mentions: BTreeSet<_> = vec![local_user(2)].into_iter().collect();
+
+        for &visibility in &[
+            Visibility::Public,
+            Visibility::Unlisted,
+            Visibility::FollowersOnly,
+            Visibility::Direct,
+        ] {
+            let post_id = PostId::new(Id::new(0), Id::new(0), clock.now());
+            let msg = NewPostIn(
+                post_id,
+                local_user(0),
+                mentions.clone(),
+                Content::default(),
+                visibility,
+                None,
+            );
+
+            let activity = msg.to_activity(&mut iris, local_user(0), local_user(1));
+            let public = activity.to.iter().chain(&activity.cc).any(|iri| iri == PUBLIC);
+            assert_eq!(public, visibility.is_public());
+
+            match delivered(msg, &mut iris, &mut remote, local_user(0), local_user(1)) {
+                Ok(Incoming::NewPost(NewPostIn(_, _, _, _, parsed, _))) => {
+                    assert_eq!(parsed, visibility)
+                }
+                incoming => panic!("Expected a new post, got {:?}", incoming),
+            }
+        }
+    }
+
+    #[test]
+    fn follow_messages_round_trip() {
+        let mut iris = IriMap::new(BASE, Storage::memory());
+        let (alice, bob) = (local_user(0), local_user(1));
+
+        let incoming = round_trip(FollowRequest(alice), &mut iris, alice, bob);
+        assert_eq!(incoming, Incoming::FollowRequest(FollowRequest(alice)));
+
+        let incoming = round_trip(FollowRequestAccepted(bob), &mut iris, bob, alice);
+        assert_eq!(
+            incoming,
+            Incoming::FollowRequestAccepted(FollowRequestAccepted(bob))
+        );
+
+        let incoming = round_trip(FollowRequestDenied(bob), &mut iris, bob, alice);
+        assert_eq!(
+            incoming,
+            Incoming::FollowRequestDenied(FollowRequestDenied(bob))
+        );
+
+        let incoming = round_trip(Unfollowed(alice), &mut iris, alice, bob);
+        assert_eq!(incoming, Incoming::Unfollowed(Unfollowed(alice)));
+
+        let incoming = round_trip(FollowerRemoved(bob), &mut iris, bob, alice);
+        assert_eq!(incoming, Incoming::FollowerRemoved(FollowerRemoved(bob)));
+    }
+
+    #[test]
+    fn undone_follows_are_addressed_to_the_other_side() {
+        let mut iris = IriMap::new(BASE, Storage::memory());
+        let (alice, bob) = (local_user(0), local_user(1));
+
+        let unfollow = Unfollowed(alice).to_activity(&mut iris, alice, bob);
+        let removal = FollowerRemoved(bob).to_activity(&mut iris, bob, alice);
+
+        assert_eq!(unfollow.to, vec![iris.user_iri(bob)]);
+        assert_eq!(removal.to, vec![iris.user_iri(alice)]);
+
+        // Both undo the same follow, alice following bob
+        match (unfollow.object, removal.object) {
+            (ObjectRef::Activity(unfollowed), ObjectRef::Activity(removed)) => {
+                assert_eq!(unfollowed.actor, iris.user_iri(alice));
+                assert_eq!(removed.actor, iris.user_iri(alice));
+                assert_eq!(unfollowed.object, ObjectRef::Iri(iris.user_iri(bob)));
+            }
+            objects => panic!("Expected embedded follows, got {:?}", objects),
+        }
+    }
+
+    #[test]
+    fn block_and_delete_round_trip() {
+        let mut iris = IriMap::new(BASE, Storage::memory());
+        let (alice, bob) = (local_user(0), local_user(1));
+        let post_id = PostId::new(Id::new(0), Id::new(0), Clock::new().now());
+
+        let incoming = round_trip(Blocked(alice), &mut iris, alice, bob);
+        assert_eq!(incoming, Incoming::Blocked(Blocked(alice)));
+
+        let incoming = round_trip(Unblocked(alice), &mut iris, alice, bob);
+        assert_eq!(incoming, Incoming::Unblocked(Unblocked(alice)));
+
+        // Deletes are only taken from the post's author, and never for posts made here
+        let mut remote = IriMap::new(REMOTE, Storage::memory());
+        let create = NewPostIn(
+            post_id,
+            alice,
+            BTreeSet::new(),
+            Content::default(),
+            Visibility::Public,
+            None,
+        );
+        assert!(delivered(create, &mut iris, &mut remote, alice, bob).is_ok());
+        let remote_post_id = remote.post_id(&iris.post_iri(post_id)).unwrap();
+
+        let incoming = delivered(DeletePost(post_id), &mut iris, &mut remote, alice, bob);
+        assert_eq!(incoming, Ok(Incoming::DeletePost(DeletePost(remote_post_id))));
+
+        let incoming = delivered(DeletePost(post_id), &mut iris, &mut remote, bob, alice);
+        assert_eq!(incoming, Err(Error::NotOwned(iris.post_iri(post_id))));
+
+        let activity = DeletePost(post_id).to_activity(&mut iris, alice, bob);
+        assert_eq!(
+            Incoming::from_activity(activity, &mut iris),
+            Err(Error::NotOwned(iris.post_iri(post_id)))
+        );
+    }
+
+    #[test]
+    fn boosts_round_trip() {
+        let mut iris = IriMap::new(BASE, Storage::memory());
+        let (alice, bob) = (local_user(0), local_user(1));
+        let post_id = PostId::new(Id::new(0), Id::new(0), Clock::new().now());
+
+        let incoming = round_trip(Boosted(post_id, alice), &mut iris, alice, bob);
+        assert_eq!(incoming, Incoming::Boosted(Boosted(post_id, alice)));
+
+        let activity = Unboosted(post_id, alice).to_activity(&mut iris, alice, bob);
+        match activity.object {
+            ObjectRef::Activity(ref announce) => {
+                assert_eq!(announce.kind, "Announce");
+                assert_eq!(announce.object, ObjectRef::Iri(iris.post_iri(post_id)));
+            }
+            ref object => panic!("Expected an embedded announce, got {:?}", object),
+        }
+
+        let incoming = round_trip(Unboosted(post_id, alice), &mut iris, alice, bob);
+        assert_eq!(incoming, Incoming::Unboosted(Unboosted(post_id, alice)));
+    }
+
+    #[test]
+    fn likes_round_trip() {
+        let mut iris = IriMap::new(BASE, Storage::memory());
+        let (alice, bob) = (local_user(0), local_user(1));
+        let post_id = PostId::new(Id::new(0), Id::new(0), Clock::new().now());
+
+        let incoming = round_trip(Liked(post_id, alice), &mut iris, alice, bob);
+        assert_eq!(incoming, Incoming::Liked(Liked(post_id, alice)));
+
+        let incoming = round_trip(Unliked(post_id, alice), &mut iris, alice, bob);
+        assert_eq!(incoming, Incoming::Unliked(Unliked(post_id, alice)));
+    }
+
+    #[test]
+    fn undone_announces_must_be_the_actors_own() {
+        let mut iris = IriMap::new(BASE, Storage::memory());
+        let (alice, bob) = (local_user(0), local_user(1));
+        let post_id = PostId::new(Id::new(0), Id::new(0), Clock::new().now());
+
+        let mut activity = Unboosted(post_id, alice).to_activity(&mut iris, alice, bob);
+        activity.actor = iris.user_iri(bob);
+
+        assert_eq!(
+            Incoming::from_activity(activity, &mut iris),
+            Err(Error::InvalidObject)
+        );
+    }
+
+    #[test]
+    fn remote_actors_keep_their_iri() {
+        let mut iris = IriMap::new(BASE, Storage::memory());
+        let remote = "https://mastodon.example/users/alice";
+
+        let user_id = iris.user_id(remote).unwrap();
+
+        assert!(iris.is_remote(user_id));
+        assert_eq!(iris.user_id(remote).unwrap(), user_id);
+        assert_eq!(iris.user_iri(user_id), remote);
+        assert!(!iris.is_remote(local_user(0)));
+    }
+
+    #[test]
+    fn person_links_to_the_users_collections() {
+        let iris = IriMap::new(BASE, Storage::memory());
+        let mut profile = Profile::new("alice");
+        profile.display_name = Some("Alice".to_owned());
+
+        let keys = KeyPair::from_pem(TEST_KEY).unwrap();
+
+        let person = Person::new(&iris, local_user(3), profile, keys.public_key_pem());
+        let json = serde_json::to_value(&person).unwrap();
+
+        assert_eq!(iris.host(), "example.com");
+        assert_eq!(json["id"], "https://example.com/users/0-3");
+        assert_eq!(json["type"], "Person");
+        assert_eq!(json["preferredUsername"], "alice");
+        assert_eq!(json["name"], "Alice");
+        assert_eq!(json["inbox"], "https://example.com/users/0-3/inbox");
+        assert_eq!(json["outbox"], "https://example.com/users/0-3/outbox");
+        assert!(json.get("summary").is_none());
+        assert_eq!(json["publicKey"]["id"], "https://example.com/users/0-3#main-key");
+        assert_eq!(json["publicKey"]["owner"], "https://example.com/users/0-3");
+    }
+
+    fn signed_headers(body: &[u8]) -> Vec<(&'static str, String)> {
+        vec![
+            ("Host", "remote.example".to_owned()),
+            ("Date", fmt_http_date(SystemTime::now())),
+            ("Digest", digest(body)),
+        ]
+    }
+
+    fn header(headers: &[(&'static str, String)], name: &str) -> Option<String> {
+        headers
+            .iter()
+            .find(|&&(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|&(_, ref value)| value.clone())
+    }
+
+    fn sign(keys: &KeyPair, headers: &[(&'static str, String)]) -> Signature {
+        let headers: Vec<_> = headers.iter().map(|&(k, ref v)| (k, v.as_str())).collect();
+
+        keys.sign(&key_id(BASE), "POST", "/users/0-1/inbox", &headers)
+            .unwrap()
+            .parse()
+            .unwrap()
+    }
+
+    #[test]
+    fn signatures_verify_with_the_signers_key() {
+        let keys = KeyPair::from_pem(TEST_KEY).unwrap();
+        let body = br#"{"type":"Follow"}"#;
+        let headers = signed_headers(body);
+
+        let signature = sign(&keys, &headers);
+
+        assert_eq!(signature.key_id, "https://example.com#main-key");
+        assert_eq!(signature.headers, vec!["(request-target)", "host", "date", "digest"]);
+        let res = signature.verify(&keys.public_key_pem(), "POST", "/users/0-1/inbox", |name| {
+            header(&headers, name)
+        });
+
+        assert_eq!(res, Ok(()));
+        assert_eq!(signature.verify_digest(Some(&digest(body)), body), Ok(()));
+    }
+
+    #[test]
+    fn tampered_requests_are_rejected() {
+        let keys = KeyPair::from_pem(TEST_KEY).unwrap();
+        let body = br#"{"type":"Follow"}"#;
+        let headers = signed_headers(body);
+        let pem = keys.public_key_pem();
+
+        let signature = sign(&keys, &headers);
+
+        assert_eq!(
+            signature.verify(&pem, "POST", "/users/0-2/inbox", |name| header(&headers, name)),
+            Err(SignatureError::Invalid)
+        );
+        assert_eq!(
+            signature.verify_digest(Some(&digest(body)), br#"{"type":"Block"}"#),
+            Err(SignatureError::DigestMismatch)
+        );
+
+        let mut missing_date = headers.clone();
+        missing_date.remove(1);
+        assert_eq!(
+            signature.verify(&pem, "POST", "/users/0-1/inbox", |name| header(&missing_date, name)),
+            Err(SignatureError::MissingHeader("date".to_owned()))
+        );
+    }
+
+    #[test]
+    fn stale_or_unsigned_dates_are_rejected() {
+        let keys = KeyPair::from_pem(TEST_KEY).unwrap();
+        let pem = keys.public_key_pem();
+        let verify = |signature: &Signature, headers: &[(&'static str, String)]| {
+            signature.verify(&pem, "POST", "/users/0-1/inbox", |name| header(headers, name))
+        };
+
+        let mut stale = signed_headers(b"");
+        let an_hour_ago = SystemTime::now() - Duration::from_secs(3600);
+        stale[1].1 = fmt_http_date(an_hour_ago);
+        assert_eq!(
+            verify(&sign(&keys, &stale), &stale),
+            Err(SignatureError::StaleDate(stale[1].1.clone()))
+        );
+
+        let headers = signed_headers(b"");
+        let mut unsigned = sign(&keys, &headers);
+        unsigned.headers.retain(|name| name != "date" && name != "host");
+        assert_eq!(
+            verify(&unsigned, &headers),
+            Err(SignatureError::MissingHeader("host".to_owned()))
+        );
+    }
+
+    #[test]
+    fn parses_mastodon_signature_header() {
+        let header = "keyId=\"https://mastodon.example/users/alice#main-key\",\
+                      algorithm=\"rsa-sha256\",\
+                      headers=\"(request-target) host date digest content-type\",\
+                      signature=\"c2lnbmF0dXJl\"";
+
+        let signature: Signature = header.parse().unwrap();
+
+        assert_eq!(signature.key_id, "https://mastodon.example/users/alice#main-key");
+        assert_eq!(signature.headers.len(), 5);
+        assert_eq!(signature.signature, b"signature".to_vec());
+        assert_eq!(
+            signature.verify_digest(None, b""),
+            Err(SignatureError::MissingHeader("digest".to_owned()))
+        );
+        assert_eq!(
+            "keyId=\"a\",signature=\"!!\"".parse::<Signature>(),
+            Err(SignatureError::Malformed)
+        );
+    }
+
+    #[test]
+    fn unknown_local_post_is_rejected() {
+        let mut iris = IriMap::new(BASE, Storage::memory());
+
+        let res = iris.post_id("https://example.com/posts/0-12");
+
+        assert_eq!(
+            res,
+            Err(Error::UnknownPost("https://example.com/posts/0-12".to_owned()))
+        );
+    }
+
+    #[test]
+    fn local_posts_are_named_by_their_full_id() {
+        let post_id = PostId::new(Id::new(2), Id::new(12), Clock::new().now());
+        let iri = IriMap::new(BASE, Storage::memory()).post_iri(post_id);
+
+        // A restarted node hasn't seen the post, but still knows it from its IRI
+        assert_eq!(IriMap::new(BASE, Storage::memory()).post_id(&iri), Ok(post_id));
+    }
+
+    #[test]
+    fn remote_ids_survive_a_restart() {
+        let storage = Storage::memory();
+        let (user_id, post_id) = {
+            let mut iris = IriMap::new(BASE, storage.clone());
+            let user_id = iris.user_id("https://remote.example/users/alice").unwrap();
+            let post_id = iris.created_post("https://remote.example/notes/1", user_id).unwrap();
+
+            (user_id, post_id)
+        };
+
+        let mut iris = IriMap::new(BASE, storage);
+        assert_eq!(iris.user_id("https://remote.example/users/alice"), Ok(user_id));
+        assert_eq!(iris.authored_post("https://remote.example/notes/1", user_id), Ok(post_id));
+
+        // Ids handed out after the restart don't reuse the stored ones
+        assert!(iris.user_id("https://remote.example/users/bob").unwrap() != user_id);
+        assert!(iris.post_id("https://remote.example/notes/2").unwrap() != post_id);
+    }
+
+    #[test]
+    fn parses_mastodon_follow() {
+        let mut iris = IriMap::new(BASE, Storage::memory());
+        let json = r#"{
+            "@context": "https://www.w3.org/ns/activitystreams",
+            "id": "https://mastodon.example/4e7f2a8c-7d1b-4c53-9a0e-5b9bd2f6f0a1",
+            "type": "Follow",
+            "actor": "https://mastodon.example/users/alice",
+            "object": "https://example.com/users/0-1"
+        }"#;
+
+        let activity: Activity = serde_json::from_str(json).unwrap();
+        let incoming = Incoming::from_activity(activity, &mut iris).unwrap();
+
+        let alice = iris.user_id("https://mastodon.example/users/alice").unwrap();
+        assert_eq!(incoming, Incoming::FollowRequest(FollowRequest(alice)));
+    }
+
+    #[test]
+    fn parses_mastodon_create() {
+        let mut iris = IriMap::new(BASE, Storage::memory());
+        let json = r##"{
+            "@context": [
+                "https://www.w3.org/ns/activitystreams",
+                "https://w3id.org/security/v1",
+                {"sensitive": "as:sensitive", "ostatus": "http://ostatus.org#"}
+            ],
+            "id": "https://mastodon.example/users/alice/statuses/99/activity",
+            "type": "Create",
+            "actor": "https://mastodon.example/users/alice",
+            "published": "2018-01-20T18:05:12Z",
+            "to": ["https://www.w3.org/ns/activitystreams#Public"],
+            "cc": [
+                "https://mastodon.example/users/alice/followers",
+                "https://example.com/users/0-1"
+            ],
+            "object": {
+                "id": "https://mastodon.example/users/alice/statuses/99",
+                "type": "Note",
+                "summary": null,
+                "inReplyTo": null,
+                "published": "2018-01-20T18:05:12Z",
+                "url": "https://mastodon.example/@alice/99",
+                "attributedTo": "https://mastodon.example/users/alice",
+                "to": ["https://www.w3.org/ns/activitystreams#Public"],
+                "cc": [
+                    "https://mastodon.example/users/alice/followers",
+                    "https://example.com/users/0-1"
+                ],
+                "sensitive": false,
+                "content": "<p><span class=\"h-card\"><a href=\"https://example.com/users/0-1\" class=\"u-url mention\">@<span>bob</span></a></span> hi</p>",
+                "attachment": [],
+                "tag": [
+                    {
+                        "type": "Mention",
+                        "href": "https://example.com/users/0-1",
+                        "name": "@bob@example.com"
+                    },
+                    {
+                        "type": "Hashtag",
+                        "href": "https://mastodon.example/tags/hello",
+                        "name": "#hello"
+                    }
+                ]
+            },
+            "signature": {
+                "type": "RsaSignature2017",
+                "creator": "https://mastodon.example/users/alice#main-key",
+                "created": "2018-01-20T18:05:12Z",
+                "signatureValue": "c2lnbmF0dXJl"
+            }
+        }"##;
+
+        let activity: Activity = serde_json::from_str(json).unwrap();
+        let incoming = Incoming::from_activity(activity, &mut iris).unwrap();
+
+        let alice = iris.user_id("https://mastodon.example/users/alice").unwrap();
+        let post_id = iris
+            .post_id("https://mastodon.example/users/alice/statuses/99")
+            .unwrap();
+        let mut mentions = BTreeSet::new();
+        mentions.insert(local_user(1));
+
+        let content = Content {
+            html: "<p><span class=\"h-card\"><a href=\"https://example.com/users/0-1\" \
+                   class=\"u-url mention\">@<span>bob</span></a></span> hi</p>"
+                .to_owned(),
+            ..Content::default()
+        };
+
+        assert_eq!(
+            incoming,
+            Incoming::NewPost(NewPostIn(
+                post_id,
+                alice,
+                mentions,
+                content,
+                Visibility::Public,
+                None,
+            ))
+        );
+    }
+
+    #[test]
+    fn parses_mastodon_delete() {
+        let mut iris = IriMap::new(BASE, Storage::memory());
+        let alice = iris.user_id("https://mastodon.example/users/alice").unwrap();
+        let post_id = iris
+            .created_post("https://mastodon.example/users/alice/statuses/99", alice)
+            .unwrap();
+        let json = r#"{
+            "@context": "https://www.w3.org/ns/activitystreams",
+            "id": "https://mastodon.example/users/alice/statuses/99#delete",
+            "type": "Delete",
+            "actor": "https://mastodon.example/users/alice",
+            "to": ["https://www.w3.org/ns/activitystreams#Public"],
+            "object": {
+                "id": "https://mastodon.example/users/alice/statuses/99",
+                "type": "Tombstone",
+                "atomUri": "https://mastodon.example/users/alice/statuses/99"
+            }
+        }"#;
+
+        let activity: Activity = serde_json::from_str(json).unwrap();
+        let incoming = Incoming::from_activity(activity, &mut iris).unwrap();
+
+        assert_eq!(incoming, Incoming::DeletePost(DeletePost(post_id)));
+    }
+
+    #[test]
+    fn parses_mastodon_announce() {
+        let mut iris = IriMap::new(BASE, Storage::memory());
+        let booster = iris.user_id("https://mastodon.example/users/alice").unwrap();
+        let post_id = iris
+            .post_id("https://mastodon.example/users/bob/statuses/7")
+            .unwrap();
+        let json = r#"{
+            "@context": "https://www.w3.org/ns/activitystreams",
+            "id": "https://mastodon.example/users/alice/statuses/100/activity",
+            "type": "Announce",
+            "actor": "https://mastodon.example/users/alice",
+            "published": "2018-03-01T12:00:00Z",
+            "to": ["https://www.w3.org/ns/activitystreams#Public"],
+            "cc": [
+                "https://mastodon.example/users/bob",
+                "https://mastodon.example/users/alice/followers"
+            ],
+            "object": "https://mastodon.example/users/bob/statuses/7"
+        }"#;
+
+        let activity: Activity = serde_json::from_str(json).unwrap();
+        let incoming = Incoming::from_activity(activity, &mut iris).unwrap();
+
+        assert_eq!(incoming, Incoming::Boosted(Boosted(post_id, booster)));
+    }
+
+    #[test]
+    fn parses_mastodon_like() {
+        let mut iris = IriMap::new(BASE, Storage::memory());
+        let liker = iris.user_id("https://mastodon.example/users/alice").unwrap();
+        let post_id = iris
+            .post_id("https://mastodon.example/users/bob/statuses/7")
+            .unwrap();
+        let json = r#"{
+            "@context": "https://www.w3.org/ns/activitystreams",
+            "id": "https://mastodon.example/users/alice#likes/12",
+            "type": "Like",
+            "actor": "https://mastodon.example/users/alice",
+            "object": "https://mastodon.example/users/bob/statuses/7"
+        }"#;
+
+        let activity: Activity = serde_json::from_str(json).unwrap();
+        let incoming = Incoming::from_activity(activity, &mut iris).unwrap();
+
+        assert_eq!(incoming, Incoming::Liked(Liked(post_id, liker)));
+    }
+
+    #[test]
+    fn parses_pleroma_accept_and_reject() {
+        let mut iris = IriMap::new(BASE, Storage::memory());
+        let accept = r#"{
+            "@context": [
+                "https://www.w3.org/ns/activitystreams",
+                "https://pleroma.example/schemas/litepub-0.1.jsonld"
+            ],
+            "id": "https://pleroma.example/activities/0b9a3c2e-8f0e-4f5e-a3c4-2a9d3a1c6b7d",
+            "type": "Accept",
+            "actor": "https://pleroma.example/users/carol",
+            "to": ["https://example.com/users/0-0"],
+            "cc": [],
+            "object": {
+                "id": "https://example.com/activities/3",
+                "type": "Follow",
+                "actor": "https://example.com/users/0-0",
+                "object": "https://pleroma.example/users/carol",
+                "state": "accept"
+            }
+        }"#;
+        let reject = r#"{
+            "@context": "https://www.w3.org/ns/activitystreams",
+            "id": "https://pleroma.example/activities/5c1e8f0a-2b3d-4e6f-8a9b-0c1d2e3f4a5b",
+            "type": "Reject",
+            "actor": "https://pleroma.example/users/carol",
+            "to": "https://example.com/users/0-0",
+            "object": "https://example.com/activities/3"
+        }"#;
+
+        let activity: Activity = serde_json::from_str(accept).unwrap();
+        let accepted = Incoming::from_activity(activity, &mut iris).unwrap();
+        let activity: Activity = serde_json::from_str(reject).unwrap();
+        let rejected = Incoming::from_activity(activity, &mut iris).unwrap();
+
+        let carol = iris.user_id("https://pleroma.example/users/carol").unwrap();
+        assert_eq!(
+            accepted,
+            Incoming::FollowRequestAccepted(FollowRequestAccepted(carol))
+        );
+        assert_eq!(
+            rejected,
+            Incoming::FollowRequestDenied(FollowRequestDenied(carol))
+        );
+    }
+
+    #[test]
+    fn parses_pleroma_block() {
+        let mut iris = IriMap::new(BASE, Storage::memory());
+        let json = r#"{
+            "@context": [
+                "https://www.w3.org/ns/activitystreams",
+                "https://pleroma.example/schemas/litepub-0.1.jsonld"
+            ],
+            "id": "https://pleroma.example/activities/9d8c7b6a-5f4e-3d2c-1b0a-9f8e7d6c5b4a",
+            "type": "Block",
+            "actor": "https://pleroma.example/users/carol",
+            "to": ["https://example.com/users/0-0"],
+            "cc": [],
+            "object": "https://example.com/users/0-0"
+        }"#;
+
+        let activity: Activity = serde_json::from_str(json).unwrap();
+        let incoming = Incoming::from_activity(activity, &mut iris).unwrap();
+
+        let carol = iris.user_id("https://pleroma.example/users/carol").unwrap();
+        assert_eq!(incoming, Incoming::Blocked(Blocked(carol)));
+    }
+
+    #[test]
+    fn unsupported_activities_are_errors() {
+        let mut iris = IriMap::new(BASE, Storage::memory());
+        let json = r#"{
+            "@context": "https://www.w3.org/ns/activitystreams",
+            "type": "Move",
+            "actor": "https://mastodon.example/users/alice",
+            "object": "https://mastodon.example/users/alice"
+        }"#;
+
+        let activity: Activity = serde_json::from_str(json).unwrap();
+
+        assert_eq!(
+            Incoming::from_activity(activity, &mut iris),
+            Err(Error::Unsupported("Move".to_owned()))
+        );
+    }
+
+    #[test]
+    fn parses_client_activities() {
+        let mut iris = IriMap::new(BASE, Storage::memory());
+        let create = r#"{
+            "@context": "https://www.w3.org/ns/activitystreams",
+            "type": "Create",
+            "actor": "https://example.com/users/0-0",
+            "object": {
+                "type": "Note",
+                "to": "https://example.com/users/0-0/followers",
+                "cc": "https://www.w3.org/ns/activitystreams#Public",
+                "content": "<p>hi</p>",
+                "contentMap": {"en": "<p>hi</p>"},
+                "source": {"content": "hi", "mediaType": "text/plain"},
+                "attachment": [{
+                    "type": "Document",
+                    "url": "https://example.com/media/1.png",
+                    "mediaType": "image/png",
+                    "name": "a wave"
+                }],
+                "tag": [{"type": "Mention", "href": "https://example.com/users/0-1"}]
+            }
+        }"#;
+        let accept = r#"{
+            "@context": "https://www.w3.org/ns/activitystreams",
+            "type": "Accept",
+            "actor": "https://example.com/users/0-0",
+            "object": {
+                "type": "Follow",
+                "actor": "https://example.com/users/0-2",
+                "object": "https://example.com/users/0-0"
+            }
+        }"#;
+        let undo = r#"{
+            "@context": "https://www.w3.org/ns/activitystreams",
+            "type": "Undo",
+            "actor": "https://example.com/users/0-0",
+            "object": {
+                "type": "Follow",
+                "actor": "https://example.com/users/0-0",
+                "object": "https://example.com/users/0-4"
+            }
+        }"#;
+        let remove = r#"{
+            "@context": "https://www.w3.org/ns/activitystreams",
+            "type": "Undo",
+            "actor": "https://example.com/users/0-0",
+            "object": {
+                "type": "Follow",
+                "actor": "https://example.com/users/0-5",
+                "object": "https://example.com/users/0-0"
+            }
+        }"#;
+        let block = r#"{
+            "@context": "https://www.w3.org/ns/activitystreams",
+            "type": "Block",
+            "actor": "https://example.com/users/0-0",
+            "object": "https://example.com/users/0-3"
+        }"#;
+        let unblock = r#"{
+            "@context": "https://www.w3.org/ns/activitystreams",
+            "type": "Undo",
+            "actor": "https://example.com/users/0-0",
+            "object": {
+                "type": "Block",
+                "actor": "https://example.com/users/0-0",
+                "object": "https://example.com/users/0-3"
+            }
+        }"#;
+
+        let activity: Activity = serde_json::from_str(create).unwrap();
+        let mut mentions = BTreeSet::new();
+        mentions.insert(local_user(1));
+        let content = Content {
+            html: "<p>hi</p>".to_owned(),
+            text: Some("hi".to_owned()),
+            summary: None,
+            language: Some("en".to_owned()),
+            attachments: vec![Attachment {
+                url: "https://example.com/media/1.png".to_owned(),
+                media_type: "image/png".to_owned(),
+                alt: Some("a wave".to_owned()),
+                blurhash: None,
+            }],
+        };
+        assert_eq!(
+            Outgoing::from_activity(activity, local_user(0), &mut iris),
+            Ok(Outgoing::NewPost(NewPostOut(
+                mentions,
+                content,
+                Visibility::Unlisted,
+                None,
+            )))
+        );
+
+        let activity: Activity = serde_json::from_str(accept).unwrap();
+        assert_eq!(
+            Outgoing::from_activity(activity, local_user(0), &mut iris),
+            Ok(Outgoing::AcceptFollowRequest(AcceptFollowRequest(local_user(2))))
+        );
+
+        let activity: Activity = serde_json::from_str(undo).unwrap();
+        assert_eq!(
+            Outgoing::from_activity(activity, local_user(0), &mut iris),
+            Ok(Outgoing::Unfollow(Unfollow(local_user(4))))
+        );
+
+        let activity: Activity = serde_json::from_str(remove).unwrap();
+        assert_eq!(
+            Outgoing::from_activity(activity, local_user(0), &mut iris),
+            Ok(Outgoing::RemoveFollower(RemoveFollower(local_user(5))))
+        );
+
+        let activity: Activity = serde_json::from_str(block).unwrap();
+        assert_eq!(
+            Outgoing::from_activity(activity, local_user(0), &mut iris),
+            Ok(Outgoing::BlockUser(BlockUser(local_user(3))))
+        );
+
+        let activity: Activity = serde_json::from_str(unblock).unwrap();
+        assert_eq!(
+            Outgoing::from_activity(activity, local_user(0), &mut iris),
+            Ok(Outgoing::UnblockUser(UnblockUser(local_user(3))))
+        );
+
+        let activity: Activity = serde_json::from_str(block).unwrap();
+        assert_eq!(
+            Outgoing::from_activity(activity, local_user(1), &mut iris),
+            Err(Error::WrongActor("https://example.com/users/0-0".to_owned()))
+        );
+    }
+
+    #[test]
+    fn parses_client_boosts_and_likes() {
+        let mut iris = IriMap::new(BASE, Storage::memory());
+        let post_id = PostId::new(Id::new(0), Id::new(0), Clock::new().now());
+        let post_iri = iris.post_iri(post_id);
+        let announce = format!(
+            r#"{{
+            "type": "Announce",
+            "actor": "https://example.com/users/0-0",
+            "object": "{}"
+        }}"#,
+            post_iri
+        );
+        let undo = format!(
+            r#"{{
+            "type": "Undo",
+            "actor": "https://example.com/users/0-0",
+            "object": {}
+        }}"#,
+            announce
+        );
+
+        let activity: Activity = serde_json::from_str(&announce).unwrap();
+        assert_eq!(
+            Outgoing::from_activity(activity, local_user(0), &mut iris),
+            Ok(Outgoing::Boost(Boost(post_id)))
+        );
+
+        let activity: Activity = serde_json::from_str(&undo).unwrap();
+        assert_eq!(
+            Outgoing::from_activity(activity, local_user(0), &mut iris),
+            Ok(Outgoing::Unboost(Unboost(post_id)))
+        );
+
+        let like = announce.replace("Announce", "Like");
+        let unlike = undo.replace("Announce", "Like");
+
+        let activity: Activity = serde_json::from_str(&like).unwrap();
+        assert_eq!(
+            Outgoing::from_activity(activity, local_user(0), &mut iris),
+            Ok(Outgoing::Like(Like(post_id)))
+        );
+
+        let activity: Activity = serde_json::from_str(&unlike).unwrap();
+        assert_eq!(
+            Outgoing::from_activity(activity, local_user(0), &mut iris),
+            Ok(Outgoing::Unlike(Unlike(post_id)))
+        );
+    }
+}
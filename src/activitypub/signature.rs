@@ -0,0 +1,300 @@
+//! HTTP Signatures, as described by draft-cavage-http-signatures and used by Mastodon
+//!
+//! Activities delivered to remote inboxes are signed with the sending user's key, covering the
+//! request target, `host`, `date` and a `digest` of the body. Incoming deliveries must carry a
+//! signature covering all four, dated close enough to now that it can't be replayed later.
+
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
+
+use base64;
+use httpdate::parse_http_date;
+use openssl::error::ErrorStack;
+use openssl::hash::MessageDigest;
+use openssl::pkey::{PKey, Private, Public};
+use openssl::rsa::Rsa;
+use openssl::sha::sha256;
+use openssl::sign::{Signer, Verifier};
+
+const KEY_BITS: u32 = 2048;
+const ALGORITHM: &'static str = "rsa-sha256";
+const REQUEST_TARGET: &'static str = "(request-target)";
+/// How far the `date` of an incoming request may be from the current time, either way
+const MAX_CLOCK_SKEW_SECS: u64 = 300;
+
+/// The headers an incoming signature must cover, besides the `digest` checked with the body
+const REQUIRED_HEADERS: [&'static str; 3] = [REQUEST_TARGET, "host", "date"];
+
+/// The headers signed on outgoing requests, in signing order
+pub const SIGNED_HEADERS: [&'static str; 4] = [REQUEST_TARGET, "host", "date", "digest"];
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum SignatureError {
+    Key(String),
+    Malformed,
+    MissingHeader(String),
+    DigestMismatch,
+    StaleDate(String),
+    UnknownKey(String),
+    KeyOwner(String),
+    Invalid,
+}
+
+impl fmt::Display for SignatureError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SignatureError::Key(ref e) => write!(f, "Key error: {}", e),
+            SignatureError::Malformed => write!(f, "Malformed signature header"),
+            SignatureError::MissingHeader(ref header) => {
+                write!(f, "Header {} is not present or not signed", header)
+            }
+            SignatureError::DigestMismatch => write!(f, "Digest does not match the body"),
+            SignatureError::StaleDate(ref date) => {
+                write!(f, "Date {} is unreadable or too far from now", date)
+            }
+            SignatureError::UnknownKey(ref key_id) => write!(f, "Unknown key: {}", key_id),
+            SignatureError::KeyOwner(ref key_id) => {
+                write!(f, "Key {} does not belong to the activity's actor", key_id)
+            }
+            SignatureError::Invalid => write!(f, "Signature does not verify"),
+        }
+    }
+}
+
+impl From<ErrorStack> for SignatureError {
+    fn from(e: ErrorStack) -> Self {
+        SignatureError::Key(e.to_string())
+    }
+}
+
+/// A user's RSA keypair
+#[derive(Clone)]
+pub struct KeyPair {
+    key: PKey<Private>,
+}
+
+impl KeyPair {
+    pub fn generate() -> Result<Self, SignatureError> {
+        let key = PKey::from_rsa(Rsa::generate(KEY_BITS)?)?;
+
+        Ok(KeyPair { key })
+    }
+
+    pub fn from_pem(pem: &str) -> Result<Self, SignatureError> {
+        let key = PKey::private_key_from_pem(pem.as_bytes())?;
+
+        Ok(KeyPair { key })
+    }
+
+    /// The whole keypair, PEM encoded so it can be stored and read back with `from_pem`
+    pub fn private_key_pem(&self) -> Result<String, SignatureError> {
+        let pem = self.key.private_key_to_pem_pkcs8()?;
+
+        String::from_utf8(pem).map_err(|e| SignatureError::Key(e.to_string()))
+    }
+
+    /// The public half, PEM encoded as it appears in actor documents
+    pub fn public_key_pem(&self) -> String {
+        let pem = self.key
+            .public_key_to_pem()
+            .expect("Failed to encode public key");
+
+        String::from_utf8(pem).expect("PEM is not UTF-8")
+    }
+
+    /// Builds the `Signature` header for a request with the given headers
+    ///
+    /// `headers` must hold every entry of `SIGNED_HEADERS` besides the request target.
+    pub fn sign(
+        &self,
+        key_id: &str,
+        method: &str,
+        path: &str,
+        headers: &[(&str, &str)],
+    ) -> Result<String, SignatureError> {
+        let names: Vec<String> = SIGNED_HEADERS.iter().map(|name| name.to_string()).collect();
+        let signing_string = signing_string(&names, method, path, |name| {
+            headers
+                .iter()
+                .find(|&&(key, _)| key.eq_ignore_ascii_case(name))
+                .map(|&(_, value)| value.to_owned())
+        })?;
+
+        let mut signer = Signer::new(MessageDigest::sha256(), &self.key)?;
+        signer.update(signing_string.as_bytes())?;
+
+        let signature = Signature {
+            key_id: key_id.to_owned(),
+            headers: names,
+            signature: signer.sign_to_vec()?,
+        };
+
+        Ok(signature.to_string())
+    }
+}
+
+impl fmt::Debug for KeyPair {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "KeyPair(..)")
+    }
+}
+
+/// A parsed `Signature` header
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Signature {
+    pub key_id: String,
+    pub headers: Vec<String>,
+    pub signature: Vec<u8>,
+}
+
+impl Signature {
+    /// Checks the signature against the sender's PEM encoded public key
+    ///
+    /// `header` looks up the value of a request header by its lowercase name, and `path` is the
+    /// request's path along with its query string.
+    pub fn verify<F>(
+        &self,
+        public_key_pem: &str,
+        method: &str,
+        path: &str,
+        header: F,
+    ) -> Result<(), SignatureError>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        for required in &REQUIRED_HEADERS {
+            if !self.headers.iter().any(|name| name == required) {
+                return Err(SignatureError::MissingHeader(required.to_string()));
+            }
+        }
+
+        let date = header("date").ok_or_else(|| SignatureError::MissingHeader("date".to_owned()))?;
+        check_date(&date, SystemTime::now())?;
+
+        let signing_string = signing_string(&self.headers, method, path, header)?;
+        let key: PKey<Public> = PKey::public_key_from_pem(public_key_pem.as_bytes())?;
+
+        let mut verifier = Verifier::new(MessageDigest::sha256(), &key)?;
+        verifier.update(signing_string.as_bytes())?;
+
+        if verifier.verify(&self.signature)? {
+            Ok(())
+        } else {
+            Err(SignatureError::Invalid)
+        }
+    }
+
+    /// Checks that the signature covers a `digest` header matching `body`
+    pub fn verify_digest(
+        &self,
+        digest_header: Option<&str>,
+        body: &[u8],
+    ) -> Result<(), SignatureError> {
+        if !self.headers.iter().any(|name| name == "digest") {
+            return Err(SignatureError::MissingHeader("digest".to_owned()));
+        }
+
+        match digest_header {
+            Some(header) if header == digest(body) => Ok(()),
+            Some(_) => Err(SignatureError::DigestMismatch),
+            None => Err(SignatureError::MissingHeader("digest".to_owned())),
+        }
+    }
+}
+
+impl FromStr for Signature {
+    type Err = SignatureError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut key_id = None;
+        let mut headers = None;
+        let mut signature = None;
+
+        for param in s.split(',') {
+            let mut parts = param.trim().splitn(2, '=');
+            let key = parts.next().ok_or(SignatureError::Malformed)?;
+            let value = parts
+                .next()
+                .ok_or(SignatureError::Malformed)?
+                .trim_matches('"');
+
+            match key {
+                "keyId" => key_id = Some(value.to_owned()),
+                "algorithm" if value != ALGORITHM && value != "hs2019" => {
+                    return Err(SignatureError::Malformed)
+                }
+                "headers" => headers = Some(value.split(' ').map(str::to_lowercase).collect()),
+                "signature" => {
+                    signature = Some(base64::decode(value).map_err(|_| SignatureError::Malformed)?)
+                }
+                _ => (),
+            }
+        }
+
+        Ok(Signature {
+            key_id: key_id.ok_or(SignatureError::Malformed)?,
+            // Without a headers parameter, only the date is signed
+            headers: headers.unwrap_or_else(|| vec!["date".to_owned()]),
+            signature: signature.ok_or(SignatureError::Malformed)?,
+        })
+    }
+}
+
+impl fmt::Display for Signature {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "keyId=\"{}\",algorithm=\"{}\",headers=\"{}\",signature=\"{}\"",
+            self.key_id,
+            ALGORITHM,
+            self.headers.join(" "),
+            base64::encode(&self.signature)
+        )
+    }
+}
+
+/// Checks that a `date` header is within `MAX_CLOCK_SKEW_SECS` of `now`
+fn check_date(date: &str, now: SystemTime) -> Result<(), SignatureError> {
+    let sent = parse_http_date(date).map_err(|_| SignatureError::StaleDate(date.to_owned()))?;
+
+    let skew = match now.duration_since(sent) {
+        Ok(skew) => skew,
+        Err(e) => e.duration(),
+    };
+
+    if skew > Duration::from_secs(MAX_CLOCK_SKEW_SECS) {
+        return Err(SignatureError::StaleDate(date.to_owned()));
+    }
+
+    Ok(())
+}
+
+/// The value of the `Digest` header for a request body
+pub fn digest(body: &[u8]) -> String {
+    format!("SHA-256={}", base64::encode(&sha256(body)))
+}
+
+fn signing_string<F>(
+    names: &[String],
+    method: &str,
+    path: &str,
+    header: F,
+) -> Result<String, SignatureError>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    names
+        .iter()
+        .map(|name| {
+            if name == REQUEST_TARGET {
+                Ok(format!("{}: {} {}", name, method.to_lowercase(), path))
+            } else {
+                header(name)
+                    .map(|value| format!("{}: {}", name, value))
+                    .ok_or_else(|| SignatureError::MissingHeader(name.to_owned()))
+            }
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .map(|lines| lines.join("\n"))
+}
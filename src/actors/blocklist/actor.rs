@@ -1,14 +1,19 @@
+use std::any::Any;
 use std::collections::HashSet;
 
-use actors::peered::{HandleAnnounce, HandleMessage, HandleMessageType};
+use actix::SyncAddress;
 
-use super::{Blocklists, UserId};
+use actors::peered::{HandleAnnounce, HandleMessage, HandleMessageType, Peered, Stamp};
+use actors::peered::messages::Announce;
+use actors::peered::transport::Networked;
+
+use super::{BlocklistError, Blocklists, UserId};
 use super::messages::*;
 
 impl HandleMessage<Block> for Blocklists {
     type Broadcast = Block;
     type Item = ();
-    type Error = ();
+    type Error = BlocklistError;
 
     fn handle_message(
         &mut self,
@@ -23,24 +28,28 @@ impl HandleMessage<Block> for Blocklists {
 impl HandleMessage<Unblock> for Blocklists {
     type Broadcast = Unblock;
     type Item = ();
-    type Error = ();
+    type Error = BlocklistError;
 
     fn handle_message(
         &mut self,
         msg: Unblock,
     ) -> HandleMessageType<Self::Item, Self::Error, Self::Broadcast> {
-        self.unblock_user(msg.0, msg.1);
-
-        (Ok(()), Some(msg))
+        match self.unblock_user(msg.0, msg.1) {
+            Ok(()) => (Ok(()), Some(msg)),
+            Err(e) => (Err(e), None),
+        }
     }
 }
 
 impl HandleMessage<GetBlocklist> for Blocklists {
     type Broadcast = ();
     type Item = HashSet<UserId>;
-    type Error = ();
+    type Error = BlocklistError;
 
-    fn handle_message(&mut self, msg: GetBlocklist) -> HandleMessageType<HashSet<UserId>, (), ()> {
+    fn handle_message(
+        &mut self,
+        msg: GetBlocklist,
+    ) -> HandleMessageType<Self::Item, Self::Error, Self::Broadcast> {
         (Ok(self.get_blocked_users(msg.0)), None)
     }
 }
@@ -48,9 +57,12 @@ impl HandleMessage<GetBlocklist> for Blocklists {
 impl HandleMessage<GetBlockedBy> for Blocklists {
     type Broadcast = ();
     type Item = HashSet<UserId>;
-    type Error = ();
+    type Error = BlocklistError;
 
-    fn handle_message(&mut self, msg: GetBlockedBy) -> HandleMessageType<HashSet<UserId>, (), ()> {
+    fn handle_message(
+        &mut self,
+        msg: GetBlockedBy,
+    ) -> HandleMessageType<Self::Item, Self::Error, Self::Broadcast> {
         (Ok(self.is_blocked_by(msg.0)), None)
     }
 }
@@ -58,16 +70,16 @@ impl HandleMessage<GetBlockedBy> for Blocklists {
 impl HandleMessage<CanSpeak> for Blocklists {
     type Broadcast = ();
     type Item = bool;
-    type Error = ();
+    type Error = BlocklistError;
 
-    fn handle_message(&mut self, msg: CanSpeak) -> HandleMessageType<bool, (), ()> {
+    fn handle_message(&mut self, msg: CanSpeak) -> HandleMessageType<bool, BlocklistError, ()> {
         (Ok(self.can_interact(msg.0, msg.1)), None)
     }
 }
 
 impl HandleAnnounce<Block> for Blocklists {
     type Item = ();
-    type Error = ();
+    type Error = BlocklistError;
 
     fn handle_announce(&mut self, msg: Block) -> Result<Self::Item, Self::Error> {
         self.block_user(msg.0, msg.1);
@@ -78,11 +90,47 @@ impl HandleAnnounce<Block> for Blocklists {
 
 impl HandleAnnounce<Unblock> for Blocklists {
     type Item = ();
-    type Error = ();
+    type Error = BlocklistError;
 
     fn handle_announce(&mut self, msg: Unblock) -> Result<Self::Item, Self::Error> {
-        self.unblock_user(msg.0, msg.1);
+        self.unblock_user(msg.0, msg.1)
+    }
+}
 
-        Ok(())
+/// Every node keeps every block, so nothing is forwarded
+impl Networked for Blocklists {
+    const SERVICE: &'static str = "blocklists";
+
+    type Broadcast = BlocklistBroadcast;
+    type Query = ();
+    type Answer = ();
+
+    fn to_wire(broadcast: &Any) -> Option<BlocklistBroadcast> {
+        if let Some(block) = broadcast.downcast_ref::<Block>() {
+            return Some(BlocklistBroadcast::Block(*block));
+        }
+
+        broadcast
+            .downcast_ref::<Unblock>()
+            .map(|unblock| BlocklistBroadcast::Unblock(*unblock))
+    }
+
+    fn announce(local: &SyncAddress<Peered<Self>>, stamp: Stamp, broadcast: BlocklistBroadcast) {
+        match broadcast {
+            BlocklistBroadcast::Block(block) => local.send(Announce::new(stamp, block)),
+            BlocklistBroadcast::Unblock(unblock) => local.send(Announce::new(stamp, unblock)),
+        }
+    }
+
+    fn query_to_wire(_: &Any) -> Option<()> {
+        None
+    }
+
+    fn answer(_: &SyncAddress<Peered<Self>>, _: ()) -> Option<()> {
+        None
+    }
+
+    fn from_answer(answer: ()) -> Box<Any + Send> {
+        Box::new(answer)
     }
 }
@@ -1,11 +1,11 @@
 use super::UserId;
 
 /// Block(acting_user, blocked_user)
-#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
 pub struct Block(pub UserId, pub UserId);
 
 /// Unblock(acting_user, blocked_user)
-#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
 pub struct Unblock(pub UserId, pub UserId);
 
 #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
@@ -16,3 +16,10 @@ pub struct GetBlockedBy(pub UserId);
 
 #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
 pub struct CanSpeak(pub UserId, pub UserId);
+
+/// The broadcasts `Blocklists` sends to peers in other processes
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum BlocklistBroadcast {
+    Block(Block),
+    Unblock(Unblock),
+}
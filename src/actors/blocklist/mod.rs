@@ -1,22 +1,77 @@
 use std::collections::{BTreeMap, HashSet};
+use std::fmt;
 
-use actors::peered::PeeredInner;
+use actors::peered::{Digest, PeeredError, PeeredInner};
+use storage::Storage;
 use super::UserId;
 
 mod actor;
 pub mod messages;
 
+const BACKFILL_CHUNK_SIZE: usize = 100;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum BlocklistError {
+    /// NotBlocked(acting_user, unblocked_user)
+    NotBlocked(UserId, UserId),
+    Peered(PeeredError),
+}
+
+impl fmt::Display for BlocklistError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BlocklistError::NotBlocked(active_user, unblocked_user) => {
+                write!(f, "{:?} has not blocked {:?}", active_user, unblocked_user)
+            }
+            BlocklistError::Peered(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<PeeredError> for BlocklistError {
+    fn from(e: PeeredError) -> Self {
+        BlocklistError::Peered(e)
+    }
+}
+
 pub struct Blocklists {
     lists: BTreeMap<UserId, HashSet<UserId>>,
     inverses: BTreeMap<UserId, HashSet<UserId>>,
+    /// How many times each (acting_user, blocked_user) pair has been blocked or unblocked, odd
+    /// while the block is in place, so the replica that saw more changes wins when reconciling
+    versions: BTreeMap<(UserId, UserId), u64>,
+    storage: Storage,
 }
 
 impl Blocklists {
-    pub fn new() -> Self {
-        Blocklists::default()
+    pub fn new(storage: Storage) -> Self {
+        let mut blocklists = Blocklists {
+            lists: BTreeMap::new(),
+            inverses: BTreeMap::new(),
+            versions: BTreeMap::new(),
+            storage: storage,
+        };
+
+        for (active_user, blocked_user) in blocklists.storage.blocks() {
+            blocklists.insert_block(active_user, blocked_user);
+            blocklists.versions.insert((active_user, blocked_user), 1);
+        }
+
+        blocklists
     }
 
     fn block_user(&mut self, active_user: UserId, blocked_user: UserId) {
+        let version = self.versions.entry((active_user, blocked_user)).or_insert(0);
+
+        if *version % 2 == 0 {
+            *version += 1;
+        }
+
+        self.storage.save_block(active_user, blocked_user);
+        self.insert_block(active_user, blocked_user);
+    }
+
+    fn insert_block(&mut self, active_user: UserId, blocked_user: UserId) {
         self.lists
             .entry(active_user)
             .or_insert(HashSet::new())
@@ -28,17 +83,30 @@ impl Blocklists {
             .insert(active_user);
     }
 
-    fn unblock_user(&mut self, active_user: UserId, unblocked_user: UserId) {
-        let is_empty = self.lists.get_mut(&active_user).map(|list| {
-            list.remove(&unblocked_user);
-
-            list.is_empty()
-        });
-
-        if let Some(true) = is_empty {
-            self.lists.remove(&active_user);
+    fn unblock_user(
+        &mut self,
+        active_user: UserId,
+        unblocked_user: UserId,
+    ) -> Result<(), BlocklistError> {
+        let removed = self.lists
+            .get_mut(&active_user)
+            .map(|list| (list.remove(&unblocked_user), list.is_empty()));
+
+        match removed {
+            Some((false, _)) | None => {
+                return Err(BlocklistError::NotBlocked(active_user, unblocked_user));
+            }
+            Some((true, true)) => {
+                self.lists.remove(&active_user);
+            }
+            Some((true, false)) => (),
         }
 
+        self.storage.delete_block(active_user, unblocked_user);
+        *self.versions
+            .entry((active_user, unblocked_user))
+            .or_insert(1) += 1;
+
         let is_empty = self.inverses.get_mut(&unblocked_user).map(|inverse| {
             inverse.remove(&active_user);
 
@@ -48,6 +116,8 @@ impl Blocklists {
         if let Some(true) = is_empty {
             self.inverses.remove(&unblocked_user);
         }
+
+        Ok(())
     }
 
     fn get_blocked_users(&self, user_id: UserId) -> HashSet<UserId> {
@@ -75,26 +145,20 @@ impl Blocklists {
     }
 }
 
-impl Default for Blocklists {
-    fn default() -> Self {
-        Blocklists {
-            lists: BTreeMap::new(),
-            inverses: BTreeMap::new(),
-        }
-    }
-}
-
 impl PeeredInner for Blocklists {
-    type Backfill = BTreeMap<UserId, HashSet<UserId>>;
+    type Backfill = (usize, BTreeMap<UserId, HashSet<UserId>>);
     type Request = usize;
+    type Diff = Vec<((UserId, UserId), u64)>;
 
     fn backfill(&self, req: Self::Request) -> Self::Backfill {
-        self.lists
+        let lists = self.lists
             .iter()
             .skip(req)
-            .take(10)
-            .map(|(uid, set)| (uid.clone(), set.clone()))
-            .collect()
+            .take(BACKFILL_CHUNK_SIZE)
+            .map(|(uid, set)| (*uid, set.clone()))
+            .collect();
+
+        (req, lists)
     }
 
     fn backfill_init(&self) -> Self::Request {
@@ -102,20 +166,58 @@ impl PeeredInner for Blocklists {
     }
 
     fn handle_backfill(&mut self, backfill: Self::Backfill) -> Option<Self::Request> {
-        for (user, blocklist) in backfill {
-            for blocked_user in &blocklist {
-                self.inverses
-                    .entry(*blocked_user)
-                    .or_insert(HashSet::new())
-                    .insert(user);
+        let ret = if backfill.1.len() == BACKFILL_CHUNK_SIZE {
+            Some(backfill.0 + BACKFILL_CHUNK_SIZE)
+        } else {
+            None
+        };
+
+        for (user, blocklist) in backfill.1 {
+            for blocked_user in blocklist {
+                self.block_user(user, blocked_user);
             }
+        }
+
+        ret
+    }
+    /// Blocklists are never sharded, so every block is shared with every peer
+    fn digest(&self, _shared: &Fn(&[u64]) -> bool) -> Digest {
+        let mut digest = Digest::new();
 
-            self.lists
-                .entry(user)
-                .or_insert(HashSet::new())
-                .extend(blocklist);
+        for (pair, version) in &self.versions {
+            digest.insert(pair, version);
         }
 
-        None
+        digest
+    }
+
+    fn diff(&self, buckets: &[usize], _shared: &Fn(&[u64]) -> bool) -> Self::Diff {
+        self.versions
+            .iter()
+            .filter(|&(pair, _)| buckets.contains(&Digest::bucket(pair)))
+            .map(|(pair, version)| (*pair, *version))
+            .collect()
+    }
+
+    fn reconcile(&mut self, diff: Self::Diff) {
+        for ((active_user, blocked_user), version) in diff {
+            let current = self.versions
+                .get(&(active_user, blocked_user))
+                .cloned()
+                .unwrap_or(0);
+
+            if version <= current {
+                continue;
+            }
+
+            if version % 2 == 1 {
+                self.block_user(active_user, blocked_user);
+            } else {
+                // Not being blocked here already is what the peer's newer version says anyway
+                let _ = self.unblock_user(active_user, blocked_user);
+            }
+
+            self.versions.insert((active_user, blocked_user), version);
+        }
     }
 }
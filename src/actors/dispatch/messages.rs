@@ -2,7 +2,7 @@ use std::collections::BTreeSet;
 
 use actix::ResponseType;
 
-use super::UserId;
+use super::{DispatchError, UserId};
 
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct DispatchMessage<T>(pub T, pub UserId, pub UserId)
@@ -14,7 +14,7 @@ where
     T: Send + 'static,
 {
     type Item = ();
-    type Error = ();
+    type Error = DispatchError;
 }
 
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -27,5 +27,5 @@ where
     T: Clone + Send + 'static,
 {
     type Item = ();
-    type Error = ();
+    type Error = DispatchError;
 }
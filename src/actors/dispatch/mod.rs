@@ -1,30 +1,127 @@
-use actix::{Actor, ActorFuture, Context, Handler, ResponseFuture, ResponseType, SyncAddress};
-use actix::fut::result;
+use std::collections::BTreeSet;
+use std::fmt;
 
-use super::blocklist::Blocklists;
+use actix::{Actor, ActorFuture, Arbiter, Context, Handler, ResponseFuture, ResponseType,
+            SyncAddress};
+use actix::fut::{err, ok, result, wrap_future, Either};
+
+use activitypub::{KeyPair, ToActivity};
+use federation::{Federation, FederationError};
+use super::blocklist::{BlocklistError, Blocklists};
 use super::blocklist::messages::{CanSpeak, GetBlockedBy, GetBlocklist};
 use super::peered::Peered;
 use super::peered::messages::Message;
 use super::user::inbox::Inbox;
+use super::user::messages::InboxMessage;
 use super::UserId;
-use super::users::Users;
-use super::users::messages::{Lookup, LookupMany};
+use super::users::{Users, UsersError};
+use super::users::messages::{Deliver, Lookup, LookupMany};
 
 pub mod messages;
 
 use self::messages::*;
 
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum DispatchError {
+    /// Blocked(source, target), one of the users has blocked the other
+    Blocked(UserId, UserId),
+    Users(UsersError),
+    Blocklist(BlocklistError),
+    /// The remote server couldn't be delivered to
+    PeerUnreachable(FederationError),
+    MailboxClosed,
+}
+
+impl fmt::Display for DispatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DispatchError::Blocked(source, target) => {
+                write!(f, "There is a block between {:?} and {:?}", source, target)
+            }
+            DispatchError::Users(ref e) => write!(f, "{}", e),
+            DispatchError::Blocklist(ref e) => write!(f, "{}", e),
+            DispatchError::PeerUnreachable(ref e) => write!(f, "Could not deliver: {}", e),
+            DispatchError::MailboxClosed => write!(f, "Mailbox closed"),
+        }
+    }
+}
+
+impl From<UsersError> for DispatchError {
+    fn from(e: UsersError) -> Self {
+        DispatchError::Users(e)
+    }
+}
+
+impl From<BlocklistError> for DispatchError {
+    fn from(e: BlocklistError) -> Self {
+        DispatchError::Blocklist(e)
+    }
+}
+
 pub struct Dispatch {
     users: SyncAddress<Peered<Users>>,
     blocklists: SyncAddress<Peered<Blocklists>>,
+    federation: Federation,
+    keys: KeyPair,
 }
 
 impl Dispatch {
     pub fn new(
         users: SyncAddress<Peered<Users>>,
         blocklists: SyncAddress<Peered<Blocklists>>,
+        federation: Federation,
+        keys: KeyPair,
     ) -> Self {
-        Dispatch { users, blocklists }
+        Dispatch {
+            users,
+            blocklists,
+            federation,
+            keys,
+        }
+    }
+
+    /// Sends `message` to a user on another server, resolving once their server accepted it
+    fn deliver<T>(
+        &self,
+        message: &T,
+        source: UserId,
+        target: UserId,
+    ) -> Box<::futures::Future<Item = (), Error = DispatchError>>
+    where
+        T: ToActivity,
+    {
+        use futures::Future;
+
+        let fut = self.federation
+            .deliver(message, &self.keys, source, target)
+            .map_err(DispatchError::PeerUnreachable);
+
+        Box::new(fut)
+    }
+
+    /// Sends `message` to a user on another server, in the background
+    fn deliver_remote<T>(&self, message: &T, source: UserId, target: UserId)
+    where
+        T: ToActivity,
+    {
+        // Scoped here, since it clashes with ActorFuture in the handlers
+        use futures::Future;
+
+        let fut = self.federation
+            .deliver(message, &self.keys, source, target)
+            .map_err(move |e| error!("Error delivering to {:?}: {}", target, e));
+
+        Arbiter::handle().spawn(fut);
+    }
+
+    /// Sends `message` to local users whose actors may run in another process, in the background
+    fn deliver_elsewhere<T>(&self, message: T, recipients: BTreeSet<UserId>)
+    where
+        T: Into<InboxMessage>,
+    {
+        if !recipients.is_empty() {
+            self.users.send(Message::new(Deliver(recipients, message.into())));
+        }
     }
 }
 
@@ -34,7 +131,8 @@ impl Actor for Dispatch {
 
 impl<T> Handler<DispatchMessage<T>> for Dispatch
 where
-    T: ResponseType<Item = (), Error = ()> + Send + 'static,
+    T: ResponseType<Item = ()> + ToActivity + Into<InboxMessage> + Send + 'static,
+    T::Error: Send,
     Inbox: Handler<T>,
 {
     type Result = ResponseFuture<Self, DispatchMessage<T>>;
@@ -51,15 +149,32 @@ where
                     .call(dispatch, Message::new(CanSpeak(source, target)))
                     .map(|speak_result, _, _| (addr_result, speak_result))
             })
-            .map_err(|e, _, _| error!("Error: {}", e))
+            .map_err(|_, _, _| DispatchError::MailboxClosed)
             .and_then(|(addr_result, speak_result), _, _| {
-                let res = addr_result.and_then(|addr| speak_result.map(|speak| (addr, speak)));
-
-                result(res)
+                result(speak_result.map(|speak| (addr_result, speak)).map_err(From::from))
             })
-            .map(move |(addr, can_speak), _, _| {
-                if can_speak {
-                    addr.inbox().send(message);
+            .and_then(move |(addr_result, can_speak), dispatch, _| {
+                if !can_speak {
+                    return Either::A(err(DispatchError::Blocked(source, target)));
+                }
+
+                match addr_result {
+                    Ok(addr) => {
+                        addr.inbox().send(message);
+
+                        Either::A(ok(()))
+                    }
+                    Err(UsersError::UserNotFound(_))
+                        if dispatch.federation.iris().is_remote(target) =>
+                    {
+                        Either::B(wrap_future(dispatch.deliver(&message, source, target)))
+                    }
+                    Err(UsersError::UserElsewhere(_)) => {
+                        dispatch.deliver_elsewhere(message, vec![target].into_iter().collect());
+
+                        Either::A(ok(()))
+                    }
+                    Err(e) => Either::A(err(e.into())),
                 }
             });
 
@@ -69,7 +184,8 @@ where
 
 impl<T> Handler<DispatchAnnounce<T>> for Dispatch
 where
-    T: ResponseType<Item = (), Error = ()> + Clone + Send + 'static,
+    T: ResponseType<Item = ()> + ToActivity + Into<InboxMessage> + Clone + Send + 'static,
+    T::Error: Send,
     Inbox: Handler<T>,
 {
     type Result = ResponseFuture<Self, DispatchAnnounce<T>>;
@@ -87,12 +203,12 @@ where
                     .call(dispatch, Message::new(GetBlockedBy(source)))
                     .map(|blocked_by_res, _, _| (blocklist_res, blocked_by_res))
             })
-            .map_err(|e, _, _| error!("Error: {}", e))
+            .map_err(|_, _, _| DispatchError::MailboxClosed)
             .and_then(|(blocklist_res, blocked_by_res), _, _| {
                 let res = blocklist_res
                     .and_then(|blocklist| blocked_by_res.map(|blocked_by| (blocklist, blocked_by)));
 
-                result(res)
+                result(res.map_err(From::from))
             })
             .map(move |(blocklist, blocked_by), _, _| {
                 recipients
@@ -103,13 +219,33 @@ where
             .and_then(move |recipients, dispatch, _| {
                 users
                     .call(dispatch, Message::new(LookupMany(recipients)))
-                    .map_err(|e, _, _| error!("Error: {}", e))
+                    .map_err(|_, _, _| DispatchError::MailboxClosed)
             })
-            .and_then(|res, _, _| result(res))
-            .map(move |(addrs, _missing_ids), _, _| {
+            .and_then(|res, _, _| result(res.map_err(From::from)))
+            .map(move |(addrs, missing_ids), dispatch, _| {
                 for addr in addrs {
                     addr.inbox().send(message.clone());
                 }
+
+                // Users missing here are either on other servers or run in other processes
+                let (remote_ids, elsewhere): (Vec<_>, Vec<_>) = {
+                    let iris = dispatch.federation.iris();
+
+                    missing_ids
+                        .into_iter()
+                        .partition(|user_id| iris.is_remote(*user_id))
+                };
+
+                for user_id in remote_ids {
+                    dispatch.deliver_remote(&message, source, user_id);
+                }
+
+                dispatch.deliver_elsewhere(message, elsewhere.into_iter().collect());
+            })
+            .map_err(|e, _, _| {
+                // Announces are usually sent without waiting on the result
+                error!("Error: {}", e);
+                e
             });
 
         Box::new(fut)
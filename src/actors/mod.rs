@@ -6,26 +6,155 @@ pub mod user;
 pub mod users;
 
 use std::cmp::Ordering;
-use std::time::Instant;
+use std::fmt;
+use std::num::ParseIntError;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde::de;
+
+/// How long deleted posts and users are remembered, so that peers that missed the delete don't
+/// bring them back. A peer unreachable for longer than this may still do so.
+const TOMBSTONE_TTL_MS: u64 = 30 * 24 * 60 * 60 * 1_000;
+
+/// The wall clock time, in milliseconds since the epoch
+fn millis_since_epoch() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_secs() * 1_000 + u64::from(now.subsec_nanos()) / 1_000_000)
+        .unwrap_or(0)
+}
 
-#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
 pub struct Id(u64);
 
+impl Id {
+    pub fn new(id: u64) -> Self {
+        Id(id)
+    }
+}
+
+impl fmt::Display for Id {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl FromStr for Id {
+    type Err = ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse().map(Id)
+    }
+}
+
 pub type PostsId = Id;
 pub type UsersId = Id;
 
-#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
-pub struct PostId(pub PostsId, pub Id, pub Instant);
+/// The `UsersId` and `PostsId` under which actors and posts from other servers are registered
+pub const REMOTE_ID: Id = Id(::std::u64::MAX);
+
+/// A reading of a hybrid logical clock, ordered by wall-clock milliseconds first
+///
+/// `counter` tells apart readings made within the same millisecond, or while the wall clock is
+/// behind a reading observed from another node.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
+pub struct Timestamp {
+    pub millis: u64,
+    pub counter: u32,
+}
+
+/// Hands out `Timestamp`s later than every one it has handed out or observed
+///
+/// Nodes observe the timestamps of the posts they receive, so a post always sorts after the
+/// posts its author could have seen, whichever node they were made on.
+#[derive(Clone, Debug)]
+pub struct Clock {
+    last: Timestamp,
+}
+
+impl Clock {
+    pub fn new() -> Self {
+        Clock {
+            last: Timestamp {
+                millis: 0,
+                counter: 0,
+            },
+        }
+    }
+
+    pub fn now(&mut self) -> Timestamp {
+        let millis = millis_since_epoch();
+
+        self.last = if millis > self.last.millis {
+            Timestamp {
+                millis: millis,
+                counter: 0,
+            }
+        } else if let Some(counter) = self.last.counter.checked_add(1) {
+            Timestamp {
+                millis: self.last.millis,
+                counter: counter,
+            }
+        } else {
+            // The counter ran out, so the clock moves on to the next millisecond
+            Timestamp {
+                millis: self.last.millis + 1,
+                counter: 0,
+            }
+        };
+
+        self.last
+    }
+
+    /// Moves the clock past a timestamp made elsewhere
+    pub fn observe(&mut self, timestamp: Timestamp) {
+        if timestamp > self.last {
+            self.last = timestamp;
+        }
+    }
+}
+
+/// PostId(posts_id, post_id, created)
+///
+/// Ids sort by when they were created, so timelines merged from several nodes stay in order.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+pub struct PostId(pub PostsId, pub Id, pub Timestamp);
 
 impl PostId {
-    pub fn new(posts_id: PostsId, post_id: Id) -> Self {
-        PostId(posts_id, post_id, Instant::now())
+    pub fn new(posts_id: PostsId, post_id: Id, created: Timestamp) -> Self {
+        PostId(posts_id, post_id, created)
+    }
+}
+
+/// Formats as `{posts_id}-{post_id}-{millis}.{counter}`, the form used in IRIs and storage keys
+impl fmt::Display for PostId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}-{}-{}.{}", self.0, self.1, self.2.millis, self.2.counter)
+    }
+}
+
+impl FromStr for PostId {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(3, '-');
+
+        let posts_id = parts.next().ok_or(())?.parse().map_err(|_| ())?;
+        let post_id = parts.next().ok_or(())?.parse().map_err(|_| ())?;
+
+        let mut time = parts.next().ok_or(())?.splitn(2, '.');
+        let millis = time.next().ok_or(())?.parse().map_err(|_| ())?;
+        let counter = time.next().ok_or(())?.parse().map_err(|_| ())?;
+
+        Ok(PostId(posts_id, post_id, Timestamp { millis, counter }))
     }
 }
 
 impl Ord for PostId {
     fn cmp(&self, other: &PostId) -> Ordering {
-        self.2.cmp(&other.2)
+        (self.2, self.0, self.1).cmp(&(other.2, other.0, other.1))
     }
 }
 
@@ -60,6 +189,42 @@ impl PartialOrd for UserId {
     }
 }
 
+/// Formats as `{users_id}-{user_id}`, the form used in IRIs
+impl fmt::Display for UserId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}-{}", self.0, self.1)
+    }
+}
+
+impl FromStr for UserId {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, '-');
+
+        let users_id = parts.next().ok_or(())?.parse().map_err(|_| ())?;
+        let user_id = parts.next().ok_or(())?.parse().map_err(|_| ())?;
+
+        Ok(UserId(users_id, user_id))
+    }
+}
+
+/// Serialized in its `Display` form, so it can key JSON maps
+impl Serialize for UserId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for UserId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+
+        s.parse()
+            .map_err(|_| de::Error::custom(format!("invalid user id {:?}", s)))
+    }
+}
+
 /* Posts is disjoint
  *
  * Users depends on Posts
@@ -74,39 +239,100 @@ impl PartialOrd for UserId {
 #[cfg(test)]
 mod tests {
     use std::collections::BTreeSet;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
     use std::time::Duration;
 
-    use actix::{Actor, Arbiter, SyncAddress, System};
-    use actix::msgs::{Execute, SystemExit};
+    use actix::{Actor, Arbiter, Context, Handler, SyncAddress, System};
+    use actix::msgs::{Execute, StartActor, SystemExit};
     use futures::{Future, Stream};
     use futures::future;
     use futures::stream::iter_ok;
-    use tokio_timer::Timer;
+    use serde_json;
+    use tokio_timer::{wheel, Timer};
+
+    use activitypub::{Activity, IriMap, Signature};
+    use federation::{Client, ClientError, Federation};
+    use storage::{Relation, Storage};
+    use super::blocklist::{BlocklistError, Blocklists};
+    use super::blocklist::messages::{Block, CanSpeak, Unblock};
+    use super::{millis_since_epoch, Clock, Id, PostId, Timestamp, UserId, TOMBSTONE_TTL_MS};
+    use super::dispatch::DispatchError;
+    use super::peered::{shard_key, Digest, PeerId, Peered, PeeredInner, Stamp, VersionVector};
+    use super::peered::transport::Transport;
+    use super::peered::messages::{Announce, BackfillProgress, BackfillStatus, Leave,
+                                  MembershipEvent, Message, PeerSize, Reconcile,
+                                  SubscribeMembership};
+    use super::posts::{Content, Post, PostStats, Posts, PostsError, Reaction, Visibility};
+    use super::posts::messages::{AddReaction, DeletePost, GetPostStats, GetPostsByIds,
+                                 GetPublicPostIds, GetThread, NewPost, PostSize, RemoveReaction};
+    use super::user::{Notification, Profile, UserError};
+    use super::user::messages::{AcceptFollowRequest, BlockUser, Boost, DenyFollowRequest,
+                                FollowRequest, GetFollowers, GetNotifications, GetPostIds,
+                                GetPosts, GetPublicKey, GetUserPostIds, GetVisiblePostIds, Like,
+                                NewPostOut, RemoveFollower, RequestFollow, UnblockUser, Unboost,
+                                Unfollow, Unlike};
+    use super::users::{UserAddress, UserEntry, Users, UsersError};
+    use super::users::messages::{Lookup, LookupMany, LookupUsername, NewUser, RestoreUsers,
+                                 UserSize};
+
+    const SECRET: &'static str = "secret";
+
+    #[test]
+    fn clock_moves_on_when_its_counter_runs_out() {
+        let mut clock = Clock::new();
+
+        let now = clock.now();
+        let last = Timestamp {
+            millis: now.millis + 3_600_000,
+            counter: u32::max_value(),
+        };
+        clock.observe(last);
+
+        let next = clock.now();
+
+        assert!(next > last);
+        assert_eq!(next.millis, last.millis + 1);
+    }
+
+    #[test]
+    fn post_ids_from_different_nodes_sort_by_creation() {
+        let (mut clock_0, mut clock_1) = (Clock::new(), Clock::new());
+
+        // The first node's wall clock runs an hour ahead of the second's
+        let now = clock_0.now();
+        clock_0.observe(Timestamp {
+            millis: now.millis + 3_600_000,
+            counter: 0,
+        });
+
+        let first = PostId::new(Id(0), Id(7), clock_0.now());
+        let unrelated = PostId::new(Id(1), Id(0), clock_1.now());
+
+        clock_1.observe(first.2);
+        let reply = PostId::new(Id(1), Id(1), clock_1.now());
+        let again = PostId::new(Id(1), Id(2), clock_1.now());
+
+        assert!(unrelated < first);
+        assert!(first < reply);
+        assert!(reply < again);
 
-    use super::blocklist::Blocklists;
-    use super::blocklist::messages::CanSpeak;
-    use super::{Id, UserId};
-    use super::peered::Peered;
-    use super::peered::messages::{Message, PeerSize};
-    use super::posts::Posts;
-    use super::posts::messages::PostSize;
-    use super::user::messages::{AcceptFollowRequest, BlockUser, DenyFollowRequest, GetFollowers,
-                                GetPostIds, GetUserPostIds, NewPostOut, RequestFollow};
-    use super::users::{UserAddress, Users};
-    use super::users::messages::{Lookup, LookupMany, NewUser, UserSize};
+        let json = serde_json::to_string(&reply).unwrap();
+        assert_eq!(serde_json::from_str::<PostId>(&json).unwrap(), reply);
+    }
 
     #[test]
     fn peered_users_can_iteract() {
         let system = System::new("test");
         let handle = Arbiter::handle();
 
-        let posts_1: SyncAddress<_> = Peered::new(Posts::new(Id(0))).start();
-        let posts_2: SyncAddress<_> = Peered::new(Posts::new(Id(1)))
+        let posts_1: SyncAddress<_> = Peered::new(Posts::new(Id(0), Storage::memory())).start();
+        let posts_2: SyncAddress<_> = Peered::new(Posts::new(Id(1), Storage::memory()))
             .add_peer(posts_1.clone())
             .start();
 
-        let users_1: SyncAddress<_> = Peered::new(Users::new(Id(0), posts_1.clone())).start();
-        let users_2: SyncAddress<_> = Peered::new(Users::new(Id(1), posts_2.clone()))
+        let users_1: SyncAddress<_> = Peered::new(users(Id(0), posts_1.clone())).start();
+        let users_2: SyncAddress<_> = Peered::new(users(Id(1), posts_2.clone()))
             .add_peer(users_1.clone())
             .start();
 
@@ -115,12 +341,20 @@ mod tests {
         let ping_3 = users_1.call_fut(Message::new(UserSize));
         let ping_4 = users_2.call_fut(Message::new(UserSize));
 
-        let blocklists: SyncAddress<_> = Peered::new(Blocklists::new()).start();
-
-        let new_u1 = Message::new(NewUser(users_1.clone(), blocklists.clone()));
-        let u1 = users_1.call_fut(new_u1).map_err(|_| ()).and_then(|res| res);
-        let new_u2 = Message::new(NewUser(users_2.clone(), blocklists.clone()));
-        let u2 = users_2.call_fut(new_u2).map_err(|_| ()).and_then(|res| res);
+        let blocklists: SyncAddress<_> = Peered::new(Blocklists::new(Storage::memory())).start();
+
+        let new_u1 = Message::new(NewUser(
+            users_1.clone(),
+            blocklists.clone(),
+            Profile::new("user1"),
+        ));
+        let u1 = users_1.call_fut(new_u1).map_err(|_| ()).and_then(|res| res.map_err(|_| ()));
+        let new_u2 = Message::new(NewUser(
+            users_2.clone(),
+            blocklists.clone(),
+            Profile::new("user2"),
+        ));
+        let u2 = users_2.call_fut(new_u2).map_err(|_| ()).and_then(|res| res.map_err(|_| ()));
 
         let duration = Duration::from_millis(200);
 
@@ -135,7 +369,7 @@ mod tests {
                 users_1
                     .call_fut(Message::new(LookupMany(uid_vec.iter().cloned().collect())))
                     .map_err(|_| ())
-                    .and_then(|res| res)
+                    .and_then(|res| res.map_err(|_| ()))
                     .map(|(user_addrs, _)| (uid_vec, user_addrs))
             })
             .map(|(ids_vec, addrs_vec)| {
@@ -179,7 +413,7 @@ mod tests {
                 // user 1 makes post
                 addrs_vec[1]
                     .outbox()
-                    .call_fut(NewPostOut(BTreeSet::new()))
+                    .call_fut(new_post(BTreeSet::new()))
                     .map_err(|_| ())
                     .map(|_| (ids_vec, addrs_vec))
             })
@@ -195,7 +429,7 @@ mod tests {
                     .user()
                     .call_fut(GetUserPostIds(10))
                     .map_err(|_| ())
-                    .and_then(|res| res)
+                    .and_then(|res| res.map_err(|_| ()))
                     .map(|post_ids| assert!(!post_ids.is_empty()));
 
                 // user 0 should have a post in inbox
@@ -203,7 +437,7 @@ mod tests {
                     .user()
                     .call_fut(GetPostIds(10))
                     .map_err(|_| ())
-                    .and_then(|res| res)
+                    .and_then(|res| res.map_err(|_| ()))
                     .map(|post_ids| assert!(!post_ids.is_empty()));
 
                 // user 0 should not own a post
@@ -211,7 +445,7 @@ mod tests {
                     .user()
                     .call_fut(GetUserPostIds(10))
                     .map_err(|_| ())
-                    .and_then(|res| res)
+                    .and_then(|res| res.map_err(|_| ()))
                     .map(|post_ids| assert!(post_ids.is_empty()));
 
                 fut.and_then(|_| fut2).and_then(|_| fut3)
@@ -230,19 +464,19 @@ mod tests {
         let arbiter = Arbiter::new("test-exec");
         let handle = Arbiter::handle();
 
-        let posts_1: SyncAddress<_> = Peered::new(Posts::new(Id(0))).start();
-        let posts_2: SyncAddress<_> = Peered::new(Posts::new(Id(1)))
+        let posts_1: SyncAddress<_> = Peered::new(Posts::new(Id(0), Storage::memory())).start();
+        let posts_2: SyncAddress<_> = Peered::new(Posts::new(Id(1), Storage::memory()))
             .add_peer(posts_1.clone())
             .start();
-        let posts_3: SyncAddress<_> = Peered::new(Posts::new(Id(2)))
+        let posts_3: SyncAddress<_> = Peered::new(Posts::new(Id(2), Storage::memory()))
             .add_peer(posts_2.clone())
             .start();
 
-        let users_1: SyncAddress<_> = Peered::new(Users::new(Id(0), posts_1.clone())).start();
-        let users_2: SyncAddress<_> = Peered::new(Users::new(Id(1), posts_2.clone()))
+        let users_1: SyncAddress<_> = Peered::new(users(Id(0), posts_1.clone())).start();
+        let users_2: SyncAddress<_> = Peered::new(users(Id(1), posts_2.clone()))
             .add_peer(users_1.clone())
             .start();
-        let users_3: SyncAddress<_> = Peered::new(Users::new(Id(2), posts_2.clone()))
+        let users_3: SyncAddress<_> = Peered::new(users(Id(2), posts_2.clone()))
             .add_peer(users_2.clone())
             .start();
 
@@ -269,37 +503,37 @@ mod tests {
                 let fut_1 = posts_1
                     .call_fut(PeerSize)
                     .map_err(|_| ())
-                    .and_then(|res| res)
+                    .and_then(|res| res.map_err(|_| ()))
                     .map(|peer_size| assert_eq!(peer_size, 2));
 
                 let fut_2 = posts_2
                     .call_fut(PeerSize)
                     .map_err(|_| ())
-                    .and_then(|res| res)
+                    .and_then(|res| res.map_err(|_| ()))
                     .map(|peer_size| assert_eq!(peer_size, 2));
 
                 let fut_3 = posts_3
                     .call_fut(PeerSize)
                     .map_err(|_| ())
-                    .and_then(|res| res)
+                    .and_then(|res| res.map_err(|_| ()))
                     .map(|peer_size| assert_eq!(peer_size, 2));
 
                 let fut_4 = users_1
                     .call_fut(PeerSize)
                     .map_err(|_| ())
-                    .and_then(|res| res)
+                    .and_then(|res| res.map_err(|_| ()))
                     .map(|peer_size| assert_eq!(peer_size, 2));
 
                 let fut_5 = users_2
                     .call_fut(PeerSize)
                     .map_err(|_| ())
-                    .and_then(|res| res)
+                    .and_then(|res| res.map_err(|_| ()))
                     .map(|peer_size| assert_eq!(peer_size, 2));
 
                 let fut_6 = users_3
                     .call_fut(PeerSize)
                     .map_err(|_| ())
-                    .and_then(|res| res)
+                    .and_then(|res| res.map_err(|_| ()))
                     .map(|peer_size| assert_eq!(peer_size, 2));
 
                 fut_1
@@ -323,238 +557,2540 @@ mod tests {
     }
 
     #[test]
-    fn test_new_users() {
-        with_users(|_, _, _| future::result(Ok(())))
+    fn digests_cover_only_shared_keys() {
+        let _system = System::new("test");
+
+        let posts: SyncAddress<_> = Peered::new(Posts::new(Id(0), Storage::memory())).start();
+        let mut users = users(Id(0), posts);
+        let (shared, unshared) = (user(1), user(2));
+
+        let deleted_at = millis_since_epoch();
+        users.reconcile((Vec::new(), vec![(shared, deleted_at), (unshared, deleted_at)]));
+
+        let key = shard_key(&shared);
+        let only_shared = |keys: &[u64]| keys.contains(&key);
+
+        assert_eq!(users.digest(&|_| false), Digest::new());
+        assert_eq!(
+            Digest::new().differing(&users.digest(&only_shared)),
+            vec![Digest::bucket(&shared)]
+        );
+
+        let buckets = Digest::new().differing(&users.digest(&|_| true));
+        let (found, deleted) = users.diff(&buckets, &only_shared);
+
+        assert!(found.is_empty());
+        assert_eq!(deleted, vec![(shared, deleted_at)]);
     }
 
     #[test]
-    fn test_blocked_user_doesnt_receive_post() {
-        with_users(|ids_vec, addrs_vec, blocklists| {
-            let u0_a = addrs_vec[0].clone();
-            let u0_b = addrs_vec[0].clone();
-            let uid0 = ids_vec[0];
-            let u1_a = addrs_vec[1].clone();
-            let u1_b = u1_a.clone();
-            let u1_c = u1_a.clone();
-            let u1_d = u1_a.clone();
-            let uid1 = ids_vec[1];
+    fn deletes_are_remembered_across_restarts_until_they_expire() {
+        let _system = System::new("test");
 
-            let blocklists_clone = blocklists.clone();
+        let storage = Storage::memory();
+        let now = millis_since_epoch();
+        let long_ago = now - TOMBSTONE_TTL_MS;
+        let everything = |_: &[u64]| true;
 
-            // User 0 requests to follow User 1
-            u0_a.outbox()
-                .call_fut(RequestFollow(ids_vec[1]))
+        let (recent_post, old_post) = (
+            PostId::new(Id(0), Id(0), Clock::new().now()),
+            PostId::new(Id(0), Id(1), Clock::new().now()),
+        );
+        let mut posts = Posts::new(Id(0), storage.clone());
+        posts.reconcile((
+            Vec::new(),
+            vec![(recent_post, now), (old_post, long_ago)],
+            Vec::new(),
+        ));
+
+        let restarted = Posts::new(Id(0), storage.clone());
+        let buckets = Digest::new().differing(&restarted.digest(&everything));
+        assert_eq!(restarted.diff(&buckets, &everything).1, vec![(recent_post, now)]);
+
+        let posts: SyncAddress<_> = Peered::new(restarted).start();
+        let (recent_user, old_user) = (user(1), user(2));
+        let mut users = Users::new(Id(0), posts.clone(), federation(), storage.clone());
+        users.reconcile((Vec::new(), vec![(recent_user, now), (old_user, long_ago)]));
+
+        let restarted = Users::new(Id(0), posts, federation(), storage);
+        let buckets = Digest::new().differing(&restarted.digest(&everything));
+        assert_eq!(restarted.diff(&buckets, &everything).1, vec![(recent_user, now)]);
+    }
+
+    #[test]
+    fn username_conflicts_go_to_the_lower_user_id() {
+        let system = System::new("test");
+
+        let posts: SyncAddress<_> = Peered::new(Posts::new(Id(0), Storage::memory())).start();
+        let users_1: SyncAddress<_> = Peered::new(users(Id(0), posts.clone())).start();
+        let users_2: SyncAddress<_> = Peered::new(users(Id(1), posts)).start();
+        let blocklists: SyncAddress<_> = Peered::new(Blocklists::new(Storage::memory())).start();
+
+        // Both nodes hand out the username before hearing of each other
+        let new_user = |users: &SyncAddress<Peered<Users>>| {
+            let (users, lookups) = (users.clone(), users.clone());
+            let profile = Profile::new("alice");
+
+            users
+                .call_fut(Message::new(NewUser(users.clone(), blocklists.clone(), profile)))
                 .map_err(|_| ())
-                .and_then(move |_| {
-                    // user 0 and user 1 have no established blocks
-                    blocklists
-                        .clone()
-                        .call_fut(Message::new(CanSpeak(uid0, uid1)))
-                        .map_err(|_| ())
-                        .and_then(|res| res)
-                        .map(|can_speak| assert!(can_speak))
-                })
-                .map(|_| Timer::default().sleep(Duration::from_millis(100)))
-                .and_then(move |_| {
-                    // user 1 accepts user 0's follow request
-                    u1_a.outbox()
-                        .call_fut(AcceptFollowRequest(uid0))
-                        .map_err(|_| ())
-                })
-                .map(|_| Timer::default().sleep(Duration::from_millis(100)))
-                .and_then(move |_| {
-                    u1_b.outbox()
-                        .call_fut(NewPostOut(BTreeSet::new()))
-                        .map_err(|_| ())
-                })
-                .map(|_| Timer::default().sleep(Duration::from_millis(100)))
-                .and_then(move |_| {
-                    // user 0 should have a post in inbox
-                    u0_b.user()
-                        .call_fut(GetPostIds(10))
-                        .map_err(|_| ())
-                        .and_then(|res| res)
-                        .map(|post_ids| assert!(!post_ids.is_empty()))
-                })
-                .map(|_| Timer::default().sleep(Duration::from_millis(100)))
-                .and_then(move |_| {
-                    // user 1 blocks user 0
-                    u1_c.outbox().call_fut(BlockUser(uid0)).map_err(|_| ())
-                })
-                .map(|_| Timer::default().sleep(Duration::from_millis(100)))
-                .and_then(move |_| {
-                    // user 1 makes post
-                    u1_d.outbox()
-                        .call_fut(NewPostOut(BTreeSet::new()))
+                .and_then(|res| res.map_err(|_| ()))
+                .and_then(move |user_id| {
+                    lookups
+                        .call_fut(Message::new(Lookup(user_id)))
                         .map_err(|_| ())
+                        .and_then(|res| res.map_err(|_| ()))
+                        .map(move |user_address| (user_id, user_address))
                 })
-                .and_then(move |_| {
-                    // user 1 should own two posts
-                    let fut = addrs_vec[1]
-                        .user()
-                        .call_fut(GetUserPostIds(10))
-                        .map_err(|_| ())
-                        .and_then(|res| res)
-                        .map(|post_ids| assert_eq!(post_ids.len(), 2));
+        };
 
-                    // user 0 should not have a post in inbox
-                    let fut2 = addrs_vec[0]
-                        .user()
-                        .call_fut(GetPostIds(10))
-                        .map_err(|_| ())
-                        .and_then(|res| res)
-                        .map(|post_ids| assert!(post_ids.is_empty()));
+        let (lookup_1, lookup_2) = (users_1.clone(), users_2.clone());
 
-                    // user 1 and user 0 have a block separating them
-                    let fut3 = blocklists_clone
-                        .call_fut(Message::new(CanSpeak(uid0, uid1)))
+        let fut = new_user(&users_1)
+            .join(new_user(&users_2))
+            .and_then(move |(first, second)| {
+                let entry = |&(user_id, ref addr): &(UserId, UserAddress)| {
+                    (vec![(user_id, UserEntry::Started(addr.clone()))], Vec::new())
+                };
+                let sender = PeerId::generate();
+                users_1.send(Reconcile(sender, None, Vec::new(), entry(&second)));
+                users_2.send(Reconcile(sender, None, Vec::new(), entry(&first)));
+
+                settle().map(move |_| (first.0, second.0))
+            })
+            .and_then(move |(winner, loser)| {
+                assert!(winner < loser);
+
+                let owner = |users: SyncAddress<Peered<Users>>| {
+                    users
+                        .call_fut(Message::new(LookupUsername("alice".to_owned())))
                         .map_err(|_| ())
-                        .and_then(|res| res)
-                        .map(|can_speak| assert!(!can_speak));
+                        .and_then(|res| res.map_err(|_| ()))
+                        .map(|(user_id, _)| user_id)
+                };
+                let loser_found = lookup_1
+                    .call_fut(Message::new(Lookup(loser)))
+                    .map_err(|_| ())
+                    .map(|res| assert!(res.is_ok()));
+
+                owner(lookup_1)
+                    .join3(owner(lookup_2), loser_found)
+                    .map(move |(first, second, _)| {
+                        assert_eq!(first, winner);
+                        assert_eq!(second, winner);
+                    })
+            })
+            .map(|_| Arbiter::system().send(SystemExit(0)))
+            .map_err(|_| panic!("Future error case"));
 
-                    fut.and_then(|_| fut2).and_then(|_| fut3)
-                })
-        })
+        Arbiter::handle().spawn(fut);
+
+        system.run();
     }
 
     #[test]
-    fn test_no_follow_and_no_post_propagation() {
-        with_users(|ids_vec, addrs_vec, _| {
-            // User 0 requests to follow User 1
-            addrs_vec[0].outbox().send(RequestFollow(ids_vec[1]));
+    fn departing_peers_are_removed() {
+        let system = System::new("test");
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let membership: SyncAddress<_> = Membership(events.clone()).start();
+
+        let posts_1: SyncAddress<_> = Peered::new(Posts::new(Id(0), Storage::memory())).start();
+        posts_1.send(SubscribeMembership(membership.subscriber()));
+        let posts_2: SyncAddress<_> = Peered::new(Posts::new(Id(1), Storage::memory()))
+            .add_peer(posts_1.clone())
+            .start();
+        let posts_1_clone = posts_1.clone();
 
-            // user 1 denies user 0's follow request
-            addrs_vec[1].outbox().send(DenyFollowRequest(ids_vec[0]));
+        let fut = settle()
+            .and_then(move |_| {
+                posts_1
+                    .call_fut(PeerSize)
+                    .map_err(|_| ())
+                    .and_then(|res| res.map_err(|_| ()))
+                    .map(|peer_size| assert_eq!(peer_size, 1))
+            })
+            .and_then(move |_| {
+                posts_2.send(Leave);
+                settle()
+            })
+            .and_then(move |_| {
+                posts_1_clone
+                    .call_fut(PeerSize)
+                    .map_err(|_| ())
+                    .and_then(|res| res.map_err(|_| ()))
+                    .map(|peer_size| assert_eq!(peer_size, 0))
+            })
+            .map(move |_| {
+                let events = events.lock().unwrap();
+                assert_eq!(events.len(), 2);
+
+                if let MembershipEvent::Joined(id) = events[0] {
+                    assert_eq!(events[1], MembershipEvent::Left(id));
+                } else {
+                    panic!("Expected a join, got {:?}", events[0]);
+                }
+            });
 
-            // user 1 makes post
-            addrs_vec[1].outbox().send(NewPostOut(BTreeSet::new()));
+        Arbiter::handle().spawn(
+            fut.map(|_| Arbiter::system().send(SystemExit(0)))
+                .map_err(|_| panic!("Future error case")),
+        );
 
-            // user 1 should own a post
-            let fut = addrs_vec[1]
-                .user()
-                .call_fut(GetUserPostIds(10))
-                .map_err(|_| ())
-                .and_then(|res| res)
-                .map(|post_ids| assert!(!post_ids.is_empty()));
+        system.run();
+    }
 
-            // user 0 should not have a post in inbox
-            let fut2 = addrs_vec[0]
-                .user()
-                .call_fut(GetPostIds(10))
-                .map_err(|_| ())
-                .and_then(|res| res)
-                .map(|post_ids| assert!(post_ids.is_empty()));
+    #[test]
+    fn unresponsive_peers_are_evicted() {
+        let system = System::new("test");
+        let arbiter = Arbiter::new("unresponsive");
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let membership: SyncAddress<_> = Membership(events.clone()).start();
 
-            // user 2 should not have a post in inbox
-            let fut3 = addrs_vec[2]
-                .user()
-                .call_fut(GetPostIds(10))
-                .map_err(|_| ())
-                .and_then(|res| res)
-                .map(|post_ids| assert!(post_ids.is_empty()));
+        let posts_1: SyncAddress<_> = Peered::new(Posts::new(Id(0), Storage::memory()))
+            .heartbeat(Duration::from_millis(20), Duration::from_millis(80))
+            .start();
+        posts_1.send(SubscribeMembership(membership.subscriber()));
+        let posts_1_clone = posts_1.clone();
 
-            fut.and_then(|_| fut2).and_then(|_| fut3)
-        })
+        let fut = arbiter
+            .call_fut(StartActor::new(move |_| {
+                Peered::new(Posts::new(Id(1), Storage::memory())).add_peer(posts_1_clone)
+            }))
+            .map_err(|_| ())
+            .and_then(|res| res.map_err(|_| ()))
+            .and_then(|_| settle())
+            .and_then(move |_| {
+                let events = events.clone();
+                assert_eq!(events.lock().unwrap().len(), 1);
+
+                // Keep the second peer's arbiter too busy to answer pings
+                arbiter.send(Execute::new(|| -> Result<(), ()> {
+                    thread::sleep(Duration::from_millis(500));
+                    Ok(())
+                }));
+
+                settle().and_then(|_| settle()).map(move |_| events)
+            })
+            .and_then(move |events| {
+                posts_1
+                    .call_fut(PeerSize)
+                    .map_err(|_| ())
+                    .and_then(|res| res.map_err(|_| ()))
+                    .map(|peer_size| assert_eq!(peer_size, 0))
+                    .map(move |_| {
+                        let events = events.lock().unwrap();
+                        assert_eq!(events.len(), 2);
+
+                        if let MembershipEvent::Joined(id) = events[0] {
+                            assert_eq!(events[1], MembershipEvent::Evicted(id));
+                        } else {
+                            panic!("Expected a join, got {:?}", events[0]);
+                        }
+                    })
+            });
+
+        Arbiter::handle().spawn(
+            fut.map(|_| Arbiter::system().send(SystemExit(0)))
+                .map_err(|_| panic!("Future error case")),
+        );
+
+        system.run();
     }
 
     #[test]
-    fn test_follow_and_post_propagation() {
-        with_users(|ids_vec, addrs_vec, _| {
-            // User 0 requests to follow User 1
-            addrs_vec[0].outbox().send(RequestFollow(ids_vec[1]));
+    fn backfill_moves_on_from_unresponsive_peers() {
+        let system = System::new("test");
+        let arbiter = Arbiter::new("unresponsive");
 
-            // User 2 requests to follow User 1
-            addrs_vec[2].outbox().send(RequestFollow(ids_vec[1]));
+        let posts_0: SyncAddress<_> = Peered::new(Posts::new(Id(0), Storage::memory())).start();
+        let author = UserId::new(Id(0), Id(0));
 
-            // user 1 accepts user 0's follow request
-            addrs_vec[1].outbox().send(AcceptFollowRequest(ids_vec[0]));
+        let new_posts: Vec<_> = (0..250)
+            .map(|_| {
+                posts_0
+                    .call_fut(Message::new(public_post(author, Content::default())))
+                    .map_err(|_| ())
+                    .and_then(|res| res.map_err(|_| ()))
+            })
+            .collect();
 
-            // user 1 accepts user 2's follow request
-            addrs_vec[1].outbox().send(AcceptFollowRequest(ids_vec[2]));
+        let fut = future::join_all(new_posts)
+            .and_then(move |_| {
+                arbiter
+                    .call_fut(StartActor::new(|_| {
+                        Peered::new(Posts::new(Id(1), Storage::memory()))
+                    }))
+                    .map_err(|_| ())
+                    .and_then(|res| res.map_err(|_| ()))
+                    .map(move |slow| (arbiter, slow))
+            })
+            .and_then(move |(arbiter, slow)| {
+                // Keep the first seed too busy to answer until backfill is done elsewhere
+                arbiter.send(Execute::new(|| -> Result<(), ()> {
+                    thread::sleep(Duration::from_millis(500));
+                    Ok(())
+                }));
+
+                let posts_2: SyncAddress<_> = Peered::new(Posts::new(Id(2), Storage::memory()))
+                    .add_peer(slow)
+                    .add_peer(posts_0)
+                    .backfill_timeout(Duration::from_millis(100))
+                    .start();
+
+                let status = posts_2
+                    .call_fut(BackfillStatus)
+                    .map_err(|_| ())
+                    .and_then(|res| res.map_err(|_| ()))
+                    .map(|progress| {
+                        assert_eq!(
+                            progress,
+                            BackfillProgress::InProgress {
+                                chunks: 0,
+                                retries: 0,
+                            }
+                        )
+                    });
+
+                iter_ok(0..6)
+                    .for_each(|_| settle())
+                    .and_then(|_| status)
+                    .map(move |_| posts_2)
+            })
+            .and_then(|posts_2| {
+                posts_2
+                    .call_fut(BackfillStatus)
+                    .map_err(|_| ())
+                    .and_then(|res| res.map_err(|_| ()))
+                    .map(|progress| {
+                        assert_eq!(
+                            progress,
+                            BackfillProgress::Complete {
+                                chunks: 3,
+                                retries: 1,
+                            }
+                        )
+                    })
+                    .and_then(move |_| {
+                        posts_2
+                            .call_fut(Message::new(PostSize))
+                            .map_err(|_| ())
+                            .and_then(|res| res.map_err(|_| ()))
+                            .map(|size| assert_eq!(size, 250))
+                    })
+            });
 
-            // user 1 makes post
-            addrs_vec[1].outbox().send(NewPostOut(BTreeSet::new()));
+        Arbiter::handle().spawn(
+            fut.map(|_| Arbiter::system().send(SystemExit(0)))
+                .map_err(|_| panic!("Future error case")),
+        );
 
-            // user 1 owns post
-            let fut = addrs_vec[1]
-                .user()
-                .call_fut(GetUserPostIds(10))
-                .map_err(|_| ())
-                .and_then(|res| res)
-                .map(|post_ids| assert!(!post_ids.is_empty()));
+        system.run();
+    }
 
-            // user 0 should have a post in inbox
-            let fut2 = addrs_vec[0]
-                .user()
-                .call_fut(GetPostIds(10))
-                .map_err(|_| ())
-                .and_then(|res| res)
-                .map(|post_ids| assert!(!post_ids.is_empty()));
+    #[test]
+    fn sharded_posts_are_kept_by_their_owners() {
+        let system = System::new("test");
 
-            // user 2 should have a post in inbox
-            let fut3 = addrs_vec[2]
-                .user()
-                .call_fut(GetPostIds(10))
-                .map_err(|_| ())
-                .and_then(|res| res)
-                .map(|post_ids| assert!(!post_ids.is_empty()));
+        let posts_0: SyncAddress<_> = Peered::new(Posts::new(Id(0), Storage::memory()))
+            .sharded(2)
+            .start();
+        let nodes: Vec<SyncAddress<_>> = vec![posts_0.clone()]
+            .into_iter()
+            .chain((1..3).map(|i| {
+                Peered::new(Posts::new(Id(i), Storage::memory()))
+                    .sharded(2)
+                    .add_peer(posts_0.clone())
+                    .start()
+            }))
+            .collect();
+        let author = UserId::new(Id(0), Id(0));
+
+        let fut = settle()
+            .and_then(move |_| {
+                let new_posts: Vec<_> = (0..30)
+                    .map(|i| {
+                        let content = Content {
+                            html: format!("<p>{}</p>", i),
+                            ..Content::default()
+                        };
+
+                        posts_0
+                            .call_fut(Message::new(public_post(author, content)))
+                            .map_err(|_| ())
+                            .and_then(|res| res.map_err(|_| ()))
+                    })
+                    .collect();
+
+                future::join_all(new_posts)
+            })
+            .and_then(|post_ids| settle().map(move |_| post_ids))
+            .and_then(move |post_ids| {
+                let sizes: Vec<_> = nodes
+                    .iter()
+                    .map(|node| {
+                        node.call_fut(Message::new(PostSize))
+                            .map_err(|_| ())
+                            .and_then(|res| res.map_err(|_| ()))
+                    })
+                    .collect();
+
+                // Each post is kept by two of the three nodes
+                let sizes = future::join_all(sizes).map(|sizes| {
+                    assert_eq!(sizes.iter().sum::<usize>(), 60);
+                    assert!(sizes.iter().all(|size| *size < 30));
+                });
+
+                let lookup = nodes[2]
+                    .call_fut(Message::new(GetPostsByIds(post_ids.clone(), None)))
+                    .map_err(|_| ())
+                    .and_then(|res| res.map_err(|_| ()))
+                    .map(move |(posts, missing)| {
+                        assert!(missing.is_empty());
+                        assert!(posts.iter().all(|post| !post.content.html.is_empty()));
 
-            // user 0 should not own a post
-            let fut4 = addrs_vec[0]
-                .user()
-                .call_fut(GetUserPostIds(10))
-                .map_err(|_| ())
-                .and_then(|res| res)
-                .map(|post_ids| assert!(post_ids.is_empty()));
+                        let mut found: Vec<_> = posts.iter().map(|post| post.post_id).collect();
+                        found.sort();
+                        assert_eq!(found, post_ids);
+                    });
 
-            // user 2 should not own a post
-            let fut5 = addrs_vec[2]
-                .user()
-                .call_fut(GetUserPostIds(10))
-                .map_err(|_| ())
-                .and_then(|res| res)
-                .map(|post_ids| assert!(post_ids.is_empty()));
+                sizes.join(lookup)
+            });
 
-            fut.join5(fut2, fut3, fut4, fut5).map(|_| ())
-        })
+        Arbiter::handle().spawn(
+            fut.map(|_| Arbiter::system().send(SystemExit(0)))
+                .map_err(|_| panic!("Future error case")),
+        );
+
+        system.run();
     }
 
-    fn with_users<F, G>(f: F)
-    where
-        F: FnOnce(Vec<UserId>, Vec<UserAddress>, SyncAddress<Peered<Blocklists>>) -> G + 'static,
-        G: Future<Item = (), Error = ()>,
-    {
+    #[test]
+    fn posts_are_only_shown_to_their_audience() {
         let system = System::new("test");
-        let arbiter = Arbiter::new("test-exec");
 
-        let posts: SyncAddress<_> = Peered::new(Posts::new(Id(0))).start();
-        let users: SyncAddress<_> = Peered::new(Users::new(Id(0), posts)).start();
-        let blocklists: SyncAddress<_> = Peered::new(Blocklists::new()).start();
-        let blocklists_clone = blocklists.clone();
+        let posts: SyncAddress<_> = Peered::new(Posts::new(Id(0), Storage::memory())).start();
+        let (author, follower, stranger) = (
+            UserId::new(Id(0), Id(0)),
+            UserId::new(Id(0), Id(1)),
+            UserId::new(Id(0), Id(2)),
+        );
+        let audience: BTreeSet<_> = vec![follower].into_iter().collect();
+
+        let new_posts: Vec<_> = vec![
+            (Visibility::Public, BTreeSet::new()),
+            (Visibility::Unlisted, BTreeSet::new()),
+            (Visibility::FollowersOnly, audience.clone()),
+            (Visibility::Direct, audience),
+        ].into_iter()
+            .map(|(visibility, audience)| {
+                let post = NewPost {
+                    visibility: visibility,
+                    audience: audience,
+                    ..public_post(author, Content::default())
+                };
+
+                posts
+                    .call_fut(Message::new(post))
+                    .map_err(|_| ())
+                    .and_then(|res| res.map_err(|_| ()))
+            })
+            .collect();
 
-        let users_clone = users.clone();
+        let fut = future::join_all(new_posts).and_then(move |post_ids| {
+            let (public, restricted) = post_ids.split_at(2);
+            let (public, restricted) = (public.to_vec(), restricted.to_vec());
+            let all = post_ids.clone();
 
-        let user_addrs_fut = iter_ok(0..3)
-            .and_then(move |_| {
-                users.call_fut(Message::new(NewUser(users.clone(), blocklists.clone())))
+            let visible = |viewer| {
+                posts
+                    .call_fut(Message::new(GetPostsByIds(post_ids.clone(), viewer)))
+                    .map_err(|_| ())
+                    .and_then(|res| res.map_err(|_| ()))
+                    .map(|(posts, missing)| {
+                        let ids: Vec<_> = posts.iter().map(|post| post.post_id).collect();
+                        (ids, missing)
+                    })
+            };
+
+            let timeline = posts
+                .call_fut(Message::new(GetPublicPostIds(0)))
+                .map_err(|_| ())
+                .and_then(|res| res.map_err(|_| ()));
+
+            visible(None)
+                .join4(visible(Some(stranger)), visible(Some(follower)), timeline)
+                .map(move |(anonymous, strangers, followers, timeline)| {
+                    assert_eq!(anonymous, (public.clone(), restricted.clone()));
+                    assert_eq!(strangers, (public.clone(), restricted.clone()));
+                    assert_eq!(followers, (all, Vec::new()));
+                    assert_eq!(timeline, public.into_iter().collect());
+                })
+        });
+
+        Arbiter::handle().spawn(
+            fut.map(|_| Arbiter::system().send(SystemExit(0)))
+                .map_err(|_| panic!("Future error case")),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn threads_list_ancestors_and_descendants() {
+        let system = System::new("test");
+
+        let posts: SyncAddress<_> = Peered::new(Posts::new(Id(0), Storage::memory())).start();
+        let author = UserId::new(Id(0), Id(0));
+
+        fn reply(
+            posts: &SyncAddress<Peered<Posts>>,
+            author: UserId,
+            in_reply_to: Option<PostId>,
+        ) -> Box<Future<Item = PostId, Error = ()>> {
+            let post = NewPost {
+                in_reply_to: in_reply_to,
+                ..public_post(author, Content::default())
+            };
+
+            Box::new(
+                posts
+                    .call_fut(Message::new(post))
+                    .map_err(|_| ())
+                    .and_then(|res| res.map_err(|_| ())),
+            )
+        }
+
+        let (posts_2, posts_3, posts_4, posts_5) =
+            (posts.clone(), posts.clone(), posts.clone(), posts.clone());
+
+        // root <- first <- nested, and root <- second
+        let fut = reply(&posts, author, None)
+            .and_then(move |root| reply(&posts_2, author, Some(root)).map(move |r| (root, r)))
+            .and_then(move |(root, first)| {
+                reply(&posts_3, author, Some(first)).map(move |nested| (root, first, nested))
+            })
+            .and_then(move |(root, first, nested)| {
+                reply(&posts_4, author, Some(root)).map(move |second| (root, first, nested, second))
+            })
+            .and_then(move |(root, first, nested, second)| {
+                let thread = |post_id| {
+                    posts_5
+                        .call_fut(Message::new(GetThread(post_id, None)))
+                        .map_err(|_| ())
+                        .and_then(|res| res.map_err(|_| ()))
+                        .map(|(ancestors, descendants)| {
+                            let ids = |posts: Vec<Post>| -> Vec<PostId> {
+                                posts.into_iter().map(|post| post.post_id).collect()
+                            };
+
+                            (ids(ancestors), ids(descendants))
+                        })
+                };
+
+                thread(root).join(thread(nested)).map(move |(of_root, of_nested)| {
+                    assert_eq!(of_root, (vec![], vec![first, nested, second]));
+                    assert_eq!(of_nested, (vec![root, first], vec![]));
+                })
+            });
+
+        Arbiter::handle().spawn(
+            fut.map(|_| Arbiter::system().send(SystemExit(0)))
+                .map_err(|_| panic!("Future error case")),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn joining_nodes_backfill_every_blocklist() {
+        let system = System::new("test");
+
+        let node_0: SyncAddress<_> = Peered::new(Blocklists::new(Storage::memory())).start();
+        let node_0_clone = node_0.clone();
+
+        let fut = iter_ok(0..350)
+            .for_each(move |i| {
+                node_0_clone
+                    .call_fut(Message::new(Block(user(i), user(i + 1))))
+                    .map_err(|_| ())
+                    .and_then(|res| res.map_err(|_| ()))
+            })
+            .and_then(move |_| {
+                let node_1: SyncAddress<_> = Peered::new(Blocklists::new(Storage::memory()))
+                    .add_peer(node_0.clone())
+                    .start();
+
+                settle().map(move |_| (node_0, node_1))
+            })
+            .and_then(|(node_0, node_1)| {
+                let status = node_1
+                    .call_fut(BackfillStatus)
+                    .map_err(|_| ())
+                    .and_then(|res| res.map_err(|_| ()))
+                    .map(|progress| {
+                        assert_eq!(
+                            progress,
+                            BackfillProgress::Complete {
+                                chunks: 4,
+                                retries: 0,
+                            }
+                        )
+                    });
+
+                let pairs = (0..350).flat_map(|i| vec![(i, i + 1), (i + 1, i), (i, i + 2)]);
+
+                let parity = iter_ok(pairs)
+                    .for_each(move |(a, b)| {
+                        can_speak(&node_0, a, b)
+                            .join(can_speak(&node_1, a, b))
+                            .map(move |(expected, actual)| {
+                                assert_eq!(expected, actual, "{} and {}", a, b);
+                                assert_eq!(actual, b == a + 2);
+                            })
+                    });
+
+                status.and_then(|_| parity)
+            });
+
+        Arbiter::handle().spawn(
+            fut.map(|_| Arbiter::system().send(SystemExit(0)))
+                .map_err(|_| panic!("Future error case")),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn announces_are_delivered_in_causal_order() {
+        let system = System::new("test");
+
+        let node: SyncAddress<_> = Peered::new(Blocklists::new(Storage::memory())).start();
+
+        // One node blocks, another unblocks after seeing the block, and the unblock arrives
+        // first, followed by the block twice
+        let (a, b) = (PeerId::generate(), PeerId::generate());
+        let block_deps: VersionVector = vec![(a, 1)].into_iter().collect();
+        let unblock_deps: VersionVector = vec![(a, 1), (b, 1)].into_iter().collect();
+        let block = Stamp {
+            origin: a,
+            deps: block_deps,
+        };
+        let unblock = Stamp {
+            origin: b,
+            deps: unblock_deps,
+        };
+
+        let announces = node.call_fut(Announce::new(unblock, Unblock(user(0), user(1))))
+            .and_then({
+                let node = node.clone();
+                let block = block.clone();
+                move |_| node.call_fut(Announce::new(block, Block(user(0), user(1))))
+            })
+            .and_then({
+                let node = node.clone();
+                move |_| node.call_fut(Announce::new(block, Block(user(0), user(1))))
+            })
+            .map_err(|_| ());
+
+        let fut = announces.and_then(move |_| {
+            node.call_fut(Message::new(CanSpeak(user(0), user(1))))
+                .map_err(|_| ())
+                .and_then(|res| res.map_err(|_| ()))
+                .map(|can_speak| assert!(can_speak))
+        });
+
+        Arbiter::handle().spawn(
+            fut.map(|_| Arbiter::system().send(SystemExit(0)))
+                .map_err(|_| panic!("Future error case")),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn replicas_reconcile_announces_they_missed() {
+        let system = System::new("test");
+
+        fn stamp() -> Stamp {
+            let origin = PeerId::generate();
+
+            Stamp {
+                origin: origin,
+                deps: vec![(origin, 1)].into_iter().collect(),
+            }
+        }
+
+        let node_0: SyncAddress<_> = Peered::new(Blocklists::new(Storage::memory()))
+            .anti_entropy(Duration::from_millis(20))
+            .start();
+        let node_1: SyncAddress<_> = Peered::new(Blocklists::new(Storage::memory()))
+            .anti_entropy(Duration::from_millis(20))
+            .add_peer(node_0.clone())
+            .start();
+
+        // Each node gets one announce the other never sees
+        let fut = settle()
+            .and_then({
+                let node_0 = node_0.clone();
+                move |_| {
+                    node_0
+                        .call_fut(Message::new(Block(user(0), user(1))))
+                        .map_err(|_| ())
+                        .and_then(|res| res.map_err(|_| ()))
+                }
+            })
+            .and_then(|_| settle())
+            .and_then({
+                let node_0 = node_0.clone();
+                move |_| {
+                    node_0
+                        .call_fut(Announce::new(stamp(), Block(user(2), user(3))))
+                        .map_err(|_| ())
+                }
+            })
+            .and_then({
+                let node_1 = node_1.clone();
+                move |_| {
+                    node_1
+                        .call_fut(Announce::new(stamp(), Unblock(user(0), user(1))))
+                        .map_err(|_| ())
+                }
+            })
+            .and_then(|_| settle())
+            .and_then(move |_| {
+                let checks = vec![node_0, node_1].into_iter().map(|node| {
+                    can_speak(&node, 0, 1)
+                        .join(can_speak(&node, 2, 3))
+                        .map(|(unblocked, blocked)| {
+                            assert!(unblocked);
+                            assert!(!blocked);
+                        })
+                });
+
+                future::join_all(checks.collect::<Vec<_>>())
+            });
+
+        Arbiter::handle().spawn(
+            fut.map(|_| Arbiter::system().send(SystemExit(0)))
+                .map_err(|_| panic!("Future error case")),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn blocklists_peer_over_tcp() {
+        let system = System::new("test");
+
+        let transports: Vec<Transport> = (0..3)
+            .map(|_| Transport::bind("127.0.0.1:0", SECRET).unwrap())
+            .collect();
+
+        let node_0 = transports[0].start(Peered::new(Blocklists::new(Storage::memory())), &[]);
+        let node_0_clone = node_0.clone();
+        let seed_0 = vec![transports[0].address().to_owned()];
+        let seed_1 = vec![transports[1].address().to_owned()];
+
+        // Blocks made before the others join reach them through backfill
+        let fut = node_0
+            .call_fut(Message::new(Block(user(0), user(1))))
+            .map_err(|_| ())
+            .and_then(|res| res.map_err(|_| ()))
+            .and_then(move |_| {
+                let blocklists = || Peered::new(Blocklists::new(Storage::memory()));
+                let node_1 = transports[1].start(blocklists(), &seed_0);
+                let node_2 = transports[2].start(blocklists(), &seed_1);
+
+                settle()
+                    .and_then(|_| settle())
+                    .map(move |_| vec![node_0_clone, node_1, node_2])
+            })
+            .and_then(|nodes| {
+                let peer_sizes: Vec<_> = nodes
+                    .iter()
+                    .map(|node| {
+                        node.call_fut(PeerSize)
+                            .map_err(|_| ())
+                            .and_then(|res| res.map_err(|_| ()))
+                    })
+                    .collect();
+
+                future::join_all(peer_sizes).map(move |sizes| {
+                    assert_eq!(sizes, vec![2, 2, 2]);
+                    nodes
+                })
+            })
+            .and_then(|nodes| {
+                nodes[1]
+                    .call_fut(Message::new(CanSpeak(user(0), user(1))))
+                    .map_err(|_| ())
+                    .and_then(|res| res.map_err(|_| ()))
+                    .map(|can_speak| assert!(!can_speak))
+                    .map(move |_| nodes)
+            })
+            .and_then(|nodes| {
+                // Announces made anywhere reach every process
+                nodes[2]
+                    .call_fut(Message::new(Block(user(2), user(0))))
+                    .map_err(|_| ())
+                    .and_then(|res| res.map_err(|_| ()))
+                    .and_then(|_| settle())
+                    .map(move |_| nodes)
+            })
+            .and_then(|nodes| {
+                nodes[0]
+                    .call_fut(Message::new(CanSpeak(user(0), user(2))))
+                    .map_err(|_| ())
+                    .and_then(|res| res.map_err(|_| ()))
+                    .map(|can_speak| assert!(!can_speak))
+                    .map(move |_| nodes)
+            })
+            .and_then(|nodes| {
+                nodes[1].send(Leave);
+
+                settle().and_then(move |_| {
+                    nodes[0]
+                        .call_fut(PeerSize)
+                        .map_err(|_| ())
+                        .and_then(|res| res.map_err(|_| ()))
+                        .map(|peer_size| assert_eq!(peer_size, 1))
+                })
+            });
+
+        Arbiter::handle().spawn(
+            fut.map(|_| Arbiter::system().send(SystemExit(0)))
+                .map_err(|_| panic!("Future error case")),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn peers_must_know_the_secret() {
+        let system = System::new("test");
+
+        let transport_0 = Transport::bind("127.0.0.1:0", SECRET).unwrap();
+        let transport_1 = Transport::bind("127.0.0.1:0", "wrong").unwrap();
+        let seed_0 = vec![transport_0.address().to_owned()];
+
+        let node_0 = transport_0.start(Peered::new(Blocklists::new(Storage::memory())), &[]);
+        let _node_1 = transport_1.start(Peered::new(Blocklists::new(Storage::memory())), &seed_0);
+
+        let fut = settle().and_then(move |_| {
+            node_0
+                .call_fut(PeerSize)
+                .map_err(|_| ())
+                .and_then(|res| res.map_err(|_| ()))
+                .map(|peer_size| assert_eq!(peer_size, 0))
+        });
+
+        Arbiter::handle().spawn(
+            fut.map(|_| Arbiter::system().send(SystemExit(0)))
+                .map_err(|_| panic!("Future error case")),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn posts_and_users_cross_processes() {
+        let system = System::new("test");
+
+        let transports: Vec<Transport> = (0..2)
+            .map(|_| Transport::bind("127.0.0.1:0", SECRET).unwrap())
+            .collect();
+        let seed_0 = vec![transports[0].address().to_owned()];
+
+        let posts_0 = transports[0].start(Peered::new(Posts::new(Id(0), Storage::memory())), &[]);
+        let users_0 = transports[0].start(Peered::new(users(Id(0), posts_0.clone())), &[]);
+        let blocklists: SyncAddress<_> = Peered::new(Blocklists::new(Storage::memory())).start();
+
+        let new_user = move |users: &SyncAddress<Peered<Users>>| {
+            users
+                .call_fut(Message::new(NewUser(
+                    users.clone(),
+                    blocklists.clone(),
+                    Profile::new("alice"),
+                )))
+                .map_err(|_| ())
+        };
+        let new_user_clone = new_user.clone();
+
+        // A user and a post made before the second process joins reach it through backfill
+        let fut = new_user(&users_0)
+            .and_then(|res| res.map_err(|_| ()))
+            .and_then({
+                let posts_0 = posts_0.clone();
+                move |alice| {
+                    posts_0
+                        .call_fut(Message::new(public_post(alice, Content::default())))
+                        .map_err(|_| ())
+                        .and_then(|res| res.map_err(|_| ()))
+                        .map(move |post_id| (alice, post_id))
+                }
+            })
+            .and_then(move |(alice, post_id)| {
+                let posts_1 = transports[1].start(
+                    Peered::new(Posts::new(Id(1), Storage::memory())),
+                    &seed_0,
+                );
+                let users_1 = transports[1].start(
+                    Peered::new(users(Id(1), posts_1.clone())),
+                    &seed_0,
+                );
+
+                settle()
+                    .and_then(|_| settle())
+                    .map(move |_| (alice, post_id, posts_1, users_1))
+            })
+            .and_then(move |(alice, post_id, posts_1, users_1)| {
+                let post = posts_1
+                    .call_fut(Message::new(GetPostsByIds(vec![post_id], None)))
+                    .map_err(|_| ())
+                    .and_then(|res| res.map_err(|_| ()))
+                    .map(|(posts, _)| assert_eq!(posts.len(), 1));
+
+                // The user's actors stay in the first process, so only its name is known here
+                let lookup = users_1
+                    .call_fut(Message::new(Lookup(alice)))
+                    .map_err(|_| ())
+                    .map(move |res| assert_eq!(res.err(), Some(UsersError::UserElsewhere(alice))));
+
+                let taken = new_user_clone(&users_1).map(|res| {
+                    assert_eq!(res.err(), Some(UsersError::UsernameTaken("alice".to_owned())))
+                });
+
+                post.join3(lookup, taken).map(move |_| (alice, posts_1))
+            })
+            .and_then(|(alice, posts_1)| {
+                // Posts made once both are running reach the other process through an announce
+                posts_1
+                    .call_fut(Message::new(public_post(alice, Content::default())))
+                    .map_err(|_| ())
+                    .and_then(|res| res.map_err(|_| ()))
+                    .and_then(|post_id| settle().map(move |_| post_id))
+            })
+            .and_then(move |post_id| {
+                posts_0
+                    .call_fut(Message::new(GetPostsByIds(vec![post_id], None)))
+                    .map_err(|_| ())
+                    .and_then(|res| res.map_err(|_| ()))
+                    .map(|(posts, _)| assert_eq!(posts.len(), 1))
+            });
+
+        Arbiter::handle().spawn(
+            fut.map(|_| Arbiter::system().send(SystemExit(0)))
+                .map_err(|_| panic!("Future error case")),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn users_in_other_processes_are_dispatched_to() {
+        let system = System::new("test");
+
+        let transports: Vec<Transport> = (0..2)
+            .map(|_| Transport::bind("127.0.0.1:0", SECRET).unwrap())
+            .collect();
+        let seed_0 = vec![transports[0].address().to_owned()];
+
+        let posts_0 = transports[0].start(Peered::new(Posts::new(Id(0), Storage::memory())), &[]);
+        let users_0 = transports[0].start(Peered::new(users(Id(0), posts_0)), &[]);
+        let posts_1 = transports[1].start(
+            Peered::new(Posts::new(Id(1), Storage::memory())),
+            &seed_0,
+        );
+        let users_1 = transports[1].start(Peered::new(users(Id(1), posts_1)), &seed_0);
+        let blocklists: SyncAddress<_> = Peered::new(Blocklists::new(Storage::memory())).start();
+
+        let new_user = move |users: &SyncAddress<Peered<Users>>, username: &str| {
+            let users_clone = users.clone();
+
+            users
+                .call_fut(Message::new(NewUser(
+                    users.clone(),
+                    blocklists.clone(),
+                    Profile::new(username),
+                )))
+                .map_err(|_| ())
+                .and_then(|res| res.map_err(|_| ()))
+                .and_then(move |user_id| {
+                    users_clone
+                        .call_fut(Message::new(Lookup(user_id)))
+                        .map_err(|_| ())
+                        .and_then(|res| res.map_err(|_| ()))
+                        .map(move |addr| (user_id, addr))
+                })
+        };
+
+        // Alice's actors run in the first process and Bob's in the second
+        let fut = settle()
+            .and_then(move |_| new_user(&users_0, "alice").join(new_user(&users_1, "bob")))
+            .and_then(|((_, alice_addr), bob)| {
+                alice_addr
+                    .outbox()
+                    .call_fut(new_post(BTreeSet::new()))
+                    .map_err(|_| ())
+                    .and_then(|res| res.map_err(|_| ()))
+                    .and_then(|post_id| settle().map(move |_| (alice_addr, bob, post_id)))
+            })
+            .and_then(|(alice_addr, (bob, bob_addr), post_id)| {
+                bob_addr
+                    .outbox()
+                    .call_fut(Like(post_id))
+                    .map_err(|_| ())
+                    .and_then(|res| res.map_err(|_| ()))
+                    .and_then(|_| settle())
+                    .map(move |_| (alice_addr, bob, post_id))
+            })
+            .and_then(|(alice_addr, bob, post_id)| {
+                alice_addr
+                    .user()
+                    .call_fut(GetNotifications(0))
+                    .map_err(|_| ())
+                    .and_then(|res| res.map_err(|_| ()))
+                    .map(move |notifications| {
+                        assert_eq!(notifications, vec![Notification::Liked(post_id, bob)])
+                    })
+            });
+
+        Arbiter::handle().spawn(
+            fut.map(|_| Arbiter::system().send(SystemExit(0)))
+                .map_err(|_| panic!("Future error case")),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn keys_owned_by_other_processes_are_forwarded() {
+        let system = System::new("test");
+
+        let transports: Vec<Transport> = (0..2)
+            .map(|_| Transport::bind("127.0.0.1:0", SECRET).unwrap())
+            .collect();
+        let seed_0 = vec![transports[0].address().to_owned()];
+
+        let posts_0 = transports[0].start(
+            Peered::new(Posts::new(Id(0), Storage::memory())).sharded(1),
+            &[],
+        );
+        let posts_1 = transports[1].start(
+            Peered::new(Posts::new(Id(1), Storage::memory())).sharded(1),
+            &seed_0,
+        );
+        let author = user(0);
+
+        // Each post is kept by one of the processes, which asks the other for those it doesn't
+        let fut = settle()
+            .and_then(move |_| {
+                let new_posts: Vec<_> = (0..20)
+                    .map(|_| {
+                        posts_0
+                            .call_fut(Message::new(public_post(author, Content::default())))
+                            .map_err(|_| ())
+                            .and_then(|res| res.map_err(|_| ()))
+                    })
+                    .collect();
+
+                future::join_all(new_posts)
+            })
+            .and_then(|post_ids| settle().map(move |_| post_ids))
+            .and_then(move |post_ids| {
+                posts_1
+                    .call_fut(Message::new(GetPostsByIds(post_ids, None)))
+                    .map_err(|_| ())
+                    .and_then(|res| res.map_err(|_| ()))
+                    .map(|(posts, missing)| {
+                        assert_eq!(posts.len(), 20);
+                        assert!(missing.is_empty());
+                    })
+            });
+
+        Arbiter::handle().spawn(
+            fut.map(|_| Arbiter::system().send(SystemExit(0)))
+                .map_err(|_| panic!("Future error case")),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn test_new_users() {
+        with_users(|_, _, _| future::result(Ok(())))
+    }
+
+    #[test]
+    fn test_blocked_user_doesnt_receive_post() {
+        with_users(|ids_vec, addrs_vec, blocklists| {
+            let u0_a = addrs_vec[0].clone();
+            let u0_b = addrs_vec[0].clone();
+            let uid0 = ids_vec[0];
+            let u1_a = addrs_vec[1].clone();
+            let u1_b = u1_a.clone();
+            let u1_c = u1_a.clone();
+            let u1_d = u1_a.clone();
+            let uid1 = ids_vec[1];
+
+            let blocklists_clone = blocklists.clone();
+
+            // User 0 requests to follow User 1
+            u0_a.outbox()
+                .call_fut(RequestFollow(ids_vec[1]))
+                .map_err(|_| ())
+                .and_then(move |_| {
+                    // user 0 and user 1 have no established blocks
+                    blocklists
+                        .clone()
+                        .call_fut(Message::new(CanSpeak(uid0, uid1)))
+                        .map_err(|_| ())
+                        .and_then(|res| res.map_err(|_| ()))
+                        .map(|can_speak| assert!(can_speak))
+                })
+                .and_then(|_| settle())
+                .and_then(move |_| {
+                    // user 1 accepts user 0's follow request
+                    u1_a.outbox()
+                        .call_fut(AcceptFollowRequest(uid0))
+                        .map_err(|_| ())
+                })
+                .and_then(|_| settle())
+                .and_then(move |_| {
+                    u1_b.outbox()
+                        .call_fut(new_post(BTreeSet::new()))
+                        .map_err(|_| ())
+                })
+                .and_then(|_| settle())
+                .and_then(move |_| {
+                    // user 0 should have a post in inbox
+                    u0_b.user()
+                        .call_fut(GetPostIds(10))
+                        .map_err(|_| ())
+                        .and_then(|res| res.map_err(|_| ()))
+                        .map(|post_ids| assert!(!post_ids.is_empty()))
+                })
+                .and_then(|_| settle())
+                .and_then(move |_| {
+                    // user 1 blocks user 0
+                    u1_c.outbox().call_fut(BlockUser(uid0)).map_err(|_| ())
+                })
+                .and_then(|_| settle())
+                .and_then(move |_| {
+                    // user 1 makes post
+                    u1_d.outbox()
+                        .call_fut(new_post(BTreeSet::new()))
+                        .map_err(|_| ())
+                })
+                .and_then(|_| settle())
+                .and_then(move |_| {
+                    // user 1 should own two posts
+                    let fut = addrs_vec[1]
+                        .user()
+                        .call_fut(GetUserPostIds(10))
+                        .map_err(|_| ())
+                        .and_then(|res| res.map_err(|_| ()))
+                        .map(|post_ids| assert_eq!(post_ids.len(), 2));
+
+                    // user 0 should not have a post in inbox
+                    let fut2 = addrs_vec[0]
+                        .user()
+                        .call_fut(GetPostIds(10))
+                        .map_err(|_| ())
+                        .and_then(|res| res.map_err(|_| ()))
+                        .map(|post_ids| assert!(post_ids.is_empty()));
+
+                    // user 1 and user 0 have a block separating them
+                    let fut3 = blocklists_clone
+                        .call_fut(Message::new(CanSpeak(uid0, uid1)))
+                        .map_err(|_| ())
+                        .and_then(|res| res.map_err(|_| ()))
+                        .map(|can_speak| assert!(!can_speak));
+
+                    fut.and_then(|_| fut2).and_then(|_| fut3)
+                })
+        })
+    }
+
+    #[test]
+    fn blocking_severs_follows_in_both_directions() {
+        with_users(|ids_vec, addrs_vec, _| {
+            // users 0 and 1 follow each other
+            addrs_vec[0].outbox().send(RequestFollow(ids_vec[1]));
+            addrs_vec[1].outbox().send(RequestFollow(ids_vec[0]));
+
+            settle()
+                .and_then(move |_| {
+                    addrs_vec[1].outbox().send(AcceptFollowRequest(ids_vec[0]));
+                    addrs_vec[0].outbox().send(AcceptFollowRequest(ids_vec[1]));
+
+                    settle().map(move |_| (ids_vec, addrs_vec))
+                })
+                .and_then(|(ids_vec, addrs_vec)| {
+                    // user 1 is followed by user 0 before the block
+                    addrs_vec[1]
+                        .user()
+                        .call_fut(GetFollowers)
+                        .map_err(|_| ())
+                        .and_then(|res| res.map_err(|_| ()))
+                        .map(move |followers| {
+                            assert!(followers.contains(&ids_vec[0]));
+                            (ids_vec, addrs_vec)
+                        })
+                })
+                .and_then(|(ids_vec, addrs_vec)| {
+                    // user 1 blocks user 0
+                    addrs_vec[1]
+                        .outbox()
+                        .call_fut(BlockUser(ids_vec[0]))
+                        .map_err(|_| ())
+                        .map(move |_| addrs_vec)
+                })
+                .and_then(|addrs_vec| settle().map(move |_| addrs_vec))
+                .and_then(|addrs_vec| {
+                    // neither user lists the other as a follower
+                    let fut = addrs_vec[1]
+                        .user()
+                        .call_fut(GetFollowers)
+                        .map_err(|_| ())
+                        .and_then(|res| res.map_err(|_| ()))
+                        .map(|followers| assert!(followers.is_empty()));
+
+                    let fut2 = addrs_vec[0]
+                        .user()
+                        .call_fut(GetFollowers)
+                        .map_err(|_| ())
+                        .and_then(|res| res.map_err(|_| ()))
+                        .map(|followers| assert!(followers.is_empty()));
+
+                    fut.join(fut2).map(|_| ())
+                })
+        })
+    }
+
+    #[test]
+    fn follows_survive_blocks_that_fail() {
+        with_users(|ids_vec, addrs_vec, blocklists| {
+            addrs_vec[0].outbox().send(RequestFollow(ids_vec[1]));
+
+            settle()
+                .and_then(move |_| {
+                    addrs_vec[1].outbox().send(AcceptFollowRequest(ids_vec[0]));
+
+                    settle().map(move |_| (ids_vec, addrs_vec))
+                })
+                .and_then(move |(ids_vec, addrs_vec)| {
+                    // With the blocklists gone, the block can't be recorded
+                    blocklists.send(Leave);
+
+                    addrs_vec[1]
+                        .outbox()
+                        .call_fut(BlockUser(ids_vec[0]))
+                        .map_err(|_| ())
+                        .map(move |res| {
+                            assert_eq!(res, Err(UserError::MailboxClosed));
+                            (ids_vec, addrs_vec)
+                        })
+                })
+                .and_then(|(ids_vec, addrs_vec)| {
+                    addrs_vec[1]
+                        .user()
+                        .call_fut(GetFollowers)
+                        .map_err(|_| ())
+                        .and_then(|res| res.map_err(|_| ()))
+                        .map(move |followers| assert!(followers.contains(&ids_vec[0])))
+                })
+        })
+    }
+
+    #[test]
+    fn posts_flow_again_after_unblocking_and_following() {
+        with_users(|ids_vec, addrs_vec, blocklists| {
+            let (uid0, uid1) = (ids_vec[0], ids_vec[1]);
+            let (u0_a, u0_b) = (addrs_vec[0].clone(), addrs_vec[0].clone());
+            let u1_a = addrs_vec[1].clone();
+            let (u1_b, u1_c, u1_d) = (u1_a.clone(), u1_a.clone(), u1_a.clone());
+
+            // user 1 blocks and then unblocks user 0
+            u1_a.outbox()
+                .call_fut(BlockUser(uid0))
+                .map_err(|_| ())
+                .and_then(|_| settle())
+                .and_then(move |_| {
+                    u1_b.outbox()
+                        .call_fut(UnblockUser(uid0))
+                        .map_err(|_| ())
+                        .map(|res| assert_eq!(res, Ok(())))
+                })
+                .and_then(move |_| {
+                    // the block is gone, so there is nothing left to unblock
+                    let unblocked = blocklists
+                        .call_fut(Message::new(CanSpeak(uid0, uid1)))
+                        .map_err(|_| ())
+                        .and_then(|res| res.map_err(|_| ()))
+                        .map(|can_speak| assert!(can_speak));
+                    let again = u1_c.outbox()
+                        .call_fut(UnblockUser(uid0))
+                        .map_err(|_| ())
+                        .map(move |res| {
+                            assert_eq!(
+                                res,
+                                Err(UserError::Blocklist(BlocklistError::NotBlocked(uid1, uid0)))
+                            )
+                        });
+
+                    unblocked.join(again)
+                })
+                .and_then(move |_| {
+                    // user 0 follows user 1 afresh
+                    u0_a.outbox()
+                        .call_fut(RequestFollow(uid1))
+                        .map_err(|_| ())
+                        .and_then(|res| res.map_err(|_| ()))
+                })
+                .and_then(|_| settle())
+                .and_then(move |_| {
+                    u1_d.outbox()
+                        .call_fut(AcceptFollowRequest(uid0))
+                        .map_err(|_| ())
+                        .and_then(|res| res.map_err(|_| ()))
+                })
+                .and_then(|_| settle())
+                .and_then(move |_| {
+                    addrs_vec[1]
+                        .outbox()
+                        .call_fut(new_post(BTreeSet::new()))
+                        .map_err(|_| ())
+                        .and_then(|res| res.map_err(|_| ()))
+                })
+                .and_then(|post_id| settle().map(move |_| post_id))
+                .and_then(move |post_id| {
+                    // user 0 should have the post in inbox
+                    u0_b.user()
+                        .call_fut(GetPostIds(10))
+                        .map_err(|_| ())
+                        .and_then(|res| res.map_err(|_| ()))
+                        .map(move |post_ids| assert!(post_ids.contains(&post_id)))
+                })
+        })
+    }
+
+    #[test]
+    fn test_no_follow_and_no_post_propagation() {
+        with_users(|ids_vec, addrs_vec, _| {
+            // User 0 requests to follow User 1
+            addrs_vec[0].outbox().send(RequestFollow(ids_vec[1]));
+
+            settle()
+                .and_then(move |_| {
+                    // user 1 denies user 0's follow request
+                    addrs_vec[1].outbox().send(DenyFollowRequest(ids_vec[0]));
+
+                    settle().map(move |_| addrs_vec)
+                })
+                .and_then(|addrs_vec| {
+                    // user 1 makes post
+                    addrs_vec[1].outbox().send(new_post(BTreeSet::new()));
+
+                    settle().map(move |_| addrs_vec)
+                })
+                .and_then(|addrs_vec| {
+                    // user 1 should own a post
+                    let fut = addrs_vec[1]
+                        .user()
+                        .call_fut(GetUserPostIds(10))
+                        .map_err(|_| ())
+                        .and_then(|res| res.map_err(|_| ()))
+                        .map(|post_ids| assert!(!post_ids.is_empty()));
+
+                    // user 0 should not have a post in inbox
+                    let fut2 = addrs_vec[0]
+                        .user()
+                        .call_fut(GetPostIds(10))
+                        .map_err(|_| ())
+                        .and_then(|res| res.map_err(|_| ()))
+                        .map(|post_ids| assert!(post_ids.is_empty()));
+
+                    // user 2 should not have a post in inbox
+                    let fut3 = addrs_vec[2]
+                        .user()
+                        .call_fut(GetPostIds(10))
+                        .map_err(|_| ())
+                        .and_then(|res| res.map_err(|_| ()))
+                        .map(|post_ids| assert!(post_ids.is_empty()));
+
+                    fut.and_then(|_| fut2).and_then(|_| fut3)
+                })
+        })
+    }
+
+    #[test]
+    fn test_follow_and_post_propagation() {
+        with_users(|ids_vec, addrs_vec, _| {
+            // User 0 requests to follow User 1
+            addrs_vec[0].outbox().send(RequestFollow(ids_vec[1]));
+
+            // User 2 requests to follow User 1
+            addrs_vec[2].outbox().send(RequestFollow(ids_vec[1]));
+
+            settle()
+                .and_then(move |_| {
+                    // user 1 accepts user 0's follow request
+                    addrs_vec[1].outbox().send(AcceptFollowRequest(ids_vec[0]));
+
+                    // user 1 accepts user 2's follow request
+                    addrs_vec[1].outbox().send(AcceptFollowRequest(ids_vec[2]));
+
+                    settle().map(move |_| addrs_vec)
+                })
+                .and_then(|addrs_vec| {
+                    // user 1 makes post
+                    addrs_vec[1].outbox().send(new_post(BTreeSet::new()));
+
+                    settle().map(move |_| addrs_vec)
+                })
+                .and_then(|addrs_vec| {
+                    // user 1 owns post
+                    let fut = addrs_vec[1]
+                        .user()
+                        .call_fut(GetUserPostIds(10))
+                        .map_err(|_| ())
+                        .and_then(|res| res.map_err(|_| ()))
+                        .map(|post_ids| assert!(!post_ids.is_empty()));
+
+                    // user 0 should have a post in inbox
+                    let fut2 = addrs_vec[0]
+                        .user()
+                        .call_fut(GetPostIds(10))
+                        .map_err(|_| ())
+                        .and_then(|res| res.map_err(|_| ()))
+                        .map(|post_ids| assert!(!post_ids.is_empty()));
+
+                    // user 2 should have a post in inbox
+                    let fut3 = addrs_vec[2]
+                        .user()
+                        .call_fut(GetPostIds(10))
+                        .map_err(|_| ())
+                        .and_then(|res| res.map_err(|_| ()))
+                        .map(|post_ids| assert!(!post_ids.is_empty()));
+
+                    // user 0 should not own a post
+                    let fut4 = addrs_vec[0]
+                        .user()
+                        .call_fut(GetUserPostIds(10))
+                        .map_err(|_| ())
+                        .and_then(|res| res.map_err(|_| ()))
+                        .map(|post_ids| assert!(post_ids.is_empty()));
+
+                    // user 2 should not own a post
+                    let fut5 = addrs_vec[2]
+                        .user()
+                        .call_fut(GetUserPostIds(10))
+                        .map_err(|_| ())
+                        .and_then(|res| res.map_err(|_| ()))
+                        .map(|post_ids| assert!(post_ids.is_empty()));
+
+                    fut.join5(fut2, fut3, fut4, fut5).map(|_| ())
+                })
+        })
+    }
+
+    #[test]
+    fn unfollowed_and_removed_followers_stop_receiving_posts() {
+        with_users(|ids_vec, addrs_vec, _| {
+            // users 0 and 2 follow user 1
+            addrs_vec[0].outbox().send(RequestFollow(ids_vec[1]));
+            addrs_vec[2].outbox().send(RequestFollow(ids_vec[1]));
+
+            settle()
+                .and_then(move |_| {
+                    addrs_vec[1].outbox().send(AcceptFollowRequest(ids_vec[0]));
+                    addrs_vec[1].outbox().send(AcceptFollowRequest(ids_vec[2]));
+
+                    settle().map(move |_| (ids_vec, addrs_vec))
+                })
+                .and_then(|(ids_vec, addrs_vec)| {
+                    // user 0 unfollows user 1, and user 1 removes user 2
+                    let unfollow = addrs_vec[0]
+                        .outbox()
+                        .call_fut(Unfollow(ids_vec[1]))
+                        .map_err(|_| ())
+                        .and_then(|res| res.map_err(|_| ()));
+                    let remove = addrs_vec[1]
+                        .outbox()
+                        .call_fut(RemoveFollower(ids_vec[2]))
+                        .map_err(|_| ())
+                        .and_then(|res| res.map_err(|_| ()));
+
+                    unfollow.join(remove).map(move |_| addrs_vec)
+                })
+                .and_then(|addrs_vec| settle().map(move |_| addrs_vec))
+                .and_then(|addrs_vec| {
+                    // user 1 makes post
+                    addrs_vec[1].outbox().send(new_post(BTreeSet::new()));
+
+                    settle().map(move |_| addrs_vec)
+                })
+                .and_then(|addrs_vec| {
+                    // user 1 has no followers left
+                    let fut = addrs_vec[1]
+                        .user()
+                        .call_fut(GetFollowers)
+                        .map_err(|_| ())
+                        .and_then(|res| res.map_err(|_| ()))
+                        .map(|followers| assert!(followers.is_empty()));
+
+                    // neither user 0 nor user 2 should have a post in inbox
+                    let fut2 = addrs_vec[0]
+                        .user()
+                        .call_fut(GetPostIds(10))
+                        .map_err(|_| ())
+                        .and_then(|res| res.map_err(|_| ()))
+                        .map(|post_ids| assert!(post_ids.is_empty()));
+
+                    let fut3 = addrs_vec[2]
+                        .user()
+                        .call_fut(GetPostIds(10))
+                        .map_err(|_| ())
+                        .and_then(|res| res.map_err(|_| ()))
+                        .map(|post_ids| assert!(post_ids.is_empty()));
+
+                    fut.join3(fut2, fut3).map(|_| ())
+                })
+        })
+    }
+
+    #[test]
+    fn mentioned_non_followers_receive_posts() {
+        with_users(|ids_vec, addrs_vec, _| {
+            // user 1 makes a post mentioning user 0, who doesn't follow them
+            let mentions = vec![ids_vec[0]].into_iter().collect();
+
+            addrs_vec[1]
+                .outbox()
+                .call_fut(new_post(mentions))
+                .map_err(|_| ())
+                .and_then(|res| res.map_err(|_| ()))
+                .and_then(|post_id| settle().map(move |_| post_id))
+                .and_then(move |post_id| {
+                    // user 0 should have the post in inbox
+                    let fut = addrs_vec[0]
+                        .user()
+                        .call_fut(GetPostIds(10))
+                        .map_err(|_| ())
+                        .and_then(|res| res.map_err(|_| ()))
+                        .map(move |post_ids| assert!(post_ids.contains(&post_id)));
+
+                    // user 2 was neither mentioned nor following
+                    let fut2 = addrs_vec[2]
+                        .user()
+                        .call_fut(GetPostIds(10))
+                        .map_err(|_| ())
+                        .and_then(|res| res.map_err(|_| ()))
+                        .map(|post_ids| assert!(post_ids.is_empty()));
+
+                    fut.join(fut2).map(|_| ())
+                })
+        })
+    }
+
+    #[test]
+    fn direct_posts_skip_followers() {
+        with_users(|ids_vec, addrs_vec, _| {
+            // user 0 follows user 1
+            addrs_vec[0].outbox().send(RequestFollow(ids_vec[1]));
+
+            settle()
+                .and_then(move |_| {
+                    addrs_vec[1].outbox().send(AcceptFollowRequest(ids_vec[0]));
+
+                    settle().map(move |_| (ids_vec, addrs_vec))
+                })
+                .and_then(|(ids_vec, addrs_vec)| {
+                    // user 1 sends a direct post to user 2
+                    let mentions = vec![ids_vec[2]].into_iter().collect();
+
+                    addrs_vec[1]
+                        .outbox()
+                        .call_fut(NewPostOut(
+                            mentions,
+                            Content::default(),
+                            Visibility::Direct,
+                            None,
+                        ))
+                        .map_err(|_| ())
+                        .and_then(|res| res.map_err(|_| ()))
+                        .and_then(|post_id| settle().map(move |_| (addrs_vec, post_id)))
+                })
+                .and_then(|(addrs_vec, post_id)| {
+                    // the follower doesn't receive it
+                    let fut = addrs_vec[0]
+                        .user()
+                        .call_fut(GetPostIds(10))
+                        .map_err(|_| ())
+                        .and_then(|res| res.map_err(|_| ()))
+                        .map(|post_ids| assert!(post_ids.is_empty()));
+
+                    // the mentioned user does
+                    let fut2 = addrs_vec[2]
+                        .user()
+                        .call_fut(GetPostIds(10))
+                        .map_err(|_| ())
+                        .and_then(|res| res.map_err(|_| ()))
+                        .map(move |post_ids| assert!(post_ids.contains(&post_id)));
+
+                    fut.join(fut2).map(|_| ())
+                })
+        })
+    }
+
+    #[test]
+    fn anonymous_viewers_only_see_public_posts() {
+        with_users(|ids_vec, addrs_vec, _| {
+            let outbox = addrs_vec[1].outbox().clone();
+            let author = ids_vec[1];
+
+            let post = |visibility| {
+                NewPostOut(BTreeSet::new(), Content::default(), visibility, None)
+            };
+
+            outbox
+                .call_fut(post(Visibility::Public))
+                .join(outbox.call_fut(post(Visibility::FollowersOnly)))
+                .map_err(|_| ())
+                .and_then(|(public, followers_only)| {
+                    public
+                        .and_then(|public| followers_only.map(|hidden| (public, hidden)))
+                        .map_err(|_| ())
+                })
+                .and_then(move |(public, hidden)| {
+                    let fut = outbox
+                        .call_fut(GetVisiblePostIds(0, None))
+                        .map_err(|_| ())
+                        .and_then(|res| res.map_err(|_| ()))
+                        .map(move |post_ids| {
+                            assert_eq!(post_ids, vec![public].into_iter().collect())
+                        });
+
+                    // the author still sees all of their posts
+                    let fut2 = outbox
+                        .call_fut(GetVisiblePostIds(0, Some(author)))
+                        .map_err(|_| ())
+                        .and_then(|res| res.map_err(|_| ()))
+                        .map(move |post_ids| {
+                            assert_eq!(post_ids, vec![public, hidden].into_iter().collect())
+                        });
+
+                    fut.join(fut2).map(|_| ())
+                })
+        })
+    }
+
+    #[test]
+    fn replies_reach_the_author_replied_to() {
+        with_users(|_, addrs_vec, _| {
+            let replier = addrs_vec[2].outbox().clone();
+
+            // user 1 makes a post, which user 2 replies to without following or mentioning them
+            addrs_vec[1]
+                .outbox()
+                .call_fut(new_post(BTreeSet::new()))
+                .map_err(|_| ())
+                .and_then(|res| res.map_err(|_| ()))
+                .and_then(move |post_id| {
+                    let reply = NewPostOut(
+                        BTreeSet::new(),
+                        Content::default(),
+                        Visibility::Public,
+                        Some(post_id),
+                    );
+
+                    replier
+                        .call_fut(reply)
+                        .map_err(|_| ())
+                        .and_then(|res| res.map_err(|_| ()))
+                })
+                .and_then(|reply_id| settle().map(move |_| reply_id))
+                .and_then(move |reply_id| {
+                    // user 1 should have the reply in inbox
+                    let fut = addrs_vec[1]
+                        .user()
+                        .call_fut(GetPostIds(10))
+                        .map_err(|_| ())
+                        .and_then(|res| res.map_err(|_| ()))
+                        .map(move |post_ids| assert!(post_ids.contains(&reply_id)));
+
+                    // user 0 wasn't involved
+                    let fut2 = addrs_vec[0]
+                        .user()
+                        .call_fut(GetPostIds(10))
+                        .map_err(|_| ())
+                        .and_then(|res| res.map_err(|_| ()))
+                        .map(|post_ids| assert!(post_ids.is_empty()));
+
+                    fut.join(fut2).map(|_| ())
+                })
+        })
+    }
+
+    #[test]
+    fn replies_need_a_parent_the_replier_can_see() {
+        with_users(|_, addrs_vec, _| {
+            let replier = addrs_vec[2].outbox().clone();
+
+            // user 1 makes a direct post mentioning no one, so user 2 can't see it
+            addrs_vec[1]
+                .outbox()
+                .call_fut(NewPostOut(
+                    BTreeSet::new(),
+                    Content::default(),
+                    Visibility::Direct,
+                    None,
+                ))
+                .map_err(|_| ())
+                .and_then(|res| res.map_err(|_| ()))
+                .and_then(move |post_id| {
+                    let reply = NewPostOut(
+                        BTreeSet::new(),
+                        Content::default(),
+                        Visibility::Public,
+                        Some(post_id),
+                    );
+
+                    replier.call_fut(reply).map_err(|_| ()).map(move |res| {
+                        assert_eq!(res, Err(UserError::Posts(PostsError::PostNotFound(post_id))))
+                    })
+                })
+        })
+    }
+
+    #[test]
+    fn boosts_reach_the_boosters_followers() {
+        with_users(|ids_vec, addrs_vec, _| {
+            // user 0 follows user 1
+            addrs_vec[0].outbox().send(RequestFollow(ids_vec[1]));
+
+            settle()
+                .and_then(move |_| {
+                    addrs_vec[1].outbox().send(AcceptFollowRequest(ids_vec[0]));
+
+                    settle().map(move |_| addrs_vec)
+                })
+                .and_then(|addrs_vec| {
+                    // user 2 makes a post, which user 1 boosts
+                    addrs_vec[2]
+                        .outbox()
+                        .call_fut(new_post(BTreeSet::new()))
+                        .map_err(|_| ())
+                        .and_then(|res| res.map_err(|_| ()))
+                        .and_then(move |post_id| {
+                            addrs_vec[1]
+                                .outbox()
+                                .call_fut(Boost(post_id))
+                                .map_err(|_| ())
+                                .and_then(|res| res.map_err(|_| ()))
+                                .and_then(move |_| settle().map(move |_| (addrs_vec, post_id)))
+                        })
+                })
+                .and_then(|(addrs_vec, post_id)| {
+                    // the follower received it
+                    addrs_vec[0]
+                        .user()
+                        .call_fut(GetPostIds(10))
+                        .map_err(|_| ())
+                        .and_then(|res| res.map_err(|_| ()))
+                        .map(move |post_ids| {
+                            assert_eq!(post_ids, vec![post_id].into_iter().collect());
+                            (addrs_vec, post_id)
+                        })
+                })
+                .and_then(|(addrs_vec, post_id)| {
+                    addrs_vec[1]
+                        .outbox()
+                        .call_fut(Unboost(post_id))
+                        .map_err(|_| ())
+                        .and_then(|res| res.map_err(|_| ()))
+                        .and_then(move |_| settle().map(move |_| addrs_vec))
+                })
+                .and_then(|addrs_vec| {
+                    // and loses it once it's no longer boosted
+                    addrs_vec[0]
+                        .user()
+                        .call_fut(GetPostIds(10))
+                        .map_err(|_| ())
+                        .and_then(|res| res.map_err(|_| ()))
+                        .map(|post_ids| assert!(post_ids.is_empty()))
+                })
+        })
+    }
+
+    #[test]
+    fn boosts_respect_the_authors_blocklist() {
+        with_users(|ids_vec, addrs_vec, _| {
+            // user 0 follows user 1
+            addrs_vec[0].outbox().send(RequestFollow(ids_vec[1]));
+
+            settle()
+                .and_then(move |_| {
+                    addrs_vec[1].outbox().send(AcceptFollowRequest(ids_vec[0]));
+
+                    settle().map(move |_| (ids_vec, addrs_vec))
+                })
+                .and_then(|(ids_vec, addrs_vec)| {
+                    // user 2 blocks user 0, then makes a post
+                    addrs_vec[2]
+                        .outbox()
+                        .call_fut(BlockUser(ids_vec[0]))
+                        .map_err(|_| ())
+                        .and_then(|_| settle())
+                        .and_then(move |_| {
+                            addrs_vec[2]
+                                .outbox()
+                                .call_fut(new_post(BTreeSet::new()))
+                                .map_err(|_| ())
+                                .and_then(|res| res.map_err(|_| ()))
+                                .map(move |post_id| (ids_vec, addrs_vec, post_id))
+                        })
+                })
+                .and_then(|(ids_vec, addrs_vec, post_id)| {
+                    // user 1 boosts it, which doesn't reach user 0
+                    addrs_vec[1]
+                        .outbox()
+                        .call_fut(Boost(post_id))
+                        .map_err(|_| ())
+                        .and_then(|res| res.map_err(|_| ()))
+                        .and_then(|_| settle())
+                        .and_then(move |_| {
+                            addrs_vec[0]
+                                .user()
+                                .call_fut(GetPostIds(10))
+                                .map_err(|_| ())
+                                .and_then(|res| res.map_err(|_| ()))
+                                .map(move |post_ids| {
+                                    assert!(post_ids.is_empty());
+                                    (ids_vec, addrs_vec, post_id)
+                                })
+                        })
+                })
+                .and_then(|(ids_vec, addrs_vec, post_id)| {
+                    // once user 2 blocks user 1 too, user 1 can't boost it anymore
+                    addrs_vec[2]
+                        .outbox()
+                        .call_fut(BlockUser(ids_vec[1]))
+                        .map_err(|_| ())
+                        .and_then(|_| settle())
+                        .and_then(move |_| {
+                            addrs_vec[1]
+                                .outbox()
+                                .call_fut(Boost(post_id))
+                                .map_err(|_| ())
+                                .map(move |res| match res {
+                                    Err(UserError::Dispatch(DispatchError::Blocked(
+                                        booster,
+                                        author,
+                                    ))) => {
+                                        assert_eq!(booster, ids_vec[1]);
+                                        assert_eq!(author, ids_vec[2]);
+                                    }
+                                    res => panic!("Expected the boost to be blocked: {:?}", res),
+                                })
+                        })
+                })
+        })
+    }
+
+    #[test]
+    fn only_authors_delete_their_posts() {
+        with_users(|_, addrs_vec, _| {
+            let author = addrs_vec[0].outbox().clone();
+
+            // user 0 makes two posts
+            author
+                .call_fut(new_post(BTreeSet::new()))
+                .join(author.call_fut(new_post(BTreeSet::new())))
+                .map_err(|_| ())
+                .and_then(|(res_1, res_2)| res_1.and_then(|p1| res_2.map(|p2| (p1, p2))))
+                .map_err(|_| ())
+                .and_then(move |(first, second)| {
+                    // 0 means all of them, otherwise the newest come first
+                    let all = author
+                        .call_fut(GetUserPostIds(0))
+                        .map_err(|_| ())
+                        .and_then(|res| res.map_err(|_| ()));
+                    let newest = author
+                        .call_fut(GetUserPostIds(1))
+                        .map_err(|_| ())
+                        .and_then(|res| res.map_err(|_| ()));
+
+                    all.join(newest).map(move |(all, newest)| {
+                        assert_eq!(all, vec![first, second].into_iter().collect());
+                        assert_eq!(newest, vec![second].into_iter().collect());
+                        (addrs_vec, first)
+                    })
+                })
+                .and_then(|(addrs_vec, first)| {
+                    // user 1 can't delete user 0's post
+                    addrs_vec[1]
+                        .outbox()
+                        .call_fut(DeletePost(first))
+                        .map_err(|_| ())
+                        .map(move |res| {
+                            assert_eq!(res, Err(UserError::Posts(PostsError::NotAuthor(first))))
+                        })
+                        .and_then(|_| settle())
+                        .and_then(move |_| {
+                            addrs_vec[0]
+                                .outbox()
+                                .call_fut(GetUserPostIds(0))
+                                .map_err(|_| ())
+                                .and_then(|res| res.map_err(|_| ()))
+                                .map(move |post_ids| assert!(post_ids.contains(&first)))
+                        })
+                })
+        })
+    }
+
+    #[test]
+    fn likes_are_counted_and_reach_the_author() {
+        with_users(|ids_vec, addrs_vec, _| {
+            let (liker_1, liker_2) = (addrs_vec[1].outbox().clone(), addrs_vec[2].outbox().clone());
+
+            // user 0 makes a post, which users 1 and 2 like and user 2 boosts
+            addrs_vec[0]
+                .outbox()
+                .call_fut(new_post(BTreeSet::new()))
+                .map_err(|_| ())
+                .and_then(|res| res.map_err(|_| ()))
+                .and_then(move |post_id| {
+                    liker_1
+                        .call_fut(Like(post_id))
+                        .join3(liker_2.call_fut(Like(post_id)), liker_2.call_fut(Boost(post_id)))
+                        .map_err(|_| ())
+                        .and_then(|(res_1, res_2, res_3)| {
+                            res_1.and(res_2).and(res_3).map_err(|_| ())
+                        })
+                        .and_then(move |_| {
+                            // and user 1 replies to
+                            let reply = NewPostOut(
+                                BTreeSet::new(),
+                                Content::default(),
+                                Visibility::Public,
+                                Some(post_id),
+                            );
+
+                            liker_1
+                                .call_fut(reply)
+                                .map_err(|_| ())
+                                .and_then(|res| res.map_err(|_| ()))
+                        })
+                        .and_then(|_| settle())
+                        .map(move |_| (ids_vec, addrs_vec, post_id))
+                })
+                .and_then(|(ids_vec, addrs_vec, post_id)| {
+                    let stats = addrs_vec[0]
+                        .outbox()
+                        .call_fut(GetPosts)
+                        .map_err(|_| ())
+                        .and_then(|res| res.map_err(|_| ()))
+                        .and_then(move |posts| {
+                            posts
+                                .call_fut(Message::new(GetPostStats(post_id)))
+                                .map_err(|_| ())
+                                .and_then(|res| res.map_err(|_| ()))
+                        });
+
+                    let notifications = addrs_vec[0]
+                        .user()
+                        .call_fut(GetNotifications(0))
+                        .map_err(|_| ())
+                        .and_then(|res| res.map_err(|_| ()));
+
+                    stats.join(notifications).map(move |(stats, notifications)| {
+                        let expected = PostStats {
+                            likes: 2,
+                            boosts: 1,
+                            replies: 1,
+                        };
+                        assert_eq!(stats, expected);
+                        assert_eq!(notifications.len(), 2);
+                        assert!(notifications.contains(&Notification::Liked(post_id, ids_vec[1])));
+                        assert!(notifications.contains(&Notification::Liked(post_id, ids_vec[2])));
+
+                        (ids_vec, addrs_vec, post_id)
+                    })
+                })
+                .and_then(|(ids_vec, addrs_vec, post_id)| {
+                    // user 1 takes their like back
+                    addrs_vec[1]
+                        .outbox()
+                        .call_fut(Unlike(post_id))
+                        .map_err(|_| ())
+                        .and_then(|res| res.map_err(|_| ()))
+                        .and_then(|_| settle())
+                        .and_then(move |_| {
+                            addrs_vec[0]
+                                .user()
+                                .call_fut(GetNotifications(0))
+                                .map_err(|_| ())
+                                .and_then(|res| res.map_err(|_| ()))
+                                .map(move |notifications| {
+                                    let liked = Notification::Liked(post_id, ids_vec[2]);
+                                    assert_eq!(notifications, vec![liked]);
+                                })
+                        })
+                })
+        })
+    }
+
+    #[test]
+    fn mentions_are_filtered_through_blocklists() {
+        with_users(|ids_vec, addrs_vec, _| {
+            let mentions = vec![ids_vec[0]].into_iter().collect();
+            let author = addrs_vec[1].outbox().clone();
+
+            // user 0 blocks user 1
+            addrs_vec[0]
+                .outbox()
+                .call_fut(BlockUser(ids_vec[1]))
+                .map_err(|_| ())
+                .and_then(|_| settle())
+                .and_then(move |_| {
+                    // user 1 makes a post mentioning user 0
+                    author.call_fut(new_post(mentions)).map_err(|_| ())
+                })
+                .and_then(|_| settle())
+                .and_then(move |_| {
+                    // user 0 should not have a post in inbox
+                    addrs_vec[0]
+                        .user()
+                        .call_fut(GetPostIds(10))
+                        .map_err(|_| ())
+                        .and_then(|res| res.map_err(|_| ()))
+                        .map(|post_ids| assert!(post_ids.is_empty()))
+                })
+        })
+    }
+
+    #[test]
+    fn failed_follow_requests_are_not_left_pending() {
+        let storage = Storage::memory();
+        let pending = storage.clone();
+
+        with_node(storage, federation(), move |ids_vec, addrs_vec, _| {
+            let missing = UserId(Id(0), Id(99));
+
+            addrs_vec[0]
+                .outbox()
+                .call_fut(RequestFollow(missing))
+                .map_err(|_| ())
+                .map(|res| assert!(res.is_err()))
+                .and_then(|_| settle())
+                .map(move |_| {
+                    let pending = pending.relation(ids_vec[0], Relation::PendingFollows);
+                    assert!(pending.is_empty());
+                })
+        })
+    }
+
+    #[test]
+    fn follow_requests_tell_blocked_and_missing_users_apart() {
+        with_users(|ids_vec, addrs_vec, _| {
+            let (uid0, uid1) = (ids_vec[0], ids_vec[1]);
+            let missing = UserId(Id(0), Id(99));
+            let outbox = addrs_vec[1].outbox().clone();
+
+            // user 0 blocks user 1
+            addrs_vec[0]
+                .outbox()
+                .call_fut(BlockUser(uid1))
+                .map_err(|_| ())
+                .and_then(|_| settle())
+                .and_then(move |_| {
+                    let blocked = outbox.call_fut(RequestFollow(uid0)).map_err(|_| ());
+                    let missing_user = outbox.call_fut(RequestFollow(missing)).map_err(|_| ());
+
+                    blocked.join(missing_user)
+                })
+                .map(move |(blocked, missing_user)| {
+                    assert_eq!(
+                        blocked,
+                        Err(UserError::Dispatch(DispatchError::Blocked(uid1, uid0)))
+                    );
+                    assert_eq!(
+                        missing_user,
+                        Err(UserError::Dispatch(DispatchError::Users(
+                            UsersError::UserNotFound(missing)
+                        )))
+                    );
+                })
+        })
+    }
+
+    #[test]
+    fn follow_requests_are_delivered_to_remote_users() {
+        let peer = FakePeer::default();
+        let deliveries = peer.deliveries.clone();
+        let federation = federation_with(peer);
+        let alice = federation.iris().user_id(ALICE).unwrap();
+
+        with_node(Storage::memory(), federation, move |_, addrs_vec, _| {
+            addrs_vec[0].outbox().send(RequestFollow(alice));
+
+            settle()
+                .and_then(move |_| {
+                    addrs_vec[0]
+                        .user()
+                        .call_fut(GetPublicKey)
+                        .map_err(|_| ())
+                        .and_then(|res| res.map_err(|_| ()))
+                })
+                .map(move |public_key| {
+                    let deliveries = deliveries.lock().unwrap();
+                    assert_eq!(deliveries.len(), 1);
+
+                    let delivery = &deliveries[0];
+                    assert_eq!(delivery.url, ALICE_INBOX);
+                    assert_eq!(delivery.activity().kind, "Follow");
+                    assert!(delivery.verify(&public_key).is_ok());
+                })
+        })
+    }
+
+    #[test]
+    fn posts_are_delivered_to_remote_followers() {
+        let peer = FakePeer::default();
+        let deliveries = peer.deliveries.clone();
+        let federation = federation_with(peer);
+        let alice = federation.iris().user_id(ALICE).unwrap();
+
+        with_node(Storage::memory(), federation, move |_, addrs_vec, _| {
+            // alice's follow request arrives through user 1's inbox
+            addrs_vec[1].inbox().send(FollowRequest(alice));
+
+            settle()
+                .and_then(move |_| {
+                    addrs_vec[1].outbox().send(AcceptFollowRequest(alice));
+
+                    settle().map(move |_| addrs_vec)
+                })
+                .and_then(|addrs_vec| {
+                    addrs_vec[1].outbox().send(new_post(BTreeSet::new()));
+
+                    settle()
+                })
+                .map(move |_| {
+                    let kinds: Vec<_> = deliveries
+                        .lock()
+                        .unwrap()
+                        .iter()
+                        .map(|delivery| {
+                            assert_eq!(delivery.url, ALICE_INBOX);
+                            delivery.activity().kind
+                        })
+                        .collect();
+
+                    assert_eq!(kinds, vec!["Accept", "Create"]);
+                })
+        })
+    }
+
+    #[test]
+    fn remote_actors_are_kept_between_lookups() {
+        let peer = FakePeer::default();
+        let fetches = peer.fetches.clone();
+        let federation = federation_with(peer);
+
+        let actor = federation.actor(ALICE).wait().unwrap();
+        assert_eq!(federation.actor(ALICE).wait(), Ok(actor.clone()));
+
+        // Only just fetched, so a failed verification doesn't fetch them again yet
+        assert_eq!(federation.refetch_actor(ALICE).wait(), Ok(actor));
+        assert_eq!(*fetches.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn restarted_node_restores_its_state() {
+        let storage = Storage::memory();
+
+        with_node(storage.clone(), federation(), |ids_vec, addrs_vec, _| {
+            addrs_vec[0].outbox().send(RequestFollow(ids_vec[1]));
+
+            settle()
+                .and_then(move |_| {
+                    addrs_vec[1].outbox().send(AcceptFollowRequest(ids_vec[0]));
+
+                    settle().map(move |_| (ids_vec, addrs_vec))
+                })
+                .and_then(|(ids_vec, addrs_vec)| {
+                    addrs_vec[1].outbox().send(new_post(BTreeSet::new()));
+                    addrs_vec[2].outbox().send(BlockUser(ids_vec[0]));
+
+                    settle()
+                })
+        });
+
+        // A fresh set of actors, as after a restart
+        let system = System::new("test-restart");
+
+        let posts: SyncAddress<_> = Peered::new(Posts::new(Id(0), storage.clone())).start();
+
+        // A peer that kept running, which only hears of the restored users through an announce
+        let watcher: SyncAddress<_> = Peered::new(users(Id(1), posts.clone())).start();
+        let users: SyncAddress<_> =
+            Peered::new(Users::new(Id(0), posts.clone(), federation(), storage.clone()))
+                .add_peer(watcher.clone())
+                .start();
+        let blocklists: SyncAddress<_> = Peered::new(Blocklists::new(storage)).start();
+        let blocklists_2 = blocklists.clone();
+
+        let lookup = |username: &str| {
+            users
+                .call_fut(Message::new(LookupUsername(username.to_owned())))
+                .map_err(|_| ())
+                .and_then(|res| res.map_err(|_| ()))
+        };
+
+        let restored = users
+            .call_fut(Message::new(RestoreUsers(users.clone(), blocklists.clone())))
+            .map_err(|_| ())
+            .and_then(|res| res.map_err(|_| ()))
+            .map(|count| assert_eq!(count, 3))
+            .and_then(|_| settle())
+            .and_then(move |_| {
+                watcher
+                    .call_fut(Message::new(LookupUsername("user1".to_owned())))
+                    .map_err(|_| ())
+                    .map(|res| assert!(res.is_ok()))
+            });
+
+        let user_0 = lookup("user0");
+        let user_1 = lookup("user1");
+        let user_2 = lookup("user2");
+
+        let fut = restored
+            .and_then(|_| user_0.join3(user_1, user_2))
+            .and_then(move |((id_0, addr_0), (_, addr_1), (id_2, _))| {
+                let followers = addr_1
+                    .user()
+                    .call_fut(GetFollowers)
+                    .map_err(|_| ())
+                    .and_then(|res| res.map_err(|_| ()))
+                    .map(move |followers| assert!(followers.contains(&id_0)));
+
+                let own_posts = addr_1
+                    .user()
+                    .call_fut(GetUserPostIds(0))
+                    .map_err(|_| ())
+                    .and_then(|res| res.map_err(|_| ()));
+
+                let timeline = addr_0
+                    .user()
+                    .call_fut(GetPostIds(0))
+                    .map_err(|_| ())
+                    .and_then(|res| res.map_err(|_| ()));
+
+                let post_ids = own_posts
+                    .join(timeline)
+                    .map(|(own_posts, timeline)| {
+                        assert_eq!(own_posts.len(), 1);
+                        assert_eq!(own_posts, timeline);
+                    });
+
+                let post_size = posts
+                    .call_fut(Message::new(PostSize))
+                    .map_err(|_| ())
+                    .and_then(|res| res.map_err(|_| ()))
+                    .map(|size| assert_eq!(size, 1));
+
+                let can_speak = blocklists
+                    .call_fut(Message::new(CanSpeak(id_2, id_0)))
+                    .map_err(|_| ())
+                    .and_then(|res| res.map_err(|_| ()))
+                    .map(|can_speak| assert!(!can_speak));
+
+                followers.join4(post_ids, post_size, can_speak)
+            })
+            .and_then(move |_| {
+                // New users carry on from the stored ids rather than reusing them
+                users
+                    .call_fut(Message::new(NewUser(
+                        users.clone(),
+                        blocklists_2,
+                        Profile::new("user3"),
+                    )))
+                    .map_err(|_| ())
+                    .and_then(|res| res.map_err(|_| ()))
+                    .map(|user_id| assert_eq!(user_id, UserId(Id(0), Id(3))))
+            });
+
+        Arbiter::handle().spawn(
+            fut.map(|_| Arbiter::system().send(SystemExit(0)))
+                .map_err(|_| panic!("Future error case")),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn reactions_keep_their_versions_across_restarts() {
+        let storage = Storage::memory();
+
+        {
+            let system = System::new("test");
+            let posts: SyncAddress<_> = Peered::new(Posts::new(Id(0), storage.clone())).start();
+
+            // user 1 likes the post, takes it back and likes it again
+            let fut = posts
+                .call_fut(Message::new(public_post(user(0), Content::default())))
+                .map_err(|_| ())
+                .and_then(|res| res.map_err(|_| ()))
+                .and_then(move |post_id| {
+                    let like = AddReaction(post_id, user(1), Reaction::Like);
+                    let unlike = RemoveReaction(post_id, user(1), Reaction::Like);
+                    let (posts_1, posts_2) = (posts.clone(), posts.clone());
+
+                    posts
+                        .call_fut(Message::new(like))
+                        .and_then(move |_| posts_1.call_fut(Message::new(unlike)))
+                        .and_then(move |_| posts_2.call_fut(Message::new(like)))
+                        .map_err(|_| ())
+                        .and_then(|res| res.map_err(|_| ()))
+                });
+
+            Arbiter::handle().spawn(
+                fut.map(|_| Arbiter::system().send(SystemExit(0)))
+                    .map_err(|_| panic!("Future error case")),
+            );
+
+            system.run();
+        }
+
+        // A fresh set of actors, as after a restart
+        let system = System::new("test-restart");
+
+        let post_id = storage.posts()[0].post_id;
+        let posts: SyncAddress<_> = Peered::new(Posts::new(Id(0), storage.clone())).start();
+
+        let fut = posts
+            .call_fut(Message::new(RemoveReaction(post_id, user(1), Reaction::Like)))
+            .map_err(|_| ())
+            .and_then(|res| res.map_err(|_| ()))
+            .and_then(move |_| {
+                posts
+                    .call_fut(Message::new(GetPostStats(post_id)))
+                    .map_err(|_| ())
+                    .and_then(|res| res.map_err(|_| ()))
+            })
+            .map(move |stats| {
+                assert_eq!(stats.likes, 0);
+
+                // The unlike follows the third change rather than the first, so replicas that
+                // saw the like again don't keep it
+                assert_eq!(storage.reactions(), vec![(post_id, Reaction::Like, user(1), 4)]);
+            });
+
+        Arbiter::handle().spawn(
+            fut.map(|_| Arbiter::system().send(SystemExit(0)))
+                .map_err(|_| panic!("Future error case")),
+        );
+
+        system.run();
+    }
+
+    const ALICE: &'static str = "https://remote.example/users/alice";
+    const ALICE_INBOX: &'static str = "https://remote.example/users/alice/inbox";
+
+    /// A request POSTed to a `FakePeer`
+    struct Delivery {
+        url: String,
+        headers: Vec<(String, String)>,
+        body: Vec<u8>,
+    }
+
+    impl Delivery {
+        fn activity(&self) -> Activity {
+            serde_json::from_slice(&self.body).unwrap()
+        }
+
+        fn header(&self, name: &str) -> Option<String> {
+            self.headers
+                .iter()
+                .find(|&&(ref key, _)| key.eq_ignore_ascii_case(name))
+                .map(|&(_, ref value)| value.clone())
+        }
+
+        fn verify(&self, public_key_pem: &str) -> Result<(), ::activitypub::SignatureError> {
+            let signature: Signature = self.header("signature").unwrap().parse()?;
+            let digest = self.header("digest");
+
+            signature.verify_digest(digest.as_ref().map(|d| d.as_str()), &self.body)?;
+            signature.verify(public_key_pem, "POST", "/users/alice/inbox", |name| {
+                self.header(name)
+            })
+        }
+    }
+
+    /// Stands in for the server hosting alice, recording what's delivered to it
+    #[derive(Default)]
+    struct FakePeer {
+        deliveries: Arc<Mutex<Vec<Delivery>>>,
+        fetches: Arc<Mutex<usize>>,
+    }
+
+    impl Client for FakePeer {
+        fn get(&self, url: &str) -> Box<Future<Item = Vec<u8>, Error = ClientError>> {
+            *self.fetches.lock().unwrap() += 1;
+
+            if url != ALICE {
+                return Box::new(future::err(ClientError::Status(404)));
+            }
+
+            let actor = format!(
+                r#"{{"id": "{}", "type": "Person", "inbox": "{}"}}"#,
+                ALICE, ALICE_INBOX
+            );
+
+            Box::new(future::ok(actor.into_bytes()))
+        }
+
+        fn post(
+            &self,
+            url: &str,
+            headers: Vec<(String, String)>,
+            body: Vec<u8>,
+        ) -> Box<Future<Item = (), Error = ClientError>> {
+            self.deliveries.lock().unwrap().push(Delivery {
+                url: url.to_owned(),
+                headers: headers,
+                body: body,
+            });
+
+            Box::new(future::ok(()))
+        }
+    }
+
+    /// Records the membership events a peer reports
+    struct Membership(Arc<Mutex<Vec<MembershipEvent>>>);
+
+    impl Actor for Membership {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<MembershipEvent> for Membership {
+        type Result = ();
+
+        fn handle(&mut self, msg: MembershipEvent, _: &mut Context<Self>) {
+            self.0.lock().unwrap().push(msg);
+        }
+    }
+
+    fn users(users_id: Id, posts: SyncAddress<Peered<Posts>>) -> Users {
+        Users::new(users_id, posts, federation(), Storage::memory())
+    }
+
+    fn federation() -> Federation {
+        federation_with(FakePeer::default())
+    }
+
+    fn federation_with(peer: FakePeer) -> Federation {
+        let iris = IriMap::new("https://local.example", Storage::memory());
+
+        Federation::new(Arc::new(Mutex::new(iris)), Arc::new(peer))
+    }
+
+    /// Gives messages sent without waiting on a response time to propagate
+    fn settle() -> Box<Future<Item = (), Error = ()>> {
+        let timer = wheel().tick_duration(Duration::from_millis(10)).build();
+
+        Box::new(timer.sleep(Duration::from_millis(100)).map_err(|_| ()))
+    }
+
+    /// A post by a local user, delivered to their followers and the users it mentions
+    fn new_post(mentions: BTreeSet<UserId>) -> NewPostOut {
+        NewPostOut(mentions, Content::default(), Visibility::Public, None)
+    }
+
+    /// A public post as `Posts` stores it, mentioning no one
+    fn public_post(author: UserId, content: Content) -> NewPost {
+        NewPost {
+            author: author,
+            mentions: BTreeSet::new(),
+            content: content,
+            visibility: Visibility::Public,
+            audience: BTreeSet::new(),
+            in_reply_to: None,
+        }
+    }
+
+    /// A user of the first node, for tests that only need ids
+    fn user(id: u64) -> UserId {
+        UserId::new(Id(0), Id(id))
+    }
+
+    /// Whether `node` lets the users `a` and `b` speak to each other
+    fn can_speak(
+        node: &SyncAddress<Peered<Blocklists>>,
+        a: u64,
+        b: u64,
+    ) -> Box<Future<Item = bool, Error = ()>> {
+        Box::new(
+            node.call_fut(Message::new(CanSpeak(user(a), user(b))))
+                .map_err(|_| ())
+                .and_then(|res| res.map_err(|_| ())),
+        )
+    }
+
+    fn with_users<F, G>(f: F)
+    where
+        F: FnOnce(Vec<UserId>, Vec<UserAddress>, SyncAddress<Peered<Blocklists>>) -> G + 'static,
+        G: Future<Item = (), Error = ()> + 'static,
+    {
+        with_node(Storage::memory(), federation(), f)
+    }
+
+    /// Runs `f` against a node with three new users
+    fn with_node<F, G>(storage: Storage, federation: Federation, f: F)
+    where
+        F: FnOnce(Vec<UserId>, Vec<UserAddress>, SyncAddress<Peered<Blocklists>>) -> G + 'static,
+        G: Future<Item = (), Error = ()> + 'static,
+    {
+        let system = System::new("test");
+        let arbiter = Arbiter::new("test-exec");
+
+        let posts: SyncAddress<_> = Peered::new(Posts::new(Id(0), storage.clone())).start();
+        let users: SyncAddress<_> =
+            Peered::new(Users::new(Id(0), posts, federation, storage.clone())).start();
+        let blocklists: SyncAddress<_> = Peered::new(Blocklists::new(storage)).start();
+        let blocklists_clone = blocklists.clone();
+
+        let users_clone = users.clone();
+
+        let user_addrs_fut = iter_ok(0..3)
+            .and_then(move |i| {
+                let profile = Profile::new(&format!("user{}", i));
+
+                users.call_fut(Message::new(NewUser(
+                    users.clone(),
+                    blocklists.clone(),
+                    profile,
+                )))
             })
             .map_err(|_| ())
-            .and_then(|res| res)
+            .and_then(|res| res.map_err(|_| ()))
             .and_then(move |user_id| {
                 users_clone
                     .call_fut(Message::new(Lookup(user_id)))
                     .map(move |res| res.map(|user_addr| (user_id, user_addr)))
                     .map_err(|_| ())
             })
-            .and_then(|res| res)
+            .and_then(|res| res.map_err(|_| ()))
             .fold(Vec::new(), |mut acc, (user_id, user_addr)| {
                 acc.push((user_id, user_addr));
                 Ok(acc) as Result<_, ()>
             })
             .and_then(|users| {
                 let (ids, addrs) = users.into_iter().unzip();
-                f(ids, addrs, blocklists_clone);
-                Ok(())
+                f(ids, addrs, blocklists_clone)
             });
 
         Arbiter::handle().spawn(
@@ -0,0 +1,161 @@
+use std::time::{Duration, Instant};
+
+use super::{PeerId, Stamp, VersionVector};
+
+struct Buffered<T> {
+    stamp: Stamp,
+    received: Instant,
+    deliver: Box<FnMut(&mut T)>,
+}
+
+/// Holds announces back until everything they causally follow has been delivered
+///
+/// Until a node knows its baseline, the announces its backfill will already cover, every
+/// announce is held back.
+pub struct Causal<T> {
+    delivered: VersionVector,
+    baseline: bool,
+    buffer: Vec<Buffered<T>>,
+}
+
+impl<T> Causal<T> {
+    pub fn new() -> Self {
+        Causal {
+            delivered: VersionVector::new(),
+            baseline: false,
+            buffer: Vec::new(),
+        }
+    }
+
+    /// The announces delivered so far, counting the ones covered by the baseline
+    pub fn delivered(&self) -> VersionVector {
+        self.delivered.clone()
+    }
+
+    /// Stamps a broadcast made by `origin`, this node
+    pub fn stamp(&mut self, origin: PeerId) -> Stamp {
+        *self.delivered.entry(origin).or_insert(0) += 1;
+
+        Stamp {
+            origin: origin,
+            deps: self.delivered.clone(),
+        }
+    }
+
+    /// Treats the announces a peer had delivered as covered, starting delivery
+    pub fn set_baseline(&mut self, delivered: &VersionVector, inner: &mut T) {
+        for (origin, &seq) in delivered {
+            let entry = self.delivered.entry(*origin).or_insert(0);
+
+            if seq > *entry {
+                *entry = seq;
+            }
+        }
+
+        self.baseline = true;
+        self.deliver_ready(inner);
+    }
+
+    /// Delivers an announce once everything it follows has been, dropping ones seen before
+    pub fn receive<F>(&mut self, stamp: Stamp, inner: &mut T, deliver: F)
+    where
+        F: FnOnce(&mut T) + 'static,
+    {
+        let seq = stamp.seq();
+
+        let seen = seq <= self.seen(stamp.origin)
+            || self.buffer
+                .iter()
+                .any(|buffered| buffered.stamp.origin == stamp.origin && buffered.stamp.seq() == seq);
+
+        if seen {
+            debug!("Dropping announce {} from {}, already seen", seq, stamp.origin);
+            return;
+        }
+
+        let mut deliver = Some(deliver);
+
+        self.buffer.push(Buffered {
+            stamp: stamp,
+            received: Instant::now(),
+            deliver: Box::new(move |inner| {
+                if let Some(deliver) = deliver.take() {
+                    deliver(inner);
+                }
+            }),
+        });
+
+        self.deliver_ready(inner);
+    }
+
+    /// Stops waiting on announces held back for longer than `timeout`, delivering them in order
+    ///
+    /// What they were waiting on was most likely lost, or sent before this node joined.
+    pub fn flush(&mut self, timeout: Duration, inner: &mut T) {
+        let now = Instant::now();
+
+        let (mut stale, fresh): (Vec<_>, Vec<_>) = self.buffer
+            .drain(..)
+            .partition(|buffered| now.duration_since(buffered.received) > timeout);
+        self.buffer = fresh;
+
+        stale.sort_by_key(|buffered| (buffered.stamp.origin, buffered.stamp.seq()));
+
+        for mut buffered in stale {
+            let seq = buffered.stamp.seq();
+            let entry = self.delivered.entry(buffered.stamp.origin).or_insert(0);
+
+            if seq > *entry {
+                warn!("Delivering announce {} from {} out of order", seq, buffered.stamp.origin);
+                *entry = seq;
+                (buffered.deliver)(inner);
+            }
+        }
+
+        self.deliver_ready(inner);
+    }
+
+    fn seen(&self, origin: PeerId) -> u64 {
+        self.delivered.get(&origin).cloned().unwrap_or(0)
+    }
+
+    fn deliverable(&self, stamp: &Stamp) -> bool {
+        stamp.seq() == self.seen(stamp.origin) + 1
+            && stamp
+                .deps
+                .iter()
+                .all(|(peer, &seq)| *peer == stamp.origin || self.seen(*peer) >= seq)
+    }
+
+    fn deliver_ready(&mut self, inner: &mut T) {
+        {
+            let delivered = &self.delivered;
+
+            self.buffer.retain(|buffered| {
+                let seen = delivered.get(&buffered.stamp.origin).cloned().unwrap_or(0);
+
+                buffered.stamp.seq() > seen
+            });
+        }
+
+        if !self.baseline {
+            return;
+        }
+
+        loop {
+            let index = match self.buffer
+                .iter()
+                .position(|buffered| self.deliverable(&buffered.stamp))
+            {
+                Some(index) => index,
+                None => break,
+            };
+
+            let mut buffered = self.buffer.remove(index);
+
+            self.delivered
+                .insert(buffered.stamp.origin, buffered.stamp.seq());
+            (buffered.deliver)(inner);
+        }
+    }
+}
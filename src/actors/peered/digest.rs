@@ -0,0 +1,56 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+/// How many ranges a replica's keys are split into when comparing it with a peer's
+pub const DIGEST_BUCKETS: usize = 64;
+
+/// Hashes of a replica's entries, split into buckets by key
+///
+/// Entries are combined regardless of the order they were inserted in, so replicas holding the
+/// same entries produce the same digest. Hashes are only comparable between nodes running the
+/// same build.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Digest(Vec<u64>);
+
+impl Digest {
+    pub fn new() -> Self {
+        Digest(vec![0; DIGEST_BUCKETS])
+    }
+
+    /// The bucket an entry with the given key falls in
+    pub fn bucket<K>(key: &K) -> usize
+    where
+        K: Hash,
+    {
+        (hash(key) % DIGEST_BUCKETS as u64) as usize
+    }
+
+    /// Adds an entry to the bucket of its key
+    pub fn insert<K, V>(&mut self, key: &K, value: &V)
+    where
+        K: Hash,
+        V: Hash,
+    {
+        let entry = hash(&(key, value));
+
+        if let Some(bucket) = self.0.get_mut(Digest::bucket(key)) {
+            *bucket = bucket.wrapping_add(entry);
+        }
+    }
+
+    /// The buckets where this digest and `other` disagree
+    pub fn differing(&self, other: &Digest) -> Vec<usize> {
+        (0..DIGEST_BUCKETS)
+            .filter(|&bucket| self.0.get(bucket) != other.0.get(bucket))
+            .collect()
+    }
+}
+
+fn hash<H>(value: &H) -> u64
+where
+    H: Hash,
+{
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
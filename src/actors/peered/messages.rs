@@ -1,10 +1,12 @@
+use std::fmt;
 use std::marker::PhantomData;
 
-use actix::{ResponseType, SyncAddress};
+use actix::{ResponseType, Subscriber};
 
-use super::{HandleMessage, Peered, PeeredInner};
+use super::{Digest, HandleMessage, PeerAddr, PeerId, PeeredError, PeeredInner, Stamp,
+            VersionVector};
 
-pub struct AnnouncePeer<T>(pub SyncAddress<Peered<T>>)
+pub struct AnnouncePeer<T>(pub PeerId, pub PeerAddr<T>)
 where
     T: PeeredInner + 'static;
 
@@ -16,7 +18,9 @@ where
     type Error = ();
 }
 
-pub struct RequestPeers<T>(pub SyncAddress<Peered<T>>)
+/// Asks a peer to add the given node, answered with the peer itself followed by its own peers,
+/// along with the announces it has delivered
+pub struct RequestPeers<T>(pub PeerId, pub PeerAddr<T>)
 where
     T: PeeredInner + 'static;
 
@@ -24,11 +28,12 @@ impl<T> ResponseType for RequestPeers<T>
 where
     T: PeeredInner + 'static,
 {
-    type Item = ();
-    type Error = ();
+    type Item = (Vec<(PeerId, PeerAddr<T>)>, VersionVector);
+    type Error = PeeredError;
 }
 
-pub struct ReplyPeers<T>(pub Vec<SyncAddress<Peered<T>>>)
+/// Answers a `RequestPeers` sent without waiting on a response, as remote peers do
+pub struct ReplyPeers<T>(pub Vec<(PeerId, PeerAddr<T>)>, pub VersionVector)
 where
     T: PeeredInner + 'static;
 
@@ -40,7 +45,8 @@ where
     type Error = ();
 }
 
-pub struct RequestBackfill<T>(pub SyncAddress<Peered<T>>, pub T::Request)
+/// RequestBackfill(reply_to, attempt, request)
+pub struct RequestBackfill<T>(pub PeerAddr<T>, pub u64, pub T::Request)
 where
     T: PeeredInner + 'static;
 
@@ -52,7 +58,8 @@ where
     type Error = ();
 }
 
-pub struct ReplyBackfill<T>(pub T::Backfill)
+/// ReplyBackfill(attempt, backfill)
+pub struct ReplyBackfill<T>(pub u64, pub T::Backfill)
 where
     T: PeeredInner + 'static;
 
@@ -64,11 +71,110 @@ where
     type Error = ();
 }
 
+/// SyncDigest(sender, reply_to, digest), answered with a `Reconcile` if the receiver's digest
+/// of the data both keep differs
+pub struct SyncDigest<T>(pub PeerId, pub PeerAddr<T>, pub Digest)
+where
+    T: PeeredInner + 'static;
+
+impl<T> ResponseType for SyncDigest<T>
+where
+    T: PeeredInner + 'static,
+{
+    type Item = ();
+    type Error = ();
+}
+
+/// Reconcile(sender, reply_to, buckets, diff), answered with the receiver's own diff for the
+/// same buckets when there is someone to reply to
+pub struct Reconcile<T>(pub PeerId, pub Option<PeerAddr<T>>, pub Vec<usize>, pub T::Diff)
+where
+    T: PeeredInner + 'static;
+
+impl<T> ResponseType for Reconcile<T>
+where
+    T: PeeredInner + 'static,
+{
+    type Item = ();
+    type Error = ();
+}
+
+/// Tells a node that the given peer is leaving the cluster
+pub struct LeavePeer(pub PeerId);
+
+impl ResponseType for LeavePeer {
+    type Item = ();
+    type Error = ();
+}
+
+/// Asks a node to say goodbye to its peers and stop
+pub struct Leave;
+
+impl ResponseType for Leave {
+    type Item = ();
+    type Error = ();
+}
+
+/// Heartbeat sent by the given peer, answered as long as the node is responsive
+pub struct Ping(pub PeerId);
+
+impl ResponseType for Ping {
+    type Item = ();
+    type Error = PeeredError;
+}
+
+/// A change to the set of peers a node knows about
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MembershipEvent {
+    Joined(PeerId),
+    Left(PeerId),
+    Evicted(PeerId),
+}
+
+impl fmt::Display for MembershipEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MembershipEvent::Joined(ref id) => write!(f, "Peer {} joined", id),
+            MembershipEvent::Left(ref id) => write!(f, "Peer {} left", id),
+            MembershipEvent::Evicted(ref id) => write!(f, "Peer {} was evicted", id),
+        }
+    }
+}
+
+impl ResponseType for MembershipEvent {
+    type Item = ();
+    type Error = ();
+}
+
+/// Registers a subscriber for every following `MembershipEvent`
+pub struct SubscribeMembership(pub Box<Subscriber<MembershipEvent> + Send>);
+
+impl ResponseType for SubscribeMembership {
+    type Item = ();
+    type Error = ();
+}
+
+/// Asks how far a node has got with backfilling from its peers
+pub struct BackfillStatus;
+
+impl ResponseType for BackfillStatus {
+    type Item = BackfillProgress;
+    type Error = PeeredError;
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum BackfillProgress {
+    /// Chunks are still being fetched, possibly from another peer after `retries` timeouts
+    InProgress { chunks: usize, retries: usize },
+    /// Everything peers had when asked has been received, nodes without seeds start here
+    Complete { chunks: usize, retries: usize },
+}
+
 pub struct PeerSize;
 
 impl ResponseType for PeerSize {
     type Item = usize;
-    type Error = ();
+    type Error = PeeredError;
 }
 
 pub struct Message<T, M>(pub M, pub PhantomData<T>)
@@ -92,7 +198,29 @@ where
     type Error = T::Error;
 }
 
-pub struct Announce<B>(pub B)
+/// A message handed to an owner of its key by a sharded node, answered without routing it again
+pub struct Forward<T, M>(pub M, pub PhantomData<T>)
+where
+    T: HandleMessage<M> + PeeredInner + 'static;
+
+impl<T, M> Forward<T, M>
+where
+    T: HandleMessage<M> + PeeredInner + 'static,
+{
+    pub fn new(message: M) -> Self {
+        Forward(message, PhantomData)
+    }
+}
+
+impl<T, M> ResponseType for Forward<T, M>
+where
+    T: HandleMessage<M> + PeeredInner + 'static,
+{
+    type Item = T::Item;
+    type Error = T::Error;
+}
+
+pub struct Announce<B>(pub Stamp, pub B)
 where
     B: Clone + Send;
 
@@ -100,8 +228,8 @@ impl<B> Announce<B>
 where
     B: Clone + Send,
 {
-    pub fn new(broadcast: B) -> Self {
-        Announce(broadcast)
+    pub fn new(stamp: Stamp, broadcast: B) -> Self {
+        Announce(stamp, broadcast)
     }
 }
 
@@ -110,5 +238,5 @@ where
     B: Clone + Send,
 {
     type Item = ();
-    type Error = ();
+    type Error = PeeredError;
 }
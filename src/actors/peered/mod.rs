@@ -1,14 +1,46 @@
-use actix::{Actor, AsyncContext, Context, Handler, SyncAddress};
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+use actix::{Actor, ActorContext, ActorFuture, AsyncContext, Context, Handler, ResponseFuture,
+            ResponseType, Subscriber, SyncAddress};
+use actix::fut::{result, wrap_future};
+use openssl::rand::rand_bytes;
+
+mod causal;
+mod digest;
 pub mod messages;
+mod ring;
+pub mod transport;
+
+pub use self::digest::Digest;
+pub use self::ring::shard_key;
 
+use self::causal::Causal;
 use self::messages::*;
+use self::ring::Ring;
+use self::transport::{Link, Outgoing};
+
+/// How often peers are pinged by default
+const HEARTBEAT_INTERVAL_MS: u64 = 5_000;
+/// How long a peer may stay silent by default before it is evicted
+const PEER_TIMEOUT_MS: u64 = 15_000;
+/// How long a backfill request may go unanswered by default before it is sent to another peer
+const BACKFILL_TIMEOUT_MS: u64 = 5_000;
+/// How often a node compares its data with a peer's by default
+const ANTI_ENTROPY_INTERVAL_MS: u64 = 30_000;
 
 pub trait PeeredInner {
     /// The type of data that is used to backfill the type
     type Backfill: Send;
-    /// The type of data used to request part of the backfill data
-    type Request: Send;
+    /// The type of data used to request part of the backfill data, kept to resend requests that
+    /// time out
+    type Request: Clone + Send;
+    /// The type of data sent to a peer whose digest differs, covering only the differing buckets
+    type Diff: Clone + Send;
 
     /// This method retrieves backfill data
     fn backfill(&self, req: Self::Request) -> Self::Backfill;
@@ -19,10 +51,59 @@ pub trait PeeredInner {
     /// This method handles incoming backfill data, returning whether or not more should be
     /// requested.
     fn handle_backfill(&mut self, backfill: Self::Backfill) -> Option<Self::Request>;
+
+    /// This method summarises the data, including what was deleted, to compare against a peer's
+    ///
+    /// Only data whose `shard_key`s `shared` says both nodes keep is included, so sharded nodes
+    /// compare just what they have in common.
+    fn digest(&self, shared: &Fn(&[u64]) -> bool) -> Digest;
+
+    /// This method retrieves the data falling in the given buckets of the digest, limited by
+    /// `shared` the same way
+    fn diff(&self, buckets: &[usize], shared: &Fn(&[u64]) -> bool) -> Self::Diff;
+
+    /// This method merges a peer's data for buckets that differed, so that both sides end up
+    /// with the same data whichever order they merge in
+    fn reconcile(&mut self, diff: Self::Diff);
+
+    /// This method removes and returns the data a sharded node no longer owns, given whether it
+    /// owns a `shard_key`. Types that are never sharded keep everything.
+    fn evict(&mut self, _owns: &Fn(u64) -> bool) -> Option<Self::Diff> {
+        None
+    }
 }
 
 pub type HandleMessageType<I, E, B> = (Result<I, E>, Option<B>);
 
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum PeeredError {
+    /// The actor a message was handed to stopped before answering
+    MailboxClosed,
+    /// PeerUnreachable(key), no node owning the key could be asked
+    PeerUnreachable(u64),
+}
+
+impl fmt::Display for PeeredError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PeeredError::MailboxClosed => write!(f, "Mailbox closed"),
+            PeeredError::PeerUnreachable(key) => {
+                write!(f, "No owner of key {:016x} could be reached", key)
+            }
+        }
+    }
+}
+
+/// Where a sharded node has a message answered, keys being `shard_key`s
+pub enum Route<M, I> {
+    /// By the node that received it
+    Local(M),
+    /// By an owner of the key
+    Owned(u64, M),
+    /// Each part by an owner of its key, with the answers combined by the function
+    Split(Vec<(u64, M)>, fn(Vec<I>) -> I),
+}
+
 pub trait HandleMessage<M> {
     type Broadcast: Clone + Send + 'static;
     type Item: Send;
@@ -33,14 +114,223 @@ pub trait HandleMessage<M> {
         &mut self,
         message: M,
     ) -> HandleMessageType<Self::Item, Self::Error, Self::Broadcast>;
+
+    /// Tells a sharded node where to have the message answered, by default by itself
+    fn route(message: M) -> Route<M, Self::Item> {
+        Route::Local(message)
+    }
 }
 
 pub trait HandleAnnounce<B> {
     type Item: Send;
-    type Error: Send;
+    type Error: fmt::Debug + Send;
 
     /// Handle an incomming broadcast message, returning a response
     fn handle_announce(&mut self, broadcast: B) -> Result<Self::Item, Self::Error>;
+
+    /// The `shard_key`s a broadcast stores data under, a sharded node only handling the ones it
+    /// owns one of. Broadcasts without keys, such as deletes, are handled everywhere.
+    fn keys(_broadcast: &B) -> Vec<u64> {
+        Vec::new()
+    }
+}
+
+/// Identifies a node within the cluster
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
+pub struct PeerId(u64);
+
+impl PeerId {
+    /// Generates a random id, so ids made by separate processes are very unlikely to collide
+    ///
+    /// The time and a per-process counter are mixed in, keeping ids apart should the random
+    /// source fail.
+    pub fn generate() -> Self {
+        static COUNTER: AtomicUsize = ATOMIC_USIZE_INIT;
+
+        let mut bytes = [0u8; 8];
+        if let Err(e) = rand_bytes(&mut bytes) {
+            warn!("Could not generate a random peer id: {}", e);
+        }
+        let random = bytes
+            .iter()
+            .fold(0u64, |random, byte| (random << 8) | u64::from(*byte));
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_else(|_| Duration::from_secs(0));
+        let nanos = now.as_secs()
+            .wrapping_mul(1_000_000_000)
+            .wrapping_add(u64::from(now.subsec_nanos()));
+        let count = COUNTER.fetch_add(1, Ordering::SeqCst) as u64;
+
+        PeerId(random ^ nanos ^ (count << 48))
+    }
+}
+
+impl fmt::Display for PeerId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+/// How a peer is reached, either directly within this process or through a `Link`
+pub enum PeerAddr<T>
+where
+    T: PeeredInner + 'static,
+{
+    Local(SyncAddress<Peered<T>>),
+    Remote(Arc<Link<T>>),
+}
+
+impl<T> PeerAddr<T>
+where
+    T: PeeredInner + 'static,
+{
+    fn announce_peer(&self, id: PeerId, addr: &PeerAddr<T>) -> bool {
+        match *self {
+            PeerAddr::Local(ref local) => send_local(local, AnnouncePeer(id, addr.clone())),
+            PeerAddr::Remote(ref link) => link.send(Outgoing::AnnouncePeer(id, addr)),
+        }
+    }
+
+    fn reply_peers(&self, peers: Vec<(PeerId, PeerAddr<T>)>, delivered: VersionVector) -> bool {
+        match *self {
+            PeerAddr::Local(ref local) => send_local(local, ReplyPeers(peers, delivered)),
+            PeerAddr::Remote(ref link) => link.send(Outgoing::ReplyPeers(&peers, &delivered)),
+        }
+    }
+
+    fn request_backfill(&self, reply_to: PeerAddr<T>, attempt: u64, request: T::Request) -> bool {
+        match *self {
+            PeerAddr::Local(ref local) => {
+                send_local(local, RequestBackfill(reply_to, attempt, request))
+            }
+            PeerAddr::Remote(ref link) => {
+                link.send(Outgoing::RequestBackfill(&reply_to, attempt, &request))
+            }
+        }
+    }
+
+    fn reply_backfill(&self, attempt: u64, backfill: T::Backfill) -> bool {
+        match *self {
+            PeerAddr::Local(ref local) => send_local(local, ReplyBackfill(attempt, backfill)),
+            PeerAddr::Remote(ref link) => link.send(Outgoing::ReplyBackfill(attempt, &backfill)),
+        }
+    }
+
+    fn announce<B>(&self, stamp: &Stamp, broadcast: &B) -> bool
+    where
+        Peered<T>: Handler<Announce<B>>,
+        B: Clone + Send + 'static,
+    {
+        match *self {
+            PeerAddr::Local(ref local) => {
+                send_local(local, Announce::new(stamp.clone(), broadcast.clone()))
+            }
+            PeerAddr::Remote(ref link) => link.send(Outgoing::Announce(stamp, broadcast)),
+        }
+    }
+
+    fn sync_digest(&self, id: PeerId, reply_to: PeerAddr<T>, digest: Digest) -> bool {
+        match *self {
+            PeerAddr::Local(ref local) => send_local(local, SyncDigest(id, reply_to, digest)),
+            PeerAddr::Remote(ref link) => link.send(Outgoing::SyncDigest(id, &reply_to, &digest)),
+        }
+    }
+
+    fn reconcile(
+        &self,
+        id: PeerId,
+        reply_to: Option<PeerAddr<T>>,
+        buckets: Vec<usize>,
+        diff: T::Diff,
+    ) -> bool {
+        match *self {
+            PeerAddr::Local(ref local) => {
+                send_local(local, Reconcile(id, reply_to, buckets, diff))
+            }
+            PeerAddr::Remote(ref link) => {
+                link.send(Outgoing::Reconcile(id, reply_to.as_ref(), &buckets, &diff))
+            }
+        }
+    }
+
+    fn leave(&self, id: PeerId) -> bool {
+        match *self {
+            PeerAddr::Local(ref local) => send_local(local, LeavePeer(id)),
+            PeerAddr::Remote(ref link) => link.send(Outgoing::LeavePeer(id)),
+        }
+    }
+}
+
+impl<T> Clone for PeerAddr<T>
+where
+    T: PeeredInner + 'static,
+{
+    fn clone(&self) -> Self {
+        match *self {
+            PeerAddr::Local(ref local) => PeerAddr::Local(local.clone()),
+            PeerAddr::Remote(ref link) => PeerAddr::Remote(Arc::clone(link)),
+        }
+    }
+}
+
+impl<T> From<SyncAddress<Peered<T>>> for PeerAddr<T>
+where
+    T: PeeredInner + 'static,
+{
+    fn from(addr: SyncAddress<Peered<T>>) -> Self {
+        PeerAddr::Local(addr)
+    }
+}
+
+/// Sends `msg`, returning whether the actor is still around to receive it
+fn send_local<T, M>(addr: &SyncAddress<Peered<T>>, msg: M) -> bool
+where
+    T: PeeredInner + 'static,
+    Peered<T>: Handler<M>,
+    M: ResponseType + Send + 'static,
+    M::Item: Send,
+    M::Error: Send,
+{
+    addr.send(msg);
+    addr.connected()
+}
+
+/// Where a node is in backfilling from its peers
+struct Backfill<R> {
+    /// The request to send next, `None` once there is nothing left to fetch
+    next: Option<R>,
+    /// Numbers requests, so replies to ones that were given up on can be told apart
+    attempt: u64,
+    chunks: usize,
+    retries: usize,
+}
+
+/// How many broadcasts from each node have been delivered
+pub type VersionVector = BTreeMap<PeerId, u64>;
+
+/// Where a broadcast comes from, and what its origin had delivered when making it
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Stamp {
+    pub origin: PeerId,
+    /// Counts the broadcast itself under `origin`
+    pub deps: VersionVector,
+}
+
+impl Stamp {
+    /// The broadcast's position among those made by its origin, starting at 1
+    pub fn seq(&self) -> u64 {
+        self.deps.get(&self.origin).cloned().unwrap_or(0)
+    }
+}
+
+struct Peer<T>
+where
+    T: PeeredInner + 'static,
+{
+    addr: PeerAddr<T>,
+    last_seen: Instant,
 }
 
 pub struct Peered<T>
@@ -48,7 +338,21 @@ where
     T: PeeredInner + 'static,
 {
     inner: T,
-    peers: Vec<SyncAddress<Peered<T>>>,
+    id: PeerId,
+    peers: BTreeMap<PeerId, Peer<T>>,
+    seeds: Vec<PeerAddr<T>>,
+    pending_seeds: BTreeMap<usize, SyncAddress<Peered<T>>>,
+    backfill_peer: Option<PeerAddr<T>>,
+    backfill: Backfill<T::Request>,
+    backfill_timeout: Duration,
+    causal: Causal<T>,
+    anti_entropy_interval: Duration,
+    anti_entropy_rounds: usize,
+    replication: Option<usize>,
+    ring: Option<Ring>,
+    heartbeat_interval: Duration,
+    peer_timeout: Duration,
+    subscribers: Vec<Box<Subscriber<MembershipEvent> + Send>>,
 }
 
 impl<T> Peered<T>
@@ -58,18 +362,341 @@ where
     pub fn new(inner: T) -> Self {
         Peered {
             inner: inner,
-            peers: Vec::new(),
+            id: PeerId::generate(),
+            peers: BTreeMap::new(),
+            seeds: Vec::new(),
+            pending_seeds: BTreeMap::new(),
+            backfill_peer: None,
+            backfill: Backfill {
+                next: None,
+                attempt: 0,
+                chunks: 0,
+                retries: 0,
+            },
+            backfill_timeout: Duration::from_millis(BACKFILL_TIMEOUT_MS),
+            causal: Causal::new(),
+            anti_entropy_interval: Duration::from_millis(ANTI_ENTROPY_INTERVAL_MS),
+            anti_entropy_rounds: 0,
+            replication: None,
+            ring: None,
+            heartbeat_interval: Duration::from_millis(HEARTBEAT_INTERVAL_MS),
+            peer_timeout: Duration::from_millis(PEER_TIMEOUT_MS),
+            subscribers: Vec::new(),
         }
     }
 
-    pub fn add_peer(mut self, peer: SyncAddress<Peered<T>>) -> Self {
-        self.peers.push(peer);
+    /// Joins the cluster through the given peer once started
+    pub fn add_peer<A>(mut self, peer: A) -> Self
+    where
+        A: Into<PeerAddr<T>>,
+    {
+        self.seeds.push(peer.into());
+        self
+    }
+
+    /// Pings every peer each `interval`, evicting the ones not heard from within `timeout`
+    pub fn heartbeat(mut self, interval: Duration, timeout: Duration) -> Self {
+        self.heartbeat_interval = interval;
+        self.peer_timeout = timeout;
+        self
+    }
+
+    /// Sends backfill requests left unanswered for `timeout` to another peer
+    pub fn backfill_timeout(mut self, timeout: Duration) -> Self {
+        self.backfill_timeout = timeout;
+        self
+    }
+
+    /// Compares data with one peer each `interval`, taking turns through the peers
+    pub fn anti_entropy(mut self, interval: Duration) -> Self {
+        self.anti_entropy_interval = interval;
+        self
+    }
+
+    /// Keeps only the keys this node owns, each key being owned by `replication` nodes
+    ///
+    /// Messages about keys owned elsewhere are answered by one of their owners, those in this
+    /// process being preferred. Messages `Networked::query_to_wire` can't send fail with
+    /// `PeeredError::PeerUnreachable` when only remote nodes own their key.
+    pub fn sharded(mut self, replication: usize) -> Self {
+        self.replication = Some(replication);
+        self.ring = Some(Ring::new(replication, vec![self.id]));
         self
     }
 
     fn peer_size(&self) -> usize {
         self.peers.len()
     }
+
+    fn peer_list(&self) -> Vec<(PeerId, PeerAddr<T>)> {
+        self.peers
+            .iter()
+            .map(|(id, peer)| (*id, peer.addr.clone()))
+            .collect()
+    }
+
+    fn join(&mut self, id: PeerId, addr: PeerAddr<T>) {
+        if id == self.id || self.peers.contains_key(&id) {
+            return;
+        }
+
+        // Introduce the newcomer and the existing peers to each other, so nodes that joined
+        // through different seeds still end up knowing about every member
+        for (peer_id, peer) in &self.peers {
+            peer.addr.announce_peer(id, &addr);
+            addr.announce_peer(*peer_id, &peer.addr);
+        }
+
+        self.peers.insert(
+            id,
+            Peer {
+                addr: addr,
+                last_seen: Instant::now(),
+            },
+        );
+        self.notify(MembershipEvent::Joined(id));
+        self.rebalance();
+    }
+
+    fn introduce(&mut self, peers: Vec<(PeerId, PeerAddr<T>)>, ctx: &mut Context<Self>) {
+        let own_addr = PeerAddr::Local(ctx.address());
+
+        for (id, peer) in peers {
+            if id == self.id || self.peers.contains_key(&id) {
+                continue;
+            }
+
+            peer.announce_peer(self.id, &own_addr);
+            self.join(id, peer);
+        }
+    }
+
+    fn remove(&mut self, event: MembershipEvent) {
+        let id = match event {
+            MembershipEvent::Left(id) | MembershipEvent::Evicted(id) => id,
+            MembershipEvent::Joined(_) => return,
+        };
+
+        if self.peers.remove(&id).is_some() {
+            self.notify(event);
+            self.rebalance();
+        }
+    }
+
+    /// Rebuilds the ring for the current peers, handing the data this node no longer owns to
+    /// the rest of the cluster
+    fn rebalance(&mut self) {
+        let replication = match self.replication {
+            Some(replication) => replication,
+            None => return,
+        };
+
+        let nodes: Vec<_> = self.peers.keys().cloned().chain(Some(self.id)).collect();
+        self.ring = Some(Ring::new(replication, nodes));
+
+        if let Some(evicted) = self.evict_unowned() {
+            // Whichever peers own the data keep it, the others drop it again
+            for peer in self.peers.values() {
+                peer.addr.reconcile(self.id, None, Vec::new(), evicted.clone());
+            }
+        }
+    }
+
+    fn evict_unowned(&mut self) -> Option<T::Diff> {
+        let id = self.id;
+
+        match self.ring {
+            Some(ref ring) => self.inner.evict(&|key| ring.owns(id, key)),
+            None => None,
+        }
+    }
+
+    /// Whether this node handles broadcasts stored under `keys`
+    fn owns_any(&self, keys: &[u64]) -> bool {
+        match self.ring {
+            Some(ref ring) => keys.is_empty() || keys.iter().any(|key| ring.owns(self.id, *key)),
+            None => true,
+        }
+    }
+
+    /// Whether this node keeps everything a broadcast stored under `keys` holds
+    fn owns_all(&self, keys: &[u64]) -> bool {
+        match self.ring {
+            Some(ref ring) => keys.iter().all(|key| ring.owns(self.id, *key)),
+            None => true,
+        }
+    }
+
+    /// Whether this node and `peer` both keep data stored under `keys`, the only data the two
+    /// compare in anti-entropy. Data without keys is kept everywhere.
+    fn shares(&self, peer: PeerId, keys: &[u64]) -> bool {
+        match self.ring {
+            Some(ref ring) => {
+                keys.is_empty()
+                    || (keys.iter().any(|key| ring.owns(self.id, *key))
+                        && keys.iter().any(|key| ring.owns(peer, *key)))
+            }
+            None => true,
+        }
+    }
+
+    /// The peer to have a message about `key` answered by, `None` if this node should answer
+    ///
+    /// Owners in this process are asked directly, the others through their link.
+    fn owner(&self, key: u64) -> Result<Option<PeerAddr<T>>, PeeredError> {
+        let owners = match self.ring {
+            Some(ref ring) => ring.owners(key),
+            None => return Ok(None),
+        };
+
+        if owners.contains(&self.id) {
+            return Ok(None);
+        }
+
+        let peers: Vec<_> = owners
+            .iter()
+            .filter_map(|owner| self.peers.get(owner))
+            .collect();
+
+        let local = peers.iter().find(|peer| match peer.addr {
+            PeerAddr::Local(ref local) => local.connected(),
+            PeerAddr::Remote(_) => false,
+        });
+        let remote = peers.iter().find(|peer| match peer.addr {
+            PeerAddr::Local(_) => false,
+            PeerAddr::Remote(_) => true,
+        });
+
+        local
+            .or(remote)
+            .map(|peer| Some(peer.addr.clone()))
+            .ok_or(PeeredError::PeerUnreachable(key))
+    }
+
+    fn seen(&mut self, id: PeerId) {
+        if let Some(peer) = self.peers.get_mut(&id) {
+            peer.last_seen = Instant::now();
+        }
+    }
+
+    fn notify(&mut self, event: MembershipEvent) {
+        info!("{}", event);
+
+        self.subscribers
+            .retain(|subscriber| subscriber.send(event).is_ok());
+    }
+
+    fn backfill_progress(&self) -> BackfillProgress {
+        match self.backfill.next {
+            Some(_) => BackfillProgress::InProgress {
+                chunks: self.backfill.chunks,
+                retries: self.backfill.retries,
+            },
+            None => BackfillProgress::Complete {
+                chunks: self.backfill.chunks,
+                retries: self.backfill.retries,
+            },
+        }
+    }
+
+    fn request_backfill(&mut self, peer: PeerAddr<T>, ctx: &mut Context<Self>) {
+        let request = match self.backfill.next {
+            Some(ref request) => request.clone(),
+            None => return,
+        };
+
+        self.backfill.attempt += 1;
+        let attempt = self.backfill.attempt;
+
+        peer.request_backfill(PeerAddr::Local(ctx.address()), attempt, request);
+        self.backfill_peer = Some(peer);
+
+        ctx.run_later(self.backfill_timeout, move |peered, ctx| {
+            peered.backfill_timed_out(attempt, ctx)
+        });
+    }
+
+    fn backfill_timed_out(&mut self, attempt: u64, ctx: &mut Context<Self>) {
+        if self.backfill.next.is_none() || attempt != self.backfill.attempt {
+            return;
+        }
+
+        // Peers that have joined are preferred, the seeds are all there is until one has
+        let mut candidates: Vec<_> = self.peers.values().map(|peer| peer.addr.clone()).collect();
+        if candidates.is_empty() {
+            candidates = self.seeds.clone();
+        }
+
+        if candidates.is_empty() {
+            return;
+        }
+
+        self.backfill.retries += 1;
+        let peer = candidates[self.backfill.retries % candidates.len()].clone();
+
+        warn!(
+            "Backfill request timed out after {} chunks, retrying",
+            self.backfill.chunks
+        );
+        self.request_backfill(peer, ctx);
+    }
+
+    fn heartbeat_round(&mut self, ctx: &mut Context<Self>) {
+        let now = Instant::now();
+        let timeout = self.peer_timeout;
+
+        let silent: Vec<PeerId> = self.peers
+            .iter()
+            .filter(|&(_, peer)| now.duration_since(peer.last_seen) > timeout)
+            .map(|(id, _)| *id)
+            .collect();
+
+        for id in silent {
+            self.remove(MembershipEvent::Evicted(id));
+        }
+
+        self.causal.flush(timeout, &mut self.inner);
+
+        for (id, addr) in self.peer_list() {
+            match addr {
+                PeerAddr::Local(addr) => {
+                    let ping = addr.call(self, Ping(self.id))
+                        .map(move |_, peered: &mut Self, _| peered.seen(id))
+                        .map_err(move |_, peered: &mut Self, _| {
+                            peered.remove(MembershipEvent::Evicted(id))
+                        });
+
+                    ctx.spawn(ping);
+                }
+                // Remote peers ping back on their own schedule, which is what keeps them seen
+                PeerAddr::Remote(link) => if !link.send(Outgoing::Ping(self.id)) {
+                    self.remove(MembershipEvent::Evicted(id));
+                },
+            }
+        }
+
+        ctx.run_later(self.heartbeat_interval, |peered, ctx| {
+            peered.heartbeat_round(ctx)
+        });
+    }
+
+    fn anti_entropy_round(&mut self, ctx: &mut Context<Self>) {
+        if !self.peers.is_empty() {
+            let index = self.anti_entropy_rounds % self.peers.len();
+            self.anti_entropy_rounds += 1;
+
+            if let Some((id, peer)) = self.peers.iter().nth(index) {
+                let digest = self.inner.digest(&|keys| self.shares(*id, keys));
+
+                peer.addr
+                    .sync_digest(self.id, PeerAddr::Local(ctx.address()), digest);
+            }
+        }
+
+        ctx.run_later(self.anti_entropy_interval, |peered, ctx| {
+            peered.anti_entropy_round(ctx)
+        });
+    }
 }
 
 impl<T> Actor for Peered<T>
@@ -79,10 +706,54 @@ where
     type Context = Context<Self>;
 
     fn started(&mut self, ctx: &mut Context<Self>) {
-        if !self.peers.is_empty() {
-            self.peers[0].send(RequestPeers(ctx.address()));
-            self.peers[0].send(RequestBackfill(ctx.address(), self.inner.backfill_init()));
+        let addr: SyncAddress<_> = ctx.address();
+        let own_addr = PeerAddr::Local(addr.clone());
+
+        // Local seeds keep receiving broadcasts until they tell us who they are, remote ones
+        // answer through a `ReplyPeers`
+        for (index, seed) in self.seeds.iter().enumerate() {
+            let seed = match *seed {
+                PeerAddr::Local(ref seed) => seed,
+                PeerAddr::Remote(ref link) => {
+                    link.send(Outgoing::RequestPeers(self.id, &own_addr));
+                    continue;
+                }
+            };
+
+            let reply = seed.call(self, RequestPeers(self.id, own_addr.clone()))
+                .map(move |res, peered: &mut Self, ctx| {
+                    peered.pending_seeds.remove(&index);
+
+                    if let Ok((peers, delivered)) = res {
+                        peered.causal.set_baseline(&delivered, &mut peered.inner);
+                        peered.introduce(peers, ctx);
+                    }
+                })
+                .map_err(move |_, peered: &mut Self, _| {
+                    warn!("Seed peer {} is unreachable", index);
+                    peered.pending_seeds.remove(&index);
+                });
+
+            ctx.spawn(reply);
+            self.pending_seeds.insert(index, seed.clone());
+        }
+
+        if let Some(seed) = self.seeds.first().cloned() {
+            self.backfill.next = Some(self.inner.backfill_init());
+            self.request_backfill(seed, ctx);
+        } else {
+            // Nothing was broadcast before the first node of a cluster started
+            self.causal
+                .set_baseline(&VersionVector::new(), &mut self.inner);
         }
+
+        ctx.run_later(self.heartbeat_interval, |peered, ctx| {
+            peered.heartbeat_round(ctx)
+        });
+
+        ctx.run_later(self.anti_entropy_interval, |peered, ctx| {
+            peered.anti_entropy_round(ctx)
+        });
     }
 }
 
@@ -93,7 +764,7 @@ where
     type Result = ();
 
     fn handle(&mut self, msg: AnnouncePeer<T>, _: &mut Context<Self>) -> Self::Result {
-        self.peers.push(msg.0);
+        self.join(msg.0, msg.1);
     }
 }
 
@@ -101,12 +772,15 @@ impl<T> Handler<RequestPeers<T>> for Peered<T>
 where
     T: PeeredInner + 'static,
 {
-    type Result = ();
+    type Result = Result<(Vec<(PeerId, PeerAddr<T>)>, VersionVector), PeeredError>;
+
+    fn handle(&mut self, msg: RequestPeers<T>, ctx: &mut Context<Self>) -> Self::Result {
+        let mut peers = vec![(self.id, PeerAddr::Local(ctx.address()))];
+        peers.extend(self.peer_list());
 
-    fn handle(&mut self, msg: RequestPeers<T>, _: &mut Context<Self>) -> Self::Result {
-        msg.0.send(ReplyPeers(self.peers.clone()));
+        self.join(msg.0, msg.1);
 
-        self.peers.push(msg.0);
+        Ok((peers, self.causal.delivered()))
     }
 }
 
@@ -117,11 +791,60 @@ where
     type Result = ();
 
     fn handle(&mut self, msg: ReplyPeers<T>, ctx: &mut Context<Self>) -> Self::Result {
-        for peer in &msg.0 {
-            peer.send(AnnouncePeer(ctx.address()));
+        self.causal.set_baseline(&msg.1, &mut self.inner);
+        self.introduce(msg.0, ctx);
+    }
+}
+
+impl<T> Handler<LeavePeer> for Peered<T>
+where
+    T: PeeredInner + 'static,
+{
+    type Result = ();
+
+    fn handle(&mut self, msg: LeavePeer, _: &mut Context<Self>) -> Self::Result {
+        self.remove(MembershipEvent::Left(msg.0));
+    }
+}
+
+impl<T> Handler<Leave> for Peered<T>
+where
+    T: PeeredInner + 'static,
+{
+    type Result = ();
+
+    fn handle(&mut self, _: Leave, ctx: &mut Context<Self>) -> Self::Result {
+        for peer in self.peers.values() {
+            peer.addr.leave(self.id);
         }
 
-        self.peers.extend(msg.0);
+        self.peers.clear();
+        self.backfill_peer = None;
+        ctx.stop();
+    }
+}
+
+impl<T> Handler<Ping> for Peered<T>
+where
+    T: PeeredInner + 'static,
+{
+    type Result = Result<(), PeeredError>;
+
+    fn handle(&mut self, msg: Ping, _: &mut Context<Self>) -> Self::Result {
+        self.seen(msg.0);
+
+        Ok(())
+    }
+}
+
+impl<T> Handler<SubscribeMembership> for Peered<T>
+where
+    T: PeeredInner + 'static,
+{
+    type Result = ();
+
+    fn handle(&mut self, msg: SubscribeMembership, _: &mut Context<Self>) -> Self::Result {
+        self.subscribers.push(msg.0);
     }
 }
 
@@ -132,9 +855,9 @@ where
     type Result = ();
 
     fn handle(&mut self, msg: RequestBackfill<T>, _: &mut Context<Self>) -> Self::Result {
-        let backfill = self.inner.backfill(msg.1);
+        let backfill = self.inner.backfill(msg.2);
 
-        msg.0.send(ReplyBackfill(backfill));
+        msg.0.reply_backfill(msg.1, backfill);
     }
 }
 
@@ -145,54 +868,290 @@ where
     type Result = ();
 
     fn handle(&mut self, msg: ReplyBackfill<T>, ctx: &mut Context<Self>) -> Self::Result {
-        if let Some(request) = self.inner.handle_backfill(msg.0) {
-            self.peers[0].send(RequestBackfill(ctx.address(), request));
+        if self.backfill.next.is_none() || msg.0 != self.backfill.attempt {
+            debug!("Ignoring reply to backfill request {}", msg.0);
+            return;
+        }
+
+        self.backfill.chunks += 1;
+        self.backfill.next = self.inner.handle_backfill(msg.1);
+        self.evict_unowned();
+
+        if self.backfill.next.is_none() {
+            info!("Backfill complete after {} chunks", self.backfill.chunks);
+            return;
+        }
+
+        if let Some(peer) = self.backfill_peer.clone() {
+            self.request_backfill(peer, ctx);
         }
     }
 }
 
+impl<T> Handler<SyncDigest<T>> for Peered<T>
+where
+    T: PeeredInner + 'static,
+{
+    type Result = ();
+
+    fn handle(&mut self, msg: SyncDigest<T>, ctx: &mut Context<Self>) -> Self::Result {
+        let SyncDigest(peer, reply_to, digest) = msg;
+
+        let buckets = self.inner
+            .digest(&|keys| self.shares(peer, keys))
+            .differing(&digest);
+
+        if buckets.is_empty() {
+            return;
+        }
+
+        debug!("Reconciling {} buckets with peer {}", buckets.len(), peer);
+        let diff = self.inner.diff(&buckets, &|keys| self.shares(peer, keys));
+
+        reply_to.reconcile(self.id, Some(PeerAddr::Local(ctx.address())), buckets, diff);
+    }
+}
+
+impl<T> Handler<Reconcile<T>> for Peered<T>
+where
+    T: PeeredInner + 'static,
+{
+    type Result = ();
+
+    fn handle(&mut self, msg: Reconcile<T>, _: &mut Context<Self>) -> Self::Result {
+        let Reconcile(peer, reply_to, buckets, diff) = msg;
+
+        // Ours is collected before merging the peer's, so the peer is sent what this node had
+        // in those buckets rather than its own data echoed back
+        if let Some(reply_to) = reply_to {
+            let own = self.inner.diff(&buckets, &|keys| self.shares(peer, keys));
+
+            reply_to.reconcile(self.id, None, buckets, own);
+        }
+
+        self.inner.reconcile(diff);
+        self.evict_unowned();
+    }
+}
+
+impl<T> Handler<BackfillStatus> for Peered<T>
+where
+    T: PeeredInner + 'static,
+{
+    type Result = Result<BackfillProgress, PeeredError>;
+
+    fn handle(&mut self, _: BackfillStatus, _: &mut Context<Self>) -> Self::Result {
+        Ok(self.backfill_progress())
+    }
+}
+
 impl<T> Handler<PeerSize> for Peered<T>
 where
     T: PeeredInner + 'static,
 {
-    type Result = Result<usize, ()>;
+    type Result = Result<usize, PeeredError>;
 
     fn handle(&mut self, _: PeerSize, _: &mut Context<Self>) -> Self::Result {
         Ok(self.peer_size())
     }
 }
 
+impl<T> Peered<T>
+where
+    T: PeeredInner + 'static,
+{
+    fn handle_locally<M>(
+        &mut self,
+        message: M,
+    ) -> Result<<T as HandleMessage<M>>::Item, <T as HandleMessage<M>>::Error>
+    where
+        T: HandleMessage<M> + HandleAnnounce<<T as HandleMessage<M>>::Broadcast>,
+    {
+        let (response, broadcast) = self.inner.handle_message(message);
+
+        if let Some(broadcast) = broadcast {
+            let stamp = self.causal.stamp(self.id);
+            let mut closed = Vec::new();
+
+            for (id, peer) in &self.peers {
+                if !peer.addr.announce(&stamp, &broadcast) {
+                    closed.push(*id);
+                }
+            }
+
+            for seed in self.pending_seeds.values() {
+                seed.send(Announce::new(stamp.clone(), broadcast.clone()));
+            }
+
+            for id in closed {
+                self.remove(MembershipEvent::Evicted(id));
+            }
+
+            // Data made here for keys owned elsewhere is only kept by its owners
+            let keys = <T as HandleAnnounce<_>>::keys(&broadcast);
+
+            if !self.owns_all(&keys) {
+                self.evict_unowned();
+            }
+        }
+
+        response
+    }
+
+    /// Has an owner of `key` answer the message, failing if none can
+    fn ask_owner<M>(
+        &mut self,
+        key: u64,
+        message: M,
+    ) -> Box<
+        ActorFuture<
+            Item = <T as HandleMessage<M>>::Item,
+            Error = <T as HandleMessage<M>>::Error,
+            Actor = Self,
+        >,
+    >
+    where
+        T: HandleMessage<M> + HandleAnnounce<<T as HandleMessage<M>>::Broadcast>,
+        <T as HandleMessage<M>>::Item: 'static,
+        <T as HandleMessage<M>>::Error: From<PeeredError> + 'static,
+        M: Send + 'static,
+    {
+        let owner = match self.owner(key) {
+            Ok(Some(owner)) => owner,
+            Ok(None) => return Box::new(result(self.handle_locally(message))),
+            Err(e) => return Box::new(result(Err(e.into()))),
+        };
+
+        let link = match owner {
+            PeerAddr::Local(owner) => {
+                let fut = owner
+                    .call(self, Forward::new(message))
+                    .then(|res, _, _| match res {
+                        Ok(res) => result(res),
+                        Err(_) => result(Err(PeeredError::MailboxClosed.into())),
+                    });
+
+                return Box::new(fut);
+            }
+            PeerAddr::Remote(link) => link,
+        };
+
+        let fut = match link.forward(&message) {
+            Some(fut) => fut,
+            None => return Box::new(result(Err(PeeredError::PeerUnreachable(key).into()))),
+        };
+
+        let fut = wrap_future::<_, Self>(fut).then(move |res, _, _| {
+            let answer: Option<
+                Result<<T as HandleMessage<M>>::Item, <T as HandleMessage<M>>::Error>,
+            > = res.ok().and_then(unbox_answer);
+
+            match answer {
+                Some(answer) => result(answer),
+                None => result(Err(PeeredError::PeerUnreachable(key).into())),
+            }
+        });
+
+        Box::new(fut)
+    }
+}
+
+/// The `Result` a forwarded message was answered with, as boxed by `Networked::from_answer`
+fn unbox_answer<I, E>(answer: Box<Any + Send>) -> Option<Result<I, E>>
+where
+    I: 'static,
+    E: 'static,
+{
+    answer.downcast().ok().map(|answer| *answer)
+}
+
 impl<T, M> Handler<Message<T, M>> for Peered<T>
 where
     T: HandleMessage<M>
         + HandleAnnounce<<T as HandleMessage<M>>::Broadcast>
         + PeeredInner
         + 'static,
+    <T as HandleMessage<M>>::Item: 'static,
+    <T as HandleMessage<M>>::Error: From<PeeredError> + 'static,
+    M: Send + 'static,
 {
-    type Result = Result<<T as HandleMessage<M>>::Item, <T as HandleMessage<M>>::Error>;
+    type Result = ResponseFuture<Self, Message<T, M>>;
 
     fn handle(&mut self, msg: Message<T, M>, _: &mut Context<Self>) -> Self::Result {
-        let (response, broadcast) = self.inner.handle_message(msg.0);
+        let route = match self.ring {
+            Some(_) => <T as HandleMessage<M>>::route(msg.0),
+            None => Route::Local(msg.0),
+        };
 
-        if let Some(broadcast) = broadcast {
-            for peer in &self.peers {
-                peer.send(Announce::new(broadcast.clone()));
+        match route {
+            Route::Local(message) => Box::new(result(self.handle_locally(message))),
+            Route::Owned(key, message) => self.ask_owner(key, message),
+            Route::Split(parts, merge) => {
+                let mut answers: Box<
+                    ActorFuture<
+                        Item = Vec<<T as HandleMessage<M>>::Item>,
+                        Error = <T as HandleMessage<M>>::Error,
+                        Actor = Self,
+                    >,
+                > = Box::new(result(Ok(Vec::new())));
+
+                for (key, message) in parts {
+                    answers = Box::new(answers.and_then(move |mut items, peered, _| {
+                        peered.ask_owner(key, message).map(move |item, _, _| {
+                            items.push(item);
+                            items
+                        })
+                    }));
+                }
+
+                Box::new(answers.map(move |items, _, _| merge(items)))
             }
         }
+    }
+}
 
-        response
+impl<T, M> Handler<Forward<T, M>> for Peered<T>
+where
+    T: HandleMessage<M>
+        + HandleAnnounce<<T as HandleMessage<M>>::Broadcast>
+        + PeeredInner
+        + 'static,
+{
+    type Result = Result<<T as HandleMessage<M>>::Item, <T as HandleMessage<M>>::Error>;
+
+    fn handle(&mut self, msg: Forward<T, M>, _: &mut Context<Self>) -> Self::Result {
+        self.handle_locally(msg.0)
     }
 }
 
 impl<T, B> Handler<Announce<B>> for Peered<T>
 where
     T: HandleAnnounce<B> + PeeredInner + 'static,
-    B: Clone + Send,
+    B: Clone + Send + 'static,
 {
-    type Result = Result<(), ()>;
+    type Result = Result<(), PeeredError>;
 
     fn handle(&mut self, msg: Announce<B>, _: &mut Context<Self>) -> Self::Result {
-        let _ = self.inner.handle_announce(msg.0);
+        let Announce(stamp, broadcast) = msg;
+        let keys = <T as HandleAnnounce<B>>::keys(&broadcast);
+        let owned = self.owns_any(&keys);
+
+        self.causal.receive(stamp, &mut self.inner, move |inner: &mut T| {
+            if !owned {
+                return;
+            }
+
+            if let Err(e) = inner.handle_announce(broadcast) {
+                debug!("Ignoring announce: {:?}", e);
+            }
+        });
+
+        // Broadcasts carrying several entries, such as restored users, may hold some that are
+        // owned elsewhere
+        if owned && !self.owns_all(&keys) {
+            self.evict_unowned();
+        }
+
         Ok(())
     }
 }
@@ -0,0 +1,74 @@
+use std::collections::BTreeMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+use super::PeerId;
+
+/// How many points each node takes on the ring, so its ranges are spread around it
+const POINTS_PER_NODE: u64 = 64;
+
+/// Hashes a key to its place on the ring
+///
+/// Like digests, this is only consistent between nodes running the same build.
+pub fn shard_key<K>(key: &K) -> u64
+where
+    K: Hash,
+{
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Assigns keys to nodes by consistent hashing
+///
+/// A key is owned by the first `replication` distinct nodes found going clockwise from it, so a
+/// node joining or leaving only moves the keys next to its points.
+pub struct Ring {
+    replication: usize,
+    points: BTreeMap<u64, PeerId>,
+}
+
+impl Ring {
+    pub fn new<I>(replication: usize, nodes: I) -> Self
+    where
+        I: IntoIterator<Item = PeerId>,
+    {
+        let points = nodes
+            .into_iter()
+            .flat_map(|node| {
+                (0..POINTS_PER_NODE).map(move |point| (shard_key(&(node, point)), node))
+            })
+            .collect();
+
+        Ring {
+            replication: replication,
+            points: points,
+        }
+    }
+
+    /// The nodes owning `key`, closest first
+    pub fn owners(&self, key: u64) -> Vec<PeerId> {
+        let mut owners = Vec::new();
+
+        let clockwise = self.points
+            .range(key..)
+            .chain(self.points.range(..key))
+            .map(|(_, node)| *node);
+
+        for node in clockwise {
+            if owners.len() == self.replication {
+                break;
+            }
+
+            if !owners.contains(&node) {
+                owners.push(node);
+            }
+        }
+
+        owners
+    }
+
+    pub fn owns(&self, node: PeerId, key: u64) -> bool {
+        self.owners(key).contains(&node)
+    }
+}
@@ -0,0 +1,767 @@
+use std::any::Any;
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::marker::PhantomData;
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use actix::{Actor, Handler, SyncAddress};
+use base64;
+use futures::Future;
+use futures::future;
+use futures::sync::oneshot;
+use openssl::error::ErrorStack;
+use openssl::hash::MessageDigest;
+use openssl::memcmp;
+use openssl::pkey::PKey;
+use openssl::rand::rand_bytes;
+use openssl::sign::Signer;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json::{self, Value};
+
+use super::{Digest, HandleMessage, PeerAddr, PeerId, Peered, PeeredInner, Stamp, VersionVector};
+use super::messages::*;
+
+/// How long connecting to a peer may take before it is considered unreachable
+const CONNECT_TIMEOUT_MS: u64 = 1_000;
+/// How long writing to a peer may block before it is considered unreachable
+const WRITE_TIMEOUT_MS: u64 = 1_000;
+/// How long a peer may take to answer a forwarded message before it is considered unreachable
+const FORWARD_TIMEOUT_MS: u64 = 5_000;
+/// How long a peer may take to complete the handshake before its connection is dropped
+const HANDSHAKE_TIMEOUT_MS: u64 = 5_000;
+/// The longest line of the handshake, which only carries a nonce or its proof
+const MAX_HANDSHAKE_BYTES: usize = 1_024;
+/// The longest frame accepted from a peer, a longer one closing its connection
+const MAX_FRAME_BYTES: usize = 16 * 1024 * 1024;
+/// How many frames may wait on a peer's writer before new ones are dropped
+const QUEUE_SIZE: usize = 1_024;
+
+/// Carries messages to a peer outside of this process
+pub trait Link<T>: Send + Sync
+where
+    T: PeeredInner + 'static,
+{
+    /// The address the peer is reached at
+    fn address(&self) -> &str;
+
+    /// Sends `message` to the peer without waiting on it, false if it is known to be unreachable
+    fn send(&self, message: Outgoing<T>) -> bool;
+
+    /// Has the peer answer `message`, resolving to the `Result` it answered with, boxed
+    ///
+    /// `None` if the message can't be sent to other processes.
+    fn forward(&self, message: &Any) -> Option<Box<Future<Item = Box<Any + Send>, Error = ()>>>;
+}
+
+/// The messages a node sends to its peers
+pub enum Outgoing<'a, T>
+where
+    T: PeeredInner + 'static,
+{
+    AnnouncePeer(PeerId, &'a PeerAddr<T>),
+    RequestPeers(PeerId, &'a PeerAddr<T>),
+    ReplyPeers(&'a [(PeerId, PeerAddr<T>)], &'a VersionVector),
+    RequestBackfill(&'a PeerAddr<T>, u64, &'a T::Request),
+    ReplyBackfill(u64, &'a T::Backfill),
+    Announce(&'a Stamp, &'a Any),
+    SyncDigest(PeerId, &'a PeerAddr<T>, &'a Digest),
+    Reconcile(PeerId, Option<&'a PeerAddr<T>>, &'a [usize], &'a T::Diff),
+    LeavePeer(PeerId),
+    Ping(PeerId),
+}
+
+/// Peered types that can be served to peers in other processes
+///
+/// Their `Backfill`, `Request` and `Diff` types must also be serializable.
+pub trait Networked: PeeredInner + Sized + 'static {
+    /// Tells this type's frames apart from those of other types sharing a `Transport`
+    const SERVICE: &'static str;
+
+    /// Any of the type's broadcasts, in the form they're sent in
+    type Broadcast: Serialize + DeserializeOwned;
+
+    /// Any of the messages sharded nodes have answered by an owner in another process, in the
+    /// form they're sent in
+    type Query: Serialize + DeserializeOwned;
+
+    /// Any of the answers to those messages
+    type Answer: Serialize + DeserializeOwned;
+
+    /// Converts a broadcast for sending, `None` if it isn't one of this type's broadcasts
+    fn to_wire(broadcast: &Any) -> Option<Self::Broadcast>;
+
+    /// Hands a broadcast received from a peer to the local node
+    fn announce(local: &SyncAddress<Peered<Self>>, stamp: Stamp, broadcast: Self::Broadcast);
+
+    /// Converts a message for forwarding, `None` if only nodes in this process can answer it
+    fn query_to_wire(message: &Any) -> Option<Self::Query>;
+
+    /// Has the local node answer a query from a peer, `None` if it couldn't
+    fn answer(local: &SyncAddress<Peered<Self>>, query: Self::Query) -> Option<Self::Answer>;
+
+    /// The `Result` an answer stands for, as the sender of the forwarded message expects it
+    fn from_answer(answer: Self::Answer) -> Box<Any + Send>;
+}
+
+/// Has the local node answer `message` itself, waiting on it, for use in `Networked::answer`
+pub fn answer_locally<T, M>(
+    local: &SyncAddress<Peered<T>>,
+    message: M,
+) -> Option<Result<<T as HandleMessage<M>>::Item, <T as HandleMessage<M>>::Error>>
+where
+    T: HandleMessage<M> + PeeredInner + 'static,
+    Peered<T>: Handler<Forward<T, M>>,
+    M: Send + 'static,
+    <T as HandleMessage<M>>::Item: Send,
+    <T as HandleMessage<M>>::Error: Send,
+{
+    local.call_fut(Forward::new(message)).wait().ok()
+}
+
+/// A message as it travels between processes, with nodes named by their transport's address
+#[derive(Deserialize, Serialize)]
+enum Frame<R, B, D, W, Q, A> {
+    AnnouncePeer(PeerId, String),
+    RequestPeers(PeerId, String),
+    ReplyPeers(Vec<(PeerId, String)>, VersionVector),
+    RequestBackfill(String, u64, R),
+    ReplyBackfill(u64, B),
+    Announce(Stamp, W),
+    SyncDigest(PeerId, String, Digest),
+    Reconcile(PeerId, Option<String>, Vec<usize>, D),
+    LeavePeer(PeerId),
+    Ping(PeerId),
+    /// Forward(query_id, reply_to, query), answered with an `Answer` of the same id
+    Forward(u64, String, Q),
+    /// Answer(query_id, answer), `None` if the peer couldn't answer
+    Answer(u64, Option<A>),
+}
+
+#[derive(Deserialize, Serialize)]
+struct Envelope {
+    service: String,
+    frame: Value,
+}
+
+type Receive = Box<Fn(&Transport, Value) -> Result<(), serde_json::Error> + Send + Sync>;
+
+struct Service {
+    id: PeerId,
+    receive: Receive,
+}
+
+/// A forwarded message waiting on its answer
+struct Query {
+    answered: oneshot::Sender<Box<Any + Send>>,
+    sent: Instant,
+}
+
+struct Inner {
+    address: String,
+    services: Mutex<HashMap<&'static str, Arc<Service>>>,
+    secret: String,
+    /// The writer of each peer frames are sent to
+    connections: Mutex<HashMap<String, SyncSender<String>>>,
+    queries: Mutex<HashMap<u64, Query>>,
+    next_query: AtomicUsize,
+}
+
+/// Carries peering messages between processes over TCP
+///
+/// Every type started through a transport shares its listener. Messages are sent as
+/// newline-delimited JSON, tagged with the `Networked::SERVICE` they're for. Each peer is written
+/// to by a thread of its own, so sending never waits on the network.
+///
+/// Connections are only accepted from peers that prove they know the cluster's shared secret, by
+/// signing a nonce the listener sends them.
+///
+/// Messages forwarded to a key's owner are answered by a frame sent back to the asking
+/// transport's own listener, and fail if that doesn't arrive in time.
+#[derive(Clone)]
+pub struct Transport {
+    inner: Arc<Inner>,
+}
+
+impl Transport {
+    /// Listens for peers sharing `secret` on `addr`, a port of 0 picking any free one
+    pub fn bind(addr: &str, secret: &str) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+
+        let transport = Transport {
+            inner: Arc::new(Inner {
+                address: listener.local_addr()?.to_string(),
+                secret: secret.to_owned(),
+                services: Mutex::new(HashMap::new()),
+                connections: Mutex::new(HashMap::new()),
+                queries: Mutex::new(HashMap::new()),
+                next_query: AtomicUsize::new(0),
+            }),
+        };
+
+        let accepting = transport.clone();
+        let expiring = transport.clone();
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        let transport = accepting.clone();
+                        thread::spawn(move || transport.read(stream));
+                    }
+                    Err(e) => warn!("Could not accept peer connection: {}", e),
+                }
+            }
+        });
+
+        // Queries left unanswered are dropped, failing the messages they were forwarded for
+        thread::spawn(move || loop {
+            thread::sleep(Duration::from_millis(FORWARD_TIMEOUT_MS / 2));
+
+            let timeout = Duration::from_millis(FORWARD_TIMEOUT_MS);
+            expiring
+                .inner
+                .queries
+                .lock()
+                .unwrap()
+                .retain(|_, query| query.sent.elapsed() < timeout);
+        });
+
+        Ok(transport)
+    }
+
+    /// The address peers in other processes reach this transport at
+    pub fn address(&self) -> &str {
+        &self.inner.address
+    }
+
+    /// Starts `peered`, serving it through this transport and joining the peers at `seeds`
+    pub fn start<T>(&self, peered: Peered<T>, seeds: &[String]) -> SyncAddress<Peered<T>>
+    where
+        T: Networked,
+        T::Request: Serialize + DeserializeOwned,
+        T::Backfill: Serialize + DeserializeOwned,
+        T::Diff: Serialize + DeserializeOwned,
+    {
+        let peered = seeds
+            .iter()
+            .fold(peered, |peered, seed| peered.add_peer(self.link::<T>(seed)));
+        let id = peered.id;
+
+        let addr: SyncAddress<_> = peered.start();
+        // SyncAddress isn't Sync, so received frames take turns using it
+        let local = Mutex::new(addr.clone());
+
+        let receive: Receive = Box::new(move |transport, frame| {
+            let frame = serde_json::from_value(frame)?;
+            let local = local.lock().unwrap().clone();
+
+            transport.receive::<T>(&local, frame);
+            Ok(())
+        });
+
+        self.inner
+            .services
+            .lock()
+            .unwrap()
+            .insert(T::SERVICE, Arc::new(Service {
+                id: id,
+                receive: receive,
+            }));
+
+        addr
+    }
+
+    fn link<T>(&self, address: &str) -> PeerAddr<T>
+    where
+        T: Networked,
+        T::Request: Serialize + DeserializeOwned,
+        T::Backfill: Serialize + DeserializeOwned,
+        T::Diff: Serialize + DeserializeOwned,
+    {
+        PeerAddr::Remote(Arc::new(TcpLink {
+            transport: self.clone(),
+            address: address.to_owned(),
+            service: PhantomData,
+        }))
+    }
+
+    fn read(&self, stream: TcpStream) {
+        let peer = stream
+            .peer_addr()
+            .map(|addr| addr.to_string())
+            .unwrap_or_default();
+
+        let mut reader = match stream.try_clone() {
+            Ok(stream) => BufReader::new(stream),
+            Err(e) => {
+                debug!("Could not read from {}: {}", peer, e);
+                return;
+            }
+        };
+
+        if let Err(e) = challenge(&stream, &mut reader, &self.inner.secret) {
+            warn!("Rejecting peer connection from {}: {}", peer, e);
+            return;
+        }
+
+        loop {
+            let line = match read_line(&mut reader, MAX_FRAME_BYTES) {
+                Ok(Some(line)) => line,
+                Ok(None) => return,
+                Err(ref e) if e.kind() == io::ErrorKind::InvalidData => {
+                    warn!("Closing connection from {}: {}", peer, e);
+                    return;
+                }
+                Err(e) => {
+                    debug!("Lost connection from {}: {}", peer, e);
+                    return;
+                }
+            };
+
+            let envelope: Envelope = match serde_json::from_str(&line) {
+                Ok(envelope) => envelope,
+                Err(e) => {
+                    warn!("Ignoring malformed frame from {}: {}", peer, e);
+                    continue;
+                }
+            };
+
+            let service = self.inner
+                .services
+                .lock()
+                .unwrap()
+                .get(envelope.service.as_str())
+                .cloned();
+
+            match service {
+                Some(service) => if let Err(e) = (service.receive)(self, envelope.frame) {
+                    warn!("Ignoring {} frame from {}: {}", envelope.service, peer, e);
+                },
+                None => warn!("Ignoring frame for unknown service {}", envelope.service),
+            }
+        }
+    }
+
+    fn receive<T>(
+        &self,
+        local: &SyncAddress<Peered<T>>,
+        frame: Frame<T::Request, T::Backfill, T::Diff, T::Broadcast, T::Query, T::Answer>,
+    ) where
+        T: Networked,
+        T::Request: Serialize + DeserializeOwned,
+        T::Backfill: Serialize + DeserializeOwned,
+        T::Diff: Serialize + DeserializeOwned,
+    {
+        match frame {
+            Frame::AnnouncePeer(id, address) => {
+                local.send(AnnouncePeer(id, self.link(&address)));
+            }
+            Frame::RequestPeers(id, address) => {
+                let requester = self.link(&address);
+
+                match local.call_fut(RequestPeers(id, requester.clone())).wait() {
+                    Ok(Ok((peers, delivered))) => {
+                        requester.reply_peers(peers, delivered);
+                    }
+                    _ => warn!("Could not answer request for peers from {}", address),
+                }
+            }
+            Frame::ReplyPeers(peers, delivered) => {
+                let peers = peers
+                    .into_iter()
+                    .map(|(id, address)| (id, self.link(&address)))
+                    .collect();
+
+                local.send(ReplyPeers(peers, delivered));
+            }
+            Frame::RequestBackfill(address, attempt, request) => {
+                local.send(RequestBackfill(self.link(&address), attempt, request));
+            }
+            Frame::ReplyBackfill(attempt, backfill) => {
+                local.send(ReplyBackfill(attempt, backfill));
+            }
+            Frame::Announce(stamp, broadcast) => {
+                T::announce(local, stamp, broadcast);
+            }
+            Frame::SyncDigest(id, address, digest) => {
+                local.send(SyncDigest(id, self.link(&address), digest));
+            }
+            Frame::Reconcile(id, address, buckets, diff) => {
+                let reply_to = address.map(|address| self.link(&address));
+
+                local.send(Reconcile(id, reply_to, buckets, diff));
+            }
+            Frame::LeavePeer(id) => {
+                local.send(LeavePeer(id));
+            }
+            Frame::Ping(id) => {
+                local.send(Ping(id));
+            }
+            Frame::Forward(query_id, address, query) => {
+                let answer = T::answer(local, query);
+                let frame: Frame<(), (), (), (), (), T::Answer> = Frame::Answer(query_id, answer);
+
+                match serde_json::to_value(frame) {
+                    Ok(frame) => {
+                        self.send(&address, T::SERVICE, frame);
+                    }
+                    Err(e) => warn!("Could not serialize {} answer: {}", T::SERVICE, e),
+                }
+            }
+            Frame::Answer(query_id, answer) => {
+                let query = self.inner.queries.lock().unwrap().remove(&query_id);
+
+                // Dropping the query fails the message, as when it times out
+                if let (Some(query), Some(answer)) = (query, answer) {
+                    let _ = query.answered.send(T::from_answer(answer));
+                }
+            }
+        }
+    }
+
+    /// Sends `query` to the peer at `address`, resolving to its answer
+    fn query<Q>(
+        &self,
+        address: &str,
+        service: &str,
+        query: Q,
+    ) -> Box<Future<Item = Box<Any + Send>, Error = ()>>
+    where
+        Q: Serialize,
+    {
+        let query_id = self.inner.next_query.fetch_add(1, Ordering::Relaxed) as u64;
+        let frame: Frame<(), (), (), (), Q, ()> =
+            Frame::Forward(query_id, self.inner.address.clone(), query);
+
+        let frame = match serde_json::to_value(frame) {
+            Ok(frame) => frame,
+            Err(e) => {
+                warn!("Could not serialize {} query: {}", service, e);
+                return Box::new(future::err(()));
+            }
+        };
+
+        let (answered, answer) = oneshot::channel();
+        self.inner.queries.lock().unwrap().insert(
+            query_id,
+            Query {
+                answered: answered,
+                sent: Instant::now(),
+            },
+        );
+
+        if !self.send(address, service, frame) {
+            self.inner.queries.lock().unwrap().remove(&query_id);
+            return Box::new(future::err(()));
+        }
+
+        Box::new(answer.map_err(|_| ()))
+    }
+
+    /// Names `addr` for a peer in another process, `None` if it can't be reached from there
+    fn address_of<T>(
+        &self,
+        service: &str,
+        id: Option<PeerId>,
+        addr: &PeerAddr<T>,
+    ) -> Option<String>
+    where
+        T: PeeredInner + 'static,
+    {
+        match *addr {
+            PeerAddr::Remote(ref link) => Some(link.address().to_owned()),
+            // Only the node served through this transport can be reached through it
+            PeerAddr::Local(_) => {
+                let served = self.inner
+                    .services
+                    .lock()
+                    .unwrap()
+                    .get(service)
+                    .map(|service| service.id);
+
+                match id {
+                    Some(id) if served != Some(id) => None,
+                    _ => Some(self.inner.address.clone()),
+                }
+            }
+        }
+    }
+
+    fn send(&self, address: &str, service: &str, frame: Value) -> bool {
+        let envelope = Envelope {
+            service: service.to_owned(),
+            frame: frame,
+        };
+
+        let line = match serde_json::to_string(&envelope) {
+            Ok(line) => line,
+            Err(e) => {
+                warn!("Could not serialize {} frame: {}", service, e);
+                return false;
+            }
+        };
+
+        let mut connections = self.inner.connections.lock().unwrap();
+
+        let sent = {
+            let secret = &self.inner.secret;
+            let writer = connections
+                .entry(address.to_owned())
+                .or_insert_with(|| start_writer(address, secret));
+
+            writer.try_send(line)
+        };
+
+        match sent {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                warn!("Dropping {} frame for {}, which is falling behind", service, address);
+                false
+            }
+            // The writer gave up on the peer, a later frame starting a new one
+            Err(TrySendError::Disconnected(_)) => {
+                connections.remove(address);
+                false
+            }
+        }
+    }
+}
+
+/// Starts a thread writing the frames queued for the peer at `address`, which stops once the peer
+/// can't be connected to
+fn start_writer(address: &str, secret: &str) -> SyncSender<String> {
+    let (writer, frames) = mpsc::sync_channel(QUEUE_SIZE);
+    let address = address.to_owned();
+    let secret = secret.to_owned();
+
+    thread::spawn(move || write_frames(&address, &secret, frames));
+
+    writer
+}
+
+fn write_frames(address: &str, secret: &str, frames: Receiver<String>) {
+    let mut connection: Option<TcpStream> = None;
+
+    for line in frames {
+        // The connection may have been closed by the peer since, so retry on a fresh one
+        for _ in 0..2 {
+            if connection.is_none() {
+                match connect(address, secret) {
+                    Ok(stream) => connection = Some(stream),
+                    Err(e) => {
+                        debug!("Could not connect to {}: {}", address, e);
+                        return;
+                    }
+                }
+            }
+
+            let sent = connection
+                .as_mut()
+                .map(|stream| writeln!(stream, "{}", line).and_then(|_| stream.flush()));
+
+            match sent {
+                Some(Ok(())) => break,
+                Some(Err(e)) => debug!("Could not write to {}: {}", address, e),
+                None => (),
+            }
+
+            connection = None;
+        }
+    }
+}
+
+/// Connects to the peer at `address`, answering its challenge
+fn connect(address: &str, secret: &str) -> io::Result<TcpStream> {
+    let addr = address
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no address to connect to"))?;
+
+    let mut stream =
+        TcpStream::connect_timeout(&addr, Duration::from_millis(CONNECT_TIMEOUT_MS))?;
+    stream.set_write_timeout(Some(Duration::from_millis(WRITE_TIMEOUT_MS)))?;
+    stream.set_read_timeout(Some(Duration::from_millis(HANDSHAKE_TIMEOUT_MS)))?;
+    stream.set_nodelay(true)?;
+
+    let nonce = read_line(&mut BufReader::new(stream.try_clone()?), MAX_HANDSHAKE_BYTES)?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "closed during handshake"))?;
+    writeln!(stream, "{}", prove(secret, &nonce).map_err(handshake_error)?)?;
+    stream.flush()?;
+
+    Ok(stream)
+}
+
+/// Has the peer that connected on `stream` prove it knows `secret`
+fn challenge(
+    mut stream: &TcpStream,
+    reader: &mut BufReader<TcpStream>,
+    secret: &str,
+) -> io::Result<()> {
+    let mut nonce = [0; 32];
+    rand_bytes(&mut nonce).map_err(handshake_error)?;
+    let nonce = base64::encode(&nonce);
+
+    stream.set_read_timeout(Some(Duration::from_millis(HANDSHAKE_TIMEOUT_MS)))?;
+    writeln!(stream, "{}", nonce)?;
+    stream.flush()?;
+
+    let proof = read_line(reader, MAX_HANDSHAKE_BYTES)?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "closed during handshake"))?;
+    stream.set_read_timeout(None)?;
+
+    let expected = prove(secret, &nonce).map_err(handshake_error)?;
+
+    if proof.len() == expected.len() && memcmp::eq(proof.as_bytes(), expected.as_bytes()) {
+        Ok(())
+    } else {
+        Err(io::Error::new(io::ErrorKind::PermissionDenied, "wrong secret"))
+    }
+}
+
+/// Signs `nonce` with `secret`, which only peers knowing the secret can do
+fn prove(secret: &str, nonce: &str) -> Result<String, ErrorStack> {
+    let key = PKey::hmac(secret.as_bytes())?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+    signer.update(nonce.as_bytes())?;
+
+    Ok(base64::encode(&signer.sign_to_vec()?))
+}
+
+fn handshake_error(e: ErrorStack) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e)
+}
+
+/// Reads a line of at most `limit` bytes, `None` once the peer closed the connection
+fn read_line<R: BufRead>(reader: &mut R, limit: usize) -> io::Result<Option<String>> {
+    let mut line = Vec::new();
+    reader.take(limit as u64 + 1).read_until(b'\n', &mut line)?;
+
+    if line.is_empty() {
+        return Ok(None);
+    }
+
+    if line.last() != Some(&b'\n') {
+        return Err(if line.len() > limit {
+            io::Error::new(io::ErrorKind::InvalidData, "line too long")
+        } else {
+            io::Error::new(io::ErrorKind::UnexpectedEof, "closed mid-line")
+        });
+    }
+
+    line.pop();
+
+    String::from_utf8(line)
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+struct TcpLink<T> {
+    transport: Transport,
+    address: String,
+    service: PhantomData<fn() -> T>,
+}
+
+impl<T> TcpLink<T>
+where
+    T: Networked,
+    T::Request: Serialize + DeserializeOwned,
+    T::Backfill: Serialize + DeserializeOwned,
+    T::Diff: Serialize + DeserializeOwned,
+{
+    fn frame<'a>(
+        &self,
+        message: Outgoing<'a, T>,
+    ) -> Option<Frame<&'a T::Request, &'a T::Backfill, &'a T::Diff, T::Broadcast, (), ()>> {
+        let transport = &self.transport;
+
+        let frame = match message {
+            Outgoing::AnnouncePeer(id, addr) => {
+                Frame::AnnouncePeer(id, transport.address_of(T::SERVICE, Some(id), addr)?)
+            }
+            Outgoing::RequestPeers(id, addr) => {
+                Frame::RequestPeers(id, transport.address_of(T::SERVICE, Some(id), addr)?)
+            }
+            Outgoing::ReplyPeers(peers, delivered) => Frame::ReplyPeers(
+                peers
+                    .iter()
+                    .filter_map(|&(id, ref addr)| {
+                        transport
+                            .address_of(T::SERVICE, Some(id), addr)
+                            .map(|address| (id, address))
+                    })
+                    .collect(),
+                delivered.clone(),
+            ),
+            Outgoing::RequestBackfill(addr, attempt, request) => {
+                let address = transport.address_of(T::SERVICE, None, addr)?;
+
+                Frame::RequestBackfill(address, attempt, request)
+            }
+            Outgoing::ReplyBackfill(attempt, backfill) => Frame::ReplyBackfill(attempt, backfill),
+            Outgoing::Announce(stamp, broadcast) => match T::to_wire(broadcast) {
+                Some(broadcast) => Frame::Announce(stamp.clone(), broadcast),
+                None => {
+                    warn!("Not announcing unknown {} broadcast", T::SERVICE);
+                    return None;
+                }
+            },
+            Outgoing::SyncDigest(id, addr, digest) => {
+                let address = transport.address_of(T::SERVICE, None, addr)?;
+
+                Frame::SyncDigest(id, address, digest.clone())
+            }
+            Outgoing::Reconcile(id, addr, buckets, diff) => {
+                let address = match addr {
+                    Some(addr) => Some(transport.address_of(T::SERVICE, None, addr)?),
+                    None => None,
+                };
+
+                Frame::Reconcile(id, address, buckets.to_vec(), diff)
+            }
+            Outgoing::LeavePeer(id) => Frame::LeavePeer(id),
+            Outgoing::Ping(id) => Frame::Ping(id),
+        };
+
+        Some(frame)
+    }
+}
+
+impl<T> Link<T> for TcpLink<T>
+where
+    T: Networked,
+    T::Request: Serialize + DeserializeOwned,
+    T::Backfill: Serialize + DeserializeOwned,
+    T::Diff: Serialize + DeserializeOwned,
+{
+    fn address(&self) -> &str {
+        &self.address
+    }
+
+    fn send(&self, message: Outgoing<T>) -> bool {
+        // Messages that can't be represented remotely are dropped without blaming the peer
+        let frame = match self.frame(message) {
+            Some(frame) => frame,
+            None => return true,
+        };
+
+        match serde_json::to_value(frame) {
+            Ok(frame) => self.transport.send(&self.address, T::SERVICE, frame),
+            Err(e) => {
+                warn!("Could not serialize {} frame: {}", T::SERVICE, e);
+                true
+            }
+        }
+    }
+
+    fn forward(&self, message: &Any) -> Option<Box<Future<Item = Box<Any + Send>, Error = ()>>> {
+        let query = T::query_to_wire(message)?;
+
+        Some(self.transport.query(&self.address, T::SERVICE, query))
+    }
+}
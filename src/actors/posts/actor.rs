@@ -1,15 +1,26 @@
-use actors::peered::{HandleAnnounce, HandleMessage, HandleMessageType};
+use std::any::Any;
+use std::collections::BTreeSet;
+
+use actix::SyncAddress;
+
+use actors::peered::{shard_key, HandleAnnounce, HandleMessage, HandleMessageType, Peered, Route,
+                     Stamp};
+use actors::peered::messages::Announce;
+use actors::peered::transport::{answer_locally, Networked};
 use super::messages::*;
-use super::post::Post;
-use super::{PostId, Posts};
+use super::post::{Post, PostStats};
+use super::{PostId, Posts, PostsError};
 
 impl HandleMessage<NewPost> for Posts {
     type Broadcast = NewPostFull;
     type Item = PostId;
-    type Error = ();
+    type Error = PostsError;
 
-    fn handle_message(&mut self, msg: NewPost) -> HandleMessageType<PostId, (), NewPostFull> {
-        let (post_id, post) = self.new_post(msg.0, msg.1);
+    fn handle_message(
+        &mut self,
+        msg: NewPost,
+    ) -> HandleMessageType<PostId, PostsError, NewPostFull> {
+        let (post_id, post) = self.new_post(msg);
 
         (Ok(post_id), Some(NewPostFull(post_id, post)))
     }
@@ -18,51 +29,269 @@ impl HandleMessage<NewPost> for Posts {
 impl HandleMessage<DeletePost> for Posts {
     type Broadcast = DeletePost;
     type Item = ();
-    type Error = ();
+    type Error = PostsError;
+
+    fn handle_message(
+        &mut self,
+        msg: DeletePost,
+    ) -> HandleMessageType<(), PostsError, DeletePost> {
+        match self.delete_post(msg.0) {
+            Ok(()) => (Ok(()), Some(msg)),
+            Err(e) => (Err(e), None),
+        }
+    }
+}
 
-    fn handle_message(&mut self, msg: DeletePost) -> HandleMessageType<(), (), DeletePost> {
-        self.delete_post(msg.0);
+impl HandleMessage<AddReaction> for Posts {
+    type Broadcast = AddReaction;
+    type Item = Post;
+    type Error = PostsError;
 
-        (Ok(()), Some(msg))
+    fn handle_message(
+        &mut self,
+        msg: AddReaction,
+    ) -> HandleMessageType<Post, PostsError, AddReaction> {
+        match self.react(msg.0, msg.1, msg.2, true) {
+            Ok(post) => (Ok(post), Some(msg)),
+            Err(e) => (Err(e), None),
+        }
+    }
+
+    fn route(msg: AddReaction) -> Route<AddReaction, Post> {
+        Route::Owned(shard_key(&msg.0), msg)
+    }
+}
+
+impl HandleMessage<RemoveReaction> for Posts {
+    type Broadcast = RemoveReaction;
+    type Item = Post;
+    type Error = PostsError;
+
+    fn handle_message(
+        &mut self,
+        msg: RemoveReaction,
+    ) -> HandleMessageType<Post, PostsError, RemoveReaction> {
+        match self.react(msg.0, msg.1, msg.2, false) {
+            Ok(post) => (Ok(post), Some(msg)),
+            Err(e) => (Err(e), None),
+        }
+    }
+
+    fn route(msg: RemoveReaction) -> Route<RemoveReaction, Post> {
+        Route::Owned(shard_key(&msg.0), msg)
     }
 }
 
 impl HandleMessage<GetPostsByIds> for Posts {
     type Broadcast = ();
     type Item = (Vec<Post>, Vec<PostId>);
-    type Error = ();
+    type Error = PostsError;
+
+    fn handle_message(
+        &mut self,
+        msg: GetPostsByIds,
+    ) -> HandleMessageType<Self::Item, Self::Error, ()> {
+        (Ok(self.get_posts(msg.0, msg.1)), None)
+    }
+
+    fn route(msg: GetPostsByIds) -> Route<GetPostsByIds, Self::Item> {
+        let GetPostsByIds(post_ids, viewer) = msg;
+
+        let parts = post_ids
+            .into_iter()
+            .map(|post_id| (shard_key(&post_id), GetPostsByIds(vec![post_id], viewer)))
+            .collect();
+
+        Route::Split(parts, merge_posts)
+    }
+}
+
+fn merge_posts(answers: Vec<(Vec<Post>, Vec<PostId>)>) -> (Vec<Post>, Vec<PostId>) {
+    answers.into_iter().fold(
+        (Vec::new(), Vec::new()),
+        |(mut posts, mut missing), (found, not_found)| {
+            posts.extend(found);
+            missing.extend(not_found);
+
+            (posts, missing)
+        },
+    )
+}
+
+impl HandleMessage<GetPublicPostIds> for Posts {
+    type Broadcast = ();
+    type Item = BTreeSet<PostId>;
+    type Error = PostsError;
+
+    fn handle_message(
+        &mut self,
+        msg: GetPublicPostIds,
+    ) -> HandleMessageType<Self::Item, Self::Error, ()> {
+        (Ok(self.public_post_ids(msg.0)), None)
+    }
+}
+
+impl HandleMessage<GetThread> for Posts {
+    type Broadcast = ();
+    type Item = (Vec<Post>, Vec<Post>);
+    type Error = PostsError;
+
+    fn handle_message(
+        &mut self,
+        msg: GetThread,
+    ) -> HandleMessageType<Self::Item, Self::Error, ()> {
+        (self.thread(msg.0, msg.1), None)
+    }
+}
+
+impl HandleMessage<GetPostStats> for Posts {
+    type Broadcast = ();
+    type Item = PostStats;
+    type Error = PostsError;
+
+    fn handle_message(
+        &mut self,
+        msg: GetPostStats,
+    ) -> HandleMessageType<PostStats, PostsError, ()> {
+        (self.stats(msg.0), None)
+    }
 
-    fn handle_message(&mut self, msg: GetPostsByIds) -> HandleMessageType<Self::Item, (), ()> {
-        (Ok(self.get_posts(msg.0)), None)
+    fn route(msg: GetPostStats) -> Route<GetPostStats, PostStats> {
+        Route::Owned(shard_key(&msg.0), msg)
     }
 }
 
 impl HandleMessage<PostSize> for Posts {
     type Broadcast = ();
     type Item = usize;
-    type Error = ();
+    type Error = PostsError;
 
-    fn handle_message(&mut self, _: PostSize) -> HandleMessageType<usize, (), ()> {
+    fn handle_message(&mut self, _: PostSize) -> HandleMessageType<usize, PostsError, ()> {
         (Ok(self.posts.len()), None)
     }
 }
 
 impl HandleAnnounce<NewPostFull> for Posts {
     type Item = ();
-    type Error = ();
+    type Error = PostsError;
 
-    fn handle_announce(&mut self, msg: NewPostFull) -> Result<(), ()> {
-        self.posts.insert(msg.0, msg.1);
+    fn handle_announce(&mut self, msg: NewPostFull) -> Result<(), PostsError> {
+        self.add_post(msg.0, msg.1);
         Ok(())
     }
+
+    fn keys(msg: &NewPostFull) -> Vec<u64> {
+        vec![shard_key(&msg.0)]
+    }
 }
 
 impl HandleAnnounce<DeletePost> for Posts {
     type Item = ();
-    type Error = ();
+    type Error = PostsError;
+
+    fn handle_announce(&mut self, msg: DeletePost) -> Result<(), PostsError> {
+        self.delete_post(msg.0)
+    }
+}
+
+impl HandleAnnounce<AddReaction> for Posts {
+    type Item = ();
+    type Error = PostsError;
+
+    fn handle_announce(&mut self, msg: AddReaction) -> Result<(), PostsError> {
+        self.set_reaction(msg.0, msg.2, msg.1, true);
+        Ok(())
+    }
+
+    fn keys(msg: &AddReaction) -> Vec<u64> {
+        vec![shard_key(&msg.0)]
+    }
+}
+
+impl HandleAnnounce<RemoveReaction> for Posts {
+    type Item = ();
+    type Error = PostsError;
 
-    fn handle_announce(&mut self, msg: DeletePost) -> Result<(), ()> {
-        self.delete_post(msg.0);
+    fn handle_announce(&mut self, msg: RemoveReaction) -> Result<(), PostsError> {
+        self.set_reaction(msg.0, msg.2, msg.1, false);
         Ok(())
     }
+
+    fn keys(msg: &RemoveReaction) -> Vec<u64> {
+        vec![shard_key(&msg.0)]
+    }
+}
+
+impl Networked for Posts {
+    const SERVICE: &'static str = "posts";
+
+    type Broadcast = PostsBroadcast;
+    type Query = PostsQuery;
+    type Answer = PostsAnswer;
+
+    fn to_wire(broadcast: &Any) -> Option<PostsBroadcast> {
+        if let Some(new_post) = broadcast.downcast_ref::<NewPostFull>() {
+            return Some(PostsBroadcast::NewPost(new_post.clone()));
+        }
+
+        if let Some(delete) = broadcast.downcast_ref::<DeletePost>() {
+            return Some(PostsBroadcast::DeletePost(*delete));
+        }
+
+        if let Some(reaction) = broadcast.downcast_ref::<AddReaction>() {
+            return Some(PostsBroadcast::AddReaction(*reaction));
+        }
+
+        broadcast
+            .downcast_ref::<RemoveReaction>()
+            .map(|reaction| PostsBroadcast::RemoveReaction(*reaction))
+    }
+
+    fn announce(local: &SyncAddress<Peered<Self>>, stamp: Stamp, broadcast: PostsBroadcast) {
+        match broadcast {
+            PostsBroadcast::NewPost(new_post) => local.send(Announce::new(stamp, new_post)),
+            PostsBroadcast::DeletePost(delete) => local.send(Announce::new(stamp, delete)),
+            PostsBroadcast::AddReaction(reaction) => local.send(Announce::new(stamp, reaction)),
+            PostsBroadcast::RemoveReaction(reaction) => {
+                local.send(Announce::new(stamp, reaction))
+            }
+        }
+    }
+
+    fn query_to_wire(message: &Any) -> Option<PostsQuery> {
+        if let Some(reaction) = message.downcast_ref::<AddReaction>() {
+            return Some(PostsQuery::AddReaction(*reaction));
+        }
+
+        if let Some(reaction) = message.downcast_ref::<RemoveReaction>() {
+            return Some(PostsQuery::RemoveReaction(*reaction));
+        }
+
+        if let Some(get_posts) = message.downcast_ref::<GetPostsByIds>() {
+            return Some(PostsQuery::GetPostsByIds(get_posts.clone()));
+        }
+
+        message
+            .downcast_ref::<GetPostStats>()
+            .map(|get_stats| PostsQuery::GetPostStats(*get_stats))
+    }
+
+    fn answer(local: &SyncAddress<Peered<Self>>, query: PostsQuery) -> Option<PostsAnswer> {
+        match query {
+            PostsQuery::AddReaction(msg) => answer_locally(local, msg).map(PostsAnswer::Post),
+            PostsQuery::RemoveReaction(msg) => answer_locally(local, msg).map(PostsAnswer::Post),
+            PostsQuery::GetPostsByIds(msg) => answer_locally(local, msg).map(PostsAnswer::Posts),
+            PostsQuery::GetPostStats(msg) => {
+                answer_locally(local, msg).map(PostsAnswer::PostStats)
+            }
+        }
+    }
+
+    fn from_answer(answer: PostsAnswer) -> Box<Any + Send> {
+        match answer {
+            PostsAnswer::Post(res) => Box::new(res),
+            PostsAnswer::Posts(res) => Box::new(res),
+            PostsAnswer::PostStats(res) => Box::new(res),
+        }
+    }
 }
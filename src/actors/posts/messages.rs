@@ -2,24 +2,89 @@ use std::collections::BTreeSet;
 
 use actix::ResponseType;
 
-use super::{Post, PostId, UserId};
+use actors::user::UserError;
+use super::{Content, Post, PostId, PostStats, PostsError, Reaction, UserId, Visibility};
 
 #[derive(Clone, Debug)]
-pub struct NewPost(pub UserId, pub BTreeSet<UserId>);
+pub struct NewPost {
+    pub author: UserId,
+    pub mentions: BTreeSet<UserId>,
+    pub content: Content,
+    pub visibility: Visibility,
+    /// Who may read the post besides its author, see `Post::audience`
+    pub audience: BTreeSet<UserId>,
+    pub in_reply_to: Option<PostId>,
+}
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
 pub struct DeletePost(pub PostId);
 
 impl ResponseType for DeletePost {
     type Item = ();
-    type Error = ();
+    type Error = UserError;
 }
 
-#[derive(Clone, Debug)]
-pub struct GetPostsByIds(pub Vec<PostId>);
+/// AddReaction(post_id, user_id, reaction), answered with the post reacted to
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct AddReaction(pub PostId, pub UserId, pub Reaction);
 
-#[derive(Clone, Debug)]
+/// RemoveReaction(post_id, user_id, reaction), answered with the post reacted to
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct RemoveReaction(pub PostId, pub UserId, pub Reaction);
+
+/// The posts the viewer may read, any others being reported missing along with those not found
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct GetPostsByIds(pub Vec<PostId>, pub Option<UserId>);
+
+/// The newest public and unlisted posts, or all of them given 0
+///
+/// Only covers the posts this node keeps, so on sharded nodes it's this node's share of them.
+#[derive(Clone, Copy, Debug)]
+pub struct GetPublicPostIds(pub usize);
+
+/// The posts a post replies to, oldest first, and the replies to it and to those replies, in
+/// the order they'd be read
+///
+/// Posts the viewer can't read are left out, and the ancestors stop at the first of them. Like
+/// `GetPublicPostIds`, only covers the posts this node keeps.
+#[derive(Clone, Copy, Debug)]
+pub struct GetThread(pub PostId, pub Option<UserId>);
+
+/// The like, boost and reply counts of a post
+///
+/// Likes and boosts are those made by this instance's users, and replies are only counted from
+/// the posts the node owning this one keeps.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+pub struct GetPostStats(pub PostId);
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct NewPostFull(pub PostId, pub Post);
 
 #[derive(Clone, Copy, Debug)]
 pub struct PostSize;
+
+/// The broadcasts `Posts` sends to peers in other processes
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum PostsBroadcast {
+    NewPost(NewPostFull),
+    DeletePost(DeletePost),
+    AddReaction(AddReaction),
+    RemoveReaction(RemoveReaction),
+}
+
+/// The messages `Posts` has answered by the owners of a post in other processes
+#[derive(Deserialize, Serialize)]
+pub enum PostsQuery {
+    AddReaction(AddReaction),
+    RemoveReaction(RemoveReaction),
+    GetPostsByIds(GetPostsByIds),
+    GetPostStats(GetPostStats),
+}
+
+/// The answers to `PostsQuery`s
+#[derive(Deserialize, Serialize)]
+pub enum PostsAnswer {
+    Post(Result<Post, PostsError>),
+    Posts(Result<(Vec<Post>, Vec<PostId>), PostsError>),
+    PostStats(Result<PostStats, PostsError>),
+}
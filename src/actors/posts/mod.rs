@@ -1,45 +1,122 @@
 use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
 
-use super::{Id, PostId, PostsId, UserId};
-use super::peered::PeeredInner;
+use storage::Storage;
+use super::{millis_since_epoch, Clock, Id, PostId, PostsId, UserId, TOMBSTONE_TTL_MS};
+use super::peered::{shard_key, Digest, PeeredError, PeeredInner};
+use self::messages::NewPost;
 
 mod actor;
 pub mod messages;
 mod post;
 
-pub use self::post::Post;
+pub use self::post::{Attachment, Content, Post, PostStats, Reaction, Visibility};
 
 const BACKFILL_CHUNK_SIZE: usize = 100;
+const COUNTER: &'static str = "posts";
+
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum PostsError {
+    PostNotFound(PostId),
+    /// Only public and unlisted posts can be boosted
+    NotShareable(PostId),
+    /// Only a post's author can delete it
+    NotAuthor(PostId),
+    /// The post's owners couldn't answer
+    Peered(PeeredError),
+}
+
+impl fmt::Display for PostsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PostsError::PostNotFound(post_id) => write!(f, "No post {:?}", post_id),
+            PostsError::NotShareable(post_id) => write!(f, "Post {:?} can't be shared", post_id),
+            PostsError::NotAuthor(post_id) => {
+                write!(f, "Only the author of post {:?} can delete it", post_id)
+            }
+            PostsError::Peered(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<PeeredError> for PostsError {
+    fn from(e: PeeredError) -> Self {
+        PostsError::Peered(e)
+    }
+}
 
 pub struct Posts {
     posts_id: PostsId,
     current_id: u64,
+    clock: Clock,
     posts: BTreeMap<PostId, Post>,
+    /// The replies to each post that has any
+    replies: BTreeMap<PostId, BTreeSet<PostId>>,
+    /// The version of every reaction to each post, odd while it stands, so replicas keep
+    /// whichever change came last the way `Blocklists` keeps blocks
+    reactions: BTreeMap<PostId, BTreeMap<(Reaction, UserId), u64>>,
+    /// Deleted posts and when they were deleted, see `TOMBSTONE_TTL_MS`
+    deleted: BTreeMap<PostId, u64>,
+    storage: Storage,
 }
 
 impl Posts {
-    pub fn new(posts_id: PostsId) -> Self {
-        Posts {
-            posts_id: posts_id,
-            current_id: 0,
-            posts: BTreeMap::new(),
+    pub fn new(posts_id: PostsId, storage: Storage) -> Self {
+        let posts: BTreeMap<_, _> = storage
+            .posts()
+            .into_iter()
+            .map(|post| (post.post_id, post))
+            .collect();
+
+        let mut clock = Clock::new();
+        let mut replies = BTreeMap::new();
+        for post in posts.values() {
+            clock.observe(post.post_id.2);
+            index_reply(&mut replies, post);
+        }
+
+        let mut reactions = BTreeMap::new();
+        for (post_id, reaction, user_id, version) in storage.reactions() {
+            reactions
+                .entry(post_id)
+                .or_insert_with(BTreeMap::new)
+                .insert((reaction, user_id), version);
         }
+
+        let mut posts = Posts {
+            posts_id: posts_id,
+            current_id: storage.counter(COUNTER),
+            clock: clock,
+            posts: posts,
+            replies: replies,
+            reactions: reactions,
+            deleted: storage.deleted_posts().into_iter().collect(),
+            storage: storage,
+        };
+
+        posts.expire_tombstones(millis_since_epoch());
+        posts
     }
 
     fn generate_post_id(&mut self) -> PostId {
         let post_id = Id(self.current_id);
 
         self.current_id += 1;
+        self.storage.set_counter(COUNTER, self.current_id);
 
-        PostId::new(self.posts_id, post_id)
+        PostId::new(self.posts_id, post_id, self.clock.now())
     }
 
-    fn new_post(&mut self, author: UserId, mentions: BTreeSet<UserId>) -> (PostId, Post) {
+    fn new_post(&mut self, msg: NewPost) -> (PostId, Post) {
         let post_id = self.generate_post_id();
         let post = Post {
-            post_id,
-            author,
-            mentions,
+            post_id: post_id,
+            author: msg.author,
+            mentions: msg.mentions,
+            content: msg.content,
+            visibility: msg.visibility,
+            audience: msg.audience,
+            in_reply_to: msg.in_reply_to,
         };
 
         self.add_post(post_id, post.clone());
@@ -48,41 +125,297 @@ impl Posts {
     }
 
     fn add_post(&mut self, post_id: PostId, post: Post) {
+        if self.deleted.contains_key(&post_id) {
+            return;
+        }
+
+        self.clock.observe(post_id.2);
+        self.storage.save_post(&post);
+        index_reply(&mut self.replies, &post);
         self.posts.insert(post_id, post);
     }
 
-    fn delete_post(&mut self, post_id: PostId) {
-        self.posts.remove(&post_id);
+    fn delete_post(&mut self, post_id: PostId) -> Result<(), PostsError> {
+        self.remove_post(post_id)
+            .ok_or(PostsError::PostNotFound(post_id))?;
+        self.bury(post_id, millis_since_epoch());
+
+        Ok(())
+    }
+
+    /// Remembers that a post was deleted, keeping the earliest time peers report for it
+    fn bury(&mut self, post_id: PostId, deleted_at: u64) {
+        let now = millis_since_epoch();
+
+        if deleted_at + TOMBSTONE_TTL_MS > now {
+            let deleted_at = match self.deleted.get(&post_id) {
+                Some(&earlier) => earlier.min(deleted_at),
+                None => deleted_at,
+            };
+
+            self.storage.save_deleted_post(post_id, deleted_at);
+            self.deleted.insert(post_id, deleted_at);
+        }
+
+        self.expire_tombstones(now);
     }
 
-    fn get_posts(&mut self, post_ids: Vec<PostId>) -> (Vec<Post>, Vec<PostId>) {
+    /// Forgets the posts deleted longer than `TOMBSTONE_TTL_MS` ago
+    fn expire_tombstones(&mut self, now: u64) {
+        let expired: Vec<PostId> = self.deleted
+            .iter()
+            .filter(|&(_, &deleted_at)| deleted_at + TOMBSTONE_TTL_MS <= now)
+            .map(|(post_id, _)| *post_id)
+            .collect();
+
+        for post_id in expired {
+            self.deleted.remove(&post_id);
+            self.storage.forget_deleted_post(post_id);
+        }
+    }
+
+    /// Drops a post and the reactions to it from memory and storage, leaving the replies to it
+    /// in place
+    fn remove_post(&mut self, post_id: PostId) -> Option<Post> {
+        let post = self.posts.remove(&post_id)?;
+        self.storage.delete_post(post_id);
+
+        for (reaction, user_id) in self.reactions.remove(&post_id).unwrap_or_default().keys() {
+            self.storage.delete_reaction(post_id, *reaction, *user_id);
+        }
+
+        if let Some(parent) = post.in_reply_to {
+            let now_empty = match self.replies.get_mut(&parent) {
+                Some(replies) => {
+                    replies.remove(&post_id);
+                    replies.is_empty()
+                }
+                None => false,
+            };
+
+            if now_empty {
+                self.replies.remove(&parent);
+            }
+        }
+
+        Some(post)
+    }
+
+    /// Records or withdraws a user's reaction to a post they may read, returning the post
+    fn react(
+        &mut self,
+        post_id: PostId,
+        user_id: UserId,
+        reaction: Reaction,
+        stands: bool,
+    ) -> Result<Post, PostsError> {
+        let post = match self.posts.get(&post_id) {
+            Some(post) if post.visible_to(Some(user_id)) => post.clone(),
+            _ => return Err(PostsError::PostNotFound(post_id)),
+        };
+
+        if stands && reaction == Reaction::Boost && !post.visibility.is_public() {
+            return Err(PostsError::NotShareable(post_id));
+        }
+
+        self.set_reaction(post_id, reaction, user_id, stands);
+
+        Ok(post)
+    }
+
+    /// Bumps the version of a reaction if it changes
+    fn set_reaction(&mut self, post_id: PostId, reaction: Reaction, user_id: UserId, stands: bool) {
+        let version = self.reaction_version(post_id, reaction, user_id);
+
+        if (version % 2 == 1) != stands {
+            self.merge_reaction(post_id, reaction, user_id, version + 1);
+        }
+    }
+
+    fn reaction_version(&self, post_id: PostId, reaction: Reaction, user_id: UserId) -> u64 {
+        self.reactions
+            .get(&post_id)
+            .and_then(|reactions| reactions.get(&(reaction, user_id)))
+            .cloned()
+            .unwrap_or(0)
+    }
+
+    /// Takes on a version of a reaction if it's newer than ours, ignoring posts this node doesn't
+    /// keep
+    fn merge_reaction(
+        &mut self,
+        post_id: PostId,
+        reaction: Reaction,
+        user_id: UserId,
+        version: u64,
+    ) {
+        if !self.posts.contains_key(&post_id)
+            || version <= self.reaction_version(post_id, reaction, user_id)
+        {
+            return;
+        }
+
+        self.reactions
+            .entry(post_id)
+            .or_insert_with(BTreeMap::new)
+            .insert((reaction, user_id), version);
+
+        self.storage.save_reaction(post_id, reaction, user_id, version);
+    }
+
+    /// Every reaction to a post with its version, as handed to peers
+    fn reaction_versions(&self, post_id: PostId) -> Vec<(PostId, Reaction, UserId, u64)> {
+        self.reactions
+            .get(&post_id)
+            .into_iter()
+            .flat_map(|reactions| reactions.iter())
+            .map(|(&(reaction, user_id), &version)| (post_id, reaction, user_id, version))
+            .collect()
+    }
+
+    fn get_posts(
+        &mut self,
+        post_ids: Vec<PostId>,
+        viewer: Option<UserId>,
+    ) -> (Vec<Post>, Vec<PostId>) {
         post_ids.into_iter().fold(
             (Vec::new(), Vec::new()),
             |(mut posts, mut missing), post_id| {
-                match self.posts.get(&post_id).cloned() {
-                    Some(post) => posts.push(post),
-                    None => missing.push(post_id),
+                // Posts the viewer can't read are reported missing, so they don't learn of them
+                match self.posts.get(&post_id) {
+                    Some(post) if post.visible_to(viewer) => posts.push(post.clone()),
+                    _ => missing.push(post_id),
                 }
 
                 (posts, missing)
             },
         )
     }
+
+    fn thread(
+        &self,
+        post_id: PostId,
+        viewer: Option<UserId>,
+    ) -> Result<(Vec<Post>, Vec<Post>), PostsError> {
+        let visible = |post_id: PostId| match self.posts.get(&post_id) {
+            Some(post) if post.visible_to(viewer) => Some(post),
+            _ => None,
+        };
+
+        let post = visible(post_id).ok_or(PostsError::PostNotFound(post_id))?;
+
+        // A post can only reply to one that already exists, so there are no cycles to guard
+        // against here
+        let mut ancestors = Vec::new();
+        let mut parent = post.in_reply_to;
+        while let Some(post) = parent.and_then(&visible) {
+            parent = post.in_reply_to;
+            ancestors.push(post.clone());
+        }
+        ancestors.reverse();
+
+        // Depth first, so each reply comes right after the post it replies to
+        let mut descendants = Vec::new();
+        let mut pending = self.replies_to(post_id);
+        pending.reverse();
+
+        while let Some(reply_id) = pending.pop() {
+            if let Some(reply) = visible(reply_id) {
+                descendants.push(reply.clone());
+            }
+
+            pending.extend(self.replies_to(reply_id).into_iter().rev());
+        }
+
+        Ok((ancestors, descendants))
+    }
+
+    /// Counts the standing likes and boosts of a post, and the replies to it this node keeps
+    fn stats(&self, post_id: PostId) -> Result<PostStats, PostsError> {
+        if !self.posts.contains_key(&post_id) {
+            return Err(PostsError::PostNotFound(post_id));
+        }
+
+        let mut stats = PostStats {
+            likes: 0,
+            boosts: 0,
+            replies: self.replies.get(&post_id).map_or(0, |replies| replies.len()),
+        };
+
+        let standing = self.reactions
+            .get(&post_id)
+            .into_iter()
+            .flat_map(|reactions| reactions.iter())
+            .filter(|&(_, version)| version % 2 == 1);
+
+        for (&(reaction, _), _) in standing {
+            match reaction {
+                Reaction::Boost => stats.boosts += 1,
+                Reaction::Like => stats.likes += 1,
+            }
+        }
+
+        Ok(stats)
+    }
+
+    /// The replies to a post, oldest first
+    fn replies_to(&self, post_id: PostId) -> Vec<PostId> {
+        self.replies
+            .get(&post_id)
+            .map(|replies| replies.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    fn public_post_ids(&self, amount: usize) -> BTreeSet<PostId> {
+        let public = self.posts
+            .values()
+            .rev()
+            .filter(|post| post.visibility.is_public())
+            .map(|post| post.post_id);
+
+        if amount == 0 {
+            public.collect()
+        } else {
+            public.take(amount).collect()
+        }
+    }
+}
+
+fn index_reply(replies: &mut BTreeMap<PostId, BTreeSet<PostId>>, post: &Post) {
+    if let Some(parent) = post.in_reply_to {
+        replies
+            .entry(parent)
+            .or_insert_with(BTreeSet::new)
+            .insert(post.post_id);
+    }
 }
 
 impl PeeredInner for Posts {
-    type Backfill = (usize, Vec<(PostId, Post)>);
+    type Backfill = (
+        usize,
+        Vec<(PostId, Post)>,
+        Vec<(PostId, Reaction, UserId, u64)>,
+    );
     type Request = usize;
+    type Diff = (
+        Vec<(PostId, Post)>,
+        Vec<(PostId, u64)>,
+        Vec<(PostId, Reaction, UserId, u64)>,
+    );
 
     fn backfill(&self, req: Self::Request) -> Self::Backfill {
-        let p = self.posts
+        let p: Vec<_> = self.posts
             .iter()
             .skip(req)
             .take(BACKFILL_CHUNK_SIZE)
             .map(|(a, b)| (*a, b.clone()))
             .collect();
 
-        (req, p)
+        let reactions = p.iter()
+            .flat_map(|&(post_id, _)| self.reaction_versions(post_id))
+            .collect();
+
+        (req, p, reactions)
     }
 
     fn backfill_init(&self) -> Self::Request {
@@ -96,8 +429,105 @@ impl PeeredInner for Posts {
             None
         };
 
-        self.posts.extend(backfill.1);
+        for (post_id, post) in backfill.1 {
+            self.add_post(post_id, post);
+        }
+
+        for (post_id, reaction, user_id, version) in backfill.2 {
+            self.merge_reaction(post_id, reaction, user_id, version);
+        }
 
         ret
     }
+
+    fn digest(&self, shared: &Fn(&[u64]) -> bool) -> Digest {
+        let shared = |post_id: &PostId| shared(&[shard_key(post_id)]);
+        let mut digest = Digest::new();
+
+        for (post_id, post) in self.posts.iter().filter(|&(post_id, _)| shared(post_id)) {
+            digest.insert(post_id, &Some(post));
+        }
+
+        for post_id in self.deleted.keys().filter(|post_id| shared(post_id)) {
+            digest.insert(post_id, &None::<&Post>);
+        }
+
+        // Reactions fall in the same bucket as their post, so they're exchanged along with it
+        for (post_id, reactions) in self.reactions.iter().filter(|&(post_id, _)| shared(post_id)) {
+            for (reaction, version) in reactions {
+                digest.insert(post_id, &(reaction, version));
+            }
+        }
+
+        digest
+    }
+
+    fn diff(&self, buckets: &[usize], shared: &Fn(&[u64]) -> bool) -> Self::Diff {
+        let wanted = |post_id: &PostId| {
+            buckets.contains(&Digest::bucket(post_id)) && shared(&[shard_key(post_id)])
+        };
+
+        let posts = self.posts
+            .iter()
+            .filter(|&(post_id, _)| wanted(post_id))
+            .map(|(a, b)| (*a, b.clone()))
+            .collect();
+
+        let deleted = self.deleted
+            .iter()
+            .filter(|&(post_id, _)| wanted(post_id))
+            .map(|(a, b)| (*a, *b))
+            .collect();
+
+        let reactions = self.reactions
+            .keys()
+            .filter(|post_id| wanted(post_id))
+            .flat_map(|post_id| self.reaction_versions(*post_id))
+            .collect();
+
+        (posts, deleted, reactions)
+    }
+
+    fn reconcile(&mut self, diff: Self::Diff) {
+        let (posts, deleted, reactions) = diff;
+
+        for (post_id, deleted_at) in deleted {
+            self.remove_post(post_id);
+            self.bury(post_id, deleted_at);
+        }
+
+        for (post_id, post) in posts {
+            if !self.posts.contains_key(&post_id) {
+                self.add_post(post_id, post);
+            }
+        }
+
+        for (post_id, reaction, user_id, version) in reactions {
+            self.merge_reaction(post_id, reaction, user_id, version);
+        }
+    }
+
+    fn evict(&mut self, owns: &Fn(u64) -> bool) -> Option<Self::Diff> {
+        let evicted: Vec<PostId> = self.posts
+            .keys()
+            .filter(|post_id| !owns(shard_key(*post_id)))
+            .cloned()
+            .collect();
+
+        if evicted.is_empty() {
+            return None;
+        }
+
+        let reactions = evicted
+            .iter()
+            .flat_map(|post_id| self.reaction_versions(*post_id))
+            .collect();
+
+        let posts = evicted
+            .into_iter()
+            .filter_map(|post_id| self.remove_post(post_id).map(|post| (post_id, post)))
+            .collect();
+
+        Some((posts, Vec::new(), reactions))
+    }
 }
@@ -3,11 +3,32 @@ use std::collections::BTreeSet;
 
 use super::{PostId, UserId};
 
-#[derive(Clone, Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
 pub struct Post {
     pub post_id: PostId,
     pub author: UserId,
     pub mentions: BTreeSet<UserId>,
+    pub content: Content,
+    pub visibility: Visibility,
+    /// Who may read a followers-only or direct post besides its author, as it was delivered
+    ///
+    /// Empty for public and unlisted posts, since anyone may read those.
+    pub audience: BTreeSet<UserId>,
+    pub in_reply_to: Option<PostId>,
+}
+
+impl Post {
+    /// Whether `viewer` may read the post, `None` being someone who isn't signed in
+    pub fn visible_to(&self, viewer: Option<UserId>) -> bool {
+        if self.visibility.is_public() {
+            return true;
+        }
+
+        match viewer {
+            Some(viewer) => viewer == self.author || self.audience.contains(&viewer),
+            None => false,
+        }
+    }
 }
 
 impl Ord for Post {
@@ -21,3 +42,89 @@ impl PartialOrd for Post {
         Some(self.cmp(other))
     }
 }
+
+/// Who a post is for
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Visibility {
+    /// Readable by anyone and addressed to the public
+    Public,
+    /// Readable by anyone, but only copied to the public so other servers keep it off their
+    /// public timelines
+    Unlisted,
+    /// Sent to and readable by followers only
+    FollowersOnly,
+    /// Sent to and readable by the mentioned users only
+    Direct,
+}
+
+impl Visibility {
+    /// Whether anyone may read the post, and it's shown on public timelines
+    pub fn is_public(&self) -> bool {
+        match *self {
+            Visibility::Public | Visibility::Unlisted => true,
+            Visibility::FollowersOnly | Visibility::Direct => false,
+        }
+    }
+
+    /// Who a post is delivered to, given its author's followers and the users it mentions
+    pub fn recipients(
+        &self,
+        followers: &BTreeSet<UserId>,
+        mentions: &BTreeSet<UserId>,
+    ) -> BTreeSet<UserId> {
+        match *self {
+            Visibility::Direct => mentions.clone(),
+            _ => followers.union(mentions).cloned().collect(),
+        }
+    }
+}
+
+impl Default for Visibility {
+    fn default() -> Self {
+        Visibility::Public
+    }
+}
+
+/// Something a user can do to a post, and take back
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
+pub enum Reaction {
+    /// Sharing the post with the user's own followers
+    Boost,
+    /// Favouriting the post, which only its author hears of
+    Like,
+}
+
+/// The counters clients show under a post
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct PostStats {
+    pub likes: usize,
+    pub boosts: usize,
+    pub replies: usize,
+}
+
+/// What a post says, as written by its author
+#[derive(Clone, Debug, Default, Deserialize, Eq, Hash, PartialEq, Serialize)]
+pub struct Content {
+    /// The post as shown to readers
+    pub html: String,
+    /// The plain text the HTML was rendered from, kept so the author can edit what they wrote
+    pub text: Option<String>,
+    /// A content warning, shown in place of the post until the reader expands it
+    pub summary: Option<String>,
+    /// The language the post is written in, as a BCP 47 tag such as `en`
+    pub language: Option<String>,
+    pub attachments: Vec<Attachment>,
+}
+
+/// Media attached to a post, stored elsewhere and referenced by URL
+#[derive(Clone, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+pub struct Attachment {
+    pub url: String,
+    /// The MIME type, such as `image/png`
+    pub media_type: String,
+    /// A description for readers who can't see the media
+    pub alt: Option<String>,
+    /// A compact placeholder shown while the media loads
+    pub blurhash: Option<String>,
+}
@@ -3,7 +3,7 @@ use std::collections::BTreeSet;
 use actix::{Actor, Context, Handler};
 
 use actors::posts::messages::DeletePost;
-use super::{PostId, User, UserId};
+use super::{Notification, PostId, Profile, User, UserError, UserId};
 use super::messages::*;
 
 impl Actor for User {
@@ -11,23 +11,75 @@ impl Actor for User {
 }
 
 impl Handler<NewPostIn> for User {
-    type Result = ();
+    type Result = Result<(), UserError>;
 
     fn handle(&mut self, msg: NewPostIn, _: &mut Context<Self>) -> Self::Result {
         self.new_post(msg.0, msg.1, &msg.2);
+
+        Ok(())
+    }
+}
+
+impl Handler<Boosted> for User {
+    type Result = Result<(), UserError>;
+
+    fn handle(&mut self, msg: Boosted, _: &mut Context<Self>) -> Self::Result {
+        self.boosted(msg.0, msg.1);
+
+        Ok(())
+    }
+}
+
+impl Handler<Unboosted> for User {
+    type Result = Result<(), UserError>;
+
+    fn handle(&mut self, msg: Unboosted, _: &mut Context<Self>) -> Self::Result {
+        self.unboosted(msg.0, msg.1);
+
+        Ok(())
     }
 }
 
 impl Handler<DeletePost> for User {
-    type Result = ();
+    type Result = Result<(), UserError>;
 
     fn handle(&mut self, msg: DeletePost, _: &mut Context<Self>) -> Self::Result {
         self.delete_post(msg.0);
+
+        Ok(())
+    }
+}
+
+impl Handler<Liked> for User {
+    type Result = Result<(), UserError>;
+
+    fn handle(&mut self, msg: Liked, _: &mut Context<Self>) -> Self::Result {
+        self.liked(msg.0, msg.1);
+
+        Ok(())
+    }
+}
+
+impl Handler<Unliked> for User {
+    type Result = Result<(), UserError>;
+
+    fn handle(&mut self, msg: Unliked, _: &mut Context<Self>) -> Self::Result {
+        self.unliked(msg.0, msg.1);
+
+        Ok(())
+    }
+}
+
+impl Handler<GetNotifications> for User {
+    type Result = Result<Vec<Notification>, UserError>;
+
+    fn handle(&mut self, msg: GetNotifications, _: &mut Context<Self>) -> Self::Result {
+        Ok(self.get_notifications(msg.0))
     }
 }
 
 impl Handler<GetPostIds> for User {
-    type Result = Result<BTreeSet<PostId>, ()>;
+    type Result = Result<BTreeSet<PostId>, UserError>;
 
     fn handle(&mut self, msg: GetPostIds, _: &mut Context<Self>) -> Self::Result {
         Ok(self.get_post_ids(msg.0))
@@ -35,15 +87,31 @@ impl Handler<GetPostIds> for User {
 }
 
 impl Handler<GetUserPostIds> for User {
-    type Result = Result<BTreeSet<PostId>, ()>;
+    type Result = Result<BTreeSet<PostId>, UserError>;
 
     fn handle(&mut self, msg: GetUserPostIds, _: &mut Context<Self>) -> Self::Result {
         Ok(self.get_user_post_ids(msg.0))
     }
 }
 
+impl Handler<GetProfile> for User {
+    type Result = Result<Profile, UserError>;
+
+    fn handle(&mut self, _: GetProfile, _: &mut Context<Self>) -> Self::Result {
+        Ok(self.profile())
+    }
+}
+
+impl Handler<GetPublicKey> for User {
+    type Result = Result<String, UserError>;
+
+    fn handle(&mut self, _: GetPublicKey, _: &mut Context<Self>) -> Self::Result {
+        Ok(self.public_key_pem())
+    }
+}
+
 impl Handler<GetFollowers> for User {
-    type Result = Result<BTreeSet<UserId>, ()>;
+    type Result = Result<BTreeSet<UserId>, UserError>;
 
     fn handle(&mut self, _: GetFollowers, _: &mut Context<Self>) -> Self::Result {
         Ok(self.followers())
@@ -51,57 +119,132 @@ impl Handler<GetFollowers> for User {
 }
 
 impl Handler<FollowRequest> for User {
-    type Result = ();
+    type Result = Result<(), UserError>;
 
     fn handle(&mut self, msg: FollowRequest, _: &mut Context<Self>) -> Self::Result {
         self.follow_request(msg.0);
+
+        Ok(())
     }
 }
 
 impl Handler<AcceptFollowRequest> for User {
-    type Result = ();
+    type Result = Result<(), UserError>;
 
     fn handle(&mut self, msg: AcceptFollowRequest, _: &mut Context<Self>) -> Self::Result {
         self.accept_follow_request(msg.0);
+
+        Ok(())
     }
 }
 
 impl Handler<DenyFollowRequest> for User {
-    type Result = ();
+    type Result = Result<(), UserError>;
 
     fn handle(&mut self, msg: DenyFollowRequest, _: &mut Context<Self>) -> Self::Result {
         self.deny_follow_request(msg.0);
+
+        Ok(())
     }
 }
 
 impl Handler<RequestFollow> for User {
-    type Result = ();
+    type Result = Result<(), UserError>;
 
     fn handle(&mut self, msg: RequestFollow, _: &mut Context<Self>) -> Self::Result {
         self.request_follow(msg.0);
+
+        Ok(())
     }
 }
 
 impl Handler<FollowRequestAccepted> for User {
-    type Result = ();
+    type Result = Result<(), UserError>;
 
     fn handle(&mut self, msg: FollowRequestAccepted, _: &mut Context<Self>) -> Self::Result {
         self.follow_request_accepted(msg.0);
+
+        Ok(())
     }
 }
 
 impl Handler<FollowRequestDenied> for User {
-    type Result = ();
+    type Result = Result<(), UserError>;
 
     fn handle(&mut self, msg: FollowRequestDenied, _: &mut Context<Self>) -> Self::Result {
         self.follow_request_denied(msg.0);
+
+        Ok(())
+    }
+}
+
+impl Handler<Unfollow> for User {
+    type Result = Result<(), UserError>;
+
+    fn handle(&mut self, msg: Unfollow, _: &mut Context<Self>) -> Self::Result {
+        self.unfollow(msg.0);
+
+        Ok(())
+    }
+}
+
+impl Handler<RemoveFollower> for User {
+    type Result = Result<(), UserError>;
+
+    fn handle(&mut self, msg: RemoveFollower, _: &mut Context<Self>) -> Self::Result {
+        self.remove_follower(msg.0);
+
+        Ok(())
+    }
+}
+
+impl Handler<Unfollowed> for User {
+    type Result = Result<(), UserError>;
+
+    fn handle(&mut self, msg: Unfollowed, _: &mut Context<Self>) -> Self::Result {
+        self.remove_follower(msg.0);
+
+        Ok(())
+    }
+}
+
+impl Handler<FollowerRemoved> for User {
+    type Result = Result<(), UserError>;
+
+    fn handle(&mut self, msg: FollowerRemoved, _: &mut Context<Self>) -> Self::Result {
+        self.unfollow(msg.0);
+
+        Ok(())
+    }
+}
+
+impl Handler<BlockUser> for User {
+    type Result = Result<(), UserError>;
+
+    fn handle(&mut self, msg: BlockUser, _: &mut Context<Self>) -> Self::Result {
+        self.block(msg.0);
+
+        Ok(())
     }
 }
 
 impl Handler<Blocked> for User {
-    type Result = ();
+    type Result = Result<(), UserError>;
 
     fn handle(&mut self, msg: Blocked, _: &mut Context<Self>) -> Self::Result {
         self.blocked_by(msg.0);
+
+        Ok(())
+    }
+}
+
+impl Handler<Unblocked> for User {
+    type Result = Result<(), UserError>;
+
+    fn handle(&mut self, msg: Unblocked, _: &mut Context<Self>) -> Self::Result {
+        // Follows ended by the block stay ended, the users have to follow each other again
+        debug!("user {:?} was unblocked by user {:?}", self.user_id, msg.0);
+
+        Ok(())
     }
 }
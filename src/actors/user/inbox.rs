@@ -6,8 +6,9 @@ use actors::peered::messages::Message;
 use actors::posts::messages::DeletePost;
 use actors::users::Users;
 use actors::users::messages::Lookup;
+use actors::dispatch::DispatchError;
 use super::messages::*;
-use super::User;
+use super::{User, UserError};
 
 pub struct Inbox {
     user: Address<User>,
@@ -25,34 +26,112 @@ impl Actor for Inbox {
 }
 
 impl Handler<NewPostIn> for Inbox {
-    type Result = ();
+    type Result = Result<(), UserError>;
 
     fn handle(&mut self, msg: NewPostIn, _: &mut Context<Self>) -> Self::Result {
         self.user.send(msg);
+
+        Ok(())
+    }
+}
+
+impl Handler<Boosted> for Inbox {
+    type Result = Result<(), UserError>;
+
+    fn handle(&mut self, msg: Boosted, _: &mut Context<Self>) -> Self::Result {
+        self.user.send(msg);
+
+        Ok(())
+    }
+}
+
+impl Handler<Unboosted> for Inbox {
+    type Result = Result<(), UserError>;
+
+    fn handle(&mut self, msg: Unboosted, _: &mut Context<Self>) -> Self::Result {
+        self.user.send(msg);
+
+        Ok(())
+    }
+}
+
+impl Handler<Liked> for Inbox {
+    type Result = Result<(), UserError>;
+
+    fn handle(&mut self, msg: Liked, _: &mut Context<Self>) -> Self::Result {
+        self.user.send(msg);
+
+        Ok(())
+    }
+}
+
+impl Handler<Unliked> for Inbox {
+    type Result = Result<(), UserError>;
+
+    fn handle(&mut self, msg: Unliked, _: &mut Context<Self>) -> Self::Result {
+        self.user.send(msg);
+
+        Ok(())
     }
 }
 
 impl Handler<FollowRequest> for Inbox {
-    type Result = ();
+    type Result = Result<(), UserError>;
 
     fn handle(&mut self, msg: FollowRequest, _: &mut Context<Self>) -> Self::Result {
         self.user.send(msg);
+
+        Ok(())
     }
 }
 
 impl Handler<FollowRequestAccepted> for Inbox {
-    type Result = ();
+    type Result = Result<(), UserError>;
 
     fn handle(&mut self, msg: FollowRequestAccepted, _: &mut Context<Self>) -> Self::Result {
         self.user.send(msg);
+
+        Ok(())
     }
 }
 
 impl Handler<FollowRequestDenied> for Inbox {
-    type Result = ();
+    type Result = Result<(), UserError>;
 
     fn handle(&mut self, msg: FollowRequestDenied, _: &mut Context<Self>) -> Self::Result {
         self.user.send(msg);
+
+        Ok(())
+    }
+}
+
+impl Handler<Unfollowed> for Inbox {
+    type Result = Result<(), UserError>;
+
+    fn handle(&mut self, msg: Unfollowed, _: &mut Context<Self>) -> Self::Result {
+        self.user.send(msg);
+
+        Ok(())
+    }
+}
+
+impl Handler<FollowerRemoved> for Inbox {
+    type Result = Result<(), UserError>;
+
+    fn handle(&mut self, msg: FollowerRemoved, _: &mut Context<Self>) -> Self::Result {
+        self.user.send(msg);
+
+        Ok(())
+    }
+}
+
+impl Handler<DeletePost> for Inbox {
+    type Result = Result<(), UserError>;
+
+    fn handle(&mut self, msg: DeletePost, _: &mut Context<Self>) -> Self::Result {
+        self.user.send(msg);
+
+        Ok(())
     }
 }
 
@@ -65,20 +144,35 @@ impl Handler<Blocked> for Inbox {
 
         let fut = self.users
             .call(self, Message::new(Lookup(msg.0)))
-            .map_err(|e, _, _| error!("Error: {}", e))
-            .and_then(|res, _, _| result(res))
+            .map_err(|_, _, _| UserError::MailboxClosed)
+            .and_then(|res, _, _| result(res.map_err(|e| DispatchError::from(e).into())))
             .and_then(|addr, inbox, _| {
                 addr.user()
                     .call(inbox, GetUserPostIds(0))
-                    .map_err(|e, _, _| error!("Error: {}", e))
+                    .map_err(|_, _, _| UserError::MailboxClosed)
                     .and_then(|res, _, _| result(res))
             })
             .map(move |post_ids, _, _| {
                 for post_id in post_ids {
                     user.send(DeletePost(post_id));
                 }
+            })
+            .map_err(|e, _, _| {
+                // Blocks are delivered without waiting on the result
+                error!("Error: {}", e);
+                e
             });
 
         Box::new(fut)
     }
 }
+
+impl Handler<Unblocked> for Inbox {
+    type Result = Result<(), UserError>;
+
+    fn handle(&mut self, msg: Unblocked, _: &mut Context<Self>) -> Self::Result {
+        self.user.send(msg);
+
+        Ok(())
+    }
+}
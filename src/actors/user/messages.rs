@@ -2,24 +2,123 @@ use std::collections::BTreeSet;
 
 use actix::{ResponseType, SyncAddress};
 
-use super::{PostId, UserId};
+use super::{Notification, PostId, Profile, UserError, UserId};
+use super::inbox::Inbox;
 use actors::peered::Peered;
-use actors::posts::Posts;
+use actors::posts::{Content, Posts, Visibility};
+use actors::posts::messages::DeletePost;
 
-#[derive(Clone, Debug, Eq, PartialEq)]
-pub struct NewPostIn(pub PostId, pub UserId, pub BTreeSet<UserId>);
+/// NewPostIn(post_id, author, mentions, content, visibility, in_reply_to)
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct NewPostIn(
+    pub PostId,
+    pub UserId,
+    pub BTreeSet<UserId>,
+    pub Content,
+    pub Visibility,
+    pub Option<PostId>,
+);
 
 impl ResponseType for NewPostIn {
     type Item = ();
-    type Error = ();
+    type Error = UserError;
 }
 
+/// NewPostOut(mentions, content, visibility, in_reply_to)
+///
+/// Replies also mention the author of the post they reply to, if this node keeps it.
 #[derive(Clone, Debug, Eq, PartialEq)]
-pub struct NewPostOut(pub BTreeSet<UserId>);
+pub struct NewPostOut(
+    pub BTreeSet<UserId>,
+    pub Content,
+    pub Visibility,
+    pub Option<PostId>,
+);
 
 impl ResponseType for NewPostOut {
     type Item = PostId;
-    type Error = ();
+    type Error = UserError;
+}
+
+/// Shares a post with the user's followers
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Boost(pub PostId);
+
+impl ResponseType for Boost {
+    type Item = ();
+    type Error = UserError;
+}
+
+/// Stops sharing a boosted post
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Unboost(pub PostId);
+
+impl ResponseType for Unboost {
+    type Item = ();
+    type Error = UserError;
+}
+
+/// Boosted(post_id, booster), a followed user shared the post
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Boosted(pub PostId, pub UserId);
+
+impl ResponseType for Boosted {
+    type Item = ();
+    type Error = UserError;
+}
+
+/// Unboosted(post_id, booster), a followed user stopped sharing the post
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Unboosted(pub PostId, pub UserId);
+
+impl ResponseType for Unboosted {
+    type Item = ();
+    type Error = UserError;
+}
+
+/// Favourites a post, letting its author know
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Like(pub PostId);
+
+impl ResponseType for Like {
+    type Item = ();
+    type Error = UserError;
+}
+
+/// Takes back a like
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Unlike(pub PostId);
+
+impl ResponseType for Unlike {
+    type Item = ();
+    type Error = UserError;
+}
+
+/// Liked(post_id, liker), someone liked one of the user's posts
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Liked(pub PostId, pub UserId);
+
+impl ResponseType for Liked {
+    type Item = ();
+    type Error = UserError;
+}
+
+/// Unliked(post_id, liker), someone took back their like of one of the user's posts
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Unliked(pub PostId, pub UserId);
+
+impl ResponseType for Unliked {
+    type Item = ();
+    type Error = UserError;
+}
+
+/// The user's latest notifications, newest first, 0 meaning all of them
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct GetNotifications(pub usize);
+
+impl ResponseType for GetNotifications {
+    type Item = Vec<Notification>;
+    type Error = UserError;
 }
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
@@ -27,7 +126,7 @@ pub struct GetPostIds(pub usize);
 
 impl ResponseType for GetPostIds {
     type Item = BTreeSet<PostId>;
-    type Error = ();
+    type Error = UserError;
 }
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
@@ -35,7 +134,17 @@ pub struct GetUserPostIds(pub usize);
 
 impl ResponseType for GetUserPostIds {
     type Item = BTreeSet<PostId>;
-    type Error = ();
+    type Error = UserError;
+}
+
+/// The user's posts the viewer may read, out of the given number of newest ones or all of them
+/// given 0
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct GetVisiblePostIds(pub usize, pub Option<UserId>);
+
+impl ResponseType for GetVisiblePostIds {
+    type Item = BTreeSet<PostId>;
+    type Error = UserError;
 }
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
@@ -43,7 +152,24 @@ pub struct GetPosts;
 
 impl ResponseType for GetPosts {
     type Item = SyncAddress<Peered<Posts>>;
-    type Error = ();
+    type Error = UserError;
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct GetProfile;
+
+impl ResponseType for GetProfile {
+    type Item = Profile;
+    type Error = UserError;
+}
+
+/// The user's public key, PEM encoded
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct GetPublicKey;
+
+impl ResponseType for GetPublicKey {
+    type Item = String;
+    type Error = UserError;
 }
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
@@ -51,15 +177,15 @@ pub struct GetFollowers;
 
 impl ResponseType for GetFollowers {
     type Item = BTreeSet<UserId>;
-    type Error = ();
+    type Error = UserError;
 }
 
-#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
 pub struct FollowRequest(pub UserId);
 
 impl ResponseType for FollowRequest {
     type Item = ();
-    type Error = ();
+    type Error = UserError;
 }
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
@@ -67,7 +193,7 @@ pub struct AcceptFollowRequest(pub UserId);
 
 impl ResponseType for AcceptFollowRequest {
     type Item = ();
-    type Error = ();
+    type Error = UserError;
 }
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
@@ -75,7 +201,7 @@ pub struct DenyFollowRequest(pub UserId);
 
 impl ResponseType for DenyFollowRequest {
     type Item = ();
-    type Error = ();
+    type Error = UserError;
 }
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
@@ -83,23 +209,59 @@ pub struct RequestFollow(pub UserId);
 
 impl ResponseType for RequestFollow {
     type Item = ();
-    type Error = ();
+    type Error = UserError;
 }
 
-#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
 pub struct FollowRequestAccepted(pub UserId);
 
 impl ResponseType for FollowRequestAccepted {
     type Item = ();
-    type Error = ();
+    type Error = UserError;
 }
 
-#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
 pub struct FollowRequestDenied(pub UserId);
 
 impl ResponseType for FollowRequestDenied {
     type Item = ();
-    type Error = ();
+    type Error = UserError;
+}
+
+/// Stops following the user
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Unfollow(pub UserId);
+
+impl ResponseType for Unfollow {
+    type Item = ();
+    type Error = UserError;
+}
+
+/// Stops the user from following us
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct RemoveFollower(pub UserId);
+
+impl ResponseType for RemoveFollower {
+    type Item = ();
+    type Error = UserError;
+}
+
+/// The user stopped following us
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Unfollowed(pub UserId);
+
+impl ResponseType for Unfollowed {
+    type Item = ();
+    type Error = UserError;
+}
+
+/// The user stopped us from following them
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct FollowerRemoved(pub UserId);
+
+impl ResponseType for FollowerRemoved {
+    type Item = ();
+    type Error = UserError;
 }
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
@@ -107,13 +269,147 @@ pub struct BlockUser(pub UserId);
 
 impl ResponseType for BlockUser {
     type Item = ();
-    type Error = ();
+    type Error = UserError;
 }
 
-#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
 pub struct Blocked(pub UserId);
 
 impl ResponseType for Blocked {
     type Item = ();
-    type Error = ();
+    type Error = UserError;
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct UnblockUser(pub UserId);
+
+impl ResponseType for UnblockUser {
+    type Item = ();
+    type Error = UserError;
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Unblocked(pub UserId);
+
+impl ResponseType for Unblocked {
+    type Item = ();
+    type Error = UserError;
+}
+
+/// Any of the messages a user's inbox takes, in the form they're handed to the process the
+/// user runs in
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum InboxMessage {
+    NewPostIn(NewPostIn),
+    Boosted(Boosted),
+    Unboosted(Unboosted),
+    Liked(Liked),
+    Unliked(Unliked),
+    FollowRequest(FollowRequest),
+    FollowRequestAccepted(FollowRequestAccepted),
+    FollowRequestDenied(FollowRequestDenied),
+    Unfollowed(Unfollowed),
+    FollowerRemoved(FollowerRemoved),
+    DeletePost(DeletePost),
+    Blocked(Blocked),
+    Unblocked(Unblocked),
+}
+
+impl InboxMessage {
+    /// Hands the message to the inbox of the user it's for
+    pub fn send_to(self, inbox: &SyncAddress<Inbox>) {
+        match self {
+            InboxMessage::NewPostIn(msg) => inbox.send(msg),
+            InboxMessage::Boosted(msg) => inbox.send(msg),
+            InboxMessage::Unboosted(msg) => inbox.send(msg),
+            InboxMessage::Liked(msg) => inbox.send(msg),
+            InboxMessage::Unliked(msg) => inbox.send(msg),
+            InboxMessage::FollowRequest(msg) => inbox.send(msg),
+            InboxMessage::FollowRequestAccepted(msg) => inbox.send(msg),
+            InboxMessage::FollowRequestDenied(msg) => inbox.send(msg),
+            InboxMessage::Unfollowed(msg) => inbox.send(msg),
+            InboxMessage::FollowerRemoved(msg) => inbox.send(msg),
+            InboxMessage::DeletePost(msg) => inbox.send(msg),
+            InboxMessage::Blocked(msg) => inbox.send(msg),
+            InboxMessage::Unblocked(msg) => inbox.send(msg),
+        }
+    }
+}
+
+impl From<NewPostIn> for InboxMessage {
+    fn from(msg: NewPostIn) -> Self {
+        InboxMessage::NewPostIn(msg)
+    }
+}
+
+impl From<Boosted> for InboxMessage {
+    fn from(msg: Boosted) -> Self {
+        InboxMessage::Boosted(msg)
+    }
+}
+
+impl From<Unboosted> for InboxMessage {
+    fn from(msg: Unboosted) -> Self {
+        InboxMessage::Unboosted(msg)
+    }
+}
+
+impl From<Liked> for InboxMessage {
+    fn from(msg: Liked) -> Self {
+        InboxMessage::Liked(msg)
+    }
+}
+
+impl From<Unliked> for InboxMessage {
+    fn from(msg: Unliked) -> Self {
+        InboxMessage::Unliked(msg)
+    }
+}
+
+impl From<FollowRequest> for InboxMessage {
+    fn from(msg: FollowRequest) -> Self {
+        InboxMessage::FollowRequest(msg)
+    }
+}
+
+impl From<FollowRequestAccepted> for InboxMessage {
+    fn from(msg: FollowRequestAccepted) -> Self {
+        InboxMessage::FollowRequestAccepted(msg)
+    }
+}
+
+impl From<FollowRequestDenied> for InboxMessage {
+    fn from(msg: FollowRequestDenied) -> Self {
+        InboxMessage::FollowRequestDenied(msg)
+    }
+}
+
+impl From<Unfollowed> for InboxMessage {
+    fn from(msg: Unfollowed) -> Self {
+        InboxMessage::Unfollowed(msg)
+    }
+}
+
+impl From<FollowerRemoved> for InboxMessage {
+    fn from(msg: FollowerRemoved) -> Self {
+        InboxMessage::FollowerRemoved(msg)
+    }
+}
+
+impl From<DeletePost> for InboxMessage {
+    fn from(msg: DeletePost) -> Self {
+        InboxMessage::DeletePost(msg)
+    }
+}
+
+impl From<Blocked> for InboxMessage {
+    fn from(msg: Blocked) -> Self {
+        InboxMessage::Blocked(msg)
+    }
+}
+
+impl From<Unblocked> for InboxMessage {
+    fn from(msg: Unblocked) -> Self {
+        InboxMessage::Unblocked(msg)
+    }
 }
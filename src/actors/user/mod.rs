@@ -1,40 +1,128 @@
-use std::collections::BTreeSet;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
 
+use activitypub::KeyPair;
+use storage::{Relation, Storage, Timeline};
 use super::{PostId, UserId};
+use super::blocklist::BlocklistError;
+use super::dispatch::DispatchError;
+use super::posts::PostsError;
 
 mod actor;
 pub mod inbox;
 pub mod messages;
 pub mod outbox;
+mod profile;
+
+pub use self::profile::Profile;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum UserError {
+    Posts(PostsError),
+    Blocklist(BlocklistError),
+    Dispatch(DispatchError),
+    MailboxClosed,
+}
+
+impl fmt::Display for UserError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            UserError::Posts(ref e) => write!(f, "{}", e),
+            UserError::Blocklist(ref e) => write!(f, "{}", e),
+            UserError::Dispatch(ref e) => write!(f, "{}", e),
+            UserError::MailboxClosed => write!(f, "Mailbox closed"),
+        }
+    }
+}
+
+impl From<PostsError> for UserError {
+    fn from(e: PostsError) -> Self {
+        UserError::Posts(e)
+    }
+}
+
+impl From<BlocklistError> for UserError {
+    fn from(e: BlocklistError) -> Self {
+        UserError::Blocklist(e)
+    }
+}
+
+impl From<DispatchError> for UserError {
+    fn from(e: DispatchError) -> Self {
+        UserError::Dispatch(e)
+    }
+}
+
+/// Something another user did that the user should hear about
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Notification {
+    /// Liked(post_id, liker)
+    Liked(PostId, UserId),
+}
 
 pub struct User {
     user_id: UserId,
+    profile: Profile,
+    keys: KeyPair,
     posts: BTreeSet<PostId>,
     my_posts: BTreeSet<PostId>,
+    /// Posts in the home timeline only because followed users boosted them, and who did
+    boosted: BTreeMap<PostId, BTreeSet<UserId>>,
+    /// Oldest first
+    notifications: Vec<Notification>,
+    /// The sequence the next notification is stored with, keeping them in order across restarts
+    next_notification: u64,
     followers: BTreeSet<UserId>,
     following: BTreeSet<UserId>,
     follow_requests: BTreeSet<UserId>,
     pending_follows: BTreeSet<UserId>,
+    storage: Storage,
 }
 
 impl User {
-    pub fn new(user_id: UserId) -> Self {
+    /// Creates the user, picking up any timelines, boosts, notifications and relations already in
+    /// `storage`
+    pub fn new(user_id: UserId, profile: Profile, keys: KeyPair, storage: Storage) -> Self {
+        let notifications = storage.notifications(user_id);
+        let next_notification = notifications.last().map_or(0, |&(sequence, _)| sequence + 1);
+
         User {
             user_id: user_id,
-            posts: BTreeSet::new(),
-            my_posts: BTreeSet::new(),
-            followers: BTreeSet::new(),
-            following: BTreeSet::new(),
-            follow_requests: BTreeSet::new(),
-            pending_follows: BTreeSet::new(),
+            profile: profile,
+            keys: keys,
+            posts: storage.timeline(user_id, Timeline::Home),
+            my_posts: storage.timeline(user_id, Timeline::Own),
+            boosted: storage.boosted(user_id),
+            notifications: notifications
+                .into_iter()
+                .map(|(_, notification)| notification)
+                .collect(),
+            next_notification: next_notification,
+            followers: storage.relation(user_id, Relation::Followers),
+            following: storage.relation(user_id, Relation::Following),
+            follow_requests: storage.relation(user_id, Relation::FollowRequests),
+            pending_follows: storage.relation(user_id, Relation::PendingFollows),
+            storage: storage,
         }
     }
 
+    pub fn user_id(&self) -> UserId {
+        self.user_id
+    }
+
+    pub fn username(&self) -> &str {
+        &self.profile.username
+    }
+
+    pub fn keys(&self) -> &KeyPair {
+        &self.keys
+    }
+
     pub fn get_user_post_ids(&self, amount: usize) -> BTreeSet<PostId> {
         if amount == 0 {
-            self.my_posts.iter().rev().take(amount).cloned().collect()
+            self.my_posts.clone()
         } else {
-            self.my_posts.iter().rev().cloned().collect()
+            self.my_posts.iter().rev().take(amount).cloned().collect()
         }
     }
 
@@ -89,17 +177,135 @@ impl User {
         );
 
         if user_id == self.user_id {
+            self.storage.add_to_timeline(self.user_id, Timeline::Own, post_id);
             self.my_posts.insert(post_id);
         } else if self.following.contains(&user_id) || mentions.contains(&self.user_id) {
+            self.storage.add_to_timeline(self.user_id, Timeline::Home, post_id);
             self.posts.insert(post_id);
+            self.forget_boosts(post_id);
         } else {
             error!("Should not have recieved post from user {:?}", user_id);
         }
     }
 
+    fn boosted(&mut self, post_id: PostId, booster: UserId) {
+        if !self.following.contains(&booster) {
+            return error!("Should not have recieved boost from user {:?}", booster);
+        }
+
+        let already_held = self.my_posts.contains(&post_id)
+            || (self.posts.contains(&post_id) && !self.boosted.contains_key(&post_id));
+
+        if already_held {
+            return;
+        }
+
+        if self.posts.insert(post_id) {
+            self.storage.add_to_timeline(self.user_id, Timeline::Home, post_id);
+        }
+
+        let added = self.boosted
+            .entry(post_id)
+            .or_insert_with(BTreeSet::new)
+            .insert(booster);
+
+        if added {
+            self.storage.add_boost(self.user_id, post_id, booster);
+        }
+    }
+
+    fn unboosted(&mut self, post_id: PostId, booster: UserId) {
+        let now_empty = match self.boosted.get_mut(&post_id) {
+            Some(boosters) => {
+                if boosters.remove(&booster) {
+                    self.storage.remove_boost(self.user_id, post_id, booster);
+                }
+                boosters.is_empty()
+            }
+            None => false,
+        };
+
+        if now_empty {
+            self.boosted.remove(&post_id);
+
+            if self.posts.remove(&post_id) {
+                self.storage.remove_from_timeline(self.user_id, Timeline::Home, post_id);
+            }
+        }
+    }
+
+    /// Stops keeping who boosted `post_id`, once it's held for another reason or gone
+    fn forget_boosts(&mut self, post_id: PostId) {
+        if let Some(boosters) = self.boosted.remove(&post_id) {
+            for booster in boosters {
+                self.storage.remove_boost(self.user_id, post_id, booster);
+            }
+        }
+    }
+
+    fn liked(&mut self, post_id: PostId, liker: UserId) {
+        if !self.my_posts.contains(&post_id) {
+            return error!("Should not have recieved like of post {:?}", post_id);
+        }
+
+        let notification = Notification::Liked(post_id, liker);
+        if !self.notifications.contains(&notification) {
+            self.storage
+                .save_notification(self.user_id, self.next_notification, notification);
+            self.next_notification += 1;
+            self.notifications.push(notification);
+        }
+    }
+
+    fn unliked(&mut self, post_id: PostId, liker: UserId) {
+        let notification = Notification::Liked(post_id, liker);
+
+        if self.notifications.contains(&notification) {
+            self.storage.delete_notification(self.user_id, notification);
+            self.notifications.retain(|n| *n != notification);
+        }
+    }
+
+    fn get_notifications(&self, amount: usize) -> Vec<Notification> {
+        let newest = self.notifications.iter().rev().cloned();
+
+        if amount == 0 {
+            newest.collect()
+        } else {
+            newest.take(amount).collect()
+        }
+    }
+
     fn delete_post(&mut self, post_id: PostId) {
-        self.posts.remove(&post_id);
-        self.my_posts.remove(&post_id);
+        self.forget_boosts(post_id);
+
+        let user_id = self.user_id;
+        let storage = &self.storage;
+        self.notifications.retain(|notification| {
+            let about_post = match *notification {
+                Notification::Liked(liked, _) => liked == post_id,
+            };
+
+            if about_post {
+                storage.delete_notification(user_id, *notification);
+            }
+            !about_post
+        });
+
+        if self.posts.remove(&post_id) {
+            self.storage.remove_from_timeline(self.user_id, Timeline::Home, post_id);
+        }
+        if self.my_posts.remove(&post_id) {
+            self.storage.remove_from_timeline(self.user_id, Timeline::Own, post_id);
+        }
+    }
+
+    fn profile(&self) -> Profile {
+        self.profile.clone()
+    }
+
+    fn public_key_pem(&self) -> String {
+        self.keys.public_key_pem()
     }
 
     fn followers(&self) -> BTreeSet<UserId> {
@@ -112,12 +318,12 @@ impl User {
             "user {:?} received follow request from user {:?}",
             self.user_id, user_id
         );
-        self.follow_requests.insert(user_id);
+        self.add(Relation::FollowRequests, user_id);
     }
 
     fn accept_follow_request(&mut self, user_id: UserId) -> Option<UserId> {
         self.answer_follow_request(user_id).map(|user_id| {
-            self.followers.insert(user_id);
+            self.add(Relation::Followers, user_id);
             self.user_id
         })
     }
@@ -131,7 +337,7 @@ impl User {
             "user {:?} is answering follow request from user {:?}",
             self.user_id, user_id
         );
-        if self.follow_requests.remove(&user_id) {
+        if self.remove(Relation::FollowRequests, user_id) {
             Some(user_id)
         } else {
             None
@@ -139,20 +345,75 @@ impl User {
     }
 
     fn request_follow(&mut self, user_id: UserId) {
-        self.pending_follows.insert(user_id);
+        self.add(Relation::PendingFollows, user_id);
     }
 
     fn follow_request_accepted(&mut self, user_id: UserId) {
-        self.pending_follows.remove(&user_id);
-        self.following.insert(user_id);
+        self.remove(Relation::PendingFollows, user_id);
+        self.add(Relation::Following, user_id);
     }
 
     fn follow_request_denied(&mut self, user_id: UserId) {
-        self.pending_follows.remove(&user_id);
+        self.remove(Relation::PendingFollows, user_id);
+    }
+
+    fn unfollow(&mut self, user_id: UserId) {
+        debug!("user {:?} is unfollowing user {:?}", self.user_id, user_id);
+        self.remove(Relation::Following, user_id);
+        self.remove(Relation::PendingFollows, user_id);
+    }
+
+    fn remove_follower(&mut self, user_id: UserId) {
+        debug!(
+            "user {:?} is no longer followed by user {:?}",
+            self.user_id, user_id
+        );
+        self.remove(Relation::Followers, user_id);
+        self.remove(Relation::FollowRequests, user_id);
+    }
+
+    fn block(&mut self, user_id: UserId) {
+        debug!("user {:?} is blocking user {:?}", self.user_id, user_id);
+        self.sever(user_id);
     }
 
     fn blocked_by(&mut self, user_id: UserId) {
-        self.following.remove(&user_id);
-        self.pending_follows.remove(&user_id);
+        debug!("user {:?} was blocked by user {:?}", self.user_id, user_id);
+        self.sever(user_id);
+    }
+
+    /// Drops every follow between this user and `user_id`, in either direction
+    fn sever(&mut self, user_id: UserId) {
+        self.remove(Relation::Followers, user_id);
+        self.remove(Relation::Following, user_id);
+        self.remove(Relation::FollowRequests, user_id);
+        self.remove(Relation::PendingFollows, user_id);
+    }
+
+    fn relation(&mut self, relation: Relation) -> &mut BTreeSet<UserId> {
+        match relation {
+            Relation::Followers => &mut self.followers,
+            Relation::Following => &mut self.following,
+            Relation::FollowRequests => &mut self.follow_requests,
+            Relation::PendingFollows => &mut self.pending_follows,
+        }
+    }
+
+    /// Adds `user_id` to one of the user's relations, writing it through to storage
+    fn add(&mut self, relation: Relation, user_id: UserId) {
+        if self.relation(relation).insert(user_id) {
+            self.storage.add_relation(self.user_id, relation, user_id);
+        }
+    }
+
+    /// Removes `user_id` from one of the user's relations, returning whether it was there
+    fn remove(&mut self, relation: Relation, user_id: UserId) -> bool {
+        let removed = self.relation(relation).remove(&user_id);
+
+        if removed {
+            self.storage.remove_relation(self.user_id, relation, user_id);
+        }
+
+        removed
     }
 }
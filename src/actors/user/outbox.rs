@@ -1,17 +1,23 @@
-use actix::{Actor, ActorFuture, Address, Context, Handler, ResponseFuture, SyncAddress};
-use actix::fut::result;
+use std::collections::BTreeSet;
+
+use actix::{Actor, ActorFuture, Address, Context, Handler, ResponseFuture, ResponseType,
+            SyncAddress};
+use actix::fut::{err, ok, result, Either};
 
 use actors::blocklist::Blocklists;
-use actors::blocklist::messages::Block;
-use actors::dispatch::Dispatch;
+use actors::blocklist::messages::{Block, CanSpeak, GetBlockedBy, GetBlocklist, Unblock};
+use activitypub::{KeyPair, ToActivity};
+use actors::dispatch::{Dispatch, DispatchError};
 use actors::dispatch::messages::{DispatchAnnounce, DispatchMessage};
 use actors::peered::Peered;
 use actors::peered::messages::Message;
-use actors::posts::Posts;
-use actors::posts::messages::{DeletePost, NewPost};
+use actors::posts::{Posts, PostsError, Reaction};
+use actors::posts::messages::{AddReaction, DeletePost, GetPostsByIds, NewPost, RemoveReaction};
 use actors::users::Users;
+use federation::Federation;
+use super::inbox::Inbox;
 use super::messages::*;
-use super::{User, UserId};
+use super::{PostId, User, UserError, UserId};
 
 pub struct Outbox {
     user_id: UserId,
@@ -28,8 +34,10 @@ impl Outbox {
         posts: SyncAddress<Peered<Posts>>,
         users: SyncAddress<Peered<Users>>,
         blocklists: SyncAddress<Peered<Blocklists>>,
+        federation: Federation,
+        keys: KeyPair,
     ) -> Self {
-        let dispatch = Dispatch::new(users, blocklists.clone()).start();
+        let dispatch = Dispatch::new(users, blocklists.clone(), federation, keys).start();
 
         Outbox {
             user_id,
@@ -39,6 +47,129 @@ impl Outbox {
             blocklists,
         }
     }
+
+    /// Sends `message` from this user to `target`, resolving once it was handed over
+    fn send_to<T>(
+        &mut self,
+        message: T,
+        target: UserId,
+    ) -> Box<ActorFuture<Item = (), Error = UserError, Actor = Self>>
+    where
+        T: ResponseType<Item = ()> + ToActivity + Into<InboxMessage> + Send + 'static,
+        T::Error: Send,
+        Inbox: Handler<T>,
+    {
+        let fut = self.dispatch
+            .call(self, DispatchMessage(message, self.user_id, target))
+            .map_err(|_, _, _| UserError::MailboxClosed)
+            .and_then(|res, _, _| result(res.map_err(From::from)));
+
+        Box::new(fut)
+    }
+
+    /// Looks up the author of a post this user may react to, checking it's visible to them and
+    /// that neither has blocked the other
+    fn author_of(
+        &mut self,
+        post_id: PostId,
+    ) -> Box<ActorFuture<Item = UserId, Error = UserError, Actor = Self>> {
+        let user_id = self.user_id;
+
+        let fut = self.posts
+            .call(self, Message::new(GetPostsByIds(vec![post_id], Some(user_id))))
+            .map_err(|_, _, _| UserError::MailboxClosed)
+            .and_then(|res, _, _| result(res.map_err(From::from)))
+            .and_then(move |(posts, _), _, _| match posts.into_iter().next() {
+                Some(post) => ok(post.author),
+                None => err(PostsError::PostNotFound(post_id).into()),
+            })
+            .and_then(move |author, outbox, _| {
+                outbox
+                    .blocklists
+                    .call(outbox, Message::new(CanSpeak(author, user_id)))
+                    .map_err(|_, _, _| UserError::MailboxClosed)
+                    .and_then(|res, _, _| result(res.map_err(From::from)))
+                    .and_then(move |can_speak, _, _| {
+                        if can_speak {
+                            ok(author)
+                        } else {
+                            err(DispatchError::Blocked(user_id, author).into())
+                        }
+                    })
+            });
+
+        Box::new(fut)
+    }
+
+    /// Lets the author of a post know about `message`, unless it's this user's own post
+    ///
+    /// The reaction is already recorded by then, so the delivery isn't waited on.
+    fn notify<T>(&mut self, message: T, author: UserId)
+    where
+        T: ResponseType<Item = ()> + ToActivity + Into<InboxMessage> + Send + 'static,
+        T::Error: Send,
+        Inbox: Handler<T>,
+    {
+        if author != self.user_id {
+            self.dispatch.send(DispatchMessage(message, self.user_id, author));
+        }
+    }
+
+    /// Announces `message` about a post by `author` to this user's followers, leaving out those
+    /// who are blocked by or have blocked the author
+    fn share<T>(
+        &mut self,
+        message: T,
+        author: UserId,
+    ) -> Box<ActorFuture<Item = (), Error = UserError, Actor = Self>>
+    where
+        T: ResponseType<Item = ()> + ToActivity + Into<InboxMessage> + Clone + Send + 'static,
+        T::Error: Send,
+        Inbox: Handler<T>,
+    {
+        let user_id = self.user_id;
+
+        let fut = self.user
+            .call(self, GetFollowers)
+            .map_err(|_, _, _| UserError::MailboxClosed)
+            .and_then(|followers_res, _, _| result(followers_res))
+            .and_then(move |followers, outbox, _| {
+                outbox
+                    .blocklists
+                    .call(outbox, Message::new(GetBlocklist(author)))
+                    .and_then(move |blocklist_res, outbox, _| {
+                        outbox
+                            .blocklists
+                            .call(outbox, Message::new(GetBlockedBy(author)))
+                            .map(|blocked_by_res, _, _| (blocklist_res, blocked_by_res))
+                    })
+                    .map_err(|_, _, _| UserError::MailboxClosed)
+                    .and_then(|(blocklist_res, blocked_by_res), _, _| {
+                        let res = blocklist_res.and_then(|blocklist| {
+                            blocked_by_res.map(|blocked_by| (blocklist, blocked_by))
+                        });
+
+                        result(res.map_err(From::from))
+                    })
+                    .map(move |(blocklist, blocked_by), _, _| {
+                        followers
+                            .into_iter()
+                            .filter(|follower| {
+                                *follower != user_id && !blocklist.contains(follower)
+                                    && !blocked_by.contains(follower)
+                            })
+                            .collect::<BTreeSet<_>>()
+                    })
+            })
+            .map(move |recipients, outbox, _| {
+                debug!("Sharing a post by {:?} with recipients: {:?}", author, recipients);
+                outbox
+                    .dispatch
+                    .send(DispatchAnnounce(message, user_id, recipients));
+            });
+
+        Box::new(fut)
+    }
 }
 
 impl Actor for Outbox {
@@ -49,39 +180,80 @@ impl Handler<NewPostOut> for Outbox {
     type Result = ResponseFuture<Self, NewPostOut>;
 
     fn handle(&mut self, msg: NewPostOut, _: &mut Context<Self>) -> Self::Result {
-        let mentions = msg.0;
+        let NewPostOut(mut mentions, content, visibility, in_reply_to) = msg;
         let dispatch = self.dispatch.clone();
         let user = self.user.clone();
-        let user_2 = user.clone();
         let user_id = self.user_id;
         debug!("user {:?} is creating a new post", user_id);
 
-        let post_message = Message::new(NewPost(user_id, mentions.clone()));
+        // Replies mention the author of the post they reply to, so Dispatch delivers to them, and
+        // can only be made to posts the user can see
+        let parent_author = match in_reply_to {
+            Some(parent) => Either::A(
+                self.posts
+                    .call(self, Message::new(GetPostsByIds(vec![parent], Some(user_id))))
+                    .map_err(|_, _, _| UserError::MailboxClosed)
+                    .and_then(|res, _, _| result(res.map_err(From::from)))
+                    .and_then(move |(posts, _), _, _| match posts.into_iter().next() {
+                        Some(post) => ok(Some(post.author)),
+                        None => err(PostsError::PostNotFound(parent).into()),
+                    }),
+            ),
+            None => Either::B(result(Ok(None))),
+        };
+
+        // Recipients are worked out before the post is created, since followers-only and direct
+        // posts keep them as the audience allowed to read them
+        let a_fut = parent_author
+            .and_then(move |parent_author, outbox, _| {
+                if let Some(author) = parent_author {
+                    if author != user_id {
+                        mentions.insert(author);
+                    }
+                }
 
-        let a_fut = self.posts
-            .call(self, post_message)
-            .map_err(|e, _, _| error!("Error: {}", e))
-            .and_then(move |post_id_res, outbox, _| {
-                user_2
+                outbox
+                    .user
                     .call(outbox, GetFollowers)
-                    .map_err(|e, _, _| error!("Error: {}", e))
-                    .and_then(move |followers_res, _, _| result(Ok((post_id_res, followers_res))))
+                    .map_err(|_, _, _| UserError::MailboxClosed)
+                    .and_then(|followers_res, _, _| result(followers_res))
+                    .map(move |followers, _, _| (mentions, followers))
             })
-            .and_then(|(post_id_res, followers_res), _, _| {
-                let res = post_id_res
-                    .and_then(|post_id| followers_res.map(|followers| (post_id, followers)));
+            .and_then(move |(mentions, followers), outbox, _| {
+                let mut recipients = visibility.recipients(&followers, &mentions);
+                recipients.remove(&user_id);
+
+                let post = NewPost {
+                    author: user_id,
+                    mentions: mentions.clone(),
+                    content: content.clone(),
+                    visibility: visibility,
+                    audience: if visibility.is_public() {
+                        BTreeSet::new()
+                    } else {
+                        recipients.clone()
+                    },
+                    in_reply_to: in_reply_to,
+                };
 
-                result(res)
+                outbox
+                    .posts
+                    .call(outbox, Message::new(post))
+                    .map_err(|_, _, _| UserError::MailboxClosed)
+                    .and_then(|post_id_res, _, _| result(post_id_res.map_err(From::from)))
+                    .map(move |post_id, _, _| {
+                        let new_post =
+                            NewPostIn(post_id, user_id, mentions, content, visibility, in_reply_to);
+
+                        (new_post, recipients)
+                    })
             })
-            .map(move |(post_id, followers), _, _| {
-                debug!("Dispatching {:?} to followers: {:?}", post_id, followers);
-                user.send(NewPostIn(post_id, user_id, mentions.clone()));
+            .map(move |(new_post, recipients), _, _| {
+                let post_id = new_post.0;
 
-                dispatch.send(DispatchAnnounce(
-                    NewPostIn(post_id, user_id, mentions.clone()),
-                    user_id,
-                    followers,
-                ));
+                debug!("Dispatching {:?} to recipients: {:?}", post_id, recipients);
+                user.send(new_post.clone());
+                dispatch.send(DispatchAnnounce(new_post, user_id, recipients));
 
                 post_id
             });
@@ -90,69 +262,271 @@ impl Handler<NewPostOut> for Outbox {
     }
 }
 
+impl Handler<Boost> for Outbox {
+    type Result = ResponseFuture<Self, Boost>;
+
+    fn handle(&mut self, msg: Boost, _: &mut Context<Self>) -> Self::Result {
+        let Boost(post_id) = msg;
+        let user_id = self.user_id;
+        debug!("user {:?} is boosting {:?}", user_id, post_id);
+
+        let fut = self.author_of(post_id)
+            .and_then(move |author, outbox, _| {
+                outbox
+                    .posts
+                    .call(outbox, Message::new(AddReaction(post_id, user_id, Reaction::Boost)))
+                    .map_err(|_, _, _| UserError::MailboxClosed)
+                    .and_then(|res, _, _| result(res.map_err(From::from)))
+                    .and_then(move |_, outbox, _| outbox.share(Boosted(post_id, user_id), author))
+            });
+
+        Box::new(fut)
+    }
+}
+
+impl Handler<Unboost> for Outbox {
+    type Result = ResponseFuture<Self, Unboost>;
+
+    fn handle(&mut self, msg: Unboost, _: &mut Context<Self>) -> Self::Result {
+        let Unboost(post_id) = msg;
+        let user_id = self.user_id;
+        debug!("user {:?} is unboosting {:?}", user_id, post_id);
+
+        let fut = self.posts
+            .call(self, Message::new(RemoveReaction(post_id, user_id, Reaction::Boost)))
+            .map_err(|_, _, _| UserError::MailboxClosed)
+            .and_then(|res, _, _| result(res.map_err(From::from)))
+            .and_then(move |post, outbox, _| {
+                outbox.share(Unboosted(post_id, user_id), post.author)
+            });
+
+        Box::new(fut)
+    }
+}
+
+impl Handler<Like> for Outbox {
+    type Result = ResponseFuture<Self, Like>;
+
+    fn handle(&mut self, msg: Like, _: &mut Context<Self>) -> Self::Result {
+        let Like(post_id) = msg;
+        let user_id = self.user_id;
+        debug!("user {:?} is liking {:?}", user_id, post_id);
+
+        let fut = self.author_of(post_id)
+            .and_then(move |author, outbox, _| {
+                outbox
+                    .posts
+                    .call(outbox, Message::new(AddReaction(post_id, user_id, Reaction::Like)))
+                    .map_err(|_, _, _| UserError::MailboxClosed)
+                    .and_then(|res, _, _| result(res.map_err(From::from)))
+                    .map(move |_, _, _| author)
+            })
+            .map(move |author, outbox, _| outbox.notify(Liked(post_id, user_id), author));
+
+        Box::new(fut)
+    }
+}
+
+impl Handler<Unlike> for Outbox {
+    type Result = ResponseFuture<Self, Unlike>;
+
+    fn handle(&mut self, msg: Unlike, _: &mut Context<Self>) -> Self::Result {
+        let Unlike(post_id) = msg;
+        let user_id = self.user_id;
+        debug!("user {:?} is unliking {:?}", user_id, post_id);
+
+        let fut = self.posts
+            .call(self, Message::new(RemoveReaction(post_id, user_id, Reaction::Like)))
+            .map_err(|_, _, _| UserError::MailboxClosed)
+            .and_then(|res, _, _| result(res.map_err(From::from)))
+            .map(move |post, outbox, _| outbox.notify(Unliked(post_id, user_id), post.author));
+
+        Box::new(fut)
+    }
+}
+
+impl Handler<GetPosts> for Outbox {
+    type Result = Result<SyncAddress<Peered<Posts>>, UserError>;
+
+    fn handle(&mut self, _: GetPosts, _: &mut Context<Self>) -> Self::Result {
+        Ok(self.posts.clone())
+    }
+}
+
+impl Handler<GetUserPostIds> for Outbox {
+    type Result = ResponseFuture<Self, GetUserPostIds>;
+
+    fn handle(&mut self, msg: GetUserPostIds, _: &mut Context<Self>) -> Self::Result {
+        let fut = self.user
+            .call(self, msg)
+            .map_err(|_, _, _| UserError::MailboxClosed)
+            .and_then(|res, _, _| result(res));
+
+        Box::new(fut)
+    }
+}
+
+impl Handler<GetVisiblePostIds> for Outbox {
+    type Result = ResponseFuture<Self, GetVisiblePostIds>;
+
+    fn handle(&mut self, msg: GetVisiblePostIds, _: &mut Context<Self>) -> Self::Result {
+        let GetVisiblePostIds(count, viewer) = msg;
+
+        let fut = self.user
+            .call(self, GetUserPostIds(count))
+            .map_err(|_, _, _| UserError::MailboxClosed)
+            .and_then(|res, _, _| result(res))
+            .and_then(move |post_ids, outbox, _| {
+                let post_ids = post_ids.into_iter().collect();
+
+                outbox
+                    .posts
+                    .call(outbox, Message::new(GetPostsByIds(post_ids, viewer)))
+                    .map_err(|_, _, _| UserError::MailboxClosed)
+                    .and_then(|res, _, _| result(res.map_err(From::from)))
+            })
+            .map(|(posts, _), _, _| posts.into_iter().map(|post| post.post_id).collect());
+
+        Box::new(fut)
+    }
+}
+
 impl Handler<DeletePost> for Outbox {
-    type Result = ();
+    type Result = ResponseFuture<Self, DeletePost>;
 
+    /// Fails unless the post is this user's own
     fn handle(&mut self, msg: DeletePost, _: &mut Context<Self>) -> Self::Result {
-        self.user.send(msg);
-        self.posts.send(Message::new(msg));
+        let user_id = self.user_id;
+
+        let fut = self.posts
+            .call(self, Message::new(GetPostsByIds(vec![msg.0], Some(user_id))))
+            .map_err(|_, _, _| UserError::MailboxClosed)
+            .and_then(|res, _, _| result(res.map_err(From::from)))
+            .and_then(move |(posts, _), outbox, _| {
+                let author = match posts.into_iter().next() {
+                    Some(post) => post.author,
+                    None => return result(Err(PostsError::PostNotFound(msg.0).into())),
+                };
+
+                if author != user_id {
+                    debug!("user {:?} can't delete {:?}", user_id, msg.0);
+                    return result(Err(PostsError::NotAuthor(msg.0).into()));
+                }
+
+                outbox.user.send(msg);
+                outbox.posts.send(Message::new(msg));
+
+                result(Ok(()))
+            });
+
+        Box::new(fut)
     }
 }
 
 impl Handler<RequestFollow> for Outbox {
-    type Result = ();
+    type Result = ResponseFuture<Self, RequestFollow>;
 
     fn handle(&mut self, msg: RequestFollow, _: &mut Context<Self>) -> Self::Result {
         debug!(
             "user {:?} requesting to follow user {:?}",
             self.user_id, msg.0
         );
+        // Recorded before it's sent so an accept coming straight back finds it pending, and
+        // rolled back if the request never reached them
         self.user.send(msg);
 
-        self.dispatch.send(DispatchMessage(
-            FollowRequest(self.user_id),
-            self.user_id,
-            msg.0,
-        ));
+        let fut = self.send_to(FollowRequest(self.user_id), msg.0)
+            .map_err(move |e, outbox, _| {
+                outbox.user.send(FollowRequestDenied(msg.0));
+                e
+            });
+
+        Box::new(fut)
     }
 }
 
 impl Handler<AcceptFollowRequest> for Outbox {
-    type Result = ();
+    type Result = ResponseFuture<Self, AcceptFollowRequest>;
 
     fn handle(&mut self, msg: AcceptFollowRequest, _: &mut Context<Self>) -> Self::Result {
         self.user.send(msg);
 
-        self.dispatch.send(DispatchMessage(
-            FollowRequestAccepted(self.user_id),
-            self.user_id,
-            msg.0,
-        ));
+        self.send_to(FollowRequestAccepted(self.user_id), msg.0)
     }
 }
 
 impl Handler<DenyFollowRequest> for Outbox {
-    type Result = ();
+    type Result = ResponseFuture<Self, DenyFollowRequest>;
 
     fn handle(&mut self, msg: DenyFollowRequest, _: &mut Context<Self>) -> Self::Result {
         self.user.send(msg);
 
-        self.dispatch.send(DispatchMessage(
-            FollowRequestDenied(self.user_id),
-            self.user_id,
-            msg.0,
-        ));
+        self.send_to(FollowRequestDenied(self.user_id), msg.0)
+    }
+}
+
+impl Handler<Unfollow> for Outbox {
+    type Result = ResponseFuture<Self, Unfollow>;
+
+    fn handle(&mut self, msg: Unfollow, _: &mut Context<Self>) -> Self::Result {
+        self.user.send(msg);
+
+        self.send_to(Unfollowed(self.user_id), msg.0)
+    }
+}
+
+impl Handler<RemoveFollower> for Outbox {
+    type Result = ResponseFuture<Self, RemoveFollower>;
+
+    fn handle(&mut self, msg: RemoveFollower, _: &mut Context<Self>) -> Self::Result {
+        self.user.send(msg);
+
+        self.send_to(FollowerRemoved(self.user_id), msg.0)
     }
 }
 
 impl Handler<BlockUser> for Outbox {
-    type Result = ();
+    type Result = ResponseFuture<Self, BlockUser>;
 
     fn handle(&mut self, msg: BlockUser, _: &mut Context<Self>) -> Self::Result {
-        self.dispatch
-            .send(DispatchMessage(Blocked(self.user_id), self.user_id, msg.0));
+        let user_id = self.user_id;
+
+        // Dispatch won't deliver between blocked users, so the block is only recorded once the
+        // blocked user has been told about it, and follows are only severed once it's recorded
+        let fut = self.dispatch
+            .call(self, DispatchMessage(Blocked(user_id), user_id, msg.0))
+            .then(move |_, outbox, _| {
+                outbox
+                    .blocklists
+                    .call(outbox, Message::new(Block(user_id, msg.0)))
+                    .map_err(|_, _, _| UserError::MailboxClosed)
+            })
+            .and_then(|res, _, _| result(res.map_err(From::from)))
+            .map(move |_, outbox, _| outbox.user.send(msg));
+
+        Box::new(fut)
+    }
+}
+
+impl Handler<UnblockUser> for Outbox {
+    type Result = ResponseFuture<Self, UnblockUser>;
+
+    fn handle(&mut self, msg: UnblockUser, _: &mut Context<Self>) -> Self::Result {
+        let user_id = self.user_id;
+
+        // The reverse of blocking, Dispatch can only reach the user once the block is lifted
+        let fut = self.blocklists
+            .call(self, Message::new(Unblock(user_id, msg.0)))
+            .map_err(|_, _, _| UserError::MailboxClosed)
+            .and_then(|res, _, _| result(res.map_err(From::from)))
+            .and_then(move |_, outbox, _| {
+                outbox
+                    .dispatch
+                    .call(outbox, DispatchMessage(Unblocked(user_id), user_id, msg.0))
+                    .map_err(|_, _, _| UserError::MailboxClosed)
+            })
+            .and_then(|res, _, _| result(res.map_err(From::from)));
 
-        self.blocklists
-            .send(Message::new(Block(self.user_id, msg.0)));
+        Box::new(fut)
     }
 }
@@ -0,0 +1,17 @@
+/// The public details of a user, as shown in their actor document
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Profile {
+    pub username: String,
+    pub display_name: Option<String>,
+    pub summary: Option<String>,
+}
+
+impl Profile {
+    pub fn new(username: &str) -> Self {
+        Profile {
+            username: username.to_owned(),
+            display_name: None,
+            summary: None,
+        }
+    }
+}
@@ -1,80 +1,319 @@
-use actors::peered::{HandleAnnounce, HandleMessage, HandleMessageType};
+use std::any::Any;
+
+use actix::SyncAddress;
+
+use actors::millis_since_epoch;
+use actors::peered::{shard_key, HandleAnnounce, HandleMessage, HandleMessageType, Peered, Route,
+                     Stamp};
+use actors::peered::messages::Announce;
+use actors::peered::transport::{answer_locally, Networked};
 use super::messages::*;
-use super::{UserAddress, UserId, Users};
+use super::{UserAddress, UserEntry, UserId, Users, UsersError};
 
 impl HandleMessage<Lookup> for Users {
     type Broadcast = ();
     type Item = UserAddress;
-    type Error = ();
+    type Error = UsersError;
+
+    fn handle_message(&mut self, msg: Lookup) -> HandleMessageType<UserAddress, UsersError, ()> {
+        (self.get_user(msg.0), None)
+    }
+
+    fn route(msg: Lookup) -> Route<Lookup, UserAddress> {
+        Route::Owned(shard_key(&msg.0), msg)
+    }
+}
+
+impl HandleMessage<LookupUsername> for Users {
+    type Broadcast = ();
+    type Item = (UserId, UserAddress);
+    type Error = UsersError;
+
+    fn handle_message(
+        &mut self,
+        msg: LookupUsername,
+    ) -> HandleMessageType<Self::Item, Self::Error, ()> {
+        (self.get_user_by_username(&msg.0), None)
+    }
 
-    fn handle_message(&mut self, msg: Lookup) -> HandleMessageType<UserAddress, (), ()> {
-        (self.get_user(msg.0).ok_or(()), None)
+    fn route(msg: LookupUsername) -> Route<LookupUsername, Self::Item> {
+        Route::Owned(shard_key(&msg.0), msg)
     }
 }
 
 impl HandleMessage<LookupMany> for Users {
     type Broadcast = ();
     type Item = (Vec<UserAddress>, Vec<UserId>);
-    type Error = ();
+    type Error = UsersError;
 
-    fn handle_message(&mut self, msg: LookupMany) -> HandleMessageType<Self::Item, (), ()> {
+    fn handle_message(
+        &mut self,
+        msg: LookupMany,
+    ) -> HandleMessageType<Self::Item, Self::Error, ()> {
         (Ok(self.get_users(msg.0)), None)
     }
+
+    fn route(msg: LookupMany) -> Route<LookupMany, Self::Item> {
+        let parts = msg.0
+            .into_iter()
+            .map(|user_id| {
+                let single = vec![user_id].into_iter().collect();
+
+                (shard_key(&user_id), LookupMany(single))
+            })
+            .collect();
+
+        Route::Split(parts, merge_lookups)
+    }
+}
+
+fn merge_lookups(
+    answers: Vec<(Vec<UserAddress>, Vec<UserId>)>,
+) -> (Vec<UserAddress>, Vec<UserId>) {
+    answers.into_iter().fold(
+        (Vec::new(), Vec::new()),
+        |(mut addrs, mut missing), (found, not_found)| {
+            addrs.extend(found);
+            missing.extend(not_found);
+
+            (addrs, missing)
+        },
+    )
 }
 
 impl HandleMessage<NewUser> for Users {
     type Broadcast = NewUserFull;
     type Item = UserId;
-    type Error = ();
+    type Error = UsersError;
 
     fn handle_message(
         &mut self,
         msg: NewUser,
-    ) -> HandleMessageType<Self::Item, (), Self::Broadcast> {
-        let (user_id, user_address) = self.new_user(msg.0, msg.1);
+    ) -> HandleMessageType<Self::Item, Self::Error, Self::Broadcast> {
+        match self.new_user(msg.0, msg.1, msg.2) {
+            Ok((user_id, user_address)) => {
+                (Ok(user_id), Some(NewUserFull(user_id, UserEntry::Started(user_address))))
+            }
+            Err(e) => (Err(e), None),
+        }
+    }
+}
+
+impl HandleMessage<RestoreUsers> for Users {
+    type Broadcast = RestoredUsers;
+    type Item = usize;
+    type Error = UsersError;
+
+    fn handle_message(
+        &mut self,
+        msg: RestoreUsers,
+    ) -> HandleMessageType<usize, UsersError, RestoredUsers> {
+        let restored = self.restore_users(msg.0, msg.1);
+
+        if restored.is_empty() {
+            return (Ok(0), None);
+        }
 
-        (Ok(user_id), Some(NewUserFull(user_id, user_address)))
+        (Ok(restored.len()), Some(RestoredUsers(restored)))
     }
 }
 
 impl HandleMessage<DeleteUser> for Users {
     type Broadcast = DeleteUser;
     type Item = ();
-    type Error = ();
+    type Error = UsersError;
 
-    fn handle_message(&mut self, msg: DeleteUser) -> HandleMessageType<(), (), DeleteUser> {
-        self.delete_user(msg.0);
+    fn handle_message(
+        &mut self,
+        msg: DeleteUser,
+    ) -> HandleMessageType<(), UsersError, DeleteUser> {
+        self.delete_user(msg.0, millis_since_epoch());
 
         (Ok(()), Some(msg))
     }
 }
 
+impl HandleMessage<Deliver> for Users {
+    type Broadcast = Deliver;
+    type Item = ();
+    type Error = UsersError;
+
+    fn handle_message(&mut self, msg: Deliver) -> HandleMessageType<(), UsersError, Deliver> {
+        let Deliver(user_ids, message) = msg;
+        let (addrs, elsewhere) = self.get_users(user_ids);
+
+        for addr in addrs {
+            message.clone().send_to(addr.inbox());
+        }
+
+        if elsewhere.is_empty() {
+            return (Ok(()), None);
+        }
+
+        (Ok(()), Some(Deliver(elsewhere.into_iter().collect(), message)))
+    }
+}
+
 impl HandleMessage<UserSize> for Users {
     type Broadcast = ();
     type Item = usize;
-    type Error = ();
+    type Error = UsersError;
 
-    fn handle_message(&mut self, _: UserSize) -> HandleMessageType<usize, (), ()> {
+    fn handle_message(&mut self, _: UserSize) -> HandleMessageType<usize, UsersError, ()> {
         (Ok(self.users.len()), None)
     }
 }
 
 impl HandleAnnounce<NewUserFull> for Users {
     type Item = ();
-    type Error = ();
+    type Error = UsersError;
+
+    /// Errors with `UsernameTaken` when a user with a lower id holds the username
+    fn handle_announce(&mut self, msg: NewUserFull) -> Result<(), UsersError> {
+        self.add_user(msg.0, msg.1)
+    }
+
+    /// Users are kept by the owners of both their id and their username, so either finds them
+    fn keys(msg: &NewUserFull) -> Vec<u64> {
+        vec![shard_key(&msg.0), shard_key(&msg.1.username())]
+    }
+}
+
+impl HandleAnnounce<RestoredUsers> for Users {
+    type Item = ();
+    type Error = UsersError;
+
+    /// Username conflicts are logged by `add_user` and don't stop the other users being added
+    fn handle_announce(&mut self, msg: RestoredUsers) -> Result<(), UsersError> {
+        for (user_id, entry) in msg.0 {
+            let _ = self.add_user(user_id, entry);
+        }
 
-    fn handle_announce(&mut self, msg: NewUserFull) -> Result<(), ()> {
-        self.add_user(msg.0, msg.1);
         Ok(())
     }
+
+    fn keys(msg: &RestoredUsers) -> Vec<u64> {
+        msg.0
+            .iter()
+            .flat_map(|&(ref user_id, ref entry)| {
+                vec![shard_key(user_id), shard_key(&entry.username())]
+            })
+            .collect()
+    }
 }
 
 impl HandleAnnounce<DeleteUser> for Users {
     type Item = ();
-    type Error = ();
+    type Error = UsersError;
 
-    fn handle_announce(&mut self, msg: DeleteUser) -> Result<(), ()> {
-        self.delete_user(msg.0);
+    fn handle_announce(&mut self, msg: DeleteUser) -> Result<(), UsersError> {
+        self.delete_user(msg.0, millis_since_epoch());
         Ok(())
     }
 }
+
+impl HandleAnnounce<Deliver> for Users {
+    type Item = ();
+    type Error = UsersError;
+
+    /// Only the node that made a user hands it the message, so that nodes sharing its process
+    /// don't deliver it again
+    fn handle_announce(&mut self, msg: Deliver) -> Result<(), UsersError> {
+        let Deliver(user_ids, message) = msg;
+        let users_id = self.users_id;
+
+        let made_here = user_ids
+            .into_iter()
+            .filter(|user_id| user_id.0 == users_id)
+            .collect();
+
+        for addr in self.get_users(made_here).0 {
+            message.clone().send_to(addr.inbox());
+        }
+
+        Ok(())
+    }
+}
+
+/// Users in other processes are only known by their username, see `UserEntry`
+impl Networked for Users {
+    const SERVICE: &'static str = "users";
+
+    type Broadcast = UsersBroadcast;
+    type Query = UsersQuery;
+    type Answer = UsersAnswer;
+
+    fn to_wire(broadcast: &Any) -> Option<UsersBroadcast> {
+        if let Some(new_user) = broadcast.downcast_ref::<NewUserFull>() {
+            return Some(UsersBroadcast::NewUser(new_user.clone()));
+        }
+
+        if let Some(restored) = broadcast.downcast_ref::<RestoredUsers>() {
+            return Some(UsersBroadcast::RestoredUsers(restored.clone()));
+        }
+
+        if let Some(delete) = broadcast.downcast_ref::<DeleteUser>() {
+            return Some(UsersBroadcast::DeleteUser(delete.clone()));
+        }
+
+        broadcast
+            .downcast_ref::<Deliver>()
+            .map(|deliver| UsersBroadcast::Deliver(deliver.clone()))
+    }
+
+    fn announce(local: &SyncAddress<Peered<Self>>, stamp: Stamp, broadcast: UsersBroadcast) {
+        match broadcast {
+            UsersBroadcast::NewUser(new_user) => local.send(Announce::new(stamp, new_user)),
+            UsersBroadcast::RestoredUsers(restored) => local.send(Announce::new(stamp, restored)),
+            UsersBroadcast::DeleteUser(delete) => local.send(Announce::new(stamp, delete)),
+            UsersBroadcast::Deliver(deliver) => local.send(Announce::new(stamp, deliver)),
+        }
+    }
+
+    fn query_to_wire(message: &Any) -> Option<UsersQuery> {
+        if let Some(lookup) = message.downcast_ref::<Lookup>() {
+            return Some(UsersQuery::Lookup(lookup.clone()));
+        }
+
+        if let Some(lookup) = message.downcast_ref::<LookupUsername>() {
+            return Some(UsersQuery::LookupUsername(lookup.clone()));
+        }
+
+        message
+            .downcast_ref::<LookupMany>()
+            .map(|lookup| UsersQuery::LookupMany(lookup.clone()))
+    }
+
+    fn answer(local: &SyncAddress<Peered<Self>>, query: UsersQuery) -> Option<UsersAnswer> {
+        match query {
+            UsersQuery::Lookup(msg) => {
+                let user_id = msg.0;
+
+                answer_locally(local, msg).map(|res| {
+                    UsersAnswer::Lookup(res.err().unwrap_or(UsersError::UserElsewhere(user_id)))
+                })
+            }
+            UsersQuery::LookupUsername(msg) => answer_locally(local, msg).map(|res| {
+                UsersAnswer::LookupUsername(match res {
+                    Ok((user_id, _)) => UsersError::UserElsewhere(user_id),
+                    Err(e) => e,
+                })
+            }),
+            UsersQuery::LookupMany(msg) => {
+                let user_ids = msg.0.iter().cloned().collect();
+
+                answer_locally(local, msg).map(|res| UsersAnswer::LookupMany(res.map(|_| user_ids)))
+            }
+        }
+    }
+
+    fn from_answer(answer: UsersAnswer) -> Box<Any + Send> {
+        match answer {
+            UsersAnswer::Lookup(e) => Box::new(Err::<UserAddress, _>(e)),
+            UsersAnswer::LookupUsername(e) => Box::new(Err::<(UserId, UserAddress), _>(e)),
+            UsersAnswer::LookupMany(res) => {
+                Box::new(res.map(|user_ids| (Vec::<UserAddress>::new(), user_ids)))
+            }
+        }
+    }
+}
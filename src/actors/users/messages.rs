@@ -4,28 +4,82 @@ use actix::SyncAddress;
 
 use actors::blocklist::Blocklists;
 use actors::peered::Peered;
-use super::{UserAddress, UserId, Users};
+use actors::user::Profile;
+use actors::user::messages::InboxMessage;
+use super::{UserAddress, UserEntry, UserId, Users, UsersError};
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Lookup(pub UserId);
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct LookupMany(pub BTreeSet<UserId>);
 
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct LookupUsername(pub String);
+
+/// NewUser(users, blocklists, profile), failing if the username is taken
 #[derive(Clone)]
 pub struct NewUser(
     pub SyncAddress<Peered<Users>>,
     pub SyncAddress<Peered<Blocklists>>,
+    pub Profile,
 );
 
+/// RestoreUsers(users, blocklists), starting the users kept in storage
 #[derive(Clone)]
-pub struct NewUserFull(pub UserId, pub UserAddress);
+pub struct RestoreUsers(
+    pub SyncAddress<Peered<Users>>,
+    pub SyncAddress<Peered<Blocklists>>,
+);
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct NewUserFull(pub UserId, pub UserEntry);
+
+/// The users started by a `RestoreUsers`, announced so that running peers learn of them
+#[derive(Clone, Deserialize, Serialize)]
+pub struct RestoredUsers(pub Vec<(UserId, UserEntry)>);
 
 #[derive(Clone)]
 pub struct AnnounceNewUser(pub UserId, pub UserAddress);
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct DeleteUser(pub UserId);
 
 #[derive(Clone, Debug)]
 pub struct UserSize;
+
+/// Deliver(recipients, message), handing the message to the inboxes of users whose actors may run
+/// in other processes
+///
+/// Users started here get it straight away, the others through a broadcast their own process acts
+/// on.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Deliver(pub BTreeSet<UserId>, pub InboxMessage);
+
+/// The broadcasts `Users` sends to peers in other processes
+#[derive(Deserialize, Serialize)]
+pub enum UsersBroadcast {
+    NewUser(NewUserFull),
+    RestoredUsers(RestoredUsers),
+    DeleteUser(DeleteUser),
+    Deliver(Deliver),
+}
+
+/// The messages `Users` has answered by the owners of a user in other processes
+#[derive(Deserialize, Serialize)]
+pub enum UsersQuery {
+    Lookup(Lookup),
+    LookupUsername(LookupUsername),
+    LookupMany(LookupMany),
+}
+
+/// The answers to `UsersQuery`s
+///
+/// A user's actors can only be reached in the process they run in, so the users found are
+/// answered as being elsewhere, or in the case of `LookupMany` as missing.
+#[derive(Deserialize, Serialize)]
+pub enum UsersAnswer {
+    Lookup(UsersError),
+    LookupUsername(UsersError),
+    LookupMany(Result<Vec<UserId>, UsersError>),
+}
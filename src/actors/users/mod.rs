@@ -1,56 +1,128 @@
 use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
 
 use actix::SyncAddress;
 
+use activitypub::KeyPair;
+use federation::Federation;
+use storage::Storage;
 use super::blocklist::Blocklists;
-use super::{Id, UserId, UsersId};
+use super::{millis_since_epoch, Id, UserId, UsersId, TOMBSTONE_TTL_MS};
 use super::peered::Peered;
 use super::posts::Posts;
-use super::user::User;
+use super::user::{Profile, User};
 use super::user::inbox::Inbox;
 use super::user::outbox::Outbox;
-use super::peered::PeeredInner;
+use super::peered::{shard_key, Digest, PeeredError, PeeredInner};
 
 mod actor;
 pub mod messages;
 mod user_address;
 
-pub use self::user_address::UserAddress;
+pub use self::user_address::{UserAddress, UserEntry};
 
 const BACKFILL_CHUNK_SIZE: usize = 100;
+const COUNTER: &'static str = "users";
+
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum UsersError {
+    UserNotFound(UserId),
+    /// The user's actors run in another process, which only dispatched messages reach
+    UserElsewhere(UserId),
+    UsernameNotFound(String),
+    UsernameTaken(String),
+    Keys(String),
+    /// The user's owners couldn't answer
+    Peered(PeeredError),
+}
+
+impl fmt::Display for UsersError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            UsersError::UserNotFound(user_id) => write!(f, "No user {:?}", user_id),
+            UsersError::UserElsewhere(user_id) => {
+                write!(f, "User {:?} is served by another process", user_id)
+            }
+            UsersError::UsernameNotFound(ref username) => write!(f, "No user named {}", username),
+            UsersError::UsernameTaken(ref username) => {
+                write!(f, "The username {} is taken", username)
+            }
+            UsersError::Keys(ref e) => write!(f, "Could not generate keys: {}", e),
+            UsersError::Peered(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<PeeredError> for UsersError {
+    fn from(e: PeeredError) -> Self {
+        UsersError::Peered(e)
+    }
+}
 
 pub struct Users {
     users_id: UsersId,
     current_id: u64,
-    users: BTreeMap<UserId, UserAddress>,
+    users: BTreeMap<UserId, UserEntry>,
+    usernames: BTreeMap<String, UserId>,
+    /// Deleted users and when they were deleted, see `TOMBSTONE_TTL_MS`
+    deleted: BTreeMap<UserId, u64>,
     posts: SyncAddress<Peered<Posts>>,
+    federation: Federation,
+    storage: Storage,
 }
 
 impl Users {
-    pub fn new(users_id: UsersId, posts: SyncAddress<Peered<Posts>>) -> Self {
-        Users {
+    /// Creates an empty `Users`, stored users are only started once `RestoreUsers` is sent
+    pub fn new(
+        users_id: UsersId,
+        posts: SyncAddress<Peered<Posts>>,
+        federation: Federation,
+        storage: Storage,
+    ) -> Self {
+        let mut users = Users {
             users_id: users_id,
-            current_id: 0,
+            current_id: storage.counter(COUNTER),
             users: BTreeMap::new(),
+            usernames: BTreeMap::new(),
+            deleted: storage.deleted_users().into_iter().collect(),
             posts: posts,
-        }
+            federation: federation,
+            storage: storage,
+        };
+
+        users.expire_tombstones(millis_since_epoch());
+        users
     }
 
     fn gen_next_id(&mut self) -> UserId {
         let id = Id(self.current_id);
         self.current_id += 1;
+        self.storage.set_counter(COUNTER, self.current_id);
         UserId(self.users_id, id)
     }
 
-    fn get_user(&self, user_id: UserId) -> Option<UserAddress> {
-        self.users.get(&user_id).cloned()
+    fn get_user(&self, user_id: UserId) -> Result<UserAddress, UsersError> {
+        match self.users.get(&user_id) {
+            Some(&UserEntry::Started(ref user_address)) => Ok(user_address.clone()),
+            Some(&UserEntry::Elsewhere(_)) => Err(UsersError::UserElsewhere(user_id)),
+            None => Err(UsersError::UserNotFound(user_id)),
+        }
+    }
+
+    fn get_user_by_username(&self, username: &str) -> Result<(UserId, UserAddress), UsersError> {
+        let user_id = *self.usernames
+            .get(username)
+            .ok_or_else(|| UsersError::UsernameNotFound(username.to_owned()))?;
+
+        self.get_user(user_id).map(|addr| (user_id, addr))
     }
 
+    /// The addresses of the users started in this process, the others being reported missing
     fn get_users(&self, user_ids: BTreeSet<UserId>) -> (Vec<UserAddress>, Vec<UserId>) {
         user_ids.into_iter().fold(
             (Vec::new(), Vec::new()),
             |(mut addrs, mut user_ids), user_id| {
-                if let Some(addr) = self.users.get(&user_id).cloned() {
+                if let Ok(addr) = self.get_user(user_id) {
                     addrs.push(addr);
                 } else {
                     user_ids.push(user_id);
@@ -61,32 +133,158 @@ impl Users {
         )
     }
 
-    fn add_user(&mut self, user_id: UserId, user_address: UserAddress) {
-        self.users.insert(user_id, user_address);
+    /// Adds a user, the lower `UserId` keeping a username that two nodes handed out at once
+    ///
+    /// The losing user can still be looked up by id. Conflicts are logged here, the error naming
+    /// the username that was lost for callers that report it further.
+    fn add_user(&mut self, user_id: UserId, entry: UserEntry) -> Result<(), UsersError> {
+        if self.deleted.contains_key(&user_id) {
+            return Ok(());
+        }
+
+        let username = entry.username().to_owned();
+
+        // A user started here stays reachable when a peer names it as being elsewhere
+        let started_here = match (self.users.get(&user_id), &entry) {
+            (Some(&UserEntry::Started(_)), &UserEntry::Elsewhere(_)) => true,
+            _ => false,
+        };
+
+        if !started_here {
+            self.users.insert(user_id, entry);
+        }
+
+        let holder = self.usernames.get(&username).cloned();
+
+        match holder {
+            Some(holder) if holder == user_id => Ok(()),
+            Some(holder) if holder < user_id => {
+                warn!("User {} lost the username {} to {}", user_id, username, holder);
+                Err(UsersError::UsernameTaken(username))
+            }
+            Some(holder) => {
+                warn!("User {} lost the username {} to {}", holder, username, user_id);
+                self.usernames.insert(username.clone(), user_id);
+                Err(UsersError::UsernameTaken(username))
+            }
+            None => {
+                self.usernames.insert(username, user_id);
+                Ok(())
+            }
+        }
+    }
+
+    /// Frees the username of a user that is leaving, unless another user holds it
+    fn release_username(&mut self, user_id: UserId, username: &str) {
+        if self.usernames.get(username) == Some(&user_id) {
+            self.usernames.remove(username);
+        }
     }
 
     fn new_user(
         &mut self,
         users: SyncAddress<Peered<Users>>,
         blocklists: SyncAddress<Peered<Blocklists>>,
-    ) -> (UserId, UserAddress) {
-        let posts = self.posts.clone();
+        profile: Profile,
+    ) -> Result<(UserId, UserAddress), UsersError> {
+        if self.usernames.contains_key(&profile.username) {
+            return Err(UsersError::UsernameTaken(profile.username));
+        }
+
+        let keys = KeyPair::generate().map_err(|e| UsersError::Keys(format!("{}", e)))?;
+
         let user_id = self.gen_next_id();
-        let user_address = UserAddress::new(user_id, posts, users, blocklists);
+        self.storage.save_user(user_id, &profile, &keys);
 
-        self.add_user(user_id, user_address.clone());
+        let user_address = self.start_user(user_id, profile, keys, users, blocklists);
 
-        (user_id, user_address)
+        Ok((user_id, user_address))
     }
 
-    fn delete_user(&mut self, user_id: UserId) {
-        self.users.remove(&user_id);
+    /// Starts every user kept in storage, returning them so they can be announced
+    fn restore_users(
+        &mut self,
+        users: SyncAddress<Peered<Users>>,
+        blocklists: SyncAddress<Peered<Blocklists>>,
+    ) -> Vec<(UserId, UserEntry)> {
+        self.storage
+            .users()
+            .into_iter()
+            .map(|(user_id, profile, keys)| {
+                debug!("Restoring user {:?}", user_id);
+                let user_address =
+                    self.start_user(user_id, profile, keys, users.clone(), blocklists.clone());
+
+                (user_id, UserEntry::Started(user_address))
+            })
+            .collect()
+    }
+
+    fn start_user(
+        &mut self,
+        user_id: UserId,
+        profile: Profile,
+        keys: KeyPair,
+        users: SyncAddress<Peered<Users>>,
+        blocklists: SyncAddress<Peered<Blocklists>>,
+    ) -> UserAddress {
+        let user = User::new(user_id, profile, keys, self.storage.clone());
+        let posts = self.posts.clone();
+        let federation = self.federation.clone();
+        let user_address = UserAddress::new(user, posts, users, blocklists, federation);
+
+        let _ = self.add_user(user_id, UserEntry::Started(user_address.clone()));
+
+        user_address
+    }
+
+    /// Removes a user deleted at `deleted_at`, remembering it until `TOMBSTONE_TTL_MS` passed
+    fn delete_user(&mut self, user_id: UserId, deleted_at: u64) {
+        let now = millis_since_epoch();
+
+        if deleted_at + TOMBSTONE_TTL_MS > now {
+            // Peers may report the delete later than it happened, the earliest time is kept
+            let deleted_at = match self.deleted.get(&user_id) {
+                Some(&earlier) => earlier.min(deleted_at),
+                None => deleted_at,
+            };
+
+            self.storage.save_deleted_user(user_id, deleted_at);
+            self.deleted.insert(user_id, deleted_at);
+        }
+
+        self.expire_tombstones(now);
+
+        if let Some(entry) = self.users.remove(&user_id) {
+            self.release_username(user_id, entry.username());
+            self.storage.delete_user(user_id);
+        }
+    }
+
+    /// Forgets the users deleted longer than `TOMBSTONE_TTL_MS` ago
+    fn expire_tombstones(&mut self, now: u64) {
+        let expired: Vec<UserId> = self.deleted
+            .iter()
+            .filter(|&(_, &deleted_at)| deleted_at + TOMBSTONE_TTL_MS <= now)
+            .map(|(user_id, _)| *user_id)
+            .collect();
+
+        for user_id in expired {
+            self.deleted.remove(&user_id);
+            self.storage.forget_deleted_user(user_id);
+        }
     }
 }
 
+/// The `shard_key`s a user is kept under, its id and its username
+fn user_keys(user_id: &UserId, entry: &UserEntry) -> [u64; 2] {
+    [shard_key(user_id), shard_key(&entry.username())]
+}
+
 impl PeeredInner for Users {
-    type Backfill = (usize, Vec<(UserId, UserAddress)>);
+    type Backfill = (usize, Vec<(UserId, UserEntry)>);
     type Request = usize;
+    type Diff = (Vec<(UserId, UserEntry)>, Vec<(UserId, u64)>);
 
     fn backfill(&self, req: Self::Request) -> Self::Backfill {
         let u = self.users
@@ -110,8 +308,88 @@ impl PeeredInner for Users {
             None
         };
 
-        self.users.extend(backfill.1);
+        for (user_id, entry) in backfill.1 {
+            let _ = self.add_user(user_id, entry);
+        }
 
         ret
     }
+
+    /// Users are compared where both nodes keep them, by their id or by their username
+    fn digest(&self, shared: &Fn(&[u64]) -> bool) -> Digest {
+        let mut digest = Digest::new();
+
+        for (user_id, entry) in &self.users {
+            if shared(&user_keys(user_id, entry)) {
+                digest.insert(user_id, &Some(entry.username()));
+            }
+        }
+
+        for user_id in self.deleted.keys() {
+            if shared(&[shard_key(user_id)]) {
+                digest.insert(user_id, &None::<&str>);
+            }
+        }
+
+        digest
+    }
+
+    fn diff(&self, buckets: &[usize], shared: &Fn(&[u64]) -> bool) -> Self::Diff {
+        let users = self.users
+            .iter()
+            .filter(|&(user_id, entry)| {
+                buckets.contains(&Digest::bucket(user_id)) && shared(&user_keys(user_id, entry))
+            })
+            .map(|(a, b)| (*a, b.clone()))
+            .collect();
+
+        let deleted = self.deleted
+            .iter()
+            .filter(|&(user_id, _)| {
+                buckets.contains(&Digest::bucket(user_id)) && shared(&[shard_key(user_id)])
+            })
+            .map(|(a, b)| (*a, *b))
+            .collect();
+
+        (users, deleted)
+    }
+
+    fn reconcile(&mut self, diff: Self::Diff) {
+        let (users, deleted) = diff;
+
+        for (user_id, deleted_at) in deleted {
+            self.delete_user(user_id, deleted_at);
+        }
+
+        for (user_id, entry) in users {
+            if !self.users.contains_key(&user_id) {
+                let _ = self.add_user(user_id, entry);
+            }
+        }
+    }
+
+    /// Users stay in storage wherever they were created, so they're started again on restart
+    fn evict(&mut self, owns: &Fn(u64) -> bool) -> Option<Self::Diff> {
+        let evicted: Vec<UserId> = self.users
+            .iter()
+            .filter(|&(user_id, entry)| !user_keys(user_id, entry).iter().any(|key| owns(*key)))
+            .map(|(user_id, _)| *user_id)
+            .collect();
+
+        if evicted.is_empty() {
+            return None;
+        }
+
+        let users = evicted
+            .into_iter()
+            .filter_map(|user_id| {
+                let entry = self.users.remove(&user_id)?;
+                self.release_username(user_id, entry.username());
+
+                Some((user_id, entry))
+            })
+            .collect();
+
+        Some((users, Vec::new()))
+    }
 }
@@ -1,35 +1,56 @@
 use actix::{Actor, Address, SyncAddress};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
 use actors::blocklist::Blocklists;
 use actors::peered::Peered;
-use super::{Inbox, Outbox, Posts, User, UserId, Users};
+use federation::Federation;
+use super::{Inbox, Outbox, Posts, User, Users};
 
 #[derive(Clone)]
 pub struct UserAddress {
+    username: String,
     user: SyncAddress<User>,
     inbox: SyncAddress<Inbox>,
     outbox: SyncAddress<Outbox>,
 }
 
 impl UserAddress {
+    /// Starts the actors of a user
     pub fn new(
-        user_id: UserId,
+        user: User,
         posts: SyncAddress<Peered<Posts>>,
         users: SyncAddress<Peered<Users>>,
         blocklists: SyncAddress<Peered<Blocklists>>,
+        federation: Federation,
     ) -> Self {
-        let (user_local, user): (Address<_>, SyncAddress<_>) = User::new(user_id).start();
+        let user_id = user.user_id();
+        let username = user.username().to_owned();
+        let keys = user.keys().clone();
+        let (user_local, user): (Address<_>, SyncAddress<_>) = user.start();
 
         let inbox = Inbox::new(user_local.clone(), users.clone()).start();
-        let outbox = Outbox::new(user_id, user_local, posts, users, blocklists).start();
+        let outbox = Outbox::new(
+            user_id,
+            user_local,
+            posts,
+            users,
+            blocklists,
+            federation,
+            keys,
+        ).start();
 
         UserAddress {
+            username,
             user,
             inbox,
             outbox,
         }
     }
 
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+
     pub fn user(&self) -> &SyncAddress<User> {
         &self.user
     }
@@ -42,3 +63,33 @@ impl UserAddress {
         &self.outbox
     }
 }
+
+/// A user as `Users` keeps it, either started in this process or only known by its username
+/// because its actors run in another process
+#[derive(Clone)]
+pub enum UserEntry {
+    Started(UserAddress),
+    Elsewhere(String),
+}
+
+impl UserEntry {
+    pub fn username(&self) -> &str {
+        match *self {
+            UserEntry::Started(ref user_address) => user_address.username(),
+            UserEntry::Elsewhere(ref username) => username,
+        }
+    }
+}
+
+/// Actors can't be reached from other processes, so only the username is sent
+impl Serialize for UserEntry {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.username())
+    }
+}
+
+impl<'de> Deserialize<'de> for UserEntry {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer).map(UserEntry::Elsewhere)
+    }
+}
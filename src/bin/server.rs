@@ -0,0 +1,141 @@
+extern crate actix;
+extern crate actix_ap_demo;
+extern crate actix_web;
+extern crate env_logger;
+#[macro_use]
+extern crate log;
+extern crate serde;
+
+use std::env;
+use std::process;
+use std::sync::{Arc, Mutex};
+
+use actix::{Actor, SyncAddress, System};
+use actix_web::HttpServer;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use actix_ap_demo::activitypub::IriMap;
+use actix_ap_demo::actors::Id;
+use actix_ap_demo::actors::blocklist::Blocklists;
+use actix_ap_demo::actors::peered::{Peered, PeeredInner};
+use actix_ap_demo::actors::peered::messages::Message;
+use actix_ap_demo::actors::peered::transport::{Networked, Transport};
+use actix_ap_demo::actors::posts::Posts;
+use actix_ap_demo::actors::users::Users;
+use actix_ap_demo::actors::users::messages::RestoreUsers;
+use actix_ap_demo::config::Config;
+use actix_ap_demo::federation::{Federation, HttpClient};
+use actix_ap_demo::storage::{SqliteBackend, Storage, StorageError};
+use actix_ap_demo::web::{self, State};
+
+fn main() {
+    let path = env::args()
+        .nth(1)
+        .unwrap_or_else(|| "config.toml".to_owned());
+
+    let config = match Config::from_file(&path) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}: {}", path, e);
+            process::exit(1);
+        }
+    };
+
+    env_logger::Builder::new().parse(&config.log_level).init();
+
+    let storage = match open_storage(config.database.as_ref()) {
+        Ok(storage) => storage,
+        Err(e) => {
+            error!("Could not open {:?}: {}", config.database, e);
+            process::exit(1);
+        }
+    };
+
+    let system = System::new("actix-ap-demo");
+
+    let node_id = Id::new(config.node_id);
+
+    let iris = Arc::new(Mutex::new(IriMap::new(&config.base_url, storage.clone())));
+    let federation = Federation::new(iris, Arc::new(HttpClient));
+
+    let peering = config.peer_listen.as_ref().map(|peer_listen| {
+        let secret = config.peer_secret.as_ref().map_or("", |secret| secret.as_str());
+
+        match Transport::bind(peer_listen, secret) {
+            Ok(transport) => {
+                info!("Peering on {}", transport.address());
+                transport
+            }
+            Err(e) => {
+                error!("Could not bind to {}: {}", peer_listen, e);
+                process::exit(1);
+            }
+        }
+    });
+    let transport = peering.as_ref();
+
+    let posts = shard(Peered::new(Posts::new(node_id, storage.clone())), &config);
+    let posts = start(posts, transport, &config);
+    let users = Peered::new(Users::new(node_id, posts, federation.clone(), storage.clone()));
+    let users = start(shard(users, &config), transport, &config);
+    let blocklists = start(Peered::new(Blocklists::new(storage)), transport, &config);
+
+    users.send(Message::new(RestoreUsers(users.clone(), blocklists)));
+
+    // SyncAddress isn't Sync, so the state is handed to each worker through a Mutex
+    let state = Mutex::new(State::new(users, federation, config.outbox_tokens.clone()));
+
+    let bound =
+        HttpServer::new(move || web::app(state.lock().unwrap().clone())).bind(&config.listen);
+
+    match bound {
+        Ok(server) => {
+            server.start();
+        }
+        Err(e) => {
+            error!("Could not bind to {}: {}", config.listen, e);
+            process::exit(1);
+        }
+    }
+
+    info!("Node {} listening on {}", config.node_id, config.listen);
+
+    system.run();
+}
+
+/// Starts `peered`, serving it to the configured peers when peering is on
+fn start<T>(
+    peered: Peered<T>,
+    transport: Option<&Transport>,
+    config: &Config,
+) -> SyncAddress<Peered<T>>
+where
+    T: Networked,
+    T::Request: Serialize + DeserializeOwned,
+    T::Backfill: Serialize + DeserializeOwned,
+    T::Diff: Serialize + DeserializeOwned,
+{
+    match transport {
+        Some(transport) => transport.start(peered, &config.peers),
+        None => peered.start(),
+    }
+}
+
+/// Shards `peered` when the config sets a replication factor
+fn shard<T>(peered: Peered<T>, config: &Config) -> Peered<T>
+where
+    T: PeeredInner + 'static,
+{
+    match config.replication {
+        Some(replication) => peered.sharded(replication),
+        None => peered,
+    }
+}
+
+fn open_storage(database: Option<&String>) -> Result<Storage, StorageError> {
+    match database {
+        Some(path) => Ok(Storage::new(Arc::new(SqliteBackend::open(path)?))),
+        None => Ok(Storage::memory()),
+    }
+}
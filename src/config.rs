@@ -0,0 +1,223 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+use toml;
+
+use actors::UserId;
+
+/// Settings for a single node, read from a TOML file
+///
+/// ```toml
+/// node_id = 0
+/// listen = "127.0.0.1:8080"
+/// base_url = "https://example.com"
+/// peer_listen = "0.0.0.0:9090"
+/// peer_secret = "another long random string"
+/// peers = ["10.0.0.2:9090"]
+/// replication = 2
+/// log_level = "info"
+/// database = "/var/lib/actix-ap-demo/node.sqlite"
+///
+/// [outbox_tokens]
+/// "0-0" = "a long random string"
+/// ```
+#[derive(Clone, Debug, Deserialize)]
+pub struct Config {
+    /// Used as both the `PostsId` and the `UsersId` of this node, so must be unique in the cluster
+    pub node_id: u64,
+    /// The address the HTTP server binds to
+    pub listen: String,
+    /// The public URL local users and posts are named under
+    pub base_url: String,
+    /// The address other nodes of the cluster reach this one at, if unset it doesn't peer and
+    /// `peers` must be empty
+    #[serde(default)]
+    pub peer_listen: Option<String>,
+    /// The secret shared by every node of the cluster, which peers must prove they know before
+    /// they're listened to. Required with `peer_listen`.
+    #[serde(default)]
+    pub peer_secret: Option<String>,
+    /// The `peer_listen` addresses of other nodes of the cluster to peer with
+    #[serde(default)]
+    pub peers: Vec<String>,
+    /// Shards posts and users across the cluster, each being kept by this many nodes. If unset
+    /// every node keeps all of them.
+    #[serde(default)]
+    pub replication: Option<usize>,
+    /// An `env_logger` filter, such as `info` or `actix_ap_demo=debug`
+    #[serde(default = "default_log_level")]
+    pub log_level: String,
+    /// The SQLite file state is kept in, if unset nothing survives a restart
+    #[serde(default)]
+    pub database: Option<String>,
+    /// The bearer token clients post to each local user's outbox with, keyed by user id. Users
+    /// without one can't be acted for over HTTP.
+    #[serde(default)]
+    pub outbox_tokens: BTreeMap<UserId, String>,
+}
+
+impl Config {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, ConfigError> {
+        let mut contents = String::new();
+        File::open(path)?.read_to_string(&mut contents)?;
+
+        Config::parse(&contents)
+    }
+
+    pub fn parse(contents: &str) -> Result<Self, ConfigError> {
+        let config: Config = toml::from_str(contents)?;
+
+        // Peers answer through `peer_listen`, so without it they could never be joined
+        if config.peer_listen.is_none() && !config.peers.is_empty() {
+            return Err(ConfigError::Invalid("peers are set but peer_listen is not"));
+        }
+
+        // Anyone reaching `peer_listen` could otherwise pose as a peer
+        if config.peer_listen.is_some() && config.peer_secret.is_none() {
+            return Err(ConfigError::Invalid("peer_listen is set but peer_secret is not"));
+        }
+
+        if config.replication == Some(0) {
+            return Err(ConfigError::Invalid("replication must be at least 1"));
+        }
+
+        Ok(config)
+    }
+}
+
+fn default_log_level() -> String {
+    "info".to_owned()
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(io::Error),
+    Parse(toml::de::Error),
+    Invalid(&'static str),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConfigError::Io(ref e) => write!(f, "Could not read config: {}", e),
+            ConfigError::Parse(ref e) => write!(f, "Could not parse config: {}", e),
+            ConfigError::Invalid(reason) => write!(f, "Invalid config: {}", reason),
+        }
+    }
+}
+
+impl From<io::Error> for ConfigError {
+    fn from(e: io::Error) -> Self {
+        ConfigError::Io(e)
+    }
+}
+
+impl From<toml::de::Error> for ConfigError {
+    fn from(e: toml::de::Error) -> Self {
+        ConfigError::Parse(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_the_example_config() {
+        let config = Config::parse(include_str!("../config.toml.example")).unwrap();
+
+        assert_eq!(config.node_id, 0);
+        assert_eq!(config.listen, "127.0.0.1:8080");
+        assert_eq!(config.base_url, "http://127.0.0.1:8080");
+        assert_eq!(config.peer_listen, None);
+        assert_eq!(config.peer_secret, None);
+        assert!(config.peers.is_empty());
+        assert_eq!(config.replication, None);
+        assert_eq!(config.log_level, "info");
+        assert_eq!(config.database, None);
+        assert!(config.outbox_tokens.is_empty());
+    }
+
+    #[test]
+    fn outbox_tokens_are_keyed_by_user_id() {
+        let contents = r#"
+            node_id = 0
+            listen = "127.0.0.1:8080"
+            base_url = "http://127.0.0.1:8080"
+
+            [outbox_tokens]
+            "0-1" = "secret"
+        "#;
+
+        let config = Config::parse(contents).unwrap();
+
+        assert_eq!(
+            config.outbox_tokens.get(&"0-1".parse().unwrap()),
+            Some(&"secret".to_owned())
+        );
+    }
+
+    #[test]
+    fn replication_must_keep_a_copy() {
+        let contents = r#"
+            node_id = 0
+            listen = "127.0.0.1:8080"
+            base_url = "http://127.0.0.1:8080"
+            replication = 0
+        "#;
+
+        match Config::parse(contents) {
+            Err(ConfigError::Invalid(_)) => (),
+            res => panic!("Expected an invalid config, got {:?}", res),
+        }
+
+        let contents = contents.replace("replication = 0", "replication = 2");
+        assert_eq!(Config::parse(&contents).unwrap().replication, Some(2));
+    }
+
+    #[test]
+    fn peers_need_peer_listen() {
+        let contents = r#"
+            node_id = 1
+            listen = "127.0.0.1:8081"
+            base_url = "http://127.0.0.1:8081"
+            peers = ["127.0.0.1:9090"]
+        "#;
+
+        match Config::parse(contents) {
+            Err(ConfigError::Invalid(_)) => (),
+            res => panic!("Expected an invalid config, got {:?}", res),
+        }
+
+        let contents = format!(
+            "{}\npeer_listen = \"127.0.0.1:9091\"\npeer_secret = \"secret\"",
+            contents
+        );
+        let config = Config::parse(&contents).unwrap();
+
+        assert_eq!(config.peers, vec!["127.0.0.1:9090".to_owned()]);
+    }
+
+    #[test]
+    fn peer_listen_needs_a_secret() {
+        let contents = r#"
+            node_id = 0
+            listen = "127.0.0.1:8080"
+            base_url = "http://127.0.0.1:8080"
+            peer_listen = "127.0.0.1:9090"
+        "#;
+
+        match Config::parse(contents) {
+            Err(ConfigError::Invalid(_)) => (),
+            res => panic!("Expected an invalid config, got {:?}", res),
+        }
+
+        let contents = format!("{}\npeer_secret = \"secret\"", contents);
+        let config = Config::parse(&contents).unwrap();
+
+        assert_eq!(config.peer_secret, Some("secret".to_owned()));
+    }
+}
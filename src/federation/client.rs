@@ -0,0 +1,131 @@
+use std::fmt;
+use std::time::Duration;
+
+use actix::Arbiter;
+use futures::{Future, Stream};
+use futures::future::{self, Either};
+use hyper::{self, Method, Request, Uri};
+use hyper::client::HttpConnector;
+use hyper_openssl::HttpsConnector;
+use tokio_core::reactor::Timeout;
+
+use activitypub::ACTIVITY_JSON;
+
+/// How long a remote server gets to answer a request in full before it's given up on
+const REQUEST_TIMEOUT_SECS: u64 = 30;
+
+/// The HTTP requests federating with other servers takes
+///
+/// `Federation` only talks to remote servers through this trait, so tests can stand in for them
+/// without a network.
+pub trait Client: Send + Sync {
+    /// Fetches an ActivityStreams document, such as an actor
+    fn get(&self, url: &str) -> Box<Future<Item = Vec<u8>, Error = ClientError>>;
+
+    /// POSTs `body` with the given headers, such as an activity to an inbox
+    fn post(
+        &self,
+        url: &str,
+        headers: Vec<(String, String)>,
+        body: Vec<u8>,
+    ) -> Box<Future<Item = (), Error = ClientError>>;
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ClientError {
+    Request(String),
+    Status(u16),
+    Timeout,
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ClientError::Request(ref e) => write!(f, "Request failed: {}", e),
+            ClientError::Status(status) => write!(f, "Remote server responded {}", status),
+            ClientError::Timeout => write!(f, "Remote server did not respond in time"),
+        }
+    }
+}
+
+/// A `Client` making real requests, on the event loop of whichever arbiter calls it
+pub struct HttpClient;
+
+thread_local! {
+    static HYPER: hyper::Client<HttpsConnector<HttpConnector>> = {
+        let handle = Arbiter::handle();
+        let connector = HttpsConnector::new(4, handle).expect("Failed to set up TLS");
+
+        hyper::Client::configure().connector(connector).build(handle)
+    };
+}
+
+impl HttpClient {
+    fn request(
+        method: Method,
+        url: &str,
+        headers: Vec<(String, String)>,
+        body: Vec<u8>,
+    ) -> Box<Future<Item = Vec<u8>, Error = ClientError>> {
+        let uri: Uri = match url.parse() {
+            Ok(uri) => uri,
+            Err(e) => return Box::new(future::err(ClientError::Request(format!("{}", e)))),
+        };
+
+        let mut req = Request::new(method, uri);
+        for (name, value) in headers {
+            req.headers_mut().set_raw(name, value);
+        }
+        req.set_body(body);
+
+        let timeout = match Timeout::new(
+            Duration::from_secs(REQUEST_TIMEOUT_SECS),
+            Arbiter::handle(),
+        ) {
+            Ok(timeout) => timeout,
+            Err(e) => return Box::new(future::err(ClientError::Request(format!("{}", e)))),
+        };
+
+        let fut = HYPER
+            .with(|client| client.request(req))
+            .and_then(|res| {
+                let status = res.status();
+
+                res.body().concat2().map(move |body| (status, body))
+            })
+            .map_err(|e| ClientError::Request(format!("{}", e)))
+            .and_then(|(status, body)| {
+                if status.is_success() {
+                    Ok(body.to_vec())
+                } else {
+                    Err(ClientError::Status(status.as_u16()))
+                }
+            })
+            .select2(timeout)
+            .then(|res| match res {
+                Ok(Either::A((body, _))) => Ok(body),
+                Err(Either::A((e, _))) => Err(e),
+                Ok(Either::B(_)) => Err(ClientError::Timeout),
+                Err(Either::B((e, _))) => Err(ClientError::Request(format!("{}", e))),
+            });
+
+        Box::new(fut)
+    }
+}
+
+impl Client for HttpClient {
+    fn get(&self, url: &str) -> Box<Future<Item = Vec<u8>, Error = ClientError>> {
+        let headers = vec![("Accept".to_owned(), ACTIVITY_JSON.to_owned())];
+
+        HttpClient::request(Method::Get, url, headers, Vec::new())
+    }
+
+    fn post(
+        &self,
+        url: &str,
+        headers: Vec<(String, String)>,
+        body: Vec<u8>,
+    ) -> Box<Future<Item = (), Error = ClientError>> {
+        Box::new(HttpClient::request(Method::Post, url, headers, body).map(|_| ()))
+    }
+}
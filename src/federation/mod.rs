@@ -0,0 +1,210 @@
+//! Delivery to and lookups of users on other servers
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime};
+
+use futures::Future;
+use futures::future;
+use httpdate::fmt_http_date;
+use serde_json;
+
+use activitypub::{digest, key_id, IriMap, KeyPair, PublicKey, SignatureError, ToActivity,
+                  ACTIVITY_JSON};
+use actors::UserId;
+
+mod client;
+
+pub use self::client::{Client, ClientError, HttpClient};
+
+/// How long a fetched actor document is used before it's fetched again, picking up rotated keys
+const ACTOR_TTL_SECS: u64 = 24 * 60 * 60;
+
+/// How soon an actor document may be fetched again after their kept key failed to verify
+const ACTOR_REFETCH_SECS: u64 = 60;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum FederationError {
+    Client(ClientError),
+    Parse(String),
+    Signature(SignatureError),
+    InvalidUrl(String),
+}
+
+impl fmt::Display for FederationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FederationError::Client(ref e) => write!(f, "{}", e),
+            FederationError::Parse(ref e) => write!(f, "Invalid remote document: {}", e),
+            FederationError::Signature(ref e) => write!(f, "Could not sign delivery: {}", e),
+            FederationError::InvalidUrl(ref url) => write!(f, "Invalid URL: {}", url),
+        }
+    }
+}
+
+/// The parts of a remote actor's document needed to deliver to them and verify what they send
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct RemoteActor {
+    pub id: String,
+    pub inbox: String,
+    #[serde(default)]
+    pub public_key: Option<PublicKey>,
+}
+
+/// A fetched actor document, with when it was fetched
+struct CachedActor {
+    actor: RemoteActor,
+    fetched: Instant,
+}
+
+/// A handle on everything needed to talk to other servers, shared by every `Dispatch` and the
+/// HTTP routes
+#[derive(Clone)]
+pub struct Federation {
+    iris: Arc<Mutex<IriMap>>,
+    client: Arc<Client>,
+    actors: Arc<Mutex<HashMap<String, CachedActor>>>,
+}
+
+impl Federation {
+    pub fn new(iris: Arc<Mutex<IriMap>>, client: Arc<Client>) -> Self {
+        Federation {
+            iris: iris,
+            client: client,
+            actors: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn iris(&self) -> MutexGuard<IriMap> {
+        self.iris.lock().unwrap()
+    }
+
+    /// Fetches a remote actor's document, which is kept for a day of later lookups
+    pub fn actor(&self, iri: &str) -> Box<Future<Item = RemoteActor, Error = FederationError>> {
+        self.cached_actor(iri, Duration::from_secs(ACTOR_TTL_SECS))
+    }
+
+    /// Fetches a remote actor's document again, unless it was only just fetched
+    ///
+    /// For when the key kept for them fails to verify, as they may have rotated it since.
+    pub fn refetch_actor(
+        &self,
+        iri: &str,
+    ) -> Box<Future<Item = RemoteActor, Error = FederationError>> {
+        self.cached_actor(iri, Duration::from_secs(ACTOR_REFETCH_SECS))
+    }
+
+    fn cached_actor(
+        &self,
+        iri: &str,
+        max_age: Duration,
+    ) -> Box<Future<Item = RemoteActor, Error = FederationError>> {
+        if let Some(cached) = self.actors.lock().unwrap().get(iri) {
+            if cached.fetched.elapsed() < max_age {
+                return Box::new(future::ok(cached.actor.clone()));
+            }
+        }
+
+        let actors = self.actors.clone();
+        let iri = iri.to_owned();
+
+        let fut = self.client
+            .get(&iri)
+            .map_err(FederationError::Client)
+            .and_then(move |body| {
+                let actor: RemoteActor = serde_json::from_slice(&body)
+                    .map_err(|e| FederationError::Parse(format!("{}", e)))?;
+
+                let cached = CachedActor {
+                    actor: actor.clone(),
+                    fetched: Instant::now(),
+                };
+                actors.lock().unwrap().insert(iri, cached);
+
+                Ok(actor)
+            });
+
+        Box::new(fut)
+    }
+
+    /// Signs `message` with `source`'s keys and POSTs it to the remote `target`'s inbox
+    pub fn deliver<T>(
+        &self,
+        message: &T,
+        keys: &KeyPair,
+        source: UserId,
+        target: UserId,
+    ) -> Box<Future<Item = (), Error = FederationError>>
+    where
+        T: ToActivity,
+    {
+        let (activity, actor, key_id) = {
+            let mut iris = self.iris();
+
+            let activity = message.to_activity(&mut iris, source, target);
+            (activity, iris.user_iri(target), key_id(&iris.user_iri(source)))
+        };
+
+        let body = match serde_json::to_vec(&activity) {
+            Ok(body) => body,
+            Err(e) => return Box::new(future::err(FederationError::Parse(format!("{}", e)))),
+        };
+
+        let keys = keys.clone();
+        let client = self.client.clone();
+
+        let fut = self.actor(&actor).and_then(move |actor| {
+            let headers = match signed_headers(&keys, &key_id, &actor.inbox, &body) {
+                Ok(headers) => headers,
+                Err(e) => return future::Either::A(future::err(e)),
+            };
+
+            future::Either::B(
+                client
+                    .post(&actor.inbox, headers, body)
+                    .map_err(FederationError::Client),
+            )
+        });
+
+        Box::new(fut)
+    }
+}
+
+/// The headers for POSTing `body` to `url`, including its signature
+fn signed_headers(
+    keys: &KeyPair,
+    key_id: &str,
+    url: &str,
+    body: &[u8],
+) -> Result<Vec<(String, String)>, FederationError> {
+    let (host, path) = split_url(url).ok_or_else(|| FederationError::InvalidUrl(url.to_owned()))?;
+    let date = fmt_http_date(SystemTime::now());
+    let digest = digest(body);
+
+    let signature = keys.sign(
+        key_id,
+        "POST",
+        path,
+        &[("host", host), ("date", &date), ("digest", &digest)],
+    ).map_err(FederationError::Signature)?;
+
+    Ok(vec![
+        ("Host".to_owned(), host.to_owned()),
+        ("Date".to_owned(), date),
+        ("Digest".to_owned(), digest),
+        ("Signature".to_owned(), signature),
+        ("Content-Type".to_owned(), ACTIVITY_JSON.to_owned()),
+    ])
+}
+
+/// Splits an absolute URL into its host and path
+fn split_url(url: &str) -> Option<(&str, &str)> {
+    let rest = url.splitn(2, "://").nth(1)?;
+
+    match rest.find('/') {
+        Some(index) => Some(rest.split_at(index)),
+        None => Some((rest, "/")),
+    }
+}
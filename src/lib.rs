@@ -1,9 +1,26 @@
 extern crate actix;
 extern crate actix_web;
+extern crate base64;
 extern crate futures;
+extern crate httpdate;
+extern crate hyper;
+extern crate hyper_openssl;
 #[macro_use]
 extern crate log;
+extern crate openssl;
+extern crate rusqlite;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
+extern crate tokio_core;
+extern crate toml;
 #[cfg(test)]
 extern crate tokio_timer;
 
+pub mod activitypub;
 pub mod actors;
+pub mod config;
+pub mod federation;
+pub mod storage;
+pub mod web;
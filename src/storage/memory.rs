@@ -0,0 +1,54 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use super::{Backend, StorageError};
+
+/// Keeps every tree in memory, so nothing survives a restart
+#[derive(Default)]
+pub struct MemoryBackend {
+    trees: Mutex<BTreeMap<String, BTreeMap<String, String>>>,
+}
+
+impl MemoryBackend {
+    pub fn new() -> Self {
+        MemoryBackend::default()
+    }
+}
+
+impl Backend for MemoryBackend {
+    fn insert(&self, tree: &str, key: &str, value: &str) -> Result<(), StorageError> {
+        self.trees
+            .lock()
+            .unwrap()
+            .entry(tree.to_owned())
+            .or_insert_with(BTreeMap::new)
+            .insert(key.to_owned(), value.to_owned());
+
+        Ok(())
+    }
+
+    fn remove(&self, tree: &str, key: &str) -> Result<(), StorageError> {
+        if let Some(entries) = self.trees.lock().unwrap().get_mut(tree) {
+            entries.remove(key);
+        }
+
+        Ok(())
+    }
+
+    fn scan(&self, tree: &str, prefix: &str) -> Result<Vec<(String, String)>, StorageError> {
+        let trees = self.trees.lock().unwrap();
+
+        let entries = trees
+            .get(tree)
+            .map(|entries| {
+                entries
+                    .range(prefix.to_owned()..)
+                    .take_while(|&(key, _)| key.starts_with(prefix))
+                    .map(|(key, value)| (key.clone(), value.clone()))
+                    .collect()
+            })
+            .unwrap_or_else(Vec::new);
+
+        Ok(entries)
+    }
+}
@@ -0,0 +1,755 @@
+//! Persistence for the actors' state
+//!
+//! `Posts`, `Users`, `User`, `Blocklists` and the `IriMap` keep their state in memory as before,
+//! but write every change through a `Storage` handle and read it back when they're created.
+//! Whether that outlives the process depends on the `Backend` underneath.
+//!
+//! Everything is kept as string keys and JSON values in named trees, so a backend only needs to
+//! insert, remove and scan by key prefix.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::sync::Arc;
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json;
+
+use activitypub::KeyPair;
+use actors::{PostId, UserId};
+use actors::posts::{Content, Post, Reaction, Visibility};
+use actors::user::{Notification, Profile};
+
+mod memory;
+mod sqlite;
+
+pub use self::memory::MemoryBackend;
+pub use self::sqlite::SqliteBackend;
+
+const POSTS: &'static str = "posts";
+const USERS: &'static str = "users";
+const BLOCKS: &'static str = "blocks";
+const REACTIONS: &'static str = "reactions";
+const COUNTERS: &'static str = "counters";
+const REMOTE_ACTORS: &'static str = "remote_actors";
+const REMOTE_POSTS: &'static str = "remote_posts";
+const DELETED_POSTS: &'static str = "deleted_posts";
+const DELETED_USERS: &'static str = "deleted_users";
+const BOOSTS: &'static str = "boosts";
+const NOTIFICATIONS: &'static str = "notifications";
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum StorageError {
+    Backend(String),
+    Corrupt(String),
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StorageError::Backend(ref e) => write!(f, "Storage failed: {}", e),
+            StorageError::Corrupt(ref e) => write!(f, "Stored record is corrupt: {}", e),
+        }
+    }
+}
+
+impl From<serde_json::Error> for StorageError {
+    fn from(e: serde_json::Error) -> Self {
+        StorageError::Corrupt(e.to_string())
+    }
+}
+
+/// Where `Storage` keeps its trees
+pub trait Backend: Send + Sync {
+    fn insert(&self, tree: &str, key: &str, value: &str) -> Result<(), StorageError>;
+
+    fn remove(&self, tree: &str, key: &str) -> Result<(), StorageError>;
+
+    /// Every entry of `tree` whose key starts with `prefix`
+    fn scan(&self, tree: &str, prefix: &str) -> Result<Vec<(String, String)>, StorageError>;
+}
+
+/// The posts a user's timeline is made of
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Timeline {
+    /// Posts received from others
+    Home,
+    /// Posts the user wrote
+    Own,
+}
+
+impl Timeline {
+    fn tree(&self) -> &'static str {
+        match *self {
+            Timeline::Home => "timelines",
+            Timeline::Own => "own_posts",
+        }
+    }
+}
+
+/// The sets of other users a user keeps
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Relation {
+    Followers,
+    Following,
+    FollowRequests,
+    PendingFollows,
+}
+
+impl Relation {
+    fn tree(&self) -> &'static str {
+        match *self {
+            Relation::Followers => "followers",
+            Relation::Following => "following",
+            Relation::FollowRequests => "follow_requests",
+            Relation::PendingFollows => "pending_follows",
+        }
+    }
+}
+
+const RELATIONS: [Relation; 4] = [
+    Relation::Followers,
+    Relation::Following,
+    Relation::FollowRequests,
+    Relation::PendingFollows,
+];
+
+#[derive(Serialize, Deserialize)]
+struct PostRecord {
+    author: String,
+    mentions: Vec<String>,
+    /// Missing from posts saved before they had content
+    #[serde(default)]
+    content: Content,
+    #[serde(default)]
+    visibility: Visibility,
+    #[serde(default)]
+    audience: Vec<String>,
+    #[serde(default)]
+    in_reply_to: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct UserRecord {
+    username: String,
+    display_name: Option<String>,
+    summary: Option<String>,
+    private_key_pem: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct RemotePostRecord {
+    post_id: String,
+    #[serde(default)]
+    author: Option<String>,
+}
+
+/// A handle on the node's storage, shared by every actor
+///
+/// Failed writes are logged rather than failing the message that caused them, since the actors'
+/// in-memory state stays authoritative until the node restarts.
+#[derive(Clone)]
+pub struct Storage {
+    backend: Arc<Backend>,
+}
+
+impl Storage {
+    pub fn new(backend: Arc<Backend>) -> Self {
+        Storage { backend: backend }
+    }
+
+    /// Storage that lasts as long as the process, as state did before it was persisted
+    pub fn memory() -> Self {
+        Storage::new(Arc::new(MemoryBackend::new()))
+    }
+
+    pub fn counter(&self, name: &str) -> u64 {
+        self.scan(COUNTERS, name)
+            .into_iter()
+            .find(|&(ref key, _)| key == name)
+            .and_then(|(_, value)| value.parse().ok())
+            .unwrap_or(0)
+    }
+
+    pub fn set_counter(&self, name: &str, value: u64) {
+        self.log(self.backend.insert(COUNTERS, name, &value.to_string()));
+    }
+
+    pub fn posts(&self) -> Vec<Post> {
+        self.load(POSTS, "", |key, record: PostRecord| {
+            Ok(Post {
+                post_id: parse_post_id(key)?,
+                author: parse_user_id(&record.author)?,
+                mentions: parse_user_ids(&record.mentions)?,
+                content: record.content,
+                visibility: record.visibility,
+                audience: parse_user_ids(&record.audience)?,
+                in_reply_to: match record.in_reply_to {
+                    Some(ref parent) => Some(parse_post_id(parent)?),
+                    None => None,
+                },
+            })
+        })
+    }
+
+    pub fn save_post(&self, post: &Post) {
+        let record = PostRecord {
+            author: post.author.to_string(),
+            mentions: post.mentions.iter().map(|m| m.to_string()).collect(),
+            content: post.content.clone(),
+            visibility: post.visibility,
+            audience: post.audience.iter().map(|a| a.to_string()).collect(),
+            in_reply_to: post.in_reply_to.map(|parent| self.post_key(parent)),
+        };
+
+        self.save(POSTS, &self.post_key(post.post_id), &record);
+    }
+
+    pub fn delete_post(&self, post_id: PostId) {
+        self.log(self.backend.remove(POSTS, &self.post_key(post_id)));
+    }
+
+    /// Every deleted post still remembered, as (post_id, deleted_at) in milliseconds since the
+    /// epoch
+    pub fn deleted_posts(&self) -> Vec<(PostId, u64)> {
+        self.load(DELETED_POSTS, "", |key, deleted_at: u64| {
+            Ok((parse_post_id(key)?, deleted_at))
+        })
+    }
+
+    pub fn save_deleted_post(&self, post_id: PostId, deleted_at: u64) {
+        self.save(DELETED_POSTS, &self.post_key(post_id), &deleted_at);
+    }
+
+    pub fn forget_deleted_post(&self, post_id: PostId) {
+        self.log(self.backend.remove(DELETED_POSTS, &self.post_key(post_id)));
+    }
+
+    pub fn users(&self) -> Vec<(UserId, Profile, KeyPair)> {
+        self.load(USERS, "", |key, record: UserRecord| {
+            let keys = KeyPair::from_pem(&record.private_key_pem)
+                .map_err(|e| StorageError::Corrupt(e.to_string()))?;
+            let profile = Profile {
+                username: record.username,
+                display_name: record.display_name,
+                summary: record.summary,
+            };
+
+            Ok((parse_user_id(key)?, profile, keys))
+        })
+    }
+
+    pub fn save_user(&self, user_id: UserId, profile: &Profile, keys: &KeyPair) {
+        let private_key_pem = match keys.private_key_pem() {
+            Ok(pem) => pem,
+            Err(e) => return error!("Error: {}", e),
+        };
+
+        let record = UserRecord {
+            username: profile.username.clone(),
+            display_name: profile.display_name.clone(),
+            summary: profile.summary.clone(),
+            private_key_pem: private_key_pem,
+        };
+
+        self.save(USERS, &user_id.to_string(), &record);
+    }
+
+    /// Removes a user along with their timelines, relations, boosts and notifications
+    pub fn delete_user(&self, user_id: UserId) {
+        self.log(self.backend.remove(USERS, &user_id.to_string()));
+
+        let prefix = member_prefix(user_id);
+        let trees = RELATIONS
+            .iter()
+            .map(|relation| relation.tree())
+            .chain(vec![
+                Timeline::Home.tree(),
+                Timeline::Own.tree(),
+                BOOSTS,
+                NOTIFICATIONS,
+            ]);
+
+        for tree in trees {
+            for (key, _) in self.scan(tree, &prefix) {
+                self.log(self.backend.remove(tree, &key));
+            }
+        }
+    }
+
+    /// Every deleted user still remembered, as (user_id, deleted_at) like `deleted_posts`
+    pub fn deleted_users(&self) -> Vec<(UserId, u64)> {
+        self.load(DELETED_USERS, "", |key, deleted_at: u64| {
+            Ok((parse_user_id(key)?, deleted_at))
+        })
+    }
+
+    pub fn save_deleted_user(&self, user_id: UserId, deleted_at: u64) {
+        self.save(DELETED_USERS, &user_id.to_string(), &deleted_at);
+    }
+
+    pub fn forget_deleted_user(&self, user_id: UserId) {
+        self.log(self.backend.remove(DELETED_USERS, &user_id.to_string()));
+    }
+
+    pub fn timeline(&self, user_id: UserId, timeline: Timeline) -> BTreeSet<PostId> {
+        self.members(timeline.tree(), user_id, parse_post_id)
+    }
+
+    pub fn add_to_timeline(&self, user_id: UserId, timeline: Timeline, post_id: PostId) {
+        let key = member_key(user_id, &self.post_key(post_id));
+        self.log(self.backend.insert(timeline.tree(), &key, ""));
+    }
+
+    pub fn remove_from_timeline(&self, user_id: UserId, timeline: Timeline, post_id: PostId) {
+        let key = member_key(user_id, &self.post_key(post_id));
+        self.log(self.backend.remove(timeline.tree(), &key));
+    }
+
+    pub fn relation(&self, user_id: UserId, relation: Relation) -> BTreeSet<UserId> {
+        self.members(relation.tree(), user_id, parse_user_id)
+    }
+
+    pub fn add_relation(&self, user_id: UserId, relation: Relation, other: UserId) {
+        let key = member_key(user_id, &other.to_string());
+        self.log(self.backend.insert(relation.tree(), &key, ""));
+    }
+
+    pub fn remove_relation(&self, user_id: UserId, relation: Relation, other: UserId) {
+        let key = member_key(user_id, &other.to_string());
+        self.log(self.backend.remove(relation.tree(), &key));
+    }
+
+    /// The posts in a user's home timeline only because of boosts, with who boosted each
+    pub fn boosted(&self, user_id: UserId) -> BTreeMap<PostId, BTreeSet<UserId>> {
+        let prefix = member_prefix(user_id);
+        let boosts = self.load(BOOSTS, &prefix, |key, _: ()| {
+            let mut parts = key[prefix.len()..].splitn(2, ' ');
+            let post_id = parse_post_id(parts.next().unwrap_or(""))?;
+
+            Ok((post_id, parse_user_id(parts.next().unwrap_or(""))?))
+        });
+
+        let mut boosted = BTreeMap::new();
+        for (post_id, booster) in boosts {
+            boosted
+                .entry(post_id)
+                .or_insert_with(BTreeSet::new)
+                .insert(booster);
+        }
+
+        boosted
+    }
+
+    pub fn add_boost(&self, user_id: UserId, post_id: PostId, booster: UserId) {
+        let key = self.boost_key(user_id, post_id, booster);
+        self.log(self.backend.insert(BOOSTS, &key, ""));
+    }
+
+    pub fn remove_boost(&self, user_id: UserId, post_id: PostId, booster: UserId) {
+        let key = self.boost_key(user_id, post_id, booster);
+        self.log(self.backend.remove(BOOSTS, &key));
+    }
+
+    /// A user's notifications oldest first, as (sequence, notification)
+    pub fn notifications(&self, user_id: UserId) -> Vec<(u64, Notification)> {
+        let prefix = member_prefix(user_id);
+        let mut notifications = self.load(NOTIFICATIONS, &prefix, |key, sequence: u64| {
+            let mut parts = key[prefix.len()..].splitn(3, ' ');
+            let post_id = parse_post_id(parts.next().unwrap_or(""))?;
+
+            match parts.next() {
+                Some("like") => {
+                    let liker = parse_user_id(parts.next().unwrap_or(""))?;
+                    Ok((sequence, Notification::Liked(post_id, liker)))
+                }
+                _ => Err(StorageError::Corrupt(format!("Invalid notification {}", key))),
+            }
+        });
+
+        notifications.sort_by_key(|&(sequence, _)| sequence);
+        notifications
+    }
+
+    /// Saves a notification, `sequence` ordering it among the user's others
+    pub fn save_notification(&self, user_id: UserId, sequence: u64, notification: Notification) {
+        let key = self.notification_key(user_id, notification);
+        self.save(NOTIFICATIONS, &key, &sequence);
+    }
+
+    pub fn delete_notification(&self, user_id: UserId, notification: Notification) {
+        let key = self.notification_key(user_id, notification);
+        self.log(self.backend.remove(NOTIFICATIONS, &key));
+    }
+
+    /// Every block, as (acting_user, blocked_user)
+    pub fn blocks(&self) -> Vec<(UserId, UserId)> {
+        self.load(BLOCKS, "", |key, _: ()| {
+            let mut users = key.splitn(2, ' ');
+            let active_user = parse_user_id(users.next().unwrap_or(""))?;
+            let blocked_user = parse_user_id(users.next().unwrap_or(""))?;
+
+            Ok((active_user, blocked_user))
+        })
+    }
+
+    pub fn save_block(&self, active_user: UserId, blocked_user: UserId) {
+        let key = member_key(active_user, &blocked_user.to_string());
+        self.log(self.backend.insert(BLOCKS, &key, ""));
+    }
+
+    pub fn delete_block(&self, active_user: UserId, blocked_user: UserId) {
+        let key = member_key(active_user, &blocked_user.to_string());
+        self.log(self.backend.remove(BLOCKS, &key));
+    }
+
+    /// Every reaction, withdrawn ones included, as (post_id, reaction, user_id, version)
+    ///
+    /// Reactions saved before they had versions are read back as made once.
+    pub fn reactions(&self) -> Vec<(PostId, Reaction, UserId, u64)> {
+        self.load(REACTIONS, "", |key, version: Option<u64>| {
+            let mut parts = key.splitn(3, ' ');
+            let post_id = parse_post_id(parts.next().unwrap_or(""))?;
+            let reaction = parse_reaction(parts.next().unwrap_or(""))?;
+            let user_id = parse_user_id(parts.next().unwrap_or(""))?;
+
+            Ok((post_id, reaction, user_id, version.unwrap_or(1)))
+        })
+    }
+
+    /// Records the version of a reaction, odd while it stands, keeping withdrawn reactions so
+    /// that a restarted node doesn't take an older version from its peers
+    pub fn save_reaction(
+        &self,
+        post_id: PostId,
+        reaction: Reaction,
+        user_id: UserId,
+        version: u64,
+    ) {
+        let key = self.reaction_key(post_id, reaction, user_id);
+        self.save(REACTIONS, &key, &version);
+    }
+
+    pub fn delete_reaction(&self, post_id: PostId, reaction: Reaction, user_id: UserId) {
+        let key = self.reaction_key(post_id, reaction, user_id);
+        self.log(self.backend.remove(REACTIONS, &key));
+    }
+
+    /// Every remote actor given an id, as (iri, user_id)
+    pub fn remote_actors(&self) -> Vec<(String, UserId)> {
+        self.load(REMOTE_ACTORS, "", |key, user_id: String| {
+            Ok((key.to_owned(), parse_user_id(&user_id)?))
+        })
+    }
+
+    pub fn save_remote_actor(&self, iri: &str, user_id: UserId) {
+        self.save(REMOTE_ACTORS, iri, &user_id.to_string());
+    }
+
+    /// Every remote post given an id, as (iri, post_id, author), the author being known once the
+    /// post's creation was received
+    pub fn remote_posts(&self) -> Vec<(String, PostId, Option<UserId>)> {
+        self.load(REMOTE_POSTS, "", |key, record: RemotePostRecord| {
+            let author = match record.author {
+                Some(ref author) => Some(parse_user_id(author)?),
+                None => None,
+            };
+
+            Ok((key.to_owned(), parse_post_id(&record.post_id)?, author))
+        })
+    }
+
+    pub fn save_remote_post(&self, iri: &str, post_id: PostId, author: Option<UserId>) {
+        let record = RemotePostRecord {
+            post_id: self.post_key(post_id),
+            author: author.map(|author| author.to_string()),
+        };
+
+        self.save(REMOTE_POSTS, iri, &record);
+    }
+
+    fn reaction_key(&self, post_id: PostId, reaction: Reaction, user_id: UserId) -> String {
+        let reaction = match reaction {
+            Reaction::Boost => "boost",
+            Reaction::Like => "like",
+        };
+
+        format!("{} {} {}", self.post_key(post_id), reaction, user_id)
+    }
+
+    fn boost_key(&self, user_id: UserId, post_id: PostId, booster: UserId) -> String {
+        member_key(user_id, &format!("{} {}", self.post_key(post_id), booster))
+    }
+
+    fn notification_key(&self, user_id: UserId, notification: Notification) -> String {
+        let member = match notification {
+            Notification::Liked(post_id, liker) => {
+                format!("{} like {}", self.post_key(post_id), liker)
+            }
+        };
+
+        member_key(user_id, &member)
+    }
+
+    fn post_key(&self, post_id: PostId) -> String {
+        post_id.to_string()
+    }
+
+    fn members<T, F>(&self, tree: &str, user_id: UserId, parse: F) -> BTreeSet<T>
+    where
+        T: Ord,
+        F: Fn(&str) -> Result<T, StorageError>,
+    {
+        let prefix = member_prefix(user_id);
+
+        self.load(tree, &prefix, |key, _: ()| parse(&key[prefix.len()..]))
+            .into_iter()
+            .collect()
+    }
+
+    fn save<T: Serialize>(&self, tree: &str, key: &str, value: &T) {
+        let res = serde_json::to_string(value)
+            .map_err(StorageError::from)
+            .and_then(|value| self.backend.insert(tree, key, &value));
+
+        self.log(res);
+    }
+
+    /// Parses every entry under `prefix`, skipping the ones that fail
+    ///
+    /// Entries that only need a key are stored with an empty value, read back as `()`.
+    fn load<T, V, F>(&self, tree: &str, prefix: &str, parse: F) -> Vec<T>
+    where
+        V: DeserializeOwned,
+        F: Fn(&str, V) -> Result<T, StorageError>,
+    {
+        self.scan(tree, prefix)
+            .into_iter()
+            .filter_map(|(key, value)| {
+                let res = serde_json::from_str(if value.is_empty() { "null" } else { &value })
+                    .map_err(StorageError::from)
+                    .and_then(|value| parse(&key, value));
+
+                match res {
+                    Ok(item) => Some(item),
+                    Err(e) => {
+                        error!("Error loading {} {}: {}", tree, key, e);
+                        None
+                    }
+                }
+            })
+            .collect()
+    }
+
+    fn scan(&self, tree: &str, prefix: &str) -> Vec<(String, String)> {
+        match self.backend.scan(tree, prefix) {
+            Ok(entries) => entries,
+            Err(e) => {
+                error!("Error: {}", e);
+                Vec::new()
+            }
+        }
+    }
+
+    fn log(&self, res: Result<(), StorageError>) {
+        if let Err(e) = res {
+            error!("Error: {}", e);
+        }
+    }
+}
+
+fn member_prefix(user_id: UserId) -> String {
+    format!("{} ", user_id)
+}
+
+fn member_key(user_id: UserId, member: &str) -> String {
+    format!("{}{}", member_prefix(user_id), member)
+}
+
+fn parse_user_id(s: &str) -> Result<UserId, StorageError> {
+    s.parse()
+        .map_err(|_| StorageError::Corrupt(format!("Invalid user id {}", s)))
+}
+
+fn parse_reaction(s: &str) -> Result<Reaction, StorageError> {
+    match s {
+        "boost" => Ok(Reaction::Boost),
+        "like" => Ok(Reaction::Like),
+        _ => Err(StorageError::Corrupt(format!("Invalid reaction {}", s))),
+    }
+}
+
+fn parse_user_ids(ids: &[String]) -> Result<BTreeSet<UserId>, StorageError> {
+    ids.iter().map(|id| parse_user_id(id)).collect()
+}
+
+/// Parses a post key of the form `{posts_id}-{id}-{millis}.{counter}`
+fn parse_post_id(s: &str) -> Result<PostId, StorageError> {
+    s.parse()
+        .map_err(|_| StorageError::Corrupt(format!("Invalid post id {}", s)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeMap, BTreeSet};
+    use std::env;
+    use std::fs;
+    use std::process;
+    use std::sync::Arc;
+
+    use activitypub::KeyPair;
+    use actors::{Clock, Id, PostId, UserId};
+    use actors::posts::{Attachment, Content, Post, Reaction, Visibility};
+    use actors::user::{Notification, Profile};
+    use super::{Relation, SqliteBackend, Storage, Timeline};
+
+    fn user(id: u64) -> UserId {
+        UserId::new(Id::new(0), Id::new(id))
+    }
+
+    #[test]
+    fn relations_are_kept_per_user() {
+        let storage = Storage::memory();
+
+        storage.add_relation(user(1), Relation::Followers, user(2));
+        storage.add_relation(user(1), Relation::Followers, user(3));
+        storage.add_relation(user(11), Relation::Followers, user(4));
+        storage.add_relation(user(1), Relation::Following, user(5));
+        storage.remove_relation(user(1), Relation::Followers, user(3));
+
+        let followers: BTreeSet<_> = vec![user(2)].into_iter().collect();
+        assert_eq!(storage.relation(user(1), Relation::Followers), followers);
+
+        storage.delete_user(user(1));
+        assert!(storage.relation(user(1), Relation::Following).is_empty());
+        assert_eq!(storage.relation(user(11), Relation::Followers).len(), 1);
+    }
+
+    #[test]
+    fn boosts_are_kept_per_user() {
+        let storage = Storage::memory();
+        let mut clock = Clock::new();
+        let first = PostId::new(Id::new(0), Id::new(0), clock.now());
+        let second = PostId::new(Id::new(0), Id::new(1), clock.now());
+
+        storage.add_boost(user(1), first, user(2));
+        storage.add_boost(user(1), first, user(3));
+        storage.add_boost(user(1), second, user(2));
+        storage.add_boost(user(11), first, user(4));
+        storage.remove_boost(user(1), second, user(2));
+
+        let boosters: BTreeSet<_> = vec![user(2), user(3)].into_iter().collect();
+        let boosted: BTreeMap<_, _> = vec![(first, boosters)].into_iter().collect();
+        assert_eq!(storage.boosted(user(1)), boosted);
+
+        storage.delete_user(user(1));
+        assert!(storage.boosted(user(1)).is_empty());
+        assert_eq!(storage.boosted(user(11)).len(), 1);
+    }
+
+    #[test]
+    fn notifications_are_kept_in_order() {
+        let storage = Storage::memory();
+        let mut clock = Clock::new();
+        let post_id = PostId::new(Id::new(0), Id::new(0), clock.now());
+
+        for (sequence, liker) in (8..12).enumerate() {
+            let notification = Notification::Liked(post_id, user(liker));
+            storage.save_notification(user(1), sequence as u64, notification);
+        }
+        storage.delete_notification(user(1), Notification::Liked(post_id, user(9)));
+
+        assert_eq!(
+            storage.notifications(user(1)),
+            vec![
+                (0, Notification::Liked(post_id, user(8))),
+                (2, Notification::Liked(post_id, user(10))),
+                (3, Notification::Liked(post_id, user(11))),
+            ]
+        );
+
+        storage.delete_user(user(1));
+        assert!(storage.notifications(user(1)).is_empty());
+    }
+
+    #[test]
+    fn sqlite_state_survives_reopening() {
+        let path = env::temp_dir().join(format!("actix-ap-demo-{}.sqlite", process::id()));
+        let _ = fs::remove_file(&path);
+
+        let open = || Storage::new(Arc::new(SqliteBackend::open(&path).unwrap()));
+
+        let mut clock = Clock::new();
+        let first = PostId::new(Id::new(0), Id::new(0), clock.now());
+        let second = PostId::new(Id::new(0), Id::new(1), clock.now());
+        let content = Content {
+            html: "<p>hello</p>".to_owned(),
+            text: Some("hello".to_owned()),
+            summary: Some("greetings".to_owned()),
+            language: Some("en".to_owned()),
+            attachments: vec![Attachment {
+                url: "https://example.com/media/1.png".to_owned(),
+                media_type: "image/png".to_owned(),
+                alt: Some("a wave".to_owned()),
+                blurhash: None,
+            }],
+        };
+
+        {
+            let storage = open();
+
+            for &post_id in &[first, second] {
+                storage.save_post(&Post {
+                    post_id: post_id,
+                    author: user(1),
+                    mentions: vec![user(2)].into_iter().collect(),
+                    content: content.clone(),
+                    visibility: Visibility::FollowersOnly,
+                    audience: vec![user(2)].into_iter().collect(),
+                    in_reply_to: if post_id == second { Some(first) } else { None },
+                });
+                storage.add_to_timeline(user(1), Timeline::Own, post_id);
+            }
+
+            storage.save_user(user(1), &Profile::new("alice"), &KeyPair::generate().unwrap());
+            storage.save_block(user(1), user(3));
+            storage.save_reaction(second, Reaction::Boost, user(3), 1);
+            storage.save_reaction(second, Reaction::Like, user(3), 2);
+            storage.set_counter("posts", 2);
+        }
+
+        let storage = open();
+
+        let mut posts = storage.posts();
+        posts.sort();
+        assert_eq!(posts.len(), 2);
+        assert_eq!(posts[0].post_id, first);
+        assert_eq!(posts[1].post_id, second);
+        assert_eq!(posts[1].mentions, vec![user(2)].into_iter().collect());
+        assert_eq!(posts[1].content, content);
+        assert_eq!(posts[1].visibility, Visibility::FollowersOnly);
+        assert!(posts[1].visible_to(Some(user(2))));
+        assert_eq!(posts[1].in_reply_to, Some(first));
+
+        // Restored ids are the same wherever they're loaded from, and sort before new ones
+        let timeline = storage.timeline(user(1), Timeline::Own);
+        assert_eq!(timeline, posts.iter().map(|post| post.post_id).collect());
+        assert!(posts[1].post_id < PostId::new(Id::new(0), Id::new(2), clock.now()));
+
+        let users = storage.users();
+        assert_eq!(users.len(), 1);
+        assert_eq!(users[0].1.username, "alice");
+
+        assert_eq!(storage.blocks(), vec![(user(1), user(3))]);
+        assert_eq!(
+            storage.reactions(),
+            vec![
+                (second, Reaction::Boost, user(3), 1),
+                (second, Reaction::Like, user(3), 2),
+            ]
+        );
+        assert_eq!(storage.counter("posts"), 2);
+
+        let _ = fs::remove_file(&path);
+    }
+}
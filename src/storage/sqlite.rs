@@ -0,0 +1,74 @@
+use std::path::Path;
+use std::sync::Mutex;
+
+use rusqlite::{self, Connection};
+
+use super::{Backend, StorageError};
+
+const SCHEMA: &'static str = "CREATE TABLE IF NOT EXISTS entries (
+    tree TEXT NOT NULL,
+    key TEXT NOT NULL,
+    value TEXT NOT NULL,
+    PRIMARY KEY (tree, key)
+)";
+
+/// Keeps every tree in a single SQLite file
+pub struct SqliteBackend {
+    connection: Mutex<Connection>,
+}
+
+impl SqliteBackend {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, StorageError> {
+        let connection = Connection::open(path)?;
+        connection.execute_batch(SCHEMA)?;
+
+        Ok(SqliteBackend {
+            connection: Mutex::new(connection),
+        })
+    }
+}
+
+impl Backend for SqliteBackend {
+    fn insert(&self, tree: &str, key: &str, value: &str) -> Result<(), StorageError> {
+        self.connection.lock().unwrap().execute(
+            "INSERT OR REPLACE INTO entries (tree, key, value) VALUES (?1, ?2, ?3)",
+            &[&tree, &key, &value],
+        )?;
+
+        Ok(())
+    }
+
+    fn remove(&self, tree: &str, key: &str) -> Result<(), StorageError> {
+        self.connection.lock().unwrap().execute(
+            "DELETE FROM entries WHERE tree = ?1 AND key = ?2",
+            &[&tree, &key],
+        )?;
+
+        Ok(())
+    }
+
+    fn scan(&self, tree: &str, prefix: &str) -> Result<Vec<(String, String)>, StorageError> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare(
+            "SELECT key, value FROM entries WHERE tree = ?1 AND substr(key, 1, length(?2)) = ?2",
+        )?;
+
+        let rows = statement.query_map(&[&tree, &prefix], |row| {
+            row.get_checked(0)
+                .and_then(|key| row.get_checked(1).map(|value| (key, value)))
+        })?;
+
+        let mut entries = Vec::new();
+        for row in rows {
+            entries.push(row??);
+        }
+
+        Ok(entries)
+    }
+}
+
+impl From<rusqlite::Error> for StorageError {
+    fn from(e: rusqlite::Error) -> Self {
+        StorageError::Backend(e.to_string())
+    }
+}
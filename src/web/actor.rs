@@ -0,0 +1,39 @@
+use actix_web::{AsyncResponder, Error, HttpRequest, HttpResponse};
+use actix_web::httpcodes::{HTTPInternalServerError, HTTPNotFound};
+use futures::Future;
+use futures::future::{self, Either};
+
+use activitypub::Person;
+use actors::user::messages::{GetProfile, GetPublicKey};
+use super::{activity_json, lookup, user_id, State};
+
+/// `GET /users/{id}`, the user's actor document
+pub fn get(req: HttpRequest<State>) -> Box<Future<Item = HttpResponse, Error = Error>> {
+    let user_id = match user_id(&req) {
+        Some(user_id) => user_id,
+        None => return Box::new(future::ok(HTTPNotFound.into())),
+    };
+    let state = req.state().clone();
+
+    lookup(&state, user_id)
+        .and_then(|addr| match addr {
+            Some(addr) => Either::A(
+                addr.user()
+                    .call_fut(GetProfile)
+                    .join(addr.user().call_fut(GetPublicKey))
+                    .from_err()
+                    .map(Some),
+            ),
+            None => Either::B(future::ok(None)),
+        })
+        .and_then(move |res| match res {
+            Some((Ok(profile), Ok(public_key))) => {
+                let iris = state.federation.iris();
+
+                activity_json(Person::new(&iris, user_id, profile, public_key))
+            }
+            Some(_) => Ok(HTTPInternalServerError.into()),
+            None => Ok(HTTPNotFound.into()),
+        })
+        .responder()
+}
@@ -0,0 +1,42 @@
+use actix_web::{HttpRequest, HttpResponse};
+use actix_web::httpcodes::{HTTPForbidden, HTTPUnauthorized};
+use openssl::memcmp;
+
+use actors::UserId;
+use super::State;
+
+/// Checks that the request carries the bearer token configured for `user_id`
+///
+/// Requests without a token are `401 Unauthorized`, and ones with a token that isn't the user's,
+/// or for a user without one, are `403 Forbidden`.
+pub fn authorize(req: &HttpRequest<State>, user_id: UserId) -> Result<(), HttpResponse> {
+    let token = match bearer(req) {
+        Some(token) => token,
+        None => return Err(HTTPUnauthorized.into()),
+    };
+
+    let expected = match req.state().outbox_tokens.get(&user_id) {
+        Some(expected) => expected,
+        None => return Err(HTTPForbidden.into()),
+    };
+
+    // Compared in constant time, so the token can't be guessed a byte at a time
+    if token.len() == expected.len() && memcmp::eq(token.as_bytes(), expected.as_bytes()) {
+        Ok(())
+    } else {
+        Err(HTTPForbidden.into())
+    }
+}
+
+/// The token of an `Authorization: Bearer {token}` header
+fn bearer(req: &HttpRequest<State>) -> Option<String> {
+    let value = req.headers().get("authorization")?.to_str().ok()?;
+    let mut parts = value.splitn(2, ' ');
+
+    match (parts.next(), parts.next()) {
+        (Some(scheme), Some(token)) if scheme.eq_ignore_ascii_case("bearer") => {
+            Some(token.trim().to_owned())
+        }
+        _ => None,
+    }
+}
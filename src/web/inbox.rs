@@ -0,0 +1,96 @@
+use actix::SyncAddress;
+use actix_web::{AsyncResponder, Error, HttpRequest, HttpResponse};
+use actix_web::httpcodes::{HTTPBadRequest, HTTPNotFound, HTTPUnauthorized};
+use futures::Future;
+use futures::future::{self, Either};
+use serde_json;
+
+use activitypub::{Activity, Incoming};
+use actors::UserId;
+use actors::user::inbox::Inbox;
+use super::signature::SignedRequest;
+use super::{accepted, lookup, user_id, State};
+
+/// `POST /users/{id}/inbox`
+///
+/// Deliveries must be signed by the activity's actor, so nothing reaches the `Inbox` unverified.
+pub fn post(req: HttpRequest<State>) -> Box<Future<Item = HttpResponse, Error = Error>> {
+    let user_id = match user_id(&req) {
+        Some(user_id) => user_id,
+        None => return Box::new(future::ok(HTTPNotFound.into())),
+    };
+    let signed = match SignedRequest::from_request(&req) {
+        Ok(signed) => signed,
+        Err(e) => {
+            debug!("Rejecting unsigned delivery: {}", e);
+            return Box::new(future::ok(HTTPUnauthorized.into()));
+        }
+    };
+    let state = req.state().clone();
+
+    req.body()
+        .from_err()
+        .and_then(move |body| {
+            let activity: Activity = match serde_json::from_slice(&body) {
+                Ok(activity) => activity,
+                Err(e) => {
+                    debug!("Rejecting malformed delivery: {}", e);
+                    return Either::A(future::ok(HTTPBadRequest.into()));
+                }
+            };
+
+            Either::B(
+                signed
+                    .verify(&state, &body, &activity.actor)
+                    .and_then(move |verified| match verified {
+                        Ok(()) => Either::A(receive(state, user_id, activity)),
+                        Err(e) => {
+                            debug!("Rejecting delivery: {}", e);
+                            Either::B(future::ok(HTTPUnauthorized.into()))
+                        }
+                    }),
+            )
+        })
+        .responder()
+}
+
+/// Hands a verified activity to the user's `Inbox`
+fn receive(
+    state: State,
+    user_id: UserId,
+    activity: Activity,
+) -> Box<Future<Item = HttpResponse, Error = Error>> {
+    let incoming = Incoming::from_activity(activity, &mut state.federation.iris());
+
+    let fut = lookup(&state, user_id).map(move |addr| match (addr, incoming) {
+        (None, _) => HTTPNotFound.into(),
+        (_, Err(e)) => {
+            debug!("Rejecting incoming activity: {}", e);
+            HTTPBadRequest.into()
+        }
+        (Some(addr), Ok(incoming)) => {
+            deliver(addr.inbox(), incoming);
+            accepted()
+        }
+    });
+
+    Box::new(fut)
+}
+
+fn deliver(inbox: &SyncAddress<Inbox>, incoming: Incoming) {
+    match incoming {
+        Incoming::NewPost(msg) => inbox.send(msg),
+        Incoming::FollowRequest(msg) => inbox.send(msg),
+        Incoming::FollowRequestAccepted(msg) => inbox.send(msg),
+        Incoming::FollowRequestDenied(msg) => inbox.send(msg),
+        Incoming::Unfollowed(msg) => inbox.send(msg),
+        Incoming::FollowerRemoved(msg) => inbox.send(msg),
+        Incoming::Blocked(msg) => inbox.send(msg),
+        Incoming::Unblocked(msg) => inbox.send(msg),
+        Incoming::DeletePost(msg) => inbox.send(msg),
+        Incoming::Boosted(msg) => inbox.send(msg),
+        Incoming::Unboosted(msg) => inbox.send(msg),
+        Incoming::Liked(msg) => inbox.send(msg),
+        Incoming::Unliked(msg) => inbox.send(msg),
+    }
+}
@@ -0,0 +1,89 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use actix::SyncAddress;
+use actix_web::{Application, Body, Error, HttpRequest, HttpResponse, Method, StatusCode};
+use futures::Future;
+use serde::Serialize;
+
+use activitypub::ACTIVITY_JSON;
+use actors::UserId;
+use actors::peered::Peered;
+use actors::peered::messages::Message;
+use actors::users::{UserAddress, Users};
+use actors::users::messages::Lookup;
+use federation::Federation;
+
+mod actor;
+mod auth;
+mod inbox;
+mod outbox;
+mod signature;
+mod webfinger;
+
+/// State shared by every route, cloned into each worker's `Application`
+#[derive(Clone)]
+pub struct State {
+    users: SyncAddress<Peered<Users>>,
+    federation: Federation,
+    /// The bearer token each user's outbox can be posted to with
+    outbox_tokens: Arc<BTreeMap<UserId, String>>,
+}
+
+impl State {
+    pub fn new(
+        users: SyncAddress<Peered<Users>>,
+        federation: Federation,
+        outbox_tokens: BTreeMap<UserId, String>,
+    ) -> Self {
+        State {
+            users,
+            federation,
+            outbox_tokens: Arc::new(outbox_tokens),
+        }
+    }
+}
+
+pub fn app(state: State) -> Application<State> {
+    Application::with_state(state)
+        .resource("/.well-known/webfinger", |r| {
+            r.method(Method::GET).f(webfinger::get);
+        })
+        .resource("/users/{id}", |r| {
+            r.method(Method::GET).f(actor::get);
+        })
+        .resource("/users/{id}/inbox", |r| {
+            r.method(Method::POST).f(inbox::post);
+        })
+        .resource("/users/{id}/outbox", |r| {
+            r.method(Method::GET).f(outbox::get);
+            r.method(Method::POST).f(outbox::post);
+        })
+}
+
+/// The `UserId` named by the `{id}` segment of the route
+fn user_id(req: &HttpRequest<State>) -> Option<UserId> {
+    req.match_info().get("id").and_then(|id| id.parse().ok())
+}
+
+/// Resolves a local user, yielding `None` if no such user exists
+fn lookup(
+    state: &State,
+    user_id: UserId,
+) -> Box<Future<Item = Option<UserAddress>, Error = Error>> {
+    let fut = state
+        .users
+        .call_fut(Message::new(Lookup(user_id)))
+        .from_err()
+        .map(|res| res.ok());
+
+    Box::new(fut)
+}
+
+fn accepted() -> HttpResponse {
+    HttpResponse::new(StatusCode::ACCEPTED, Body::Empty)
+}
+
+fn activity_json<T: Serialize>(value: T) -> Result<HttpResponse, Error> {
+    HttpResponse::Ok().content_type(ACTIVITY_JSON).json(value)
+}
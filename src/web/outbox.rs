@@ -0,0 +1,164 @@
+use actix_web::{AsyncResponder, Body, Error, HttpRequest, HttpResponse, StatusCode};
+use actix_web::httpcodes::{HTTPBadRequest, HTTPForbidden, HTTPInternalServerError, HTTPNotFound};
+use futures::Future;
+use futures::future::{self, Either};
+
+use activitypub::{Activity, Collection, Outgoing};
+use actors::dispatch::DispatchError;
+use actors::posts::PostsError;
+use actors::user::UserError;
+use actors::user::messages::GetVisiblePostIds;
+use actors::users::UsersError;
+use actors::users::UserAddress;
+use super::auth::authorize;
+use super::{accepted, activity_json, lookup, user_id, State};
+
+/// `GET /users/{id}/outbox`, listing the user's posts newest first
+///
+/// Requests aren't signed by anyone, so only the posts anyone may read are listed.
+pub fn get(req: HttpRequest<State>) -> Box<Future<Item = HttpResponse, Error = Error>> {
+    let user_id = match user_id(&req) {
+        Some(user_id) => user_id,
+        None => return Box::new(future::ok(HTTPNotFound.into())),
+    };
+    let state = req.state().clone();
+
+    lookup(&state, user_id)
+        .and_then(|addr| match addr {
+            Some(addr) => Either::A(
+                addr.outbox()
+                    .call_fut(GetVisiblePostIds(0, None))
+                    .from_err()
+                    .map(Some),
+            ),
+            None => Either::B(future::ok(None)),
+        })
+        .and_then(move |post_ids| match post_ids {
+            Some(Ok(post_ids)) => {
+                let iris = state.federation.iris();
+                let id = format!("{}/outbox", iris.user_iri(user_id));
+                let items = post_ids
+                    .into_iter()
+                    .rev()
+                    .map(|post_id| iris.post_iri(post_id))
+                    .collect();
+
+                activity_json(Collection::ordered(id, items))
+            }
+            Some(Err(_)) => Ok(HTTPInternalServerError.into()),
+            None => Ok(HTTPNotFound.into()),
+        })
+        .responder()
+}
+
+/// `POST /users/{id}/outbox`, submitting an activity on the user's behalf
+///
+/// Only clients holding the user's bearer token can act for them.
+pub fn post(req: HttpRequest<State>) -> Box<Future<Item = HttpResponse, Error = Error>> {
+    let user_id = match user_id(&req) {
+        Some(user_id) => user_id,
+        None => return Box::new(future::ok(HTTPNotFound.into())),
+    };
+    if let Err(res) = authorize(&req, user_id) {
+        return Box::new(future::ok(res));
+    }
+    let state = req.state().clone();
+
+    req.json()
+        .content_type("")
+        .from_err()
+        .and_then(move |activity: Activity| {
+            let outgoing =
+                Outgoing::from_activity(activity, user_id, &mut state.federation.iris());
+
+            lookup(&state, user_id).map(move |addr| (state, addr, outgoing))
+        })
+        .and_then(|(state, addr, outgoing)| match (addr, outgoing) {
+            (None, _) => Either::A(future::ok(HTTPNotFound.into())),
+            (_, Err(e)) => {
+                debug!("Rejecting outgoing activity: {}", e);
+                Either::A(future::ok(HTTPBadRequest.into()))
+            }
+            (Some(addr), Ok(outgoing)) => Either::B(submit(state, &addr, outgoing)),
+        })
+        .responder()
+}
+
+/// Hands the activity to the user's `Outbox`, waiting on it so the response can point at the
+/// created post, or say why the activity couldn't be carried out.
+fn submit(
+    state: State,
+    addr: &UserAddress,
+    outgoing: Outgoing,
+) -> Box<Future<Item = HttpResponse, Error = Error>> {
+    let outbox = addr.outbox();
+
+    match outgoing {
+        Outgoing::NewPost(msg) => {
+            let fut = outbox
+                .call_fut(msg)
+                .from_err()
+                .and_then(move |res| match res {
+                    Ok(post_id) => {
+                        let iri = state.federation.iris().post_iri(post_id);
+
+                        HttpResponse::Created()
+                            .header("Location", iri.as_str())
+                            .finish()
+                            .map_err(Error::from)
+                    }
+                    Err(e) => Ok(error_response(e)),
+                });
+
+            Box::new(fut)
+        }
+        Outgoing::RequestFollow(msg) => waited(outbox.call_fut(msg)),
+        Outgoing::AcceptFollowRequest(msg) => waited(outbox.call_fut(msg)),
+        Outgoing::DenyFollowRequest(msg) => waited(outbox.call_fut(msg)),
+        Outgoing::Unfollow(msg) => waited(outbox.call_fut(msg)),
+        Outgoing::RemoveFollower(msg) => waited(outbox.call_fut(msg)),
+        Outgoing::BlockUser(msg) => waited(outbox.call_fut(msg)),
+        Outgoing::UnblockUser(msg) => waited(outbox.call_fut(msg)),
+        Outgoing::DeletePost(msg) => waited(outbox.call_fut(msg)),
+        Outgoing::Boost(msg) => waited(outbox.call_fut(msg)),
+        Outgoing::Unboost(msg) => waited(outbox.call_fut(msg)),
+        Outgoing::Like(msg) => waited(outbox.call_fut(msg)),
+        Outgoing::Unlike(msg) => waited(outbox.call_fut(msg)),
+    }
+}
+
+/// Answers with `202 Accepted` once `fut` succeeded
+fn waited<F>(fut: F) -> Box<Future<Item = HttpResponse, Error = Error>>
+where
+    F: Future<Item = Result<(), UserError>> + 'static,
+    Error: From<F::Error>,
+{
+    let fut = fut.from_err().map(|res| match res {
+        Ok(()) => accepted(),
+        Err(e) => error_response(e),
+    });
+
+    Box::new(fut)
+}
+
+/// The response for an activity the `Outbox` couldn't carry out
+fn error_response(e: UserError) -> HttpResponse {
+    debug!("Outgoing activity failed: {}", e);
+
+    match e {
+        UserError::Dispatch(DispatchError::Blocked(..)) => HTTPForbidden.into(),
+        UserError::Dispatch(DispatchError::Users(UsersError::UserNotFound(_))) => {
+            HTTPNotFound.into()
+        }
+        UserError::Posts(PostsError::PostNotFound(_)) => HTTPNotFound.into(),
+        UserError::Posts(PostsError::NotShareable(_))
+        | UserError::Posts(PostsError::NotAuthor(_)) => HTTPForbidden.into(),
+        UserError::Dispatch(DispatchError::PeerUnreachable(_))
+        | UserError::Dispatch(DispatchError::Users(UsersError::UserElsewhere(_)))
+        | UserError::Dispatch(DispatchError::Users(UsersError::Peered(_)))
+        | UserError::Posts(PostsError::Peered(_)) => {
+            HttpResponse::new(StatusCode::BAD_GATEWAY, Body::Empty)
+        }
+        _ => HTTPInternalServerError.into(),
+    }
+}
@@ -0,0 +1,175 @@
+use std::collections::HashMap;
+
+use actix_web::{Error, HttpRequest};
+use futures::Future;
+use futures::future::{self, Either};
+
+use activitypub::{Signature, SignatureError};
+use actors::user::messages::GetPublicKey;
+use super::{lookup, State};
+
+/// The parts of a signed request needed to check its signature once the body has arrived
+pub struct SignedRequest {
+    signature: Signature,
+    method: String,
+    path: String,
+    headers: HashMap<String, String>,
+}
+
+impl SignedRequest {
+    pub fn from_request(req: &HttpRequest<State>) -> Result<Self, SignatureError> {
+        let signature: Signature = header(req, "signature")
+            .ok_or_else(|| SignatureError::MissingHeader("signature".to_owned()))?
+            .parse()?;
+
+        let mut names = signature.headers.clone();
+        names.push("digest".to_owned());
+
+        let headers = names
+            .into_iter()
+            .filter_map(|name| header(req, &name).map(|value| (name, value)))
+            .collect();
+
+        Ok(SignedRequest {
+            signature: signature,
+            method: req.method().as_str().to_owned(),
+            // The request target covers the query string as well as the path
+            path: req.uri()
+                .path_and_query()
+                .map(|path| path.as_str())
+                .unwrap_or_else(|| req.path())
+                .to_owned(),
+            headers: headers,
+        })
+    }
+
+    /// Checks that `actor` signed the request and that the body is the one they signed
+    pub fn verify(
+        self,
+        state: &State,
+        body: &[u8],
+        actor: &str,
+    ) -> Box<Future<Item = Result<(), SignatureError>, Error = Error>> {
+        let digest = self.headers.get("digest").map(|digest| digest.as_str());
+
+        if let Err(e) = self.signature.verify_digest(digest, body) {
+            return Box::new(future::ok(Err(e)));
+        }
+
+        if owner(&self.signature.key_id) != actor {
+            let e = SignatureError::KeyOwner(self.signature.key_id);
+            return Box::new(future::ok(Err(e)));
+        }
+
+        let state = state.clone();
+        let actor = actor.to_owned();
+
+        let fut = public_key(&state, &actor, false).and_then(move |key| {
+            match self.verify_with(key) {
+                // Remote actors may have rotated their key since it was fetched
+                Err(SignatureError::Invalid) | Err(SignatureError::UnknownKey(_)) => Either::A(
+                    public_key(&state, &actor, true).map(move |key| self.verify_with(key)),
+                ),
+                res => Either::B(future::ok(res)),
+            }
+        });
+
+        Box::new(fut)
+    }
+
+    fn verify_with(&self, public_key: Option<String>) -> Result<(), SignatureError> {
+        let public_key =
+            public_key.ok_or_else(|| SignatureError::UnknownKey(self.signature.key_id.clone()))?;
+        let headers = &self.headers;
+
+        self.signature
+            .verify(&public_key, &self.method, &self.path, |name| {
+                headers.get(name).cloned()
+            })
+    }
+}
+
+fn header(req: &HttpRequest<State>, name: &str) -> Option<String> {
+    req.headers()
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.to_owned())
+}
+
+/// The actor a `keyId` belongs to, by the `{actor}#{key}` convention
+fn owner(key_id: &str) -> &str {
+    key_id.split('#').next().unwrap_or(key_id)
+}
+
+/// The PEM encoded public key of an actor
+///
+/// Local users' keys are asked for directly, remote actors' keys come from their actor document,
+/// which is fetched again first when `refetch` is set.
+fn public_key(
+    state: &State,
+    actor: &str,
+    refetch: bool,
+) -> Box<Future<Item = Option<String>, Error = Error>> {
+    let user_id = {
+        let mut iris = state.federation.iris();
+
+        match iris.user_id(actor) {
+            Ok(user_id) if !iris.is_remote(user_id) => Some(user_id),
+            Ok(_) => None,
+            Err(_) => return Box::new(future::ok(None)),
+        }
+    };
+
+    let user_id = match user_id {
+        Some(user_id) => user_id,
+        None => return remote_public_key(state, actor, refetch),
+    };
+
+    let fut = lookup(state, user_id).and_then(|addr| match addr {
+        Some(addr) => Either::A(
+            addr.user()
+                .call_fut(GetPublicKey)
+                .from_err()
+                .map(|res| res.ok()),
+        ),
+        None => Either::B(future::ok(None)),
+    });
+
+    Box::new(fut)
+}
+
+fn remote_public_key(
+    state: &State,
+    actor: &str,
+    refetch: bool,
+) -> Box<Future<Item = Option<String>, Error = Error>> {
+    let owner = actor.to_owned();
+
+    let remote = if refetch {
+        state.federation.refetch_actor(actor)
+    } else {
+        state.federation.actor(actor)
+    };
+
+    let fut = remote.then(move |res| {
+        let public_key = match res {
+            Ok(remote) => remote
+                .public_key
+                .and_then(|public_key| {
+                    if public_key.owner == owner {
+                        Some(public_key.public_key_pem)
+                    } else {
+                        None
+                    }
+                }),
+            Err(e) => {
+                warn!("Could not fetch {}: {}", owner, e);
+                None
+            }
+        };
+
+        Ok::<_, Error>(public_key)
+    });
+
+    Box::new(fut)
+}
@@ -0,0 +1,78 @@
+use actix_web::{AsyncResponder, Error, HttpRequest, HttpResponse};
+use actix_web::httpcodes::{HTTPBadRequest, HTTPNotFound};
+use futures::Future;
+use futures::future;
+
+use activitypub::ACTIVITY_JSON;
+use actors::peered::messages::Message;
+use actors::users::messages::LookupUsername;
+use super::State;
+
+const JRD_JSON: &'static str = "application/jrd+json";
+
+/// A JSON Resource Descriptor, as defined by RFC 7033
+#[derive(Serialize)]
+struct Jrd {
+    subject: String,
+    aliases: Vec<String>,
+    links: Vec<Link>,
+}
+
+#[derive(Serialize)]
+struct Link {
+    rel: &'static str,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    href: String,
+}
+
+/// `GET /.well-known/webfinger?resource=acct:{username}@{host}`
+pub fn get(req: HttpRequest<State>) -> Box<Future<Item = HttpResponse, Error = Error>> {
+    let state = req.state().clone();
+
+    let host = state.federation.iris().host().to_owned();
+
+    let username = match req.query().get("resource").map(|resource| account(resource, &host)) {
+        Some(Some(username)) => username,
+        Some(None) => return Box::new(future::ok(HTTPNotFound.into())),
+        None => return Box::new(future::ok(HTTPBadRequest.into())),
+    };
+    let subject = format!("acct:{}@{}", username, host);
+
+    state
+        .users
+        .call_fut(Message::new(LookupUsername(username)))
+        .from_err()
+        .and_then(move |res| match res {
+            Ok((user_id, _)) => {
+                let href = state.federation.iris().user_iri(user_id);
+                let jrd = Jrd {
+                    subject: subject,
+                    aliases: vec![href.clone()],
+                    links: vec![
+                        Link {
+                            rel: "self",
+                            kind: ACTIVITY_JSON,
+                            href: href,
+                        },
+                    ],
+                };
+
+                HttpResponse::Ok().content_type(JRD_JSON).json(jrd)
+            }
+            Err(_) => Ok(HTTPNotFound.into()),
+        })
+        .responder()
+}
+
+/// The username in an `acct:` resource, if it names an account on `host`
+fn account(resource: &str, host: &str) -> Option<String> {
+    let mut parts = resource.trim_left_matches("acct:").rsplitn(2, '@');
+
+    match (parts.next(), parts.next()) {
+        (Some(account_host), Some(username)) if account_host == host => {
+            Some(username.to_owned())
+        }
+        _ => None,
+    }
+}